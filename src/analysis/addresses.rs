@@ -0,0 +1,378 @@
+use std::collections::{HashMap, VecDeque};
+use walrus::ir::{BinaryOp, Block, IfElse, Instr, InstrLocId, InstrSeqId, Loop, Value};
+use walrus::{
+    ConstExpr, DataKind, FunctionId, GlobalId, GlobalKind, LocalFunction, LocalId, Module, ValType,
+};
+
+// What an i32 is known to hold, relative to the start of the function
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Symbolic {
+    Const(i64),
+    // stack pointer at function entry plus a constant
+    Stack(i64),
+    // a constant plus something unknown, a pointer into a table or array at that address
+    Indexed(i64),
+    Unknown,
+}
+
+// Where a memory access lands, the `MemArg` offset included
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Address(u32),
+    // from this address on
+    Indexed(u32),
+    // offset from the stack pointer at function entry
+    Stack(i64),
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    // inside the active data segment with this index
+    Data(usize),
+    // constant address outside of the data segments
+    Static,
+    // frame of the function doing the access
+    Stack,
+    // heap pointers, parameters, anything the analysis can't follow
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone)]
+pub struct MemoryAccess {
+    pub func: FunctionId,
+    // code offset of the load or store in the input, None for instructions added by a pass
+    pub offset: Option<u32>,
+    pub kind: AccessKind,
+    pub width: u32,
+    pub target: Target,
+    pub region: Region,
+}
+
+// The mutable i32 global functions subtract their frame size from, `global.get g;
+// i32.const n; i32.sub` in the prologues
+pub fn stack_pointer(module: &Module) -> Option<GlobalId> {
+    let mut counts = HashMap::<GlobalId, usize>::new();
+
+    for (_, func) in module.funcs.iter_local() {
+        for seq in seqs(func) {
+            for window in func.block(seq).instrs.windows(3) {
+                if let [
+                    (Instr::GlobalGet(get), _),
+                    (Instr::Const(_), _),
+                    (Instr::Binop(op), _),
+                ] = window
+                    && matches!(op.op, BinaryOp::I32Sub)
+                {
+                    *counts.entry(get.global).or_default() += 1;
+                }
+            }
+        }
+    }
+
+    counts
+        .into_iter()
+        .filter(|(id, _)| {
+            let global = module.globals.get(*id);
+            global.mutable
+                && global.ty == ValType::I32
+                && matches!(global.kind, GlobalKind::Local(_))
+        })
+        .max_by_key(|(id, count)| (*count, std::cmp::Reverse(id.index())))
+        .map(|(id, _)| id)
+}
+
+// Every sequence of the function, entry first
+pub fn seqs(func: &LocalFunction) -> Vec<InstrSeqId> {
+    let mut seqs = Vec::new();
+    let mut stack = VecDeque::new();
+    stack.push_front(func.entry_block());
+
+    while let Some(seq_id) = stack.pop_back() {
+        seqs.push(seq_id);
+        for (instr, _) in func.block(seq_id).instrs.iter() {
+            match instr {
+                Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => stack.push_front(*seq),
+                Instr::IfElse(IfElse {
+                    consequent,
+                    alternative,
+                }) => {
+                    stack.push_front(*consequent);
+                    stack.push_front(*alternative);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    seqs
+}
+
+fn add(a: Symbolic, b: Symbolic) -> Symbolic {
+    match (a, b) {
+        (Symbolic::Const(a), Symbolic::Const(b)) => Symbolic::Const(a.wrapping_add(b)),
+        (Symbolic::Stack(a), Symbolic::Const(b)) | (Symbolic::Const(b), Symbolic::Stack(a)) => {
+            Symbolic::Stack(a.wrapping_add(b))
+        }
+        (Symbolic::Const(a), Symbolic::Unknown) | (Symbolic::Unknown, Symbolic::Const(a)) => {
+            Symbolic::Indexed(a)
+        }
+        (Symbolic::Indexed(a), Symbolic::Const(b)) | (Symbolic::Const(b), Symbolic::Indexed(a)) => {
+            Symbolic::Indexed(a.wrapping_add(b))
+        }
+        (Symbolic::Indexed(a), Symbolic::Unknown) | (Symbolic::Unknown, Symbolic::Indexed(a)) => {
+            Symbolic::Indexed(a)
+        }
+        _ => Symbolic::Unknown,
+    }
+}
+
+fn sub(a: Symbolic, b: Symbolic) -> Symbolic {
+    match (a, b) {
+        (Symbolic::Const(a), Symbolic::Const(b)) => Symbolic::Const(a.wrapping_sub(b)),
+        (Symbolic::Stack(a), Symbolic::Const(b)) => Symbolic::Stack(a.wrapping_sub(b)),
+        (Symbolic::Indexed(a), Symbolic::Const(b)) => Symbolic::Indexed(a.wrapping_sub(b)),
+        _ => Symbolic::Unknown,
+    }
+}
+
+// Values of the locals of one function. Flow insensitive: a local assigned the same value
+// everywhere holds it, the implicit zero before the first assignment is ignored.
+pub struct FunctionValues<'a> {
+    module: &'a Module,
+    stack_pointer: Option<GlobalId>,
+    locals: HashMap<LocalId, Symbolic>,
+}
+
+impl<'a> FunctionValues<'a> {
+    pub fn new(
+        module: &'a Module,
+        func: &'a LocalFunction,
+        stack_pointer: Option<GlobalId>,
+    ) -> Self {
+        let mut values = FunctionValues {
+            module,
+            stack_pointer,
+            locals: HashMap::new(),
+        };
+
+        // every round resolves one more link of chains like `a = c; b = a + 4`
+        for _ in 0..8 {
+            let mut assigned = HashMap::<LocalId, Symbolic>::new();
+            for seq in seqs(func) {
+                let instrs = &func.block(seq).instrs;
+                for (idx, (instr, _)) in instrs.iter().enumerate() {
+                    let local = match instr {
+                        Instr::LocalSet(set) => set.local,
+                        Instr::LocalTee(tee) => tee.local,
+                        _ => continue,
+                    };
+
+                    let value = values
+                        .operand(instrs, idx)
+                        .map(|(value, _)| value)
+                        .unwrap_or(Symbolic::Unknown);
+                    assigned
+                        .entry(local)
+                        .and_modify(|known| {
+                            if *known != value {
+                                *known = Symbolic::Unknown
+                            }
+                        })
+                        .or_insert(value);
+                }
+            }
+
+            if assigned == values.locals {
+                break;
+            }
+            values.locals = assigned;
+        }
+
+        values
+    }
+
+    pub fn local(&self, local: LocalId) -> Symbolic {
+        self.locals
+            .get(&local)
+            .copied()
+            .unwrap_or(Symbolic::Unknown)
+    }
+
+    fn global(&self, global: GlobalId) -> Symbolic {
+        if Some(global) == self.stack_pointer {
+            return Symbolic::Stack(0);
+        }
+
+        let global = self.module.globals.get(global);
+        match global.kind {
+            GlobalKind::Local(ConstExpr::Value(Value::I32(i))) if !global.mutable => {
+                Symbolic::Const(i as i64)
+            }
+            _ => Symbolic::Unknown,
+        }
+    }
+
+    // Value of the operand ending right before `end`, and where its expression starts.
+    // None when the expression has an instruction the analysis doesn't model.
+    pub fn operand(&self, instrs: &[(Instr, InstrLocId)], end: usize) -> Option<(Symbolic, usize)> {
+        let idx = end.checked_sub(1)?;
+        let (instr, _) = &instrs[idx];
+
+        match instr {
+            Instr::Const(c) => match c.value {
+                Value::I32(i) => Some((Symbolic::Const(i as i64), idx)),
+                _ => Some((Symbolic::Unknown, idx)),
+            },
+            Instr::LocalGet(get) => Some((self.local(get.local), idx)),
+            Instr::GlobalGet(get) => Some((self.global(get.global), idx)),
+            Instr::LocalTee(_) => self.operand(instrs, idx),
+            Instr::Binop(op) => {
+                let (right, start) = self.operand(instrs, idx)?;
+                let (left, start) = self.operand(instrs, start)?;
+                let value = match op.op {
+                    BinaryOp::I32Add => add(left, right),
+                    BinaryOp::I32Sub => sub(left, right),
+                    _ => Symbolic::Unknown,
+                };
+                Some((value, start))
+            }
+            Instr::Unop(_) | Instr::Load(_) => {
+                let (_, start) = self.operand(instrs, idx)?;
+                Some((Symbolic::Unknown, start))
+            }
+            Instr::Select(_) => {
+                let (_, start) = self.operand(instrs, idx)?;
+                let (b, start) = self.operand(instrs, start)?;
+                let (a, start) = self.operand(instrs, start)?;
+                Some((if a == b { a } else { Symbolic::Unknown }, start))
+            }
+            Instr::Call(call) => {
+                let ty = self.module.types.get(self.module.funcs.get(call.func).ty());
+                if ty.results().len() != 1 {
+                    return None;
+                }
+
+                let mut start = idx;
+                for _ in ty.params() {
+                    (_, start) = self.operand(instrs, start)?;
+                }
+                Some((Symbolic::Unknown, start))
+            }
+            _ => None,
+        }
+    }
+}
+
+// Data segments as (start, end) by index, empty for passive ones
+pub fn segments(module: &Module) -> Vec<(i64, i64)> {
+    module
+        .data
+        .iter()
+        .map(|data| match &data.kind {
+            DataKind::Active {
+                offset: ConstExpr::Value(Value::I32(i)),
+                ..
+            } => (*i as i64, *i as i64 + data.value.len() as i64),
+            _ => (0, 0),
+        })
+        .collect()
+}
+
+fn target(value: Symbolic, offset: u32) -> Target {
+    let address = |a: i64| u32::try_from(a + offset as i64).ok();
+    match value {
+        Symbolic::Const(a) => address(a).map_or(Target::Unknown, Target::Address),
+        Symbolic::Indexed(a) => address(a).map_or(Target::Unknown, Target::Indexed),
+        Symbolic::Stack(a) => Target::Stack(a + offset as i64),
+        Symbolic::Unknown => Target::Unknown,
+    }
+}
+
+fn region(segments: &[(i64, i64)], target: Target) -> Region {
+    match target {
+        Target::Address(a) | Target::Indexed(a) => segments
+            .iter()
+            .position(|(start, end)| (*start..*end).contains(&(a as i64)))
+            .map_or(Region::Static, Region::Data),
+        Target::Stack(_) => Region::Stack,
+        Target::Unknown => Region::Unknown,
+    }
+}
+
+// Code offset of an instruction in the input, None for instructions added by a pass
+pub fn code_offset(loc: &InstrLocId) -> Option<u32> {
+    (!loc.is_default()).then(|| loc.data())
+}
+
+// Loads and stores of one function with where they land
+pub fn function_accesses(
+    id: FunctionId,
+    func: &LocalFunction,
+    values: &FunctionValues,
+    segments: &[(i64, i64)],
+) -> Vec<MemoryAccess> {
+    let mut accesses = Vec::new();
+    for seq in seqs(func) {
+        let instrs = &func.block(seq).instrs;
+        for (idx, (instr, loc)) in instrs.iter().enumerate() {
+            let (kind, width, offset, address) = match instr {
+                Instr::Load(load) => (
+                    AccessKind::Read,
+                    load.kind.width(),
+                    load.arg.offset,
+                    values.operand(instrs, idx),
+                ),
+                Instr::Store(store) => {
+                    // the value is on top of the address
+                    let address = values
+                        .operand(instrs, idx)
+                        .and_then(|(_, start)| values.operand(instrs, start));
+                    (
+                        AccessKind::Write,
+                        store.kind.width(),
+                        store.arg.offset,
+                        address,
+                    )
+                }
+                _ => continue,
+            };
+
+            let target = target(
+                address.map_or(Symbolic::Unknown, |(value, _)| value),
+                offset,
+            );
+            accesses.push(MemoryAccess {
+                func: id,
+                offset: code_offset(loc),
+                kind,
+                width,
+                target,
+                region: region(segments, target),
+            });
+        }
+    }
+
+    accesses
+}
+
+// Loads and stores of every function
+pub fn memory_accesses(module: &Module) -> Vec<MemoryAccess> {
+    let stack_pointer = stack_pointer(module);
+    let segments = segments(module);
+
+    module
+        .funcs
+        .iter_local()
+        .flat_map(|(id, func)| {
+            let values = FunctionValues::new(module, func, stack_pointer);
+            function_accesses(id, func, &values, &segments)
+        })
+        .collect()
+}
@@ -0,0 +1,152 @@
+use crate::analysis::fingerprint::{Fnv64, signature};
+use crate::pattern::Sequence;
+use std::collections::HashMap;
+use std::hash::Hasher;
+use walrus::{ExportItem, ImportKind, Module};
+
+// What identifies a build without deobfuscating it: import and export shapes, segment
+// sizes and the constants of the memory encryption. Features are compared by name, a
+// build differing in a few of them is a close relative.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BuildFingerprint {
+    pub features: Vec<(&'static str, String)>,
+}
+
+// A feature a known build has another value for, `None` if the module lacks it
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeatureDiff {
+    pub feature: String,
+    pub known: String,
+    pub actual: Option<String>,
+}
+
+impl BuildFingerprint {
+    pub fn of(module: &Module) -> BuildFingerprint {
+        let imports = module
+            .imports
+            .iter()
+            .map(|import| {
+                let kind = match &import.kind {
+                    ImportKind::Function(f) => signature(module, module.funcs.get(*f).ty()),
+                    ImportKind::Table(_) => "table".to_string(),
+                    ImportKind::Memory(_) => "memory".to_string(),
+                    ImportKind::Global(g) => format!("global {}", module.globals.get(*g).ty),
+                };
+                format!("{}.{} {}", import.module, import.name, kind)
+            })
+            .collect::<Vec<_>>();
+
+        let exports = module
+            .exports
+            .iter()
+            .map(|export| {
+                let kind = match export.item {
+                    ExportItem::Function(f) => signature(module, module.funcs.get(f).ty()),
+                    ExportItem::Table(_) => "table".to_string(),
+                    ExportItem::Memory(_) => "memory".to_string(),
+                    ExportItem::Global(g) => format!("global {}", module.globals.get(g).ty),
+                };
+                format!("{} {}", export.name, kind)
+            })
+            .collect::<Vec<_>>();
+
+        let data = module
+            .data
+            .iter()
+            .map(|data| data.value.len().to_string())
+            .collect::<Vec<_>>();
+
+        let features = vec![
+            ("imports", shapes(&imports)),
+            ("exports", shapes(&exports)),
+            ("functions", module.funcs.iter_local().count().to_string()),
+            ("types", module.types.iter().count().to_string()),
+            ("globals", module.globals.iter().count().to_string()),
+            ("data", data.join(",")),
+            ("table_len", most_common(module, "x @ i32, I32RemU")),
+            ("page_size", most_common(module, "x @ i32, I32DivU")),
+            (
+                "page_stride",
+                most_common(module, "x @ i32, I32Mul, i32, I32Add"),
+            ),
+            (
+                "pages_start",
+                most_common(module, "i32, I32Mul, x @ i32, I32Add"),
+            ),
+        ];
+
+        BuildFingerprint { features }
+    }
+
+    pub fn get(&self, feature: &str) -> Option<&str> {
+        self.features
+            .iter()
+            .find(|(name, _)| *name == feature)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Short id of the whole fingerprint
+    pub fn hash(&self) -> u64 {
+        let mut hasher = Fnv64::default();
+        for (name, value) in self.features.iter() {
+            hasher.write(name.as_bytes());
+            hasher.write_u8(0);
+            hasher.write(value.as_bytes());
+            hasher.write_u8(0);
+        }
+        hasher.finish()
+    }
+
+    // Features of a known build this one disagrees with, features only this one has
+    // don't count
+    pub fn diff(&self, known: &[(String, String)]) -> Vec<FeatureDiff> {
+        known
+            .iter()
+            .filter(|(name, value)| self.get(name) != Some(value.as_str()))
+            .map(|(name, value)| FeatureDiff {
+                feature: name.clone(),
+                known: value.clone(),
+                actual: self.get(name).map(|actual| actual.to_string()),
+            })
+            .collect()
+    }
+}
+
+// Count and hash, the names are minified but stay put within a build family
+fn shapes(items: &[String]) -> String {
+    let mut hasher = Fnv64::default();
+    for item in items.iter() {
+        hasher.write(item.as_bytes());
+        hasher.write_u8(0);
+    }
+    format!("{}:{:016x}", items.len(), hasher.finish())
+}
+
+// Most frequent value of the `x` constant in `sequence` over the functions that look
+// like memory accessors (a table lookup and a page address), `-` if it never shows up.
+// Ties go to the smaller value so the feature stays stable.
+fn most_common(module: &Module, sequence: &str) -> String {
+    let sequence = Sequence::parse(sequence).unwrap();
+    let accessor = [
+        Sequence::parse("i32, I32RemU").unwrap(),
+        Sequence::parse("i32, I32Mul, i32, I32Add").unwrap(),
+    ];
+
+    let mut counts = HashMap::new();
+    for (_, func) in module.funcs.iter_local() {
+        if accessor.iter().any(|pattern| pattern.find(func).is_empty()) {
+            continue;
+        }
+        for (_, _, captures) in sequence.find(func) {
+            if let Some(x) = captures.i32("x") {
+                *counts.entry(x).or_insert(0) += 1;
+            }
+        }
+    }
+
+    counts
+        .into_iter()
+        .max_by_key(|(x, count)| (*count, -(*x as i64)))
+        .map(|(x, _)| x.to_string())
+        .unwrap_or_else(|| "-".to_string())
+}
@@ -0,0 +1,98 @@
+use crate::analysis::xrefs::XrefDb;
+use crate::transformations::memory::MemEncFuncType;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use walrus::{FunctionId, ImportKind, Module};
+
+pub struct Node {
+    pub id: FunctionId,
+    // `module.name` of an imported function
+    pub import: Option<String>,
+    // memory wrapper type, and whether it is a store
+    pub wrapper: Option<(MemEncFuncType, bool)>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Edge {
+    pub caller: FunctionId,
+    pub callee: FunctionId,
+    pub indirect: bool,
+}
+
+pub struct CallGraph {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+}
+
+impl CallGraph {
+    // `wrappers` come from the module before deobfuscation, the calls from `db`
+    pub fn build(
+        module: &Module,
+        db: &XrefDb,
+        wrappers: &HashMap<FunctionId, (MemEncFuncType, bool)>,
+    ) -> Self {
+        let nodes = module
+            .funcs
+            .iter()
+            .map(|func| Node {
+                id: func.id(),
+                import: module
+                    .imports
+                    .iter()
+                    .find(|import| matches!(import.kind, ImportKind::Function(f) if f == func.id()))
+                    .map(|import| format!("{}.{}", import.module, import.name)),
+                wrapper: wrappers.get(&func.id()).copied(),
+            })
+            .collect();
+
+        // one edge per call site is just noise in a graph
+        let mut seen = HashSet::new();
+        let edges = db
+            .calls
+            .iter()
+            .map(|call| Edge {
+                caller: call.caller,
+                callee: call.callee,
+                indirect: call.indirect,
+            })
+            .filter(|edge| seen.insert(*edge))
+            .collect();
+
+        CallGraph { nodes, edges }
+    }
+
+    // Functions reachable from `root` in at most `depth` calls, with the edges between them
+    pub fn reachable(self, root: FunctionId, depth: Option<usize>) -> Self {
+        let mut depths = HashMap::from([(root, 0)]);
+        let mut queue = VecDeque::from([root]);
+
+        while let Some(func) = queue.pop_front() {
+            let next = depths[&func] + 1;
+            if depth.is_some_and(|depth| next > depth) {
+                continue;
+            }
+
+            for edge in self.edges.iter().filter(|edge| edge.caller == func) {
+                if let Entry::Vacant(entry) = depths.entry(edge.callee) {
+                    entry.insert(next);
+                    queue.push_back(edge.callee);
+                }
+            }
+        }
+
+        CallGraph {
+            nodes: self
+                .nodes
+                .into_iter()
+                .filter(|node| depths.contains_key(&node.id))
+                .collect(),
+            edges: self
+                .edges
+                .into_iter()
+                .filter(|edge| {
+                    depths.contains_key(&edge.caller) && depths.contains_key(&edge.callee)
+                })
+                .collect(),
+        }
+    }
+}
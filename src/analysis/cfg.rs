@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use walrus::LocalFunction;
+use walrus::ir::{Instr, InstrSeqId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    Fallthrough,
+    Jump,
+    // `if` and `br_if` outcomes
+    True,
+    False,
+    // `br_table` entry, the default one is `Jump`
+    Case(usize),
+}
+
+#[derive(Debug, Default)]
+pub struct BasicBlock {
+    // positions in the structured code, `block`/`loop` markers are left out and branches
+    // (`if` included) end their block
+    pub instrs: Vec<(InstrSeqId, usize)>,
+    pub succs: Vec<(usize, EdgeKind)>,
+}
+
+// Basic blocks of a function. Block 0 is the entry, block 1 the exit every return and the
+// end of the body go to, it has no instructions.
+#[derive(Debug)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+}
+
+pub const ENTRY: usize = 0;
+pub const EXIT: usize = 1;
+
+struct Builder<'a> {
+    func: &'a LocalFunction,
+    blocks: Vec<BasicBlock>,
+    // where a branch to a label lands: after a `block`/`if`, on top of a `loop`
+    labels: HashMap<InstrSeqId, usize>,
+}
+
+impl Builder<'_> {
+    fn new_block(&mut self) -> usize {
+        self.blocks.push(BasicBlock::default());
+        self.blocks.len() - 1
+    }
+
+    fn edge(&mut self, from: usize, to: usize, kind: EdgeKind) {
+        self.blocks[from].succs.push((to, kind));
+    }
+
+    // Lowers `seq` starting in block `current`, returns the block the code after it goes on
+    // in (a fresh one without predecessors when it ended in a branch)
+    fn seq(&mut self, seq_id: InstrSeqId, mut current: usize) -> usize {
+        let func = self.func;
+        for (idx, (instr, _)) in func.block(seq_id).instrs.iter().enumerate() {
+            let at = (seq_id, idx);
+            match instr {
+                Instr::Block(block) => {
+                    let after = self.new_block();
+                    self.labels.insert(block.seq, after);
+                    let end = self.seq(block.seq, current);
+                    self.edge(end, after, EdgeKind::Fallthrough);
+                    current = after;
+                }
+                Instr::Loop(block) => {
+                    let head = self.new_block();
+                    let after = self.new_block();
+                    self.edge(current, head, EdgeKind::Fallthrough);
+                    self.labels.insert(block.seq, head);
+                    let end = self.seq(block.seq, head);
+                    self.edge(end, after, EdgeKind::Fallthrough);
+                    current = after;
+                }
+                Instr::IfElse(if_else) => {
+                    self.blocks[current].instrs.push(at);
+                    let (consequent, alternative, after) =
+                        (self.new_block(), self.new_block(), self.new_block());
+                    self.edge(current, consequent, EdgeKind::True);
+                    self.edge(current, alternative, EdgeKind::False);
+                    self.labels.insert(if_else.consequent, after);
+                    self.labels.insert(if_else.alternative, after);
+
+                    let end = self.seq(if_else.consequent, consequent);
+                    self.edge(end, after, EdgeKind::Fallthrough);
+                    let end = self.seq(if_else.alternative, alternative);
+                    self.edge(end, after, EdgeKind::Fallthrough);
+                    current = after;
+                }
+                Instr::Br(br) => {
+                    self.blocks[current].instrs.push(at);
+                    let target = self.labels[&br.block];
+                    self.edge(current, target, EdgeKind::Jump);
+                    // anything after it is dead, it gets a block without predecessors
+                    current = self.new_block();
+                }
+                Instr::BrIf(br) => {
+                    self.blocks[current].instrs.push(at);
+                    let target = self.labels[&br.block];
+                    let next = self.new_block();
+                    self.edge(current, target, EdgeKind::True);
+                    self.edge(current, next, EdgeKind::False);
+                    current = next;
+                }
+                Instr::BrTable(table) => {
+                    self.blocks[current].instrs.push(at);
+                    for (i, block) in table.blocks.iter().enumerate() {
+                        let target = self.labels[block];
+                        self.edge(current, target, EdgeKind::Case(i));
+                    }
+                    let target = self.labels[&table.default];
+                    self.edge(current, target, EdgeKind::Jump);
+                    current = self.new_block();
+                }
+                Instr::Return(_) | Instr::ReturnCall(_) | Instr::ReturnCallIndirect(_) => {
+                    self.blocks[current].instrs.push(at);
+                    self.edge(current, EXIT, EdgeKind::Jump);
+                    current = self.new_block();
+                }
+                Instr::Unreachable(_) => {
+                    self.blocks[current].instrs.push(at);
+                    current = self.new_block();
+                }
+                _ => self.blocks[current].instrs.push(at),
+            }
+        }
+
+        current
+    }
+}
+
+impl Cfg {
+    pub fn build(func: &LocalFunction) -> Self {
+        let mut builder = Builder {
+            func,
+            blocks: vec![BasicBlock::default(), BasicBlock::default()],
+            labels: HashMap::from([(func.entry_block(), EXIT)]),
+        };
+
+        let end = builder.seq(func.entry_block(), ENTRY);
+        builder.edge(end, EXIT, EdgeKind::Fallthrough);
+
+        let mut cfg = Cfg {
+            blocks: builder.blocks,
+        };
+        cfg.simplify();
+        cfg
+    }
+
+    pub fn preds(&self, block: usize) -> Vec<usize> {
+        (0..self.blocks.len())
+            .filter(|b| self.blocks[*b].succs.iter().any(|(s, _)| *s == block))
+            .collect()
+    }
+
+    // Empty blocks that only fall through are skipped, blocks the entry can't reach are
+    // dropped, the rest is renumbered in order
+    fn simplify(&mut self) {
+        let forward = |blocks: &[BasicBlock], mut b: usize| {
+            // a chain of empty blocks can't loop, every one of them goes forward
+            while b > EXIT
+                && blocks[b].instrs.is_empty()
+                && matches!(blocks[b].succs[..], [(_, EdgeKind::Fallthrough)])
+            {
+                b = blocks[b].succs[0].0;
+            }
+            b
+        };
+
+        for b in 0..self.blocks.len() {
+            for s in 0..self.blocks[b].succs.len() {
+                self.blocks[b].succs[s].0 = forward(&self.blocks, self.blocks[b].succs[s].0);
+            }
+        }
+
+        let mut reachable = vec![false; self.blocks.len()];
+        reachable[EXIT] = true;
+        let mut stack = vec![ENTRY];
+        while let Some(b) = stack.pop() {
+            if !reachable[b] {
+                reachable[b] = true;
+                stack.extend(self.blocks[b].succs.iter().map(|(s, _)| *s));
+            }
+        }
+
+        let mut numbers = HashMap::new();
+        for (b, _) in reachable.iter().enumerate().filter(|(_, r)| **r) {
+            numbers.insert(b, numbers.len());
+        }
+
+        let blocks = std::mem::take(&mut self.blocks);
+        self.blocks = blocks
+            .into_iter()
+            .enumerate()
+            .filter(|(b, _)| reachable[*b])
+            .map(|(_, mut block)| {
+                for (s, _) in block.succs.iter_mut() {
+                    *s = numbers[s];
+                }
+                block
+            })
+            .collect();
+    }
+}
@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::hash::Hasher;
+use walrus::ir::{dfs_in_order, Instr, InstrLocId, InstrSeq, InstrSeqId, Value, Visitor};
+use walrus::{ConstExpr, DataKind, FunctionId, LocalFunction, Module, TypeId};
+
+// FNV-1a, std's DefaultHasher is not guaranteed to be stable between releases
+// and these hashes end up being compared across runs.
+pub struct Fnv64(u64);
+
+impl Default for Fnv64 {
+    fn default() -> Self {
+        Fnv64(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv64 {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+pub struct FunctionFingerprint {
+    pub id: FunctionId,
+    pub signature: String,
+    // Hash of the instruction shapes only (opcodes, control flow, types)
+    pub structure: u64,
+    // Same as `structure`, plus immediates that are not minified churn and which local
+    // each access uses
+    pub exact: u64,
+    pub opcodes: HashMap<String, usize>,
+    pub instr_count: usize,
+    pub callees: Vec<FunctionId>,
+}
+
+impl FunctionFingerprint {
+    // Weighted jaccard over the opcode histograms
+    pub fn similarity(&self, other: &FunctionFingerprint) -> f64 {
+        let mut shared = 0;
+        let mut total = 0;
+
+        for (opcode, count) in self.opcodes.iter() {
+            let other_count = other.opcodes.get(opcode).copied().unwrap_or(0);
+            shared += (*count).min(other_count);
+            total += (*count).max(other_count);
+        }
+
+        for (opcode, count) in other.opcodes.iter() {
+            if !self.opcodes.contains_key(opcode) {
+                total += *count;
+            }
+        }
+
+        if total == 0 {
+            return 1.0;
+        }
+
+        shared as f64 / total as f64
+    }
+}
+
+pub fn signature(module: &Module, ty: TypeId) -> String {
+    let t = module.types.get(ty);
+    let params = t.params().iter().map(|p| p.to_string()).collect::<Vec<_>>();
+    let results = t.results().iter().map(|r| r.to_string()).collect::<Vec<_>>();
+
+    format!("({}) -> ({})", params.join(" "), results.join(" "))
+}
+
+pub fn fingerprint_module(module: &Module) -> Vec<FunctionFingerprint> {
+    let data_ranges = data_ranges(module);
+
+    module
+        .funcs
+        .iter_local()
+        .map(|(id, local)| fingerprint_function(module, &data_ranges, id, local))
+        .collect()
+}
+
+pub fn fingerprint_function(
+    module: &Module,
+    data_ranges: &[(i64, i64)],
+    id: FunctionId,
+    local: &LocalFunction,
+) -> FunctionFingerprint {
+    let mut visitor = FingerprintVisitor {
+        module,
+        data_ranges,
+        seqs: Vec::new(),
+        locals: local.args.iter().enumerate().map(|(i, arg)| (*arg, i)).collect(),
+        structure: Fnv64::default(),
+        exact: Fnv64::default(),
+        opcodes: HashMap::new(),
+        instr_count: 0,
+        callees: Vec::new(),
+    };
+    dfs_in_order(&mut visitor, local, local.entry_block());
+
+    FunctionFingerprint {
+        id,
+        signature: signature(module, local.ty()),
+        structure: visitor.structure.finish(),
+        exact: visitor.exact.finish(),
+        opcodes: visitor.opcodes,
+        instr_count: visitor.instr_count,
+        callees: visitor.callees,
+    }
+}
+
+// Address ranges covered by active data segments, constants pointing in there
+// move around between builds so they are hashed as a plain "addr" token.
+pub fn data_ranges(module: &Module) -> Vec<(i64, i64)> {
+    module
+        .data
+        .iter()
+        .filter_map(|data| match &data.kind {
+            DataKind::Active {
+                offset: ConstExpr::Value(Value::I32(i)),
+                ..
+            } => Some((*i as i64, *i as i64 + data.value.len() as i64)),
+            _ => None,
+        })
+        .collect()
+}
+
+struct FingerprintVisitor<'a> {
+    module: &'a Module,
+    data_ranges: &'a [(i64, i64)],
+    seqs: Vec<InstrSeqId>,
+    // canonical local numbers, params by position then the other locals by first use
+    locals: HashMap<walrus::LocalId, usize>,
+
+    structure: Fnv64,
+    exact: Fnv64,
+    opcodes: HashMap<String, usize>,
+    instr_count: usize,
+    callees: Vec<FunctionId>,
+}
+
+impl FingerprintVisitor<'_> {
+    fn push(&mut self, structure: &str, exact: Option<String>) {
+        self.structure.write(structure.as_bytes());
+        self.structure.write_u8(0);
+        self.exact.write(structure.as_bytes());
+        if let Some(exact) = exact {
+            self.exact.write(exact.as_bytes());
+        }
+        self.exact.write_u8(0);
+    }
+
+    fn depth(&self, target: InstrSeqId) -> usize {
+        self.seqs
+            .iter()
+            .rev()
+            .position(|seq| *seq == target)
+            .unwrap_or(usize::MAX)
+    }
+
+    fn local_type(&self, local: walrus::LocalId) -> String {
+        self.module.locals.get(local).ty().to_string()
+    }
+
+    fn local_number(&mut self, local: walrus::LocalId) -> String {
+        let next = self.locals.len();
+        self.locals.entry(local).or_insert(next).to_string()
+    }
+
+    fn is_data_address(&self, value: i64) -> bool {
+        self.data_ranges
+            .iter()
+            .any(|(start, end)| value >= *start && value < *end)
+    }
+
+    fn token(&mut self, instr: &Instr) -> (String, Option<String>) {
+        match instr {
+            Instr::Call(call) => {
+                self.callees.push(call.func);
+                let ty = self.module.funcs.get(call.func).ty();
+                (format!("call {}", signature(self.module, ty)), None)
+            }
+            Instr::CallIndirect(call) => (
+                format!("call_indirect {}", signature(self.module, call.ty)),
+                None,
+            ),
+            Instr::LocalGet(l) => (
+                format!("local.get {}", self.local_type(l.local)),
+                Some(self.local_number(l.local)),
+            ),
+            Instr::LocalSet(l) => (
+                format!("local.set {}", self.local_type(l.local)),
+                Some(self.local_number(l.local)),
+            ),
+            Instr::LocalTee(l) => (
+                format!("local.tee {}", self.local_type(l.local)),
+                Some(self.local_number(l.local)),
+            ),
+            Instr::GlobalGet(g) => ("global.get".to_string(), Some(g.global.index().to_string())),
+            Instr::GlobalSet(g) => ("global.set".to_string(), Some(g.global.index().to_string())),
+            Instr::Const(c) => match c.value {
+                Value::I32(i) if self.is_data_address(i as i64) => {
+                    ("i32.const".to_string(), Some("addr".to_string()))
+                }
+                Value::I32(i) => ("i32.const".to_string(), Some(i.to_string())),
+                Value::I64(i) => ("i64.const".to_string(), Some(i.to_string())),
+                Value::F32(f) => ("f32.const".to_string(), Some(f.to_bits().to_string())),
+                Value::F64(f) => ("f64.const".to_string(), Some(f.to_bits().to_string())),
+                Value::V128(v) => ("v128.const".to_string(), Some(v.to_string())),
+            },
+            Instr::Binop(b) => (format!("{:?}", b.op), None),
+            Instr::Unop(u) => (format!("{:?}", u.op), None),
+            Instr::Load(l) => (format!("load {:?}", l.kind), Some(l.arg.offset.to_string())),
+            Instr::Store(s) => (format!("store {:?}", s.kind), Some(s.arg.offset.to_string())),
+            Instr::Br(br) => (format!("br {}", self.depth(br.block)), None),
+            Instr::BrIf(br) => (format!("br_if {}", self.depth(br.block)), None),
+            Instr::BrTable(br) => {
+                let mut depths = br
+                    .blocks
+                    .iter()
+                    .map(|b| self.depth(*b).to_string())
+                    .collect::<Vec<_>>();
+                depths.push(self.depth(br.default).to_string());
+                (format!("br_table {}", depths.join(" ")), None)
+            }
+            Instr::Block(_) => ("block".to_string(), None),
+            Instr::Loop(_) => ("loop".to_string(), None),
+            Instr::IfElse(_) => ("if".to_string(), None),
+            other => {
+                let debug = format!("{:?}", other);
+                let name = debug.split(['(', ' ', '{']).next().unwrap_or_default();
+                (name.to_string(), None)
+            }
+        }
+    }
+}
+
+impl<'instr> Visitor<'instr> for FingerprintVisitor<'_> {
+    fn start_instr_seq(&mut self, seq: &'instr InstrSeq) {
+        self.seqs.push(seq.id());
+        self.push("{", None);
+    }
+
+    fn end_instr_seq(&mut self, seq: &'instr InstrSeq) {
+        debug_assert_eq!(self.seqs.last(), Some(&seq.id()));
+        self.seqs.pop();
+        self.push("}", None);
+    }
+
+    fn visit_instr(&mut self, instr: &'instr Instr, _: &'instr InstrLocId) {
+        let (structure, exact) = self.token(instr);
+        self.push(&structure, exact);
+
+        *self.opcodes.entry(structure).or_default() += 1;
+        self.instr_count += 1;
+    }
+}
@@ -0,0 +1,183 @@
+use crate::analysis::fingerprint::FunctionFingerprint;
+use std::collections::{HashMap, HashSet, VecDeque};
+use walrus::FunctionId;
+
+// Below this, two functions with the same signature are considered unrelated
+const SIMILARITY_THRESHOLD: f64 = 0.75;
+
+pub struct FunctionMatch {
+    pub old: FunctionId,
+    pub new: FunctionId,
+    pub similarity: f64,
+    pub unchanged: bool,
+}
+
+#[derive(Default)]
+pub struct FunctionMatching {
+    pub matched: Vec<FunctionMatch>,
+    pub removed: Vec<FunctionId>,
+    pub added: Vec<FunctionId>,
+}
+
+// Matches functions between two builds, in order of confidence:
+// 1. unique exact body hash
+// 2. unique structural hash (constants changed)
+// 3. callees of matched pairs, position by position
+// 4. best opcode histogram similarity among functions with the same signature
+pub fn match_functions(old: &[FunctionFingerprint], new: &[FunctionFingerprint]) -> FunctionMatching {
+    let mut state = MatchState {
+        old: old.iter().map(|f| (f.id, f)).collect(),
+        new: new.iter().map(|f| (f.id, f)).collect(),
+        old_to_new: HashMap::new(),
+        new_to_old: HashMap::new(),
+        queue: VecDeque::new(),
+    };
+
+    state.match_unique_by(old, new, |f| f.exact);
+    state.match_unique_by(old, new, |f| f.structure);
+    state.propagate_callees();
+    state.match_by_similarity(old, new);
+    state.propagate_callees();
+
+    let mut matching = FunctionMatching::default();
+    for f in old.iter() {
+        match state.old_to_new.get(&f.id) {
+            Some(new_id) => {
+                let new_fp = state.new[new_id];
+                matching.matched.push(FunctionMatch {
+                    old: f.id,
+                    new: *new_id,
+                    similarity: f.similarity(new_fp),
+                    unchanged: f.exact == new_fp.exact,
+                });
+            }
+            None => matching.removed.push(f.id),
+        }
+    }
+
+    for f in new.iter() {
+        if !state.new_to_old.contains_key(&f.id) {
+            matching.added.push(f.id);
+        }
+    }
+
+    matching
+}
+
+struct MatchState<'a> {
+    old: HashMap<FunctionId, &'a FunctionFingerprint>,
+    new: HashMap<FunctionId, &'a FunctionFingerprint>,
+    old_to_new: HashMap<FunctionId, FunctionId>,
+    new_to_old: HashMap<FunctionId, FunctionId>,
+    // Freshly matched pairs whose callees still need to be looked at
+    queue: VecDeque<(FunctionId, FunctionId)>,
+}
+
+impl MatchState<'_> {
+    fn add(&mut self, old: FunctionId, new: FunctionId) {
+        self.old_to_new.insert(old, new);
+        self.new_to_old.insert(new, old);
+        self.queue.push_back((old, new));
+    }
+
+    fn match_unique_by(
+        &mut self,
+        old: &[FunctionFingerprint],
+        new: &[FunctionFingerprint],
+        key: impl Fn(&FunctionFingerprint) -> u64,
+    ) {
+        let mut old_groups = HashMap::<u64, Vec<FunctionId>>::new();
+        for f in old.iter().filter(|f| !self.old_to_new.contains_key(&f.id)) {
+            old_groups.entry(key(f)).or_default().push(f.id);
+        }
+
+        let mut new_groups = HashMap::<u64, Vec<FunctionId>>::new();
+        for f in new.iter().filter(|f| !self.new_to_old.contains_key(&f.id)) {
+            new_groups.entry(key(f)).or_default().push(f.id);
+        }
+
+        for f in old.iter() {
+            let (Some(old_group), Some(new_group)) =
+                (old_groups.get(&key(f)), new_groups.get(&key(f)))
+            else {
+                continue;
+            };
+
+            if old_group.len() == 1 && new_group.len() == 1 {
+                self.add(old_group[0], new_group[0]);
+            }
+        }
+    }
+
+    fn propagate_callees(&mut self) {
+        while let Some((old_id, new_id)) = self.queue.pop_front() {
+            let old_callees = &self.old[&old_id].callees;
+            let new_callees = &self.new[&new_id].callees;
+            if old_callees.len() != new_callees.len() {
+                continue;
+            }
+
+            let candidates = old_callees
+                .iter()
+                .zip(new_callees.iter())
+                .map(|(a, b)| (*a, *b))
+                .collect::<Vec<_>>();
+
+            for (old_callee, new_callee) in candidates {
+                // imports don't have fingerprints
+                let (Some(old_fp), Some(new_fp)) =
+                    (self.old.get(&old_callee), self.new.get(&new_callee))
+                else {
+                    continue;
+                };
+
+                if self.old_to_new.contains_key(&old_callee)
+                    || self.new_to_old.contains_key(&new_callee)
+                {
+                    continue;
+                }
+
+                if old_fp.signature == new_fp.signature
+                    && old_fp.similarity(new_fp) >= SIMILARITY_THRESHOLD
+                {
+                    self.add(old_callee, new_callee);
+                }
+            }
+        }
+    }
+
+    fn match_by_similarity(&mut self, old: &[FunctionFingerprint], new: &[FunctionFingerprint]) {
+        let mut candidates = Vec::new();
+        for a in old.iter().filter(|f| !self.old_to_new.contains_key(&f.id)) {
+            for b in new.iter().filter(|f| !self.new_to_old.contains_key(&f.id)) {
+                if a.signature != b.signature {
+                    continue;
+                }
+
+                let similarity = a.similarity(b);
+                if similarity >= SIMILARITY_THRESHOLD {
+                    candidates.push((similarity, a.id, b.id));
+                }
+            }
+        }
+
+        // Best pairs first, ties broken by function order so the output is stable
+        candidates.sort_by(|a, b| {
+            b.0.total_cmp(&a.0)
+                .then(a.1.index().cmp(&b.1.index()))
+                .then(a.2.index().cmp(&b.2.index()))
+        });
+
+        let mut taken_old = HashSet::new();
+        let mut taken_new = HashSet::new();
+        for (_, a, b) in candidates {
+            if taken_old.contains(&a) || taken_new.contains(&b) {
+                continue;
+            }
+
+            taken_old.insert(a);
+            taken_new.insert(b);
+            self.add(a, b);
+        }
+    }
+}
@@ -0,0 +1,8 @@
+pub mod addresses;
+pub mod build;
+pub mod callgraph;
+pub mod cfg;
+pub mod fingerprint;
+pub mod matching;
+pub mod signatures;
+pub mod xrefs;
@@ -0,0 +1,113 @@
+use crate::analysis::fingerprint::{FunctionFingerprint, Fnv64, fingerprint_module};
+use anyhow::{bail, Context};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write as _;
+use std::hash::Hasher;
+use std::path::Path;
+use walrus::Module;
+
+// Tiny bodies (getters, thunks...) hash the same across unrelated functions
+const MIN_INSTRUCTIONS: usize = 10;
+
+// Signature database, stored as text:
+//
+//   # comment
+//   <16 hex digit body hash> <name>
+#[derive(Default)]
+pub struct SignatureDb {
+    pub entries: BTreeMap<u64, String>,
+}
+
+pub struct LearnStats {
+    pub learned: usize,
+    pub conflicts: usize,
+}
+
+// Hash of the normalized body, see `FunctionFingerprint::exact`
+pub fn body_hash(fingerprint: &FunctionFingerprint) -> u64 {
+    let mut hasher = Fnv64::default();
+    hasher.write(fingerprint.signature.as_bytes());
+    hasher.write_u64(fingerprint.exact);
+    hasher.finish()
+}
+
+impl SignatureDb {
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read {}", path.display()))?;
+        Self::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> Result<Self, anyhow::Error> {
+        let mut db = SignatureDb::default();
+
+        for (line_number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((hash, name)) = line.split_once(' ') else {
+                bail!("line {}: expected `<hash> <name>`", line_number + 1);
+            };
+
+            let hash = u64::from_str_radix(hash, 16)
+                .with_context(|| format!("line {}: invalid hash {}", line_number + 1, hash))?;
+            db.entries.insert(hash, name.trim().to_string());
+        }
+
+        Ok(db)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let mut content = String::from("# hcaptcha-wasm-deobfuscator signature db\n");
+        for (hash, name) in self.entries.iter() {
+            writeln!(content, "{:016x} {}", hash, name)?;
+        }
+
+        std::fs::write(path, content).with_context(|| format!("could not write {}", path.display()))
+    }
+
+    pub fn get(&self, fingerprint: &FunctionFingerprint) -> Option<&String> {
+        self.entries.get(&body_hash(fingerprint))
+    }
+
+    // Harvests the names of an annotated module (name section).
+    // Hashes that map to more than one name in the module are ambiguous and are skipped.
+    pub fn learn(&mut self, module: &Module) -> LearnStats {
+        let mut learned = BTreeMap::<u64, String>::new();
+        let mut conflicts = HashSet::new();
+
+        for fingerprint in fingerprint_module(module) {
+            if fingerprint.instr_count < MIN_INSTRUCTIONS {
+                continue;
+            }
+
+            let Some(name) = &module.funcs.get(fingerprint.id).name else {
+                continue;
+            };
+
+            let hash = body_hash(&fingerprint);
+            match learned.get(&hash) {
+                Some(existing) if existing != name => {
+                    conflicts.insert(hash);
+                }
+                _ => {
+                    learned.insert(hash, name.clone());
+                }
+            }
+        }
+
+        for hash in conflicts.iter() {
+            learned.remove(hash);
+        }
+
+        let stats = LearnStats {
+            learned: learned.len(),
+            conflicts: conflicts.len(),
+        };
+        self.entries.extend(learned);
+
+        stats
+    }
+}
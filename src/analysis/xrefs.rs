@@ -0,0 +1,233 @@
+use crate::analysis::addresses::{
+    AccessKind, FunctionValues, MemoryAccess, Symbolic, Target, code_offset, function_accesses,
+    segments, seqs, stack_pointer,
+};
+use std::collections::HashMap;
+use walrus::ir::{Instr, Value};
+use walrus::{ConstExpr, ElementItems, ElementKind, FunctionId, GlobalId, Module, TableId};
+
+#[derive(Debug, Clone)]
+pub struct CallXref {
+    pub caller: FunctionId,
+    pub callee: FunctionId,
+    pub offset: Option<u32>,
+    // through a table, `callee` is one of the functions it may land on
+    pub indirect: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct GlobalXref {
+    pub func: FunctionId,
+    pub global: GlobalId,
+    pub offset: Option<u32>,
+    pub kind: AccessKind,
+}
+
+// An `i32.const` pointing into a data segment
+#[derive(Debug, Clone)]
+pub struct ConstXref {
+    pub func: FunctionId,
+    pub address: u32,
+    pub offset: Option<u32>,
+}
+
+// Initial contents of every table, from the active element segments with a constant offset
+pub fn tables(module: &Module) -> HashMap<TableId, Vec<Option<FunctionId>>> {
+    let mut tables = module
+        .tables
+        .iter()
+        .map(|table| (table.id(), vec![None; table.initial as usize]))
+        .collect::<HashMap<_, _>>();
+
+    for element in module.elements.iter() {
+        let (
+            ElementKind::Active {
+                table,
+                offset: ConstExpr::Value(Value::I32(offset)),
+            },
+            ElementItems::Functions(functions),
+        ) = (&element.kind, &element.items)
+        else {
+            continue;
+        };
+
+        let table = tables.get_mut(table).unwrap();
+        for (i, function) in functions.iter().enumerate() {
+            if let Some(slot) = table.get_mut(*offset as usize + i) {
+                *slot = Some(*function);
+            }
+        }
+    }
+
+    tables
+}
+
+// Cross references of a module, built once and queried by address, function or global
+#[derive(Default)]
+pub struct XrefDb {
+    pub accesses: Vec<MemoryAccess>,
+    pub calls: Vec<CallXref>,
+    pub globals: Vec<GlobalXref>,
+    pub constants: Vec<ConstXref>,
+    // end of the data segment each indexed access starts in, the furthest it is assumed to reach
+    limits: Vec<Option<u32>>,
+}
+
+impl XrefDb {
+    pub fn build(module: &Module) -> Self {
+        let stack_pointer = stack_pointer(module);
+        let segments = segments(module);
+        let tables = tables(module);
+        let mut db = XrefDb::default();
+
+        for (id, func) in module.funcs.iter_local() {
+            let values = FunctionValues::new(module, func, stack_pointer);
+            db.accesses
+                .extend(function_accesses(id, func, &values, &segments));
+
+            for seq in seqs(func) {
+                let instrs = &func.block(seq).instrs;
+                for (idx, (instr, loc)) in instrs.iter().enumerate() {
+                    let offset = code_offset(loc);
+                    match instr {
+                        Instr::Call(call) => db.calls.push(CallXref {
+                            caller: id,
+                            callee: call.func,
+                            offset,
+                            indirect: false,
+                        }),
+                        Instr::CallIndirect(call) => {
+                            let table = &tables[&call.table];
+                            // a constant index lands on one slot, anything else on every
+                            // function of the right type
+                            let slots = match values.operand(instrs, idx) {
+                                Some((Symbolic::Const(i), _)) => {
+                                    table.get(i as usize).into_iter().collect::<Vec<_>>()
+                                }
+                                _ => table.iter().collect(),
+                            };
+
+                            let mut callees = slots
+                                .into_iter()
+                                .flatten()
+                                .filter(|callee| module.funcs.get(**callee).ty() == call.ty)
+                                .copied()
+                                .collect::<Vec<_>>();
+                            callees.sort_by_key(|callee| callee.index());
+                            callees.dedup();
+
+                            db.calls.extend(callees.into_iter().map(|callee| CallXref {
+                                caller: id,
+                                callee,
+                                offset,
+                                indirect: true,
+                            }));
+                        }
+                        Instr::GlobalGet(get) => db.globals.push(GlobalXref {
+                            func: id,
+                            global: get.global,
+                            offset,
+                            kind: AccessKind::Read,
+                        }),
+                        Instr::GlobalSet(set) => db.globals.push(GlobalXref {
+                            func: id,
+                            global: set.global,
+                            offset,
+                            kind: AccessKind::Write,
+                        }),
+                        Instr::Const(c) => {
+                            if let Value::I32(i) = c.value
+                                && segments
+                                    .iter()
+                                    .any(|(start, end)| (*start..*end).contains(&(i as i64)))
+                            {
+                                db.constants.push(ConstXref {
+                                    func: id,
+                                    address: i as u32,
+                                    offset,
+                                });
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        db.limits = db
+            .accesses
+            .iter()
+            .map(|access| match access.target {
+                Target::Indexed(base) => segments
+                    .iter()
+                    .find(|(start, end)| (*start..*end).contains(&(base as i64)))
+                    .map(|(_, end)| *end as u32),
+                _ => None,
+            })
+            .collect();
+
+        db
+    }
+
+    // Accesses that may touch `address`. Indexed ones match anywhere from their base to the
+    // end of its data segment, the index is unknown.
+    pub fn accesses_to(&self, address: u32) -> impl Iterator<Item = &MemoryAccess> {
+        self.accesses
+            .iter()
+            .zip(self.limits.iter())
+            .filter(move |(access, limit)| match access.target {
+                Target::Address(a) => {
+                    (a as u64..a as u64 + access.width as u64).contains(&(address as u64))
+                }
+                Target::Indexed(base) => limit.is_some_and(|end| (base..end).contains(&address)),
+                Target::Stack(_) | Target::Unknown => false,
+            })
+            .map(|(access, _)| access)
+    }
+
+    pub fn readers(&self, address: u32) -> impl Iterator<Item = &MemoryAccess> {
+        self.accesses_to(address)
+            .filter(|access| access.kind == AccessKind::Read)
+    }
+
+    pub fn writers(&self, address: u32) -> impl Iterator<Item = &MemoryAccess> {
+        self.accesses_to(address)
+            .filter(|access| access.kind == AccessKind::Write)
+    }
+
+    // Every load and store of `func`
+    pub fn touched_by(&self, func: FunctionId) -> impl Iterator<Item = &MemoryAccess> {
+        self.accesses
+            .iter()
+            .filter(move |access| access.func == func)
+    }
+
+    pub fn calls_from(&self, func: FunctionId) -> impl Iterator<Item = &CallXref> {
+        self.calls.iter().filter(move |call| call.caller == func)
+    }
+
+    pub fn callers_of(&self, func: FunctionId) -> impl Iterator<Item = &CallXref> {
+        self.calls.iter().filter(move |call| call.callee == func)
+    }
+
+    pub fn global_refs(&self, global: GlobalId) -> impl Iterator<Item = &GlobalXref> {
+        self.globals
+            .iter()
+            .filter(move |xref| xref.global == global)
+    }
+
+    pub fn globals_of(&self, func: FunctionId) -> impl Iterator<Item = &GlobalXref> {
+        self.globals.iter().filter(move |xref| xref.func == func)
+    }
+
+    // Functions using `address` as a constant
+    pub fn constants_to(&self, address: u32) -> impl Iterator<Item = &ConstXref> {
+        self.constants
+            .iter()
+            .filter(move |xref| xref.address == address)
+    }
+
+    pub fn constants_of(&self, func: FunctionId) -> impl Iterator<Item = &ConstXref> {
+        self.constants.iter().filter(move |xref| xref.func == func)
+    }
+}
@@ -0,0 +1,203 @@
+use crate::analysis::callgraph::{CallGraph, Node};
+use crate::analysis::xrefs::XrefDb;
+use crate::commands::json::{array, object, string};
+use crate::commands::{deobfuscate, find_function, function_label, load_module};
+use crate::transformations::memory::MemEncFuncType;
+use crate::transformations::memory::memory_transformer::MemoryTransformer;
+use anyhow::{Context, bail};
+use std::collections::HashMap;
+use walrus::{FunctionId, Module};
+
+const USAGE: &str = "usage: callgraph <input.wasm> [--format dot|graphml|json] [--root <index|name|events>] [--depth <n>] [--raw]";
+
+#[derive(Clone, Copy)]
+pub enum Format {
+    Dot,
+    GraphMl,
+    Json,
+}
+
+// Call graph of the deobfuscated module, `--raw` keeps the calls to the memory wrappers
+pub fn run(args: &[String]) -> Result<(), anyhow::Error> {
+    let mut input = None;
+    let mut format = Format::Dot;
+    let mut root = None;
+    let mut depth = None;
+    let mut raw = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => {
+                format = match args.next().map(|s| s.as_str()) {
+                    Some("dot") => Format::Dot,
+                    Some("graphml") => Format::GraphMl,
+                    Some("json") => Format::Json,
+                    _ => bail!(USAGE),
+                }
+            }
+            "--root" => {
+                let Some(name) = args.next() else {
+                    bail!(USAGE);
+                };
+                root = Some(name.as_str());
+            }
+            "--depth" => {
+                let Some(n) = args.next() else {
+                    bail!(USAGE);
+                };
+                depth = Some(
+                    n.parse::<usize>()
+                        .with_context(|| format!("invalid depth {}", n))?,
+                );
+            }
+            "--raw" => raw = true,
+            _ if input.is_none() => input = Some(arg.as_str()),
+            _ => bail!(USAGE),
+        }
+    }
+
+    let Some(input) = input else {
+        bail!(USAGE);
+    };
+    if depth.is_some() && root.is_none() {
+        bail!("--depth needs a --root");
+    }
+
+    let mut module = load_module(input)?;
+    // the wrappers are recognized on the original code, ids stay valid after deobfuscation
+    let wrappers = map_wrappers(&module);
+    if !raw {
+        deobfuscate(&mut module)?;
+    }
+
+    let mut graph = CallGraph::build(&module, &XrefDb::build(&module), &wrappers);
+    if let Some(root) = root {
+        graph = graph.reachable(find_function(&module, root)?, depth);
+    }
+
+    print!("{}", render(&module, &graph, format));
+    Ok(())
+}
+
+pub fn map_wrappers(module: &Module) -> HashMap<FunctionId, (MemEncFuncType, bool)> {
+    let transformer = MemoryTransformer::default();
+    let loads = transformer.map_load_functions(module).unwrap_or_default();
+    let stores = transformer.map_store_functions(module).unwrap_or_default();
+
+    loads
+        .into_iter()
+        .map(|(id, ty)| (id, (ty, false)))
+        .chain(stores.into_iter().map(|(id, ty)| (id, (ty, true))))
+        .collect()
+}
+
+// `function_label`, then the import and wrapper type on their own lines
+fn node_label(module: &Module, node: &Node) -> Vec<String> {
+    let mut lines = vec![function_label(module, node.id)];
+    if let Some(import) = &node.import {
+        lines.push(format!("import {}", import));
+    }
+    if let Some((ty, is_store)) = node.wrapper {
+        lines.push(format!(
+            "{} {:?}",
+            if is_store { "store" } else { "load" },
+            ty
+        ));
+    }
+    lines
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub fn render(module: &Module, graph: &CallGraph, format: Format) -> String {
+    let mut out = String::new();
+
+    match format {
+        Format::Dot => {
+            out.push_str("digraph callgraph {\n    node [shape=box];\n");
+            for node in graph.nodes.iter() {
+                let label = node_label(module, node)
+                    .iter()
+                    .map(|line| line.replace('\\', "\\\\").replace('"', "\\\""))
+                    .collect::<Vec<_>>()
+                    .join("\\n");
+                out.push_str(&format!(
+                    "    f{} [label=\"{}\"];\n",
+                    node.id.index(),
+                    label
+                ));
+            }
+            for edge in graph.edges.iter() {
+                let style = if edge.indirect { " [style=dashed]" } else { "" };
+                out.push_str(&format!(
+                    "    f{} -> f{}{};\n",
+                    edge.caller.index(),
+                    edge.callee.index(),
+                    style
+                ));
+            }
+            out.push_str("}\n");
+        }
+        Format::GraphMl => {
+            out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+            out.push_str("<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n");
+            out.push_str(
+                "  <key id=\"label\" for=\"node\" attr.name=\"label\" attr.type=\"string\"/>\n",
+            );
+            out.push_str("  <key id=\"indirect\" for=\"edge\" attr.name=\"indirect\" attr.type=\"boolean\"/>\n");
+            out.push_str("  <graph id=\"callgraph\" edgedefault=\"directed\">\n");
+            for node in graph.nodes.iter() {
+                out.push_str(&format!(
+                    "    <node id=\"f{}\"><data key=\"label\">{}</data></node>\n",
+                    node.id.index(),
+                    xml_escape(&node_label(module, node).join("\n"))
+                ));
+            }
+            for edge in graph.edges.iter() {
+                out.push_str(&format!(
+                    "    <edge source=\"f{}\" target=\"f{}\"><data key=\"indirect\">{}</data></edge>\n",
+                    edge.caller.index(),
+                    edge.callee.index(),
+                    edge.indirect
+                ));
+            }
+            out.push_str("  </graph>\n</graphml>\n");
+        }
+        Format::Json => {
+            let nodes = array(graph.nodes.iter().map(|node| {
+                let wrapper = node.wrapper.map_or("null".to_string(), |(ty, is_store)| {
+                    object(&[
+                        ("type", string(&format!("{:?}", ty))),
+                        ("store", is_store.to_string()),
+                    ])
+                });
+                object(&[
+                    ("func", node.id.index().to_string()),
+                    ("label", string(&function_label(module, node.id))),
+                    (
+                        "import",
+                        node.import.as_deref().map_or("null".to_string(), string),
+                    ),
+                    ("wrapper", wrapper),
+                ])
+            }));
+            let edges = array(graph.edges.iter().map(|edge| {
+                object(&[
+                    ("caller", edge.caller.index().to_string()),
+                    ("callee", edge.callee.index().to_string()),
+                    ("indirect", edge.indirect.to_string()),
+                ])
+            }));
+            out.push_str(&object(&[("nodes", nodes), ("edges", edges)]));
+            out.push('\n');
+        }
+    }
+
+    out
+}
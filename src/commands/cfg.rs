@@ -0,0 +1,157 @@
+use crate::analysis::cfg::{Cfg, ENTRY, EXIT, EdgeKind};
+use crate::commands::{find_function, function_label, load_deobfuscated};
+use crate::decompiler::{local_names, mnemonic};
+use anyhow::bail;
+use std::collections::HashMap;
+use walrus::ir::{ExtendedLoad, Instr, LoadKind, StoreKind, Value};
+use walrus::{FunctionKind, LocalFunction, LocalId, Module, TypeId};
+
+const USAGE: &str = "usage: cfg <input.wasm> --func <index|name>";
+
+// Basic blocks of one function of the deobfuscated module, as DOT
+pub fn run(args: &[String]) -> Result<(), anyhow::Error> {
+    let [input, flag, name] = args else {
+        bail!(USAGE);
+    };
+    if flag != "--func" {
+        bail!(USAGE);
+    }
+
+    let module = load_deobfuscated(input)?;
+    let id = find_function(&module, name)?;
+    let FunctionKind::Local(func) = &module.funcs.get(id).kind else {
+        bail!("{} is imported", function_label(&module, id));
+    };
+
+    print!("{}", render(&module, func, &Cfg::build(func)));
+    Ok(())
+}
+
+fn load_name(kind: &LoadKind) -> &'static str {
+    match kind {
+        LoadKind::I32 { .. } => "i32.load",
+        LoadKind::I64 { .. } => "i64.load",
+        LoadKind::F32 => "f32.load",
+        LoadKind::F64 => "f64.load",
+        LoadKind::V128 => "v128.load",
+        LoadKind::I32_8 {
+            kind: ExtendedLoad::SignExtend,
+        } => "i32.load8_s",
+        LoadKind::I32_8 { .. } => "i32.load8_u",
+        LoadKind::I32_16 {
+            kind: ExtendedLoad::SignExtend,
+        } => "i32.load16_s",
+        LoadKind::I32_16 { .. } => "i32.load16_u",
+        LoadKind::I64_8 {
+            kind: ExtendedLoad::SignExtend,
+        } => "i64.load8_s",
+        LoadKind::I64_8 { .. } => "i64.load8_u",
+        LoadKind::I64_16 {
+            kind: ExtendedLoad::SignExtend,
+        } => "i64.load16_s",
+        LoadKind::I64_16 { .. } => "i64.load16_u",
+        LoadKind::I64_32 {
+            kind: ExtendedLoad::SignExtend,
+        } => "i64.load32_s",
+        LoadKind::I64_32 { .. } => "i64.load32_u",
+    }
+}
+
+fn store_name(kind: &StoreKind) -> &'static str {
+    match kind {
+        StoreKind::I32 { .. } => "i32.store",
+        StoreKind::I64 { .. } => "i64.store",
+        StoreKind::F32 => "f32.store",
+        StoreKind::F64 => "f64.store",
+        StoreKind::V128 => "v128.store",
+        StoreKind::I32_8 { .. } => "i32.store8",
+        StoreKind::I32_16 { .. } => "i32.store16",
+        StoreKind::I64_8 { .. } => "i64.store8",
+        StoreKind::I64_16 { .. } => "i64.store16",
+        StoreKind::I64_32 { .. } => "i64.store32",
+    }
+}
+
+fn signature(module: &Module, ty: TypeId) -> String {
+    let ty = module.types.get(ty);
+    format!("{:?} -> {:?}", ty.params(), ty.results())
+}
+
+pub fn instr_text(module: &Module, locals: &HashMap<LocalId, String>, instr: &Instr) -> String {
+    let offset = |offset: u32| match offset {
+        0 => String::new(),
+        offset => format!(" offset={}", offset),
+    };
+
+    match instr {
+        Instr::Const(c) => match c.value {
+            Value::I32(v) => format!("i32.const {}", v),
+            Value::I64(v) => format!("i64.const {}", v),
+            Value::F32(v) => format!("f32.const {}", v),
+            Value::F64(v) => format!("f64.const {}", v),
+            Value::V128(v) => format!("v128.const {:#x}", v),
+        },
+        Instr::LocalGet(get) => format!("local.get {}", locals[&get.local]),
+        Instr::LocalSet(set) => format!("local.set {}", locals[&set.local]),
+        Instr::LocalTee(tee) => format!("local.tee {}", locals[&tee.local]),
+        Instr::GlobalGet(get) => format!("global.get g{}", get.global.index()),
+        Instr::GlobalSet(set) => format!("global.set g{}", set.global.index()),
+        Instr::Binop(op) => mnemonic(&format!("{:?}", op.op)),
+        Instr::Unop(op) => mnemonic(&format!("{:?}", op.op)),
+        Instr::Load(load) => format!("{}{}", load_name(&load.kind), offset(load.arg.offset)),
+        Instr::Store(store) => format!("{}{}", store_name(&store.kind), offset(store.arg.offset)),
+        Instr::Call(call) => format!("call {}", function_label(module, call.func)),
+        Instr::ReturnCall(call) => format!("return_call {}", function_label(module, call.func)),
+        Instr::CallIndirect(call) => format!("call_indirect {}", signature(module, call.ty)),
+        Instr::ReturnCallIndirect(call) => {
+            format!("return_call_indirect {}", signature(module, call.ty))
+        }
+        Instr::IfElse(_) => "if".to_string(),
+        Instr::Br(_) => "br".to_string(),
+        Instr::BrIf(_) => "br_if".to_string(),
+        Instr::BrTable(table) => format!("br_table ({} cases)", table.blocks.len()),
+        Instr::Select(_) => "select".to_string(),
+        Instr::RefFunc(func) => format!("ref.func {}", function_label(module, func.func)),
+        instr => mnemonic(&format!("{:?}", instr)),
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+pub fn render(module: &Module, func: &LocalFunction, cfg: &Cfg) -> String {
+    let locals = local_names(module, func);
+    let mut out = String::from("digraph cfg {\n    node [shape=box, fontname=monospace];\n");
+
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        let mut label = match b {
+            ENTRY => format!("bb{} (entry)\\l", b),
+            EXIT => format!("bb{} (exit)\\l", b),
+            _ => format!("bb{}\\l", b),
+        };
+        for (seq, idx) in block.instrs.iter() {
+            let (instr, _) = &func.block(*seq).instrs[*idx];
+            label.push_str(&format!(
+                "  {}\\l",
+                escape(&instr_text(module, &locals, instr))
+            ));
+        }
+        out.push_str(&format!("    bb{} [label=\"{}\"];\n", b, label));
+    }
+
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        for (s, kind) in block.succs.iter() {
+            let attrs = match kind {
+                EdgeKind::Fallthrough | EdgeKind::Jump => String::new(),
+                EdgeKind::True => " [label=\"true\"]".to_string(),
+                EdgeKind::False => " [label=\"false\"]".to_string(),
+                EdgeKind::Case(i) => format!(" [label=\"{}\"]", i),
+            };
+            out.push_str(&format!("    bb{} -> bb{}{};\n", b, s, attrs));
+        }
+    }
+
+    out.push_str("}\n");
+    out
+}
@@ -0,0 +1,87 @@
+use crate::commands::{find_function, function_label, load_deobfuscated};
+use crate::decompiler::Decompiler;
+use anyhow::{Context, bail};
+use std::fs;
+use std::path::{Path, PathBuf};
+use walrus::Module;
+
+const USAGE: &str =
+    "usage: decompile <input.wasm> [--func <index|name>] [--out <file.c> | --split <dir>]";
+
+// Pseudo-C of the deobfuscated module, to stdout, one file or one file per function
+pub fn run(args: &[String]) -> Result<(), anyhow::Error> {
+    let mut input = None;
+    let mut func = None;
+    let mut out = None;
+    let mut split = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let slot = match arg.as_str() {
+            "--func" => &mut func,
+            "--out" => &mut out,
+            "--split" => &mut split,
+            _ if input.is_none() => {
+                input = Some(arg.as_str());
+                continue;
+            }
+            _ => bail!(USAGE),
+        };
+        let Some(value) = args.next() else {
+            bail!(USAGE);
+        };
+        *slot = Some(value.as_str());
+    }
+
+    let Some(input) = input else {
+        bail!(USAGE);
+    };
+    if out.is_some() && split.is_some() {
+        bail!(USAGE);
+    }
+
+    let module = load_deobfuscated(input)?;
+    let decompiler = Decompiler::new(&module);
+
+    if let Some(dir) = split {
+        let files = write_split(&module, &decompiler, Path::new(dir))?;
+        println!("wrote {} files to {}", files.len(), dir);
+        return Ok(());
+    }
+
+    let text = match func {
+        Some(name) => {
+            let id = find_function(&module, name)?;
+            decompiler
+                .function(id)
+                .with_context(|| format!("{} is imported", function_label(&module, id)))?
+        }
+        None => decompiler.module(),
+    };
+
+    match out {
+        Some(path) => fs::write(path, text).with_context(|| format!("writing {}", path))?,
+        None => print!("{}", text),
+    }
+    Ok(())
+}
+
+// `<dir>/func_<index>.c` for every local function, returns the paths written
+pub fn write_split(
+    module: &Module,
+    decompiler: &Decompiler,
+    dir: &Path,
+) -> Result<Vec<PathBuf>, anyhow::Error> {
+    fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+
+    let mut files = Vec::new();
+    for func in module.funcs.iter() {
+        let Some(text) = decompiler.function(func.id()) else {
+            continue;
+        };
+        let path = dir.join(format!("func_{}.c", func.id().index()));
+        fs::write(&path, text).with_context(|| format!("writing {}", path.display()))?;
+        files.push(path);
+    }
+    Ok(files)
+}
@@ -0,0 +1,113 @@
+use crate::analysis::fingerprint::fingerprint_module;
+use crate::analysis::matching::match_functions;
+use crate::commands::{function_label, load_deobfuscated};
+use crate::fetcher::events::fetch_events;
+use anyhow::bail;
+use std::collections::BTreeMap;
+
+pub fn run(args: &[String]) -> Result<(), anyhow::Error> {
+    let [old_path, new_path] = args else {
+        bail!("usage: diff <old.wasm> <new.wasm>");
+    };
+
+    let mut old = load_deobfuscated(old_path)?;
+    let mut new = load_deobfuscated(new_path)?;
+
+    let old_fps = fingerprint_module(&old);
+    let new_fps = fingerprint_module(&new);
+    let matching = match_functions(&old_fps, &new_fps);
+
+    let mut changed = matching
+        .matched
+        .iter()
+        .filter(|m| !m.unchanged)
+        .collect::<Vec<_>>();
+    changed.sort_by(|a, b| a.similarity.total_cmp(&b.similarity));
+
+    println!(
+        "functions: {} -> {} (matched {}, changed {}, added {}, removed {})",
+        old_fps.len(),
+        new_fps.len(),
+        matching.matched.len(),
+        changed.len(),
+        matching.added.len(),
+        matching.removed.len()
+    );
+
+    if !changed.is_empty() {
+        println!("\nchanged:");
+        for m in changed.iter() {
+            let old_count = old_fps.iter().find(|f| f.id == m.old).unwrap().instr_count;
+            let new_count = new_fps.iter().find(|f| f.id == m.new).unwrap().instr_count;
+            println!(
+                "  ~ {} -> {} (similarity {:.2}, {} -> {} instrs)",
+                function_label(&old, m.old),
+                function_label(&new, m.new),
+                m.similarity,
+                old_count,
+                new_count
+            );
+        }
+    }
+
+    if !matching.added.is_empty() {
+        println!("\nadded:");
+        for id in matching.added.iter() {
+            println!("  + {}", function_label(&new, *id));
+        }
+    }
+
+    if !matching.removed.is_empty() {
+        println!("\nremoved:");
+        for id in matching.removed.iter() {
+            println!("  - {}", function_label(&old, *id));
+        }
+    }
+
+    let old_events = fetch_events(&mut old)?;
+    let new_events = fetch_events(&mut new)?;
+    print_events_diff(&old_events, &new_events);
+
+    Ok(())
+}
+
+// Events are `name,hash,flag` lines, keyed by name
+fn parse_events(events: &str) -> BTreeMap<&str, &str> {
+    events
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| line.split_once(',').unwrap_or((line, "")))
+        .collect()
+}
+
+fn print_events_diff(old: &str, new: &str) {
+    let old_events = parse_events(old);
+    let new_events = parse_events(new);
+
+    let mut lines = Vec::new();
+    for (name, value) in old_events.iter() {
+        match new_events.get(name) {
+            None => lines.push(format!("  - {},{}", name, value)),
+            Some(new_value) if new_value != value => {
+                lines.push(format!("  ~ {}: {} -> {}", name, value, new_value))
+            }
+            _ => {}
+        }
+    }
+
+    for (name, value) in new_events.iter() {
+        if !old_events.contains_key(name) {
+            lines.push(format!("  + {},{}", name, value));
+        }
+    }
+
+    println!(
+        "\nevents: {} -> {} ({} differences)",
+        old_events.len(),
+        new_events.len(),
+        lines.len()
+    );
+    for line in lines {
+        println!("{}", line);
+    }
+}
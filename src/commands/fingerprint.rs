@@ -0,0 +1,41 @@
+use crate::analysis::build::BuildFingerprint;
+use crate::commands::{load_module, print_differences};
+use crate::profile::Profile;
+use anyhow::bail;
+
+const USAGE: &str = "usage: fingerprint <input.wasm> [--profiles <dir>]";
+
+// Prints the build fingerprint as a profile section, and with `--profiles` which known
+// build it is or is closest to
+pub fn run(args: &[String]) -> Result<(), anyhow::Error> {
+    let (input, dir) = match args {
+        [input] => (input, None),
+        [input, flag, dir] if flag == "--profiles" => (input, Some(dir)),
+        _ => bail!(USAGE),
+    };
+
+    let module = load_module(input)?;
+    let fingerprint = BuildFingerprint::of(&module);
+    println!("# build {:016x}", fingerprint.hash());
+    print!("{}", Profile::fingerprint_section(&fingerprint));
+
+    let Some(dir) = dir else {
+        return Ok(());
+    };
+    let profiles = Profile::load_dir(dir)?;
+    match Profile::select(&profiles, &fingerprint) {
+        None => println!("no profile in {} has a fingerprint", dir),
+        Some(selection) if selection.differences.is_empty() => {
+            println!("known build, profile {}", selection.profile.name)
+        }
+        Some(selection) => {
+            println!(
+                "unknown build, closest is profile {}",
+                selection.profile.name
+            );
+            print_differences(&selection.differences);
+        }
+    }
+
+    Ok(())
+}
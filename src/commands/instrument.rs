@@ -0,0 +1,66 @@
+use crate::analysis::signatures::SignatureDb;
+use crate::commands::{check_profile, code_pass, instrument, load_module};
+use crate::profile::{Profile, PASSES};
+use crate::transformations::signatures::SignatureTransformer;
+use crate::transformations::Transformer;
+use anyhow::bail;
+use std::path::Path;
+
+const USAGE: &str =
+    "usage: instrument <input.wasm> [output.wasm] [--sigs <db.sigs>] [--passes <pass,...>] [--profile <profile.toml|profile.json>]";
+
+// Keeps the memory encryption, the output is a drop-in replacement for the original
+pub fn run(args: &[String]) -> Result<(), anyhow::Error> {
+    let mut paths = Vec::new();
+    let mut passes: Vec<Box<dyn Transformer>> = Vec::new();
+    let mut profile = Profile::default();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--sigs" => {
+                let Some(db_path) = args.next() else {
+                    bail!(USAGE);
+                };
+                let db = SignatureDb::load(Path::new(db_path))?;
+                passes.push(Box::new(SignatureTransformer::new(db)));
+            }
+            "--passes" => {
+                let Some(list) = args.next() else {
+                    bail!(USAGE);
+                };
+                for pass in list.split(',') {
+                    let Some(transformer) = code_pass(pass) else {
+                        let known = PASSES.iter().filter(|known| code_pass(known).is_some());
+                        bail!(
+                            "{} can't run in instrument mode, it runs {}",
+                            pass,
+                            known.copied().collect::<Vec<_>>().join(", ")
+                        );
+                    };
+                    passes.push(transformer);
+                }
+            }
+            "--profile" => {
+                let Some(path) = args.next() else {
+                    bail!(USAGE);
+                };
+                profile = Profile::load(path)?;
+            }
+            _ => paths.push(arg.as_str()),
+        }
+    }
+
+    let (input, output) = match paths[..] {
+        [input] => (input, "./assets/output.wasm"),
+        [input, output] => (input, output),
+        _ => bail!(USAGE),
+    };
+
+    let mut module = load_module(input)?;
+    check_profile(&module, &profile)?;
+    instrument(&mut module, &profile, &mut passes)?;
+    module.emit_wasm_file(output)?;
+
+    Ok(())
+}
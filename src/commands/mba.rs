@@ -0,0 +1,27 @@
+use crate::commands::load_module;
+use crate::transformations::Transformer;
+use crate::transformations::mba::MbaTransformer;
+use anyhow::bail;
+
+const USAGE: &str = "usage: mba <input.wasm> [output.wasm]";
+
+// Simplifies the MBA expressions of the module as it is, printing every rewrite
+pub fn run(args: &[String]) -> Result<(), anyhow::Error> {
+    let (input, output) = match args {
+        [input] => (input, None),
+        [input, output] => (input, Some(output)),
+        _ => bail!(USAGE),
+    };
+
+    let mut module = load_module(input)?;
+    let mut transformer = MbaTransformer::default();
+    transformer.transform(&mut module);
+
+    print!("{}", transformer.report(&module));
+    println!("{} expressions simplified", transformer.rewrites.len());
+
+    if let Some(output) = output {
+        module.emit_wasm_file(output)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,205 @@
+pub mod callgraph;
+pub mod cfg;
+pub mod decompile;
+pub mod diff;
+pub mod fingerprint;
+pub mod instrument;
+pub mod json;
+pub mod mba;
+pub mod profile;
+pub mod sigs;
+pub mod wrappers;
+pub mod xrefs;
+
+use crate::transformations::bulk_memory::BulkMemoryTransformer;
+use crate::transformations::globals::ConstantGlobalTransformer;
+use crate::transformations::indirect_calls::IndirectCallTransformer;
+use crate::transformations::mba::MbaTransformer;
+use crate::transformations::memory::memory_encryption::SchemeRegistry;
+use crate::transformations::memory::memory_transformer::MemoryTransformer;
+use crate::transformations::memory::reencrypt::DecryptedMemory;
+use crate::transformations::Transformer;
+use crate::fetcher::events::find_events_function;
+use crate::analysis::build::{BuildFingerprint, FeatureDiff};
+use crate::profile::{Profile, PASSES};
+use anyhow::{bail, Context};
+use std::path::Path;
+use walrus::{ExportItem, FunctionId, ImportKind, Module};
+
+pub fn load_module(path: &str) -> Result<Module, anyhow::Error> {
+    if !Path::new(path).exists() {
+        bail!("{} does not exist", path);
+    }
+
+    Module::from_file(path)
+}
+
+pub fn deobfuscate(module: &mut Module) -> Result<(), anyhow::Error> {
+    deobfuscate_with(module, &Profile::default())
+}
+
+// Fails with every mismatched field, deobfuscating with a wrong profile only reports the
+// first thing it can't find
+pub fn check_profile(module: &Module, profile: &Profile) -> Result<(), anyhow::Error> {
+    let mismatches = profile
+        .validate(module)
+        .into_iter()
+        .map(|mismatch| format!("\n  {}: {}", mismatch.field, mismatch.reason))
+        .collect::<String>();
+
+    if !mismatches.is_empty() {
+        bail!("profile {} doesn't match the module:{}", profile.name, mismatches);
+    }
+    Ok(())
+}
+
+// Runs the passes the profile lists, by default the memory transformer then bulk memory,
+// as copy and fill loops only show up once the wrappers are gone, then constant globals
+// and indirect call resolution, which can use their values. MBA simplification has to
+// come before the memory transformer.
+pub fn deobfuscate_with(module: &mut Module, profile: &Profile) -> Result<(), anyhow::Error> {
+    // a profile built in code skips the checks `Profile::parse` does, fail before any
+    // pass runs
+    if let Some(pass) = profile.passes.iter().find(|pass| !PASSES.contains(&pass.as_str())) {
+        bail!("unknown pass {}, known are {}", pass, PASSES.join(", "));
+    }
+
+    for pass in profile.passes.iter() {
+        if let Some(mut transformer) = code_pass(pass) {
+            transformer.transform(module);
+        } else {
+            MemoryTransformer::with_profile(profile.clone())
+                .try_transform(module)
+                .context("Failed to revert the memory encryption")?;
+        }
+    }
+
+    Ok(())
+}
+
+// Every pass out of `PASSES` but the memory transformer only rewrites code, they can
+// also run in instrument mode
+pub fn code_pass(pass: &str) -> Option<Box<dyn Transformer>> {
+    match pass {
+        "mba" => Some(Box::new(MbaTransformer::default())),
+        "bulk_memory" => Some(Box::new(BulkMemoryTransformer)),
+        "globals" => Some(Box::new(ConstantGlobalTransformer::default())),
+        "indirect_calls" => Some(Box::new(IndirectCallTransformer::default())),
+        _ => None,
+    }
+}
+
+// The profile in `dir` made for the build. An unknown build gets the built-in profile,
+// the closest known one is only a hint, its constants may not hold for the build.
+pub fn select_profile(module: &Module, dir: &str) -> Result<Profile, anyhow::Error> {
+    let profiles = Profile::load_dir(dir)?;
+    let fingerprint = BuildFingerprint::of(module);
+    let Some(selection) = Profile::select(&profiles, &fingerprint) else {
+        bail!("no profile in {} has a fingerprint", dir);
+    };
+
+    if selection.differences.is_empty() {
+        println!("build {:016x}: profile {}", fingerprint.hash(), selection.profile.name);
+        return Ok(selection.profile.clone());
+    }
+
+    println!(
+        "unknown build {:016x}, using the default profile, closest is profile {} (--profile to use it):",
+        fingerprint.hash(),
+        selection.profile.name
+    );
+    print_differences(&selection.differences);
+    Ok(Profile::default())
+}
+
+pub fn print_differences(differences: &[FeatureDiff]) {
+    for diff in differences.iter() {
+        println!(
+            "  {}: {} known, {} here",
+            diff.feature,
+            diff.known,
+            diff.actual.as_deref().unwrap_or("none")
+        );
+    }
+}
+
+// Runs passes that only touch code (no memory access rewriting) with the data segment
+// decrypted, then encrypts it back, so the output still works with the original loader
+pub fn instrument(
+    module: &mut Module,
+    profile: &Profile,
+    passes: &mut [Box<dyn Transformer>],
+) -> Result<(), anyhow::Error> {
+    let memory = DecryptedMemory::decrypt(module, &SchemeRegistry::default().with_profile(profile.clone()))?;
+
+    for pass in passes.iter_mut() {
+        pass.transform(module);
+    }
+
+    memory.encrypt(module)
+}
+
+pub fn load_deobfuscated(path: &str) -> Result<Module, anyhow::Error> {
+    let mut module = load_module(path)?;
+    deobfuscate(&mut module)?;
+
+    Ok(module)
+}
+
+// `func[index]`, followed by whatever names we know for it
+pub fn function_label(module: &Module, id: FunctionId) -> String {
+    let mut names = module
+        .exports
+        .iter()
+        .filter(|e| matches!(e.item, ExportItem::Function(f) if f == id))
+        .map(|e| e.name.clone())
+        .collect::<Vec<_>>();
+
+    if let Some(name) = &module.funcs.get(id).name {
+        names.insert(0, name.clone());
+    }
+
+    if names.is_empty() {
+        format!("func[{}]", id.index())
+    } else {
+        format!("func[{}] ({})", id.index(), names.join(", "))
+    }
+}
+
+// By index, `events` for the function decrypting the events, or by function, export or
+// import name
+pub fn find_function(module: &Module, name: &str) -> Result<FunctionId, anyhow::Error> {
+    if let Ok(index) = name.parse::<usize>() {
+        return module
+            .funcs
+            .iter()
+            .find(|f| f.id().index() == index)
+            .map(|f| f.id())
+            .with_context(|| format!("no function {}", index));
+    }
+
+    if name == "events" {
+        return find_events_function(module).context("could not find function that init events");
+    }
+
+    let named = module
+        .funcs
+        .iter()
+        .find(|f| f.name.as_deref() == Some(name))
+        .map(|f| f.id());
+    let exported = module.exports.iter().find_map(|e| match e.item {
+        ExportItem::Function(f) if e.name == name => Some(f),
+        _ => None,
+    });
+    let imported = module.imports.iter().find_map(|i| match i.kind {
+        ImportKind::Function(f) if i.name == name || format!("{}.{}", i.module, i.name) == name => {
+            Some(f)
+        }
+        _ => None,
+    });
+
+    named
+        .or(exported)
+        .or(imported)
+        .with_context(|| format!("no function named {}", name))
+}
@@ -0,0 +1,27 @@
+use crate::commands::load_module;
+use crate::profile::Profile;
+use anyhow::bail;
+
+const USAGE: &str = "usage: profile <input.wasm> [--profile <profile.toml|profile.json>]";
+
+// Checks a build profile against a module, the built-in one without `--profile`
+pub fn run(args: &[String]) -> Result<(), anyhow::Error> {
+    let (input, profile) = match args {
+        [input] => (input, Profile::default()),
+        [input, flag, path] if flag == "--profile" => (input, Profile::load(path)?),
+        _ => bail!(USAGE),
+    };
+
+    let module = load_module(input)?;
+    let mismatches = profile.validate(&module);
+    for mismatch in mismatches.iter() {
+        println!("{}: {}", mismatch.field, mismatch.reason);
+    }
+
+    if !mismatches.is_empty() {
+        bail!("profile {} doesn't match {}", profile.name, input);
+    }
+    println!("profile {} matches {}", profile.name, input);
+
+    Ok(())
+}
@@ -0,0 +1,59 @@
+use crate::analysis::signatures::SignatureDb;
+use crate::commands::{load_deobfuscated, load_module};
+use crate::transformations::signatures::SignatureTransformer;
+use crate::transformations::Transformer;
+use anyhow::bail;
+use std::path::Path;
+
+const USAGE: &str = "usage:
+  sigs learn <annotated.wasm> <db.sigs>
+  sigs apply <db.sigs> <input.wasm> [output.wasm]";
+
+pub fn run(args: &[String]) -> Result<(), anyhow::Error> {
+    match args {
+        [command, annotated, db_path] if command == "learn" => learn(annotated, db_path),
+        [command, db_path, input] if command == "apply" => {
+            apply(db_path, input, "./assets/output.wasm")
+        }
+        [command, db_path, input, output] if command == "apply" => apply(db_path, input, output),
+        _ => bail!(USAGE),
+    }
+}
+
+// The annotated build is expected to be already deobfuscated (e.g. an output of `sigs apply`
+// renamed by hand), so it is not run through the transformers again.
+fn learn(annotated: &str, db_path: &str) -> Result<(), anyhow::Error> {
+    let module = load_module(annotated)?;
+
+    let db_path = Path::new(db_path);
+    let mut db = if db_path.exists() {
+        SignatureDb::load(db_path)?
+    } else {
+        SignatureDb::default()
+    };
+
+    let stats = db.learn(&module);
+    db.save(db_path)?;
+
+    println!(
+        "learned {} signatures ({} ambiguous skipped), {} total",
+        stats.learned,
+        stats.conflicts,
+        db.entries.len()
+    );
+
+    Ok(())
+}
+
+fn apply(db_path: &str, input: &str, output: &str) -> Result<(), anyhow::Error> {
+    let db = SignatureDb::load(Path::new(db_path))?;
+    let mut module = load_deobfuscated(input)?;
+
+    let mut transformer = SignatureTransformer::new(db);
+    transformer.transform(&mut module);
+    println!("named {} functions", transformer.applied);
+
+    module.emit_wasm_file(output)?;
+
+    Ok(())
+}
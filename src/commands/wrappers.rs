@@ -0,0 +1,60 @@
+use crate::commands::{function_label, load_module};
+use crate::profile::Profile;
+use crate::transformations::memory::behavior::{cross_check, BehavioralMapper};
+use crate::transformations::memory::memory_transformer::MemoryTransformer;
+use crate::transformations::memory::MemEncFuncType;
+use anyhow::bail;
+use std::collections::HashMap;
+use walrus::{FunctionId, Module};
+
+const USAGE: &str = "usage: wrappers <input.wasm> [--profile <profile.toml|profile.json>]";
+
+// Classifies the memory wrappers statically and by running them, fails if they disagree
+pub fn run(args: &[String]) -> Result<(), anyhow::Error> {
+    let (input, profile) = match args {
+        [input] => (input, Profile::default()),
+        [input, flag, path] if flag == "--profile" => (input, Profile::load(path)?),
+        _ => bail!(USAGE),
+    };
+
+    let module = load_module(input)?;
+    let transformer = MemoryTransformer::with_profile(profile.clone());
+    let behavioral = BehavioralMapper::default().with_profile(profile);
+
+    let static_loads = transformer.map_load_functions(&module)?;
+    let static_stores = transformer.map_store_functions(&module)?;
+    let loads = behavioral.map_load_functions(&module)?;
+    let stores = behavioral.map_store_functions(&module, &loads)?;
+
+    print_wrappers(&module, "load", &loads);
+    print_wrappers(&module, "store", &stores);
+
+    let mismatches = cross_check(&static_loads, &loads)
+        .into_iter()
+        .chain(cross_check(&static_stores, &stores))
+        .collect::<Vec<_>>();
+
+    for mismatch in mismatches.iter() {
+        println!(
+            "mismatch {}: static {:?}, behavioral {:?}",
+            function_label(&module, mismatch.func),
+            mismatch.static_type,
+            mismatch.behavioral_type
+        );
+    }
+
+    if !mismatches.is_empty() {
+        bail!("{} wrappers classified differently", mismatches.len());
+    }
+
+    Ok(())
+}
+
+fn print_wrappers(module: &Module, kind: &str, wrappers: &HashMap<FunctionId, MemEncFuncType>) {
+    let mut wrappers = wrappers.iter().collect::<Vec<_>>();
+    wrappers.sort_by_key(|(id, _)| id.index());
+
+    for (id, ty) in wrappers {
+        println!("{} {} {:?}", kind, function_label(module, *id), ty);
+    }
+}
@@ -0,0 +1,193 @@
+use crate::analysis::addresses::{AccessKind, MemoryAccess, Region, Target};
+use crate::analysis::xrefs::XrefDb;
+use crate::commands::json::{array, object, string};
+use crate::commands::{function_label, load_deobfuscated};
+use anyhow::{Context, bail};
+use walrus::{FunctionId, GlobalId, Module};
+
+const USAGE: &str = "usage: xrefs <input.wasm> --func <index> | --addr <0x..> | --global <index>";
+
+pub enum Query {
+    Func(FunctionId),
+    Addr(u32),
+    Global(GlobalId),
+}
+
+// Cross references of a function, address or global of the deobfuscated module, as JSON
+pub fn run(args: &[String]) -> Result<(), anyhow::Error> {
+    let [input, flag, value] = args else {
+        bail!(USAGE);
+    };
+
+    let module = load_deobfuscated(input)?;
+    let query = parse_query(&module, flag, value)?;
+    let db = XrefDb::build(&module);
+
+    println!("{}", query_json(&module, &db, &query));
+    Ok(())
+}
+
+fn parse_number(value: &str) -> Result<u32, anyhow::Error> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .with_context(|| format!("invalid number {}", value))
+}
+
+pub fn parse_query(module: &Module, flag: &str, value: &str) -> Result<Query, anyhow::Error> {
+    let n = parse_number(value)?;
+
+    match flag {
+        "--func" => module
+            .funcs
+            .iter()
+            .find(|f| f.id().index() == n as usize)
+            .map(|f| Query::Func(f.id()))
+            .with_context(|| format!("no function {}", n)),
+        "--addr" => Ok(Query::Addr(n)),
+        "--global" => module
+            .globals
+            .iter()
+            .find(|g| g.id().index() == n as usize)
+            .map(|g| Query::Global(g.id()))
+            .with_context(|| format!("no global {}", n)),
+        _ => bail!(USAGE),
+    }
+}
+
+fn offset(offset: Option<u32>) -> String {
+    offset.map_or("null".to_string(), |offset| offset.to_string())
+}
+
+fn kind(kind: AccessKind) -> String {
+    string(match kind {
+        AccessKind::Read => "read",
+        AccessKind::Write => "write",
+    })
+}
+
+fn function(module: &Module, id: FunctionId) -> Vec<(&'static str, String)> {
+    vec![
+        ("func", id.index().to_string()),
+        ("label", string(&function_label(module, id))),
+    ]
+}
+
+fn access(module: &Module, access: &MemoryAccess) -> String {
+    let target = match access.target {
+        Target::Address(a) => object(&[("address", a.to_string())]),
+        Target::Indexed(a) => object(&[("indexed", a.to_string())]),
+        Target::Stack(k) => object(&[("stack", k.to_string())]),
+        Target::Unknown => "null".to_string(),
+    };
+    let region = match access.region {
+        Region::Data(segment) => object(&[("data", segment.to_string())]),
+        Region::Static => string("static"),
+        Region::Stack => string("stack"),
+        Region::Unknown => string("unknown"),
+    };
+
+    let mut fields = function(module, access.func);
+    fields.extend([
+        ("offset", offset(access.offset)),
+        ("kind", kind(access.kind)),
+        ("width", access.width.to_string()),
+        ("target", target),
+        ("region", region),
+    ]);
+    object(&fields)
+}
+
+pub fn query_json(module: &Module, db: &XrefDb, query: &Query) -> String {
+    match query {
+        Query::Func(id) => {
+            let mut fields = function(module, *id);
+            fields.extend([
+                (
+                    "calls",
+                    array(db.calls_from(*id).map(|call| {
+                        let mut fields = function(module, call.callee);
+                        fields.extend([
+                            ("offset", offset(call.offset)),
+                            ("indirect", call.indirect.to_string()),
+                        ]);
+                        object(&fields)
+                    })),
+                ),
+                (
+                    "callers",
+                    array(db.callers_of(*id).map(|call| {
+                        let mut fields = function(module, call.caller);
+                        fields.extend([
+                            ("offset", offset(call.offset)),
+                            ("indirect", call.indirect.to_string()),
+                        ]);
+                        object(&fields)
+                    })),
+                ),
+                (
+                    "globals",
+                    array(db.globals_of(*id).map(|xref| {
+                        object(&[
+                            ("global", xref.global.index().to_string()),
+                            ("offset", offset(xref.offset)),
+                            ("kind", kind(xref.kind)),
+                        ])
+                    })),
+                ),
+                (
+                    "memory",
+                    array(db.touched_by(*id).map(|a| access(module, a))),
+                ),
+                (
+                    "constants",
+                    array(db.constants_of(*id).map(|xref| {
+                        object(&[
+                            ("address", xref.address.to_string()),
+                            ("offset", offset(xref.offset)),
+                        ])
+                    })),
+                ),
+            ]);
+            object(&fields)
+        }
+        Query::Addr(address) => object(&[
+            ("address", address.to_string()),
+            (
+                "readers",
+                array(db.readers(*address).map(|a| access(module, a))),
+            ),
+            (
+                "writers",
+                array(db.writers(*address).map(|a| access(module, a))),
+            ),
+            (
+                "constants",
+                array(db.constants_to(*address).map(|xref| {
+                    let mut fields = function(module, xref.func);
+                    fields.push(("offset", offset(xref.offset)));
+                    object(&fields)
+                })),
+            ),
+        ]),
+        Query::Global(global) => {
+            let refs = |k: AccessKind| {
+                array(
+                    db.global_refs(*global)
+                        .filter(|xref| xref.kind == k)
+                        .map(|xref| {
+                            let mut fields = function(module, xref.func);
+                            fields.push(("offset", offset(xref.offset)));
+                            object(&fields)
+                        }),
+                )
+            };
+            object(&[
+                ("global", global.index().to_string()),
+                ("readers", refs(AccessKind::Read)),
+                ("writers", refs(AccessKind::Write)),
+            ])
+        }
+    }
+}
@@ -0,0 +1,423 @@
+use crate::ssa::{load_type, op_type};
+use std::collections::{HashMap, HashSet};
+use walrus::ir::{BinaryOp, Instr, InstrSeqId, InstrSeqType, LoadKind, StoreKind, UnaryOp, Value};
+use walrus::{FunctionId, GlobalId, LocalFunction, LocalId, Module, TypeId, ValType};
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Const(Value),
+    Local(LocalId),
+    Global(GlobalId),
+    Temp(usize),
+    Unop(UnaryOp, Box<Expr>),
+    Binop(BinaryOp, Box<Expr>, Box<Expr>),
+    // condition, then the value when true and when false
+    Select(Box<Expr>, Box<Expr>, Box<Expr>),
+    Load(LoadKind, u32, Box<Expr>),
+    Call(FunctionId, Vec<Expr>),
+    // table index, then the arguments
+    CallIndirect(TypeId, Box<Expr>, Vec<Expr>),
+    // instructions without an expression of their own, by name
+    Intrinsic(&'static str, Vec<Expr>),
+    // a value the lifter lost track of
+    Unknown,
+}
+
+#[derive(Debug, Clone)]
+pub enum Stmt {
+    Expr(Expr),
+    SetLocal(LocalId, Expr),
+    SetGlobal(GlobalId, Expr),
+    SetTemp(usize, Expr),
+    // kind, offset, address, value
+    Store(StoreKind, u32, Expr, Expr),
+    Block(usize, Vec<Stmt>),
+    // falling off the end leaves the loop, `Continue` starts it over
+    Loop(usize, Vec<Stmt>),
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
+    Break(usize),
+    Continue(usize),
+    // one branch per case, then the default
+    Switch(Expr, Vec<Vec<Stmt>>, Vec<Stmt>),
+    Return(Vec<Expr>),
+    Unreachable,
+    // instruction the lifter doesn't know the stack effect of
+    Unsupported(String),
+}
+
+impl Stmt {
+    // Whether the code after it never runs
+    pub fn diverges(&self) -> bool {
+        matches!(
+            self,
+            Stmt::Break(_)
+                | Stmt::Continue(_)
+                | Stmt::Switch(..)
+                | Stmt::Return(_)
+                | Stmt::Unreachable
+        )
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Label {
+    id: usize,
+    is_loop: bool,
+    // temp taking the value a `block`/`if` ends with
+    result: Option<usize>,
+}
+
+// Body of a function as statements. Temps hold the values that had to be evaluated
+// before a statement, and the results of blocks.
+pub struct Lifted {
+    pub body: Vec<Stmt>,
+    pub temps: Vec<ValType>,
+    // labels some branch goes to, the others are not printed
+    pub used_labels: HashSet<usize>,
+}
+
+struct Lifter<'a> {
+    module: &'a Module,
+    func: &'a LocalFunction,
+    labels: HashMap<InstrSeqId, Label>,
+    temps: Vec<ValType>,
+    used_labels: HashSet<usize>,
+}
+
+fn value_type(value: &Value) -> ValType {
+    match value {
+        Value::I32(_) => ValType::I32,
+        Value::I64(_) => ValType::I64,
+        Value::F32(_) => ValType::F32,
+        Value::F64(_) => ValType::F64,
+        Value::V128(_) => ValType::V128,
+    }
+}
+
+fn pop(stack: &mut Vec<Expr>) -> Expr {
+    stack.pop().unwrap_or(Expr::Unknown)
+}
+
+fn pop_n(stack: &mut Vec<Expr>, n: usize) -> Vec<Expr> {
+    let mut values = (0..n).map(|_| pop(stack)).collect::<Vec<_>>();
+    values.reverse();
+    values
+}
+
+impl Lifter<'_> {
+    fn type_of(&self, expr: &Expr) -> ValType {
+        let module = self.module;
+        match expr {
+            Expr::Const(value) => value_type(value),
+            Expr::Local(local) => module.locals.get(*local).ty(),
+            Expr::Global(global) => module.globals.get(*global).ty,
+            Expr::Temp(temp) => self.temps[*temp],
+            Expr::Unop(op, _) => op_type(&format!("{:?}", op)),
+            Expr::Binop(op, _, _) => op_type(&format!("{:?}", op)),
+            Expr::Select(_, value, _) => self.type_of(value),
+            Expr::Load(kind, _, _) => load_type(kind),
+            Expr::Call(func, _) => {
+                let ty = module.types.get(module.funcs.get(*func).ty());
+                ty.results().first().copied().unwrap_or(ValType::I32)
+            }
+            Expr::CallIndirect(ty, _, _) => {
+                let ty = module.types.get(*ty);
+                ty.results().first().copied().unwrap_or(ValType::I32)
+            }
+            Expr::Intrinsic(..) | Expr::Unknown => ValType::I32,
+        }
+    }
+
+    fn temp(&mut self, ty: ValType) -> usize {
+        self.temps.push(ty);
+        self.temps.len() - 1
+    }
+
+    fn label(&mut self, seq: InstrSeqId, is_loop: bool) -> Label {
+        let result = match self.func.block(seq).ty {
+            InstrSeqType::Simple(Some(ty)) if !is_loop => Some(self.temp(ty)),
+            _ => None,
+        };
+        let label = Label {
+            id: self.labels.len(),
+            is_loop,
+            result,
+        };
+        self.labels.insert(seq, label);
+        label
+    }
+
+    // Number of values a branch to `target` takes along
+    fn arity(&self, target: InstrSeqId) -> usize {
+        if target == self.func.entry_block() {
+            return self.module.types.get(self.func.ty()).results().len();
+        }
+        usize::from(self.labels[&target].result.is_some())
+    }
+
+    // A branch to the function body returns, the others hand their value to the temp of
+    // the label first
+    fn branch(&mut self, target: InstrSeqId, values: Vec<Expr>) -> Vec<Stmt> {
+        if target == self.func.entry_block() {
+            return vec![Stmt::Return(values)];
+        }
+
+        let label = self.labels[&target];
+        self.used_labels.insert(label.id);
+        let mut out = Vec::new();
+        if let (Some(result), Some(value)) = (label.result, values.into_iter().next()) {
+            out.push(Stmt::SetTemp(result, value));
+        }
+        out.push(match label.is_loop {
+            true => Stmt::Continue(label.id),
+            false => Stmt::Break(label.id),
+        });
+        out
+    }
+
+    // Everything on the stack that isn't a constant or a temp is moved to a temp, so it
+    // is evaluated before the statement about to be emitted
+    fn spill(&mut self, stack: &mut [Expr], out: &mut Vec<Stmt>) {
+        for expr in stack.iter_mut() {
+            if matches!(expr, Expr::Const(_) | Expr::Temp(_)) {
+                continue;
+            }
+            let temp = self.temp(self.type_of(expr));
+            let value = std::mem::replace(expr, Expr::Temp(temp));
+            out.push(Stmt::SetTemp(temp, value));
+        }
+    }
+
+    fn statement(&mut self, stmt: Stmt, stack: &mut [Expr], out: &mut Vec<Stmt>) {
+        self.spill(stack, out);
+        out.push(stmt);
+    }
+
+    fn call(&mut self, expr: Expr, results: usize, stack: &mut Vec<Expr>, out: &mut Vec<Stmt>) {
+        match results {
+            1 => stack.push(expr),
+            n => {
+                self.statement(Stmt::Expr(expr), stack, out);
+                stack.extend((0..n).map(|_| Expr::Unknown));
+            }
+        }
+    }
+
+    // Body of a `block`/`loop`/`if`, its last value goes to `result`. Also returns whether
+    // it ends in a branch.
+    fn body(&mut self, seq: InstrSeqId, result: Option<usize>) -> (Vec<Stmt>, bool) {
+        let mut out = Vec::new();
+        let mut stack = Vec::new();
+        let diverges = self.seq(seq, &mut stack, &mut out);
+
+        if let (false, Some(result)) = (diverges, result) {
+            let value = pop(&mut stack);
+            out.push(Stmt::SetTemp(result, value));
+        }
+        (out, diverges)
+    }
+
+    // Lifts `seq` into `out`, returns whether it ends in a branch. What follows one is
+    // dead and left out.
+    fn seq(&mut self, seq: InstrSeqId, stack: &mut Vec<Expr>, out: &mut Vec<Stmt>) -> bool {
+        let func = self.func;
+        let module = self.module;
+
+        for (instr, _) in func.block(seq).instrs.iter() {
+            match instr {
+                Instr::Const(c) => stack.push(Expr::Const(c.value)),
+                Instr::LocalGet(get) => stack.push(Expr::Local(get.local)),
+                Instr::GlobalGet(get) => stack.push(Expr::Global(get.global)),
+                Instr::LocalSet(set) => {
+                    let value = pop(stack);
+                    self.statement(Stmt::SetLocal(set.local, value), stack, out);
+                }
+                Instr::LocalTee(tee) => {
+                    let value = pop(stack);
+                    self.statement(Stmt::SetLocal(tee.local, value), stack, out);
+                    stack.push(Expr::Local(tee.local));
+                }
+                Instr::GlobalSet(set) => {
+                    let value = pop(stack);
+                    self.statement(Stmt::SetGlobal(set.global, value), stack, out);
+                }
+                Instr::Unop(op) => {
+                    let value = pop(stack);
+                    stack.push(Expr::Unop(op.op, Box::new(value)));
+                }
+                Instr::Binop(op) => {
+                    let [left, right] = <[Expr; 2]>::try_from(pop_n(stack, 2)).unwrap();
+                    stack.push(Expr::Binop(op.op, Box::new(left), Box::new(right)));
+                }
+                Instr::Select(_) => {
+                    let [a, b, cond] = <[Expr; 3]>::try_from(pop_n(stack, 3)).unwrap();
+                    stack.push(Expr::Select(Box::new(cond), Box::new(a), Box::new(b)));
+                }
+                Instr::Load(load) => {
+                    let address = pop(stack);
+                    stack.push(Expr::Load(load.kind, load.arg.offset, Box::new(address)));
+                }
+                Instr::Store(store) => {
+                    let [address, value] = <[Expr; 2]>::try_from(pop_n(stack, 2)).unwrap();
+                    let stmt = Stmt::Store(store.kind, store.arg.offset, address, value);
+                    self.statement(stmt, stack, out);
+                }
+                Instr::Call(call) => {
+                    let ty = module.types.get(module.funcs.get(call.func).ty());
+                    let args = pop_n(stack, ty.params().len());
+                    self.call(Expr::Call(call.func, args), ty.results().len(), stack, out);
+                }
+                Instr::CallIndirect(call) => {
+                    let ty = module.types.get(call.ty);
+                    let index = pop(stack);
+                    let args = pop_n(stack, ty.params().len());
+                    let expr = Expr::CallIndirect(call.ty, Box::new(index), args);
+                    self.call(expr, ty.results().len(), stack, out);
+                }
+                Instr::Drop(_) => match pop(stack) {
+                    Expr::Const(_)
+                    | Expr::Local(_)
+                    | Expr::Global(_)
+                    | Expr::Temp(_)
+                    | Expr::Unknown => {}
+                    expr => self.statement(Stmt::Expr(expr), stack, out),
+                },
+                Instr::MemorySize(_) => stack.push(Expr::Intrinsic("memory_size", vec![])),
+                Instr::MemoryGrow(_) => {
+                    let pages = pop(stack);
+                    stack.push(Expr::Intrinsic("memory_grow", vec![pages]));
+                }
+                Instr::MemoryCopy(_) => {
+                    let args = pop_n(stack, 3);
+                    let stmt = Stmt::Expr(Expr::Intrinsic("memcpy", args));
+                    self.statement(stmt, stack, out);
+                }
+                Instr::MemoryFill(_) => {
+                    let args = pop_n(stack, 3);
+                    let stmt = Stmt::Expr(Expr::Intrinsic("memset", args));
+                    self.statement(stmt, stack, out);
+                }
+                Instr::Block(block) => {
+                    self.spill(stack, out);
+                    let label = self.label(block.seq, false);
+                    let (body, diverges) = self.body(block.seq, label.result);
+                    out.push(Stmt::Block(label.id, body));
+                    if diverges && !self.used_labels.contains(&label.id) {
+                        return true;
+                    }
+                    stack.extend(label.result.map(Expr::Temp));
+                }
+                Instr::Loop(block) => {
+                    self.spill(stack, out);
+                    let label = self.label(block.seq, true);
+                    let result = match func.block(block.seq).ty {
+                        InstrSeqType::Simple(Some(ty)) => Some(self.temp(ty)),
+                        _ => None,
+                    };
+                    let (body, diverges) = self.body(block.seq, result);
+                    out.push(Stmt::Loop(label.id, body));
+                    // only falling off the end leaves a loop
+                    if diverges {
+                        return true;
+                    }
+                    stack.extend(result.map(Expr::Temp));
+                }
+                Instr::IfElse(if_else) => {
+                    let cond = pop(stack);
+                    self.spill(stack, out);
+                    let label = self.label(if_else.consequent, false);
+                    self.labels.insert(if_else.alternative, label);
+                    let (consequent, left) = self.body(if_else.consequent, label.result);
+                    let (alternative, right) = self.body(if_else.alternative, label.result);
+
+                    // a branch to the `if` leaves it, it needs a block to break out of
+                    let stmt = Stmt::If(cond, consequent, alternative);
+                    if self.used_labels.contains(&label.id) {
+                        out.push(Stmt::Block(label.id, vec![stmt]));
+                    } else {
+                        out.push(stmt);
+                        if left && right {
+                            return true;
+                        }
+                    }
+                    stack.extend(label.result.map(Expr::Temp));
+                }
+                Instr::Br(br) => {
+                    let values = pop_n(stack, self.arity(br.block));
+                    // the rest of the stack is dropped, but still has to be evaluated
+                    self.spill(stack, out);
+                    let stmts = self.branch(br.block, values);
+                    out.extend(stmts);
+                    return true;
+                }
+                Instr::BrIf(br) => {
+                    let cond = pop(stack);
+                    self.spill(stack, out);
+                    // the values passed along stay on the stack when it isn't taken
+                    let arity = self.arity(br.block).min(stack.len());
+                    let values = stack[stack.len() - arity..].to_vec();
+                    let taken = self.branch(br.block, values);
+                    out.push(Stmt::If(cond, taken, vec![]));
+                }
+                Instr::BrTable(table) => {
+                    let index = pop(stack);
+                    self.spill(stack, out);
+                    let values = pop_n(stack, self.arity(table.default));
+                    let cases = table
+                        .blocks
+                        .iter()
+                        .map(|target| self.branch(*target, values.clone()))
+                        .collect::<Vec<_>>();
+                    let default = self.branch(table.default, values);
+                    out.push(Stmt::Switch(index, cases, default));
+                    return true;
+                }
+                Instr::Return(_) => {
+                    let ty = module.types.get(func.ty());
+                    let values = pop_n(stack, ty.results().len());
+                    self.statement(Stmt::Return(values), stack, out);
+                    return true;
+                }
+                Instr::Unreachable(_) => {
+                    self.statement(Stmt::Unreachable, stack, out);
+                    return true;
+                }
+                instr => {
+                    let name = format!("{:?}", instr);
+                    self.statement(Stmt::Unsupported(name), stack, out);
+                    if matches!(instr, Instr::ReturnCall(_) | Instr::ReturnCallIndirect(_)) {
+                        return true;
+                    }
+                }
+            }
+        }
+
+        false
+    }
+}
+
+pub fn lift(module: &Module, func: &LocalFunction) -> Lifted {
+    let mut lifter = Lifter {
+        module,
+        func,
+        labels: HashMap::new(),
+        temps: Vec::new(),
+        used_labels: HashSet::new(),
+    };
+
+    let mut body = Vec::new();
+    let mut stack = Vec::new();
+    if !lifter.seq(func.entry_block(), &mut stack, &mut body) {
+        let results = module.types.get(func.ty()).results().len();
+        if results > 0 {
+            let values = pop_n(&mut stack, results);
+            lifter.statement(Stmt::Return(values), &mut stack, &mut body);
+        }
+    }
+
+    Lifted {
+        body,
+        temps: lifter.temps,
+        used_labels: lifter.used_labels,
+    }
+}
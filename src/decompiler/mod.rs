@@ -0,0 +1,792 @@
+pub mod lift;
+
+use lift::{Expr, Lifted, Stmt, lift};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use walrus::ir::{
+    BinaryOp, ExtendedLoad, LoadKind, StoreKind, UnaryOp, Value, Visitor, dfs_in_order,
+};
+use walrus::{
+    ConstExpr, DataKind, ExportItem, FunctionId, FunctionKind, GlobalKind, LocalFunction, LocalId,
+    Module, ValType,
+};
+
+// Longest string literal printed as is, the rest is cut
+const MAX_STRING: usize = 64;
+
+// `I32ShrU` -> `i32.shr_u`, `MemoryCopy` -> `memory.copy`: first word, then the rest in snake case
+pub fn mnemonic(name: &str) -> String {
+    let name = name
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .next()
+        .unwrap_or(name);
+    let split = name
+        .char_indices()
+        .skip(1)
+        .find(|(_, c)| c.is_ascii_uppercase())
+        .map_or(name.len(), |(i, _)| i);
+
+    let mut out = name[..split].to_ascii_lowercase();
+    for (i, c) in name[split..].char_indices() {
+        if c.is_ascii_uppercase() {
+            out.push(if i == 0 { '.' } else { '_' });
+        }
+        out.push(c.to_ascii_lowercase());
+    }
+    out
+}
+
+#[derive(Default)]
+struct LocalOrder {
+    order: Vec<LocalId>,
+}
+
+impl<'a> Visitor<'a> for LocalOrder {
+    fn visit_local_id(&mut self, local: &LocalId) {
+        if !self.order.contains(local) {
+            self.order.push(*local);
+        }
+    }
+}
+
+// Identifier made of `name`, anything else than letters, digits and `_` becomes `_`
+fn identifier(name: &str) -> String {
+    let mut out = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    if !out.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        out.insert(0, '_');
+    }
+    out
+}
+
+// The name section names if there are, else parameters are `p0..` and other locals `l0..`
+// in the order they show up
+pub fn local_names(module: &Module, func: &LocalFunction) -> HashMap<LocalId, String> {
+    let mut visitor = LocalOrder::default();
+    dfs_in_order(&mut visitor, func, func.entry_block());
+
+    let mut taken = HashSet::new();
+    let mut names = HashMap::new();
+    let locals = func.args.iter().chain(visitor.order.iter());
+    for local in locals {
+        if names.contains_key(local) {
+            continue;
+        }
+        let name = match &module.locals.get(*local).name {
+            Some(name) if taken.insert(identifier(name)) => identifier(name),
+            _ if names.len() < func.args.len() => format!("p{}", names.len()),
+            _ => format!("l{}", names.len() - func.args.len()),
+        };
+        taken.insert(name.clone());
+        names.insert(*local, name);
+    }
+    names
+}
+
+// Name section, export or import name, else `func_<index>`. Names showing up twice get
+// the index appended.
+pub fn function_names(module: &Module) -> HashMap<FunctionId, String> {
+    let mut exports = HashMap::new();
+    for export in module.exports.iter() {
+        if let ExportItem::Function(id) = export.item {
+            exports.entry(id).or_insert(export.name.as_str());
+        }
+    }
+
+    let mut taken = HashSet::new();
+    module
+        .funcs
+        .iter()
+        .map(|func| {
+            let import = match func.kind {
+                FunctionKind::Import(ref import) => {
+                    Some(module.imports.get(import.import).name.as_str())
+                }
+                _ => None,
+            };
+            let name = match func.name.as_deref().or(exports.get(&func.id()).copied()) {
+                Some(name) => identifier(name),
+                None => match import {
+                    Some(name) => identifier(name),
+                    None => format!("func_{}", func.id().index()),
+                },
+            };
+            let name = match taken.insert(name.clone()) {
+                true => name,
+                false => format!("{}_{}", name, func.id().index()),
+            };
+            (func.id(), name)
+        })
+        .collect()
+}
+
+pub fn type_name(ty: ValType) -> &'static str {
+    match ty {
+        ValType::I32 => "i32",
+        ValType::I64 => "i64",
+        ValType::F32 => "f32",
+        ValType::F64 => "f64",
+        ValType::V128 => "v128",
+        ValType::Ref(_) => "ref",
+    }
+}
+
+// `void`, the type, or the types in parentheses for several
+fn results_name(results: &[ValType]) -> String {
+    match results {
+        [] => "void".to_string(),
+        [ty] => type_name(*ty).to_string(),
+        tys => {
+            let tys = tys.iter().map(|ty| type_name(*ty)).collect::<Vec<_>>();
+            format!("({})", tys.join(", "))
+        }
+    }
+}
+
+fn load_pointer(kind: &LoadKind) -> &'static str {
+    let signed = |kind: &ExtendedLoad| matches!(kind, ExtendedLoad::SignExtend);
+    match kind {
+        LoadKind::I32 { .. } => "i32",
+        LoadKind::I64 { .. } => "i64",
+        LoadKind::F32 => "f32",
+        LoadKind::F64 => "f64",
+        LoadKind::V128 => "v128",
+        LoadKind::I32_8 { kind } | LoadKind::I64_8 { kind } if signed(kind) => "s8",
+        LoadKind::I32_8 { .. } | LoadKind::I64_8 { .. } => "u8",
+        LoadKind::I32_16 { kind } | LoadKind::I64_16 { kind } if signed(kind) => "s16",
+        LoadKind::I32_16 { .. } | LoadKind::I64_16 { .. } => "u16",
+        LoadKind::I64_32 { kind } if signed(kind) => "s32",
+        LoadKind::I64_32 { .. } => "u32",
+    }
+}
+
+fn store_pointer(kind: &StoreKind) -> &'static str {
+    match kind {
+        StoreKind::I32 { .. } => "i32",
+        StoreKind::I64 { .. } => "i64",
+        StoreKind::F32 => "f32",
+        StoreKind::F64 => "f64",
+        StoreKind::V128 => "v128",
+        StoreKind::I32_8 { .. } | StoreKind::I64_8 { .. } => "u8",
+        StoreKind::I32_16 { .. } | StoreKind::I64_16 { .. } => "u16",
+        StoreKind::I64_32 { .. } => "u32",
+    }
+}
+
+// C precedence, higher binds tighter
+const PRIMARY: u8 = 15;
+const UNARY: u8 = 14;
+const TERNARY: u8 = 3;
+
+// Infix operator and precedence of a binop, and whether its operands are signed
+fn infix(op: &str) -> Option<(&'static str, u8, bool)> {
+    Some(match op {
+        "Mul" => ("*", 13, false),
+        "DivS" => ("/", 13, true),
+        "DivU" | "Div" => ("/", 13, false),
+        "RemS" => ("%", 13, true),
+        "RemU" => ("%", 13, false),
+        "Add" => ("+", 12, false),
+        "Sub" => ("-", 12, false),
+        "Shl" => ("<<", 11, false),
+        "ShrS" => (">>", 11, true),
+        "ShrU" => (">>", 11, false),
+        "LtS" => ("<", 10, true),
+        "LtU" | "Lt" => ("<", 10, false),
+        "GtS" => (">", 10, true),
+        "GtU" | "Gt" => (">", 10, false),
+        "LeS" => ("<=", 10, true),
+        "LeU" | "Le" => ("<=", 10, false),
+        "GeS" => (">=", 10, true),
+        "GeU" | "Ge" => (">=", 10, false),
+        "Eq" => ("==", 9, false),
+        "Ne" => ("!=", 9, false),
+        "And" => ("&", 8, false),
+        "Xor" => ("^", 7, false),
+        "Or" => ("|", 6, false),
+        _ => return None,
+    })
+}
+
+// Cast standing for a conversion, the operand is cast first when its signedness matters
+fn cast(op: UnaryOp) -> Option<(&'static str, Option<&'static str>)> {
+    Some(match op {
+        UnaryOp::I32WrapI64 => ("i32", None),
+        UnaryOp::I64ExtendSI32 => ("i64", Some("s32")),
+        UnaryOp::I64ExtendUI32 => ("i64", Some("u32")),
+        UnaryOp::I32Extend8S | UnaryOp::I64Extend8S => ("s8", None),
+        UnaryOp::I32Extend16S | UnaryOp::I64Extend16S => ("s16", None),
+        UnaryOp::I64Extend32S => ("s32", None),
+        UnaryOp::F32ConvertSI32 => ("f32", Some("s32")),
+        UnaryOp::F32ConvertUI32 => ("f32", Some("u32")),
+        UnaryOp::F32ConvertSI64 => ("f32", Some("s64")),
+        UnaryOp::F32ConvertUI64 => ("f32", Some("u64")),
+        UnaryOp::F64ConvertSI32 => ("f64", Some("s32")),
+        UnaryOp::F64ConvertUI32 => ("f64", Some("u32")),
+        UnaryOp::F64ConvertSI64 => ("f64", Some("s64")),
+        UnaryOp::F64ConvertUI64 => ("f64", Some("u64")),
+        UnaryOp::F32DemoteF64 => ("f32", None),
+        UnaryOp::F64PromoteF32 => ("f64", None),
+        _ => return None,
+    })
+}
+
+// Bytes of the active data segments by address. After deobfuscation the strings in there
+// are decrypted.
+struct Data<'a> {
+    segments: Vec<(u32, &'a [u8])>,
+}
+
+impl Data<'_> {
+    fn bytes(&self, address: u32) -> Option<&[u8]> {
+        self.segments.iter().find_map(|(start, bytes)| {
+            let at = address.checked_sub(*start)? as usize;
+            bytes.get(at..).filter(|rest| !rest.is_empty())
+        })
+    }
+
+    // Whether `address` is the start of the data or follows a NUL
+    fn starts_string(&self, address: u32) -> bool {
+        self.segments.iter().any(|(start, bytes)| {
+            address == *start
+                || address
+                    .checked_sub(*start + 1)
+                    .and_then(|at| bytes.get(at as usize))
+                    .is_some_and(|b| *b == 0)
+        })
+    }
+
+    // `len` printable bytes at `address`
+    fn string(&self, address: u32, len: usize) -> Option<&[u8]> {
+        let bytes = self.bytes(address)?.get(..len)?;
+        bytes.iter().all(|b| printable(*b)).then_some(bytes)
+    }
+
+    // NUL terminated string of at least 4 printable bytes at `address`
+    fn c_string(&self, address: u32) -> Option<&[u8]> {
+        let bytes = self.bytes(address)?;
+        let len = bytes.iter().position(|b| !printable(*b))?;
+        (len >= 4 && bytes[len] == 0 && self.starts_string(address)).then_some(&bytes[..len])
+    }
+}
+
+fn printable(b: u8) -> bool {
+    b.is_ascii_graphic() || matches!(b, b' ' | b'\n' | b'\t' | b'\r')
+}
+
+fn string_literal(bytes: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for b in bytes.iter().take(MAX_STRING) {
+        match b {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\t' => out.push_str("\\t"),
+            b'\r' => out.push_str("\\r"),
+            b => out.push(*b as char),
+        }
+    }
+    if bytes.len() > MAX_STRING {
+        out.push_str("...");
+    }
+    out.push('"');
+    out
+}
+
+fn constant(value: Value) -> String {
+    match value {
+        Value::I32(v) if (-4096..4096).contains(&v) => v.to_string(),
+        Value::I32(v) => format!("{:#x}", v as u32),
+        Value::I64(v) if (-4096..4096).contains(&v) => v.to_string(),
+        Value::I64(v) => format!("{:#x}", v as u64),
+        Value::F32(v) => format!("{:?}", v),
+        Value::F64(v) => format!("{:?}", v),
+        Value::V128(v) => format!("{:#x}", v),
+    }
+}
+
+// Pseudo-C for the functions of a module
+pub struct Decompiler<'a> {
+    module: &'a Module,
+    functions: HashMap<FunctionId, String>,
+    data: Data<'a>,
+}
+
+struct Printer<'a, 'b> {
+    decompiler: &'b Decompiler<'a>,
+    locals: HashMap<LocalId, String>,
+    lifted: Lifted,
+    out: String,
+}
+
+impl Printer<'_, '_> {
+    fn line(&mut self, depth: usize, text: &str) {
+        writeln!(self.out, "{}{}", "    ".repeat(depth), text).unwrap();
+    }
+
+    // `expr` wrapped in parentheses when it binds looser than `prec`
+    fn operand(&self, expr: &Expr, prec: u8) -> String {
+        let (text, own) = self.expr(expr);
+        match own < prec {
+            true => format!("({})", text),
+            false => text,
+        }
+    }
+
+    // Operand of a binop, cast to signed first when the operator is signed and the
+    // value isn't already
+    fn binop_operand(&self, expr: &Expr, ty: &str, signed: bool, prec: u8) -> String {
+        let already = match expr {
+            Expr::Const(_) => true,
+            Expr::Load(kind, _, _) => load_pointer(kind).starts_with('s'),
+            _ => false,
+        };
+        match signed && !already {
+            true => format!("({}){}", ty.replace('i', "s"), self.operand(expr, UNARY)),
+            false => self.operand(expr, prec),
+        }
+    }
+
+    fn args(&self, args: &[Expr]) -> String {
+        let data = &self.decompiler.data;
+        let mut out = Vec::new();
+        for (i, arg) in args.iter().enumerate() {
+            let Expr::Const(Value::I32(address)) = arg else {
+                out.push(self.expr(arg).0);
+                continue;
+            };
+            // a (pointer, length) pair, or a pointer to a C string
+            let len = match args.get(i + 1) {
+                Some(Expr::Const(Value::I32(len))) if (1..=256).contains(len) => Some(*len),
+                _ => None,
+            };
+            let string = len
+                .and_then(|len| data.string(*address as u32, len as usize))
+                .or_else(|| data.c_string(*address as u32));
+            out.push(string.map_or_else(|| self.expr(arg).0, string_literal));
+        }
+        out.join(", ")
+    }
+
+    fn deref(&self, ty: &str, offset: u32, address: &Expr) -> String {
+        let address = match (address, offset) {
+            (Expr::Const(Value::I32(a)), _) => {
+                constant(Value::I32((*a as u32).wrapping_add(offset) as i32))
+            }
+            (address, 0) => self.operand(address, UNARY),
+            (address, offset) => format!(
+                "({} + {})",
+                self.operand(address, 12),
+                constant(Value::I32(offset as i32))
+            ),
+        };
+        format!("*({}*){}", ty, address)
+    }
+
+    fn expr(&self, expr: &Expr) -> (String, u8) {
+        let module = self.decompiler.module;
+        match expr {
+            Expr::Const(value) => (constant(*value), PRIMARY),
+            Expr::Local(local) => (self.locals[local].clone(), PRIMARY),
+            Expr::Global(global) => (self.decompiler.global_name(*global), PRIMARY),
+            Expr::Temp(temp) => (format!("t{}", temp), PRIMARY),
+            Expr::Unknown => ("?".to_string(), PRIMARY),
+            Expr::Load(kind, offset, address) => {
+                (self.deref(load_pointer(kind), *offset, address), UNARY)
+            }
+            Expr::Call(func, args) => (
+                format!("{}({})", self.decompiler.functions[func], self.args(args)),
+                PRIMARY,
+            ),
+            Expr::CallIndirect(ty, index, args) => {
+                let ty = module.types.get(*ty);
+                let results = results_name(ty.results());
+                let params = ty
+                    .params()
+                    .iter()
+                    .map(|ty| type_name(*ty))
+                    .collect::<Vec<_>>();
+                let pointer = format!(
+                    "(({} (*)({}))table[{}])",
+                    results,
+                    params.join(", "),
+                    self.expr(index).0
+                );
+                (format!("{}({})", pointer, self.args(args)), PRIMARY)
+            }
+            Expr::Intrinsic(name, args) => (format!("{}({})", name, self.args(args)), PRIMARY),
+            Expr::Select(cond, a, b) => (
+                format!(
+                    "{} ? {} : {}",
+                    self.operand(cond, TERNARY + 1),
+                    self.operand(a, TERNARY + 1),
+                    self.operand(b, TERNARY)
+                ),
+                TERNARY,
+            ),
+            Expr::Unop(op, value) => self.unop(*op, value),
+            Expr::Binop(op, left, right) => self.binop(*op, left, right),
+        }
+    }
+
+    fn unop(&self, op: UnaryOp, value: &Expr) -> (String, u8) {
+        let name = format!("{:?}", op);
+        if let Some((to, from)) = cast(op) {
+            let value = match from {
+                Some(from) => format!("({}){}", from, self.operand(value, UNARY)),
+                None => self.operand(value, UNARY),
+            };
+            return (format!("({}){}", to, value), UNARY);
+        }
+
+        match &name[3..] {
+            "Eqz" => (format!("!{}", self.operand(value, UNARY)), UNARY),
+            "Neg" => (format!("-{}", self.operand(value, UNARY)), UNARY),
+            _ => {
+                let function = mnemonic(&name).replace('.', "_");
+                (format!("{}({})", function, self.expr(value).0), PRIMARY)
+            }
+        }
+    }
+
+    fn binop(&self, op: BinaryOp, left: &Expr, right: &Expr) -> (String, u8) {
+        let name = format!("{:?}", op);
+        let ty = name[..3].to_ascii_lowercase();
+        match infix(&name[3..]) {
+            Some((symbol, prec, signed)) => {
+                let left = self.binop_operand(left, &ty, signed, prec);
+                let right = self.binop_operand(right, &ty, signed, prec + 1);
+                (format!("{} {} {}", left, symbol, right), prec)
+            }
+            None => {
+                let function = mnemonic(&name).replace('.', "_");
+                let text = format!(
+                    "{}({}, {})",
+                    function,
+                    self.expr(left).0,
+                    self.expr(right).0
+                );
+                (text, PRIMARY)
+            }
+        }
+    }
+
+    fn label(&self, label: usize) -> String {
+        format!("L{}", label)
+    }
+
+    // Statements leaving the current code, they fit on one line
+    fn jump(&self, stmt: &Stmt) -> String {
+        match stmt {
+            Stmt::Break(label) => format!("goto {};", self.label(*label)),
+            Stmt::Continue(label) => format!("continue {};", self.label(*label)),
+            Stmt::Return(values) => match &values[..] {
+                [] => "return;".to_string(),
+                [value] => format!("return {};", self.expr(value).0),
+                values => format!("return ({});", self.args(values)),
+            },
+            Stmt::Unreachable => "unreachable();".to_string(),
+            stmt => unreachable!("{:?} is not a jump", stmt),
+        }
+    }
+
+    fn stmts(&mut self, depth: usize, stmts: &[Stmt]) {
+        for stmt in stmts {
+            self.stmt(depth, stmt);
+        }
+    }
+
+    fn stmt(&mut self, depth: usize, stmt: &Stmt) {
+        match stmt {
+            Stmt::Expr(expr) => {
+                let text = format!("{};", self.expr(expr).0);
+                self.line(depth, &text);
+            }
+            Stmt::SetLocal(local, value) => {
+                let text = format!("{} = {};", self.locals[local], self.expr(value).0);
+                self.line(depth, &text);
+            }
+            Stmt::SetGlobal(global, value) => {
+                let name = self.decompiler.global_name(*global);
+                let text = format!("{} = {};", name, self.expr(value).0);
+                self.line(depth, &text);
+            }
+            Stmt::SetTemp(temp, value) => {
+                let text = format!("t{} = {};", temp, self.expr(value).0);
+                self.line(depth, &text);
+            }
+            Stmt::Store(kind, offset, address, value) => {
+                let target = self.deref(store_pointer(kind), *offset, address);
+                let text = format!("{} = {};", target, self.expr(value).0);
+                self.line(depth, &text);
+            }
+            // the body stays at the same depth, breaking out of it jumps past its end
+            Stmt::Block(label, body) => {
+                self.stmts(depth, body);
+                if self.lifted.used_labels.contains(label) {
+                    let text = format!("{}:", self.label(*label));
+                    self.line(depth.saturating_sub(1), &text);
+                }
+            }
+            Stmt::Loop(label, body) => {
+                // nothing goes back to the top, it runs once
+                if !self.lifted.used_labels.contains(label) {
+                    self.stmts(depth, body);
+                    return;
+                }
+                let text = format!("{}: while (1) {{", self.label(*label));
+                self.line(depth, &text);
+                self.stmts(depth + 1, body);
+                if !body.last().is_some_and(Stmt::diverges) {
+                    self.line(depth + 1, "break;");
+                }
+                self.line(depth, "}");
+            }
+            Stmt::If(cond, consequent, alternative) => {
+                self.if_else(depth, cond, consequent, alternative, false)
+            }
+            Stmt::Switch(index, cases, default) => {
+                let text = format!("switch ({}) {{", self.expr(index).0);
+                self.line(depth, &text);
+                let cases = cases
+                    .iter()
+                    .enumerate()
+                    .map(|(i, case)| (format!("case {}:", i), case));
+                for (case, body) in cases.chain([("default:".to_string(), default)]) {
+                    match &body[..] {
+                        [jump] => {
+                            let text = format!("{} {}", case, self.jump(jump));
+                            self.line(depth + 1, &text);
+                        }
+                        body => {
+                            self.line(depth + 1, &case);
+                            self.stmts(depth + 2, body);
+                        }
+                    }
+                }
+                self.line(depth, "}");
+            }
+            Stmt::Break(_) | Stmt::Continue(_) | Stmt::Return(_) | Stmt::Unreachable => {
+                let text = self.jump(stmt);
+                self.line(depth, &text);
+            }
+            Stmt::Unsupported(instr) => {
+                let text = format!("/* {} */", mnemonic(instr));
+                self.line(depth, &text);
+            }
+        }
+    }
+
+    // `else if` when the alternative is just another `if`
+    fn if_else(
+        &mut self,
+        depth: usize,
+        cond: &Expr,
+        consequent: &[Stmt],
+        alternative: &[Stmt],
+        chained: bool,
+    ) {
+        let (cond, consequent, alternative) = match consequent.is_empty() {
+            true => (
+                format!("!{}", self.operand(cond, UNARY)),
+                alternative,
+                consequent,
+            ),
+            false => (self.expr(cond).0, consequent, alternative),
+        };
+
+        let text = format!("if ({}) {{", cond);
+        match chained {
+            true => {
+                self.out.truncate(self.out.trim_end().len());
+                writeln!(self.out, " else {}", text).unwrap();
+            }
+            false => self.line(depth, &text),
+        }
+        self.stmts(depth + 1, consequent);
+        match alternative {
+            [] => self.line(depth, "}"),
+            [Stmt::If(cond, consequent, alternative)] => {
+                self.line(depth, "}");
+                self.if_else(depth, cond, consequent, alternative, true);
+            }
+            alternative => {
+                self.line(depth, "} else {");
+                self.stmts(depth + 1, alternative);
+                self.line(depth, "}");
+            }
+        }
+    }
+}
+
+impl<'a> Decompiler<'a> {
+    pub fn new(module: &'a Module) -> Self {
+        let segments = module
+            .data
+            .iter()
+            .filter_map(|data| match &data.kind {
+                DataKind::Active {
+                    offset: ConstExpr::Value(Value::I32(start)),
+                    ..
+                } => Some((*start as u32, data.value.as_slice())),
+                _ => None,
+            })
+            .collect();
+
+        Decompiler {
+            module,
+            functions: function_names(module),
+            data: Data { segments },
+        }
+    }
+
+    pub fn global_name(&self, id: walrus::GlobalId) -> String {
+        match &self.module.globals.get(id).name {
+            Some(name) => identifier(name),
+            None => format!("g{}", id.index()),
+        }
+    }
+
+    // `i32 name(i32 p0, i64 p1)`
+    fn signature(&self, id: FunctionId, params: &[String]) -> String {
+        let ty = self.module.types.get(self.module.funcs.get(id).ty());
+        let results = results_name(ty.results());
+        let params = ty
+            .params()
+            .iter()
+            .zip(params.iter())
+            .map(|(ty, name)| format!("{} {}", type_name(*ty), name))
+            .collect::<Vec<_>>();
+        format!("{} {}({})", results, self.functions[&id], params.join(", "))
+    }
+
+    // Pseudo-C of a local function, `None` for an imported one
+    pub fn function(&self, id: FunctionId) -> Option<String> {
+        let FunctionKind::Local(func) = &self.module.funcs.get(id).kind else {
+            return None;
+        };
+
+        let mut printer = Printer {
+            decompiler: self,
+            locals: local_names(self.module, func),
+            lifted: lift(self.module, func),
+            out: String::new(),
+        };
+
+        let params = func
+            .args
+            .iter()
+            .map(|arg| printer.locals[arg].clone())
+            .collect::<Vec<_>>();
+        let signature = self.signature(id, &params);
+        printer.line(0, &format!("{} {{", signature));
+
+        // declarations grouped by type, locals first
+        let mut locals = printer
+            .locals
+            .iter()
+            .filter(|(local, _)| !func.args.contains(local))
+            .map(|(local, name)| (self.module.locals.get(*local).ty(), name.clone()))
+            .collect::<Vec<_>>();
+        locals.sort_by(|a, b| {
+            type_name(a.0)
+                .cmp(type_name(b.0))
+                .then_with(|| natural(&a.1, &b.1))
+        });
+        let temps = printer
+            .lifted
+            .temps
+            .iter()
+            .enumerate()
+            .map(|(i, ty)| (*ty, format!("t{}", i)));
+        let mut declarations: Vec<(&str, Vec<String>)> = Vec::new();
+        for (ty, name) in locals.into_iter().chain(temps) {
+            match declarations.iter_mut().find(|(t, _)| *t == type_name(ty)) {
+                Some((_, names)) => names.push(name),
+                None => declarations.push((type_name(ty), vec![name])),
+            }
+        }
+        for (ty, names) in declarations.iter() {
+            printer.line(1, &format!("{} {};", ty, names.join(", ")));
+        }
+        if !declarations.is_empty() && !printer.lifted.body.is_empty() {
+            printer.line(0, "");
+        }
+
+        let body = std::mem::take(&mut printer.lifted.body);
+        printer.stmts(1, &body);
+        printer.line(0, "}");
+        Some(printer.out)
+    }
+
+    // Imports and globals, then every local function
+    pub fn module(&self) -> String {
+        let module = self.module;
+        let mut out = String::new();
+
+        for func in module.funcs.iter() {
+            if let FunctionKind::Import(import) = &func.kind {
+                let import = module.imports.get(import.import);
+                let ty = module.types.get(func.ty());
+                let params = (0..ty.params().len())
+                    .map(|i| format!("p{}", i))
+                    .collect::<Vec<_>>();
+                writeln!(
+                    out,
+                    "extern {}; // {}.{}",
+                    self.signature(func.id(), &params),
+                    import.module,
+                    import.name
+                )
+                .unwrap();
+            }
+        }
+
+        for global in module.globals.iter() {
+            let qualifier = if global.mutable { "" } else { "const " };
+            let name = self.global_name(global.id());
+            match global.kind {
+                GlobalKind::Local(ConstExpr::Value(value)) => writeln!(
+                    out,
+                    "{}{} {} = {};",
+                    qualifier,
+                    type_name(global.ty),
+                    name,
+                    constant(value)
+                ),
+                _ => writeln!(
+                    out,
+                    "extern {}{} {};",
+                    qualifier,
+                    type_name(global.ty),
+                    name
+                ),
+            }
+            .unwrap();
+        }
+
+        for func in module.funcs.iter() {
+            if let Some(text) = self.function(func.id()) {
+                if !out.is_empty() {
+                    out.push('\n');
+                }
+                out.push_str(&text);
+            }
+        }
+        out
+    }
+}
+
+// `l2` before `l10`
+fn natural(a: &str, b: &str) -> std::cmp::Ordering {
+    let split = |s: &str| {
+        let digits = s.trim_end_matches(|c: char| c.is_ascii_digit());
+        (
+            digits.to_string(),
+            s[digits.len()..].parse::<u64>().unwrap_or(0),
+        )
+    };
+    split(a).cmp(&split(b))
+}
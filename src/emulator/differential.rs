@@ -0,0 +1,367 @@
+use crate::emulator::trap::Trap;
+use crate::emulator::Emulator;
+use crate::rng::Rng;
+use crate::profile::Profile;
+use crate::transformations::memory::memory_encryption::MemoryEncryptionScheme;
+use crate::transformations::memory::memory_transformer::MemoryTransformer;
+use crate::transformations::memory::MemEncFuncType;
+use anyhow::Context;
+use std::collections::HashSet;
+use std::fmt;
+use walrus::ir::{dfs_in_order, Call, Value, Visitor};
+use walrus::{ConstExpr, DataKind, ExportItem, FunctionId, ImportKind, Module, ValType};
+
+// The original runs every memory access through a wrapper, give it that much more fuel
+const ORIGINAL_FUEL_FACTOR: u64 = 100;
+
+// Runs the original module and its `MemoryTransformer` output side by side, with the
+// same deterministic import stubs and the same random arguments, and stops at the first
+// call whose results or (decrypted) memory differ.
+pub struct DifferentialHarness<'a> {
+    original: &'a Module,
+    rewritten: &'a Module,
+    encryption: Box<dyn MemoryEncryptionScheme>,
+    // Exports that only maintain the encryption layer (page init), meaningless once it's gone
+    skipped: HashSet<String>,
+    // Byte store wrapper of the original, used to line up the initial logical memory
+    store_u8: FunctionId,
+    // Index of the encrypted data segment, see `Profile::data_segment`
+    data_segment: usize,
+
+    pub seed: u64,
+    pub calls_per_export: usize,
+    pub fuel_per_call: u64,
+}
+
+#[derive(Default)]
+pub struct Report {
+    pub calls: usize,
+    // Calls that ran out of fuel or hit the memory limit on one side, both instances are
+    // reset after those
+    pub inconclusive: usize,
+    // Calls that returned without a trap on both sides and agreed
+    pub returned: usize,
+    pub divergence: Option<Divergence>,
+}
+
+pub struct Divergence {
+    pub export: String,
+    pub args: Vec<Value>,
+    pub call: usize,
+    pub kind: DivergenceKind,
+}
+
+pub enum DivergenceKind {
+    Results {
+        original: Result<Vec<Value>, Trap>,
+        rewritten: Result<Vec<Value>, Trap>,
+    },
+    Memory {
+        address: usize,
+        original: u8,
+        rewritten: u8,
+    },
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "call #{} {}{:?}: ", self.call, self.export, self.args)?;
+
+        match &self.kind {
+            DivergenceKind::Results { original, rewritten } => write!(
+                f,
+                "results differ, original {:?}, rewritten {:?}",
+                original, rewritten
+            ),
+            DivergenceKind::Memory {
+                address,
+                original,
+                rewritten,
+            } => write!(
+                f,
+                "memory differs at {:#x}, original {:#04x}, rewritten {:#04x}",
+                address, original, rewritten
+            ),
+        }
+    }
+}
+
+#[derive(Default)]
+struct CallCollector {
+    callees: HashSet<FunctionId>,
+}
+
+impl<'a> Visitor<'a> for CallCollector {
+    fn visit_call(&mut self, instr: &Call) {
+        self.callees.insert(instr.func);
+    }
+}
+
+fn same_value(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::I32(a), Value::I32(b)) => a == b,
+        (Value::I64(a), Value::I64(b)) => a == b,
+        (Value::F32(a), Value::F32(b)) => a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan()),
+        (Value::F64(a), Value::F64(b)) => a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan()),
+        (Value::V128(a), Value::V128(b)) => a == b,
+        _ => false,
+    }
+}
+
+// Traps are compared by kind, the faulting address of the original is a physical one
+fn same_outcome(a: &Result<Vec<Value>, Trap>, b: &Result<Vec<Value>, Trap>) -> bool {
+    match (a, b) {
+        (Ok(a), Ok(b)) => a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| same_value(a, b)),
+        (Err(a), Err(b)) => std::mem::discriminant(a) == std::mem::discriminant(b),
+        _ => false,
+    }
+}
+
+impl<'a> DifferentialHarness<'a> {
+    pub fn new(original: &'a Module, rewritten: &'a Module) -> Result<Self, anyhow::Error> {
+        Self::with_profile(original, rewritten, &Profile::default())
+    }
+
+    // For builds whose layout constants or data segment differ from the defaults
+    pub fn with_profile(original: &'a Module, rewritten: &'a Module, profile: &Profile) -> Result<Self, anyhow::Error> {
+        let transformer = MemoryTransformer::with_profile(profile.clone());
+        let mapped_loads = transformer.map_load_functions(original)?;
+        let mapped_stores = transformer.map_store_functions(original)?;
+        let encryption = transformer.schemes.detect(original, &mapped_loads)?;
+        let store_u8 = mapped_stores
+            .iter()
+            .find(|(_, ty)| matches!(ty, MemEncFuncType::Signed8 | MemEncFuncType::Unsigned8))
+            .map(|(id, _)| *id)
+            .context("could not find u8 store func")?;
+
+        let mut collector = CallCollector::default();
+        for id in mapped_stores.keys() {
+            let local = original.funcs.get(*id).kind.unwrap_local();
+            dfs_in_order(&mut collector, local, local.entry_block());
+        }
+
+        let skipped = original
+            .exports
+            .iter()
+            .filter(|e| matches!(e.item, ExportItem::Function(f) if collector.callees.contains(&f)))
+            .map(|e| e.name.clone())
+            .collect();
+
+        Ok(DifferentialHarness {
+            original,
+            rewritten,
+            encryption,
+            skipped,
+            store_u8,
+            data_segment: profile.data_segment,
+            seed: 0,
+            calls_per_export: 4,
+            fuel_per_call: 1_000_000,
+        })
+    }
+
+    pub fn run(&self) -> Result<Report, anyhow::Error> {
+        // The interpreter recurses on calls and blocks, don't depend on the caller's stack size
+        std::thread::scope(|scope| {
+            std::thread::Builder::new()
+                .stack_size(256 * 1024 * 1024)
+                .spawn_scoped(scope, || self.run_inner())
+                .context("could not spawn harness thread")?
+                .join()
+                .map_err(|_| anyhow::anyhow!("harness thread panicked"))?
+        })
+    }
+
+    fn run_inner(&self) -> Result<Report, anyhow::Error> {
+        let mut rng = Rng::new(self.seed);
+        let mut report = Report::default();
+
+        let mut exports = self
+            .original
+            .exports
+            .iter()
+            .filter_map(|e| match e.item {
+                ExportItem::Function(f) if !self.skipped.contains(&e.name) => Some((e.name.clone(), f)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        exports.sort();
+
+        let (mut original, mut rewritten) = self.instantiate()?;
+        // The rewritten module also uses the low raw area for unencrypted accesses, which the
+        // original keeps apart from the logical memory, so only compare past it
+        let window = self.raw_data_end()..rewritten.memory.len();
+
+        for (name, func) in exports.iter() {
+            let params = self
+                .original
+                .types
+                .get(self.original.funcs.get(*func).ty())
+                .params()
+                .to_vec();
+
+            for _ in 0..self.calls_per_export {
+                let args = params
+                    .iter()
+                    .map(|ty| self.random_value(&mut rng, *ty))
+                    .collect::<Vec<_>>();
+
+                original.fuel = self.fuel_per_call * ORIGINAL_FUEL_FACTOR;
+                rewritten.fuel = self.fuel_per_call;
+                let original_result = original.call_export(name, &args);
+                let rewritten_result = rewritten.call_export(name, &args);
+                report.calls += 1;
+
+                let limited = |result: &Result<Vec<Value>, Trap>| {
+                    matches!(result, Err(Trap::OutOfFuel | Trap::MemoryLimit(_)))
+                };
+                if limited(&original_result) || limited(&rewritten_result)
+                {
+                    report.inconclusive += 1;
+                    (original, rewritten) = self.instantiate()?;
+                    continue;
+                }
+
+                let divergence = |kind| Divergence {
+                    export: name.clone(),
+                    args: args.clone(),
+                    call: report.calls,
+                    kind,
+                };
+
+                if !same_outcome(&original_result, &rewritten_result) {
+                    report.divergence = Some(divergence(DivergenceKind::Results {
+                        original: original_result,
+                        rewritten: rewritten_result,
+                    }));
+                    return Ok(report);
+                }
+
+                let plain = self.encryption.view(
+                    self.original,
+                    &original.memory,
+                    window.start,
+                    window.len(),
+                )?;
+                let mismatch = plain
+                    .iter()
+                    .zip(rewritten.memory[window.clone()].iter())
+                    .position(|(a, b)| a != b);
+
+                if let Some(offset) = mismatch {
+                    report.divergence = Some(divergence(DivergenceKind::Memory {
+                        address: window.start + offset,
+                        original: plain[offset],
+                        rewritten: rewritten.memory[window.start + offset],
+                    }));
+                    return Ok(report);
+                }
+
+                if original_result.is_ok() {
+                    report.returned += 1;
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    fn instantiate(&self) -> Result<(Emulator<'a>, Emulator<'a>), anyhow::Error> {
+        let mut original = Emulator::new(self.original)?;
+        let mut rewritten = Emulator::new(self.rewritten)?;
+        self.stub_imports(&mut original);
+        self.stub_imports(&mut rewritten);
+
+        // The data segments left untouched by the transformer are raw in the rewritten module
+        // and land in the first bytes of its logical memory, where the original only has zeros.
+        // Copy them over so reads of low addresses agree on both sides.
+        for address in 0..self.raw_data_end() {
+            let byte = rewritten.memory[address];
+            if byte != 0 {
+                original.call(
+                    self.store_u8,
+                    &[Value::I32(address as i32), Value::I32(byte as i32), Value::I32(0)],
+                )?;
+            }
+        }
+
+        Ok((original, rewritten))
+    }
+
+    // Each import returns its own deterministic stream of values, so both sides
+    // see the same values as long as they call imports in the same order
+    fn stub_imports(&self, emulator: &mut Emulator<'a>) {
+        let module = emulator.module();
+
+        for (i, import) in module.imports.iter().enumerate() {
+            let ImportKind::Function(func) = import.kind else {
+                continue;
+            };
+
+            let results = module.types.get(module.funcs.get(func).ty()).results().to_vec();
+            let mut rng = Rng::new(self.seed.wrapping_add(i as u64 + 1));
+
+            emulator.hook(func, move |_, _| {
+                Ok(results
+                    .iter()
+                    .map(|ty| match ty {
+                        ValType::I64 => Value::I64(rng.next_u64() as i64 % 1024),
+                        ValType::F32 => Value::F32((rng.next_u32() % 1024) as f32),
+                        ValType::F64 => Value::F64((rng.next_u32() % 1024) as f64),
+                        _ => Value::I32((rng.next_u32() % 1024) as i32),
+                    })
+                    .collect())
+            });
+        }
+    }
+
+    fn raw_data_end(&self) -> usize {
+        self.rewritten
+            .data
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != self.data_segment)
+            .filter_map(|(_, data)| match &data.kind {
+                DataKind::Active {
+                    offset: ConstExpr::Value(Value::I32(i)),
+                    ..
+                } => Some(*i as usize + data.value.len()),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Arguments are mostly pointers into the data segment or the stack, random values
+    // rarely get past the first bounds check
+    fn random_value(&self, rng: &mut Rng, ty: ValType) -> Value {
+        match ty {
+            ValType::I32 => {
+                let value = match rng.range(0, 4) {
+                    0 => rng.range(0, 16) as u32,
+                    1 => self.random_data_address(rng),
+                    2 => 1048576 - (rng.range(16, 4096) as u32 & !7),
+                    _ => rng.next_u32(),
+                };
+                Value::I32(value as i32)
+            }
+            ValType::I64 => Value::I64(rng.next_u64() as i64),
+            ValType::F32 => Value::F32(rng.next_u32() as f32 / 65536.0),
+            ValType::F64 => Value::F64(rng.next_u64() as f64 / 4294967296.0),
+            _ => Value::I32(0),
+        }
+    }
+
+    fn random_data_address(&self, rng: &mut Rng) -> u32 {
+        match self.rewritten.data.iter().nth(self.data_segment).map(|d| (&d.kind, d.value.len())) {
+            Some((
+                DataKind::Active {
+                    offset: ConstExpr::Value(Value::I32(start)),
+                    ..
+                },
+                len,
+            )) if len > 0 => (*start as u64 + rng.range(0, len as u64)) as u32,
+            _ => rng.next_u32(),
+        }
+    }
+}
@@ -0,0 +1,402 @@
+use crate::emulator::numeric::{binop, unop};
+use crate::emulator::trap::Trap;
+use crate::emulator::{zero_value, Emulator, PAGE_SIZE};
+use std::collections::HashMap;
+use walrus::ir::{ExtendedLoad, Instr, InstrSeqId, InstrSeqType, LoadKind, MemArg, StoreKind, Value};
+use walrus::{FunctionId, FunctionKind, LocalFunction, LocalId};
+
+enum Flow {
+    Continue,
+    Branch(InstrSeqId),
+    Return,
+}
+
+struct Frame<'a> {
+    func: &'a LocalFunction,
+    locals: HashMap<LocalId, Value>,
+    stack: Vec<Value>,
+}
+
+impl Frame<'_> {
+    fn pop(&mut self) -> Result<Value, Trap> {
+        self.stack
+            .pop()
+            .ok_or_else(|| Trap::Unsupported("value stack underflow".to_string()))
+    }
+
+    fn pop_i32(&mut self) -> Result<i32, Trap> {
+        match self.pop()? {
+            Value::I32(i) => Ok(i),
+            other => Err(Trap::Unsupported(format!("expected i32, got {:?}", other))),
+        }
+    }
+
+    fn pop_n(&mut self, n: usize) -> Result<Vec<Value>, Trap> {
+        if self.stack.len() < n {
+            return Err(Trap::Unsupported("value stack underflow".to_string()));
+        }
+
+        Ok(self.stack.split_off(self.stack.len() - n))
+    }
+
+    // Stack height below the `params` a block takes
+    fn height(&self, params: usize) -> Result<usize, Trap> {
+        self.stack
+            .len()
+            .checked_sub(params)
+            .ok_or_else(|| Trap::Unsupported("value stack underflow".to_string()))
+    }
+
+    // Drops everything above `height` except the top `keep` values
+    fn unwind(&mut self, height: usize, keep: usize) -> Result<(), Trap> {
+        let kept = self.pop_n(keep)?;
+        self.stack.truncate(height);
+        self.stack.extend(kept);
+        Ok(())
+    }
+}
+
+impl Emulator<'_> {
+    pub(super) fn invoke(&mut self, func: FunctionId, args: Vec<Value>) -> Result<Vec<Value>, Trap> {
+        if let Some(hook) = self.hooks.get_mut(&func) {
+            return hook(&mut self.memory, &args);
+        }
+
+        let module = self.module;
+        let ty = module.types.get(module.funcs.get(func).ty());
+
+        let local = match &module.funcs.get(func).kind {
+            FunctionKind::Local(local) => local,
+            // Unhooked imports do nothing
+            _ => return Ok(ty.results().iter().map(|ty| zero_value(*ty)).collect()),
+        };
+
+        if self.call_depth >= self.max_call_depth {
+            return Err(Trap::CallStackExhausted);
+        }
+
+        let mut frame = Frame {
+            func: local,
+            locals: local.args.iter().copied().zip(args).collect(),
+            stack: Vec::new(),
+        };
+
+        self.call_depth += 1;
+        let flow = self.exec_block(&mut frame, local.entry_block());
+        self.call_depth -= 1;
+
+        match flow? {
+            Flow::Continue | Flow::Return => {}
+            Flow::Branch(_) => unreachable!("branch out of the function body"),
+        }
+
+        frame.pop_n(ty.results().len())
+    }
+
+    fn seq_arity(&self, func: &LocalFunction, seq: InstrSeqId) -> (usize, usize) {
+        match func.block(seq).ty {
+            InstrSeqType::Simple(result) => (0, result.is_some() as usize),
+            InstrSeqType::MultiValue(ty) => {
+                let ty = self.module.types.get(ty);
+                (ty.params().len(), ty.results().len())
+            }
+        }
+    }
+
+    fn exec_block(&mut self, frame: &mut Frame, seq: InstrSeqId) -> Result<Flow, Trap> {
+        let (params, results) = self.seq_arity(frame.func, seq);
+        let height = frame.height(params)?;
+
+        match self.exec_seq(frame, seq)? {
+            Flow::Branch(target) if target == seq => {
+                frame.unwind(height, results)?;
+                Ok(Flow::Continue)
+            }
+            flow => Ok(flow),
+        }
+    }
+
+    fn exec_loop(&mut self, frame: &mut Frame, seq: InstrSeqId) -> Result<Flow, Trap> {
+        let (params, _) = self.seq_arity(frame.func, seq);
+        let height = frame.height(params)?;
+
+        loop {
+            match self.exec_seq(frame, seq)? {
+                // branching to a loop jumps back to its start
+                Flow::Branch(target) if target == seq => frame.unwind(height, params)?,
+                flow => return Ok(flow),
+            }
+        }
+    }
+
+    fn exec_seq(&mut self, frame: &mut Frame, seq: InstrSeqId) -> Result<Flow, Trap> {
+        let func = frame.func;
+
+        for (instr, _) in func.block(seq).instrs.iter() {
+            if self.fuel == 0 {
+                return Err(Trap::OutOfFuel);
+            }
+            self.fuel -= 1;
+
+            match instr {
+                Instr::Block(block) => match self.exec_block(frame, block.seq)? {
+                    Flow::Continue => {}
+                    flow => return Ok(flow),
+                },
+                Instr::Loop(lp) => match self.exec_loop(frame, lp.seq)? {
+                    Flow::Continue => {}
+                    flow => return Ok(flow),
+                },
+                Instr::IfElse(if_else) => {
+                    let seq = if frame.pop_i32()? != 0 {
+                        if_else.consequent
+                    } else {
+                        if_else.alternative
+                    };
+
+                    match self.exec_block(frame, seq)? {
+                        Flow::Continue => {}
+                        flow => return Ok(flow),
+                    }
+                }
+                Instr::Br(br) => return Ok(Flow::Branch(br.block)),
+                Instr::BrIf(br) => {
+                    if frame.pop_i32()? != 0 {
+                        return Ok(Flow::Branch(br.block));
+                    }
+                }
+                Instr::BrTable(br) => {
+                    let idx = frame.pop_i32()? as u32 as usize;
+                    return Ok(Flow::Branch(*br.blocks.get(idx).unwrap_or(&br.default)));
+                }
+                Instr::Return(_) => return Ok(Flow::Return),
+                Instr::ReturnCall(call) => {
+                    self.exec_call(frame, call.func)?;
+                    return Ok(Flow::Return);
+                }
+                Instr::ReturnCallIndirect(call) => {
+                    let func = self.resolve_indirect(frame, call.ty, call.table)?;
+                    self.exec_call(frame, func)?;
+                    return Ok(Flow::Return);
+                }
+                Instr::Unreachable(_) => return Err(Trap::Unreachable),
+                _ => self.exec_instr(frame, instr)?,
+            }
+        }
+
+        Ok(Flow::Continue)
+    }
+
+    fn exec_call(&mut self, frame: &mut Frame, func: FunctionId) -> Result<(), Trap> {
+        let ty = self.module.types.get(self.module.funcs.get(func).ty());
+        let args = frame.pop_n(ty.params().len())?;
+        let results = self.invoke(func, args)?;
+        frame.stack.extend(results);
+        Ok(())
+    }
+
+    fn resolve_indirect(
+        &self,
+        frame: &mut Frame,
+        ty: walrus::TypeId,
+        table: walrus::TableId,
+    ) -> Result<FunctionId, Trap> {
+        let idx = frame.pop_i32()? as u32;
+        let func = self.tables[&table]
+            .get(idx as usize)
+            .ok_or(Trap::TableOutOfBounds(idx))?
+            .ok_or(Trap::UninitializedElement(idx))?;
+
+        let expected = self.module.types.get(ty);
+        let actual = self.module.types.get(self.module.funcs.get(func).ty());
+        if expected.params() != actual.params() || expected.results() != actual.results() {
+            return Err(Trap::IndirectCallTypeMismatch);
+        }
+
+        Ok(func)
+    }
+
+    fn effective_address(&self, frame: &mut Frame, arg: &MemArg, size: u64) -> Result<usize, Trap> {
+        let base = frame.pop_i32()? as u32 as u64;
+        self.check_bounds(base + arg.offset as u64, size)
+    }
+
+    fn load_bytes<const N: usize>(&self, address: usize) -> [u8; N] {
+        self.memory[address..address + N].try_into().unwrap()
+    }
+
+    fn exec_instr(&mut self, frame: &mut Frame, instr: &Instr) -> Result<(), Trap> {
+        let module = self.module;
+
+        match instr {
+            Instr::Call(call) => self.exec_call(frame, call.func)?,
+            Instr::CallIndirect(call) => {
+                let func = self.resolve_indirect(frame, call.ty, call.table)?;
+                self.exec_call(frame, func)?;
+            }
+            Instr::LocalGet(l) => {
+                let value = match frame.locals.get(&l.local) {
+                    Some(value) => *value,
+                    None => zero_value(module.locals.get(l.local).ty()),
+                };
+                frame.stack.push(value);
+            }
+            Instr::LocalSet(l) => {
+                let value = frame.pop()?;
+                frame.locals.insert(l.local, value);
+            }
+            Instr::LocalTee(l) => {
+                let value = frame.pop()?;
+                frame.locals.insert(l.local, value);
+                frame.stack.push(value);
+            }
+            Instr::GlobalGet(g) => frame.stack.push(self.globals[&g.global]),
+            Instr::GlobalSet(g) => {
+                let value = frame.pop()?;
+                self.globals.insert(g.global, value);
+            }
+            Instr::Const(c) => frame.stack.push(c.value),
+            Instr::Binop(op) => {
+                let b = frame.pop()?;
+                let a = frame.pop()?;
+                frame.stack.push(binop(op.op, a, b)?);
+            }
+            Instr::Unop(op) => {
+                let a = frame.pop()?;
+                frame.stack.push(unop(op.op, a)?);
+            }
+            Instr::Select(_) => {
+                let condition = frame.pop_i32()?;
+                let b = frame.pop()?;
+                let a = frame.pop()?;
+                frame.stack.push(if condition != 0 { a } else { b });
+            }
+            Instr::Drop(_) => {
+                frame.pop()?;
+            }
+            Instr::MemorySize(_) => {
+                let pages = self.memory.len() as u64 / PAGE_SIZE;
+                frame.stack.push(Value::I32(pages as i32));
+            }
+            Instr::MemoryGrow(_) => {
+                let delta = frame.pop_i32()? as u32 as u64;
+                let pages = self.memory.len() as u64 / PAGE_SIZE;
+                if pages + delta > self.max_pages {
+                    frame.stack.push(Value::I32(-1));
+                } else if pages + delta > self.memory_limit {
+                    return Err(Trap::MemoryLimit(self.memory_limit));
+                } else {
+                    self.memory.resize(((pages + delta) * PAGE_SIZE) as usize, 0);
+                    frame.stack.push(Value::I32(pages as i32));
+                }
+            }
+            Instr::MemoryCopy(_) => {
+                let len = frame.pop_i32()? as u32 as u64;
+                let src = frame.pop_i32()? as u32 as u64;
+                let dst = frame.pop_i32()? as u32 as u64;
+                let src = self.check_bounds(src, len)?;
+                let dst = self.check_bounds(dst, len)?;
+                self.memory.copy_within(src..src + len as usize, dst);
+            }
+            Instr::MemoryFill(_) => {
+                let len = frame.pop_i32()? as u32 as u64;
+                let value = frame.pop_i32()? as u8;
+                let dst = frame.pop_i32()? as u32 as u64;
+                let dst = self.check_bounds(dst, len)?;
+                self.memory[dst..dst + len as usize].fill(value);
+            }
+            Instr::MemoryInit(init) => {
+                let len = frame.pop_i32()? as u32 as usize;
+                let src = frame.pop_i32()? as u32 as usize;
+                let dst = frame.pop_i32()? as u32 as u64;
+                let data = self.passive_data.get(&init.data).map(|d| d.as_slice()).unwrap_or(&[]);
+                if src + len > data.len() {
+                    return Err(Trap::MemoryOutOfBounds {
+                        address: src as u64,
+                        size: len as u64,
+                    });
+                }
+                let bytes = data[src..src + len].to_vec();
+                self.write(dst as u32, &bytes)?;
+            }
+            Instr::DataDrop(drop) => {
+                self.passive_data.remove(&drop.data);
+            }
+            Instr::Load(load) => {
+                let size = load.kind.width() as u64;
+                let address = self.effective_address(frame, &load.arg, size)?;
+                let value = match load.kind {
+                    LoadKind::I32 { .. } => Value::I32(i32::from_le_bytes(self.load_bytes(address))),
+                    LoadKind::I64 { .. } => Value::I64(i64::from_le_bytes(self.load_bytes(address))),
+                    LoadKind::F32 => Value::F32(f32::from_le_bytes(self.load_bytes(address))),
+                    LoadKind::F64 => Value::F64(f64::from_le_bytes(self.load_bytes(address))),
+                    LoadKind::I32_8 { kind } => {
+                        let byte = self.memory[address];
+                        Value::I32(match kind {
+                            ExtendedLoad::SignExtend => byte as i8 as i32,
+                            _ => byte as i32,
+                        })
+                    }
+                    LoadKind::I32_16 { kind } => {
+                        let half = u16::from_le_bytes(self.load_bytes(address));
+                        Value::I32(match kind {
+                            ExtendedLoad::SignExtend => half as i16 as i32,
+                            _ => half as i32,
+                        })
+                    }
+                    LoadKind::I64_8 { kind } => {
+                        let byte = self.memory[address];
+                        Value::I64(match kind {
+                            ExtendedLoad::SignExtend => byte as i8 as i64,
+                            _ => byte as i64,
+                        })
+                    }
+                    LoadKind::I64_16 { kind } => {
+                        let half = u16::from_le_bytes(self.load_bytes(address));
+                        Value::I64(match kind {
+                            ExtendedLoad::SignExtend => half as i16 as i64,
+                            _ => half as i64,
+                        })
+                    }
+                    LoadKind::I64_32 { kind } => {
+                        let word = u32::from_le_bytes(self.load_bytes(address));
+                        Value::I64(match kind {
+                            ExtendedLoad::SignExtend => word as i32 as i64,
+                            _ => word as i64,
+                        })
+                    }
+                    LoadKind::V128 => Value::V128(u128::from_le_bytes(self.load_bytes(address))),
+                };
+                frame.stack.push(value);
+            }
+            Instr::Store(store) => {
+                let value = frame.pop()?;
+                let size = store.kind.width() as u64;
+                let address = self.effective_address(frame, &store.arg, size)?;
+                let bytes = match (store.kind, value) {
+                    (StoreKind::I32 { .. }, Value::I32(v)) => v.to_le_bytes().to_vec(),
+                    (StoreKind::I64 { .. }, Value::I64(v)) => v.to_le_bytes().to_vec(),
+                    (StoreKind::F32, Value::F32(v)) => v.to_le_bytes().to_vec(),
+                    (StoreKind::F64, Value::F64(v)) => v.to_le_bytes().to_vec(),
+                    (StoreKind::V128, Value::V128(v)) => v.to_le_bytes().to_vec(),
+                    (StoreKind::I32_8 { .. }, Value::I32(v)) => vec![v as u8],
+                    (StoreKind::I32_16 { .. }, Value::I32(v)) => (v as u16).to_le_bytes().to_vec(),
+                    (StoreKind::I64_8 { .. }, Value::I64(v)) => vec![v as u8],
+                    (StoreKind::I64_16 { .. }, Value::I64(v)) => (v as u16).to_le_bytes().to_vec(),
+                    (StoreKind::I64_32 { .. }, Value::I64(v)) => (v as u32).to_le_bytes().to_vec(),
+                    (kind, value) => {
+                        return Err(Trap::Unsupported(format!("{:?} of {:?}", kind, value)));
+                    }
+                };
+                self.memory[address..address + bytes.len()].copy_from_slice(&bytes);
+            }
+            Instr::TableSize(size) => {
+                let len = self.tables[&size.table].len();
+                frame.stack.push(Value::I32(len as i32));
+            }
+            other => return Err(Trap::Unsupported(format!("{:?}", other))),
+        }
+
+        Ok(())
+    }
+}
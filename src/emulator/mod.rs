@@ -0,0 +1,258 @@
+pub mod differential;
+mod interpreter;
+pub mod numeric;
+pub mod trap;
+
+use crate::emulator::trap::Trap;
+use std::collections::HashMap;
+use walrus::ir::Value;
+use walrus::{
+    ConstExpr, DataId, DataKind, ElementItems, ElementKind, ExportItem, FunctionId,
+    GlobalId, GlobalKind, ImportKind, Module, TableId, ValType,
+};
+
+pub const PAGE_SIZE: u64 = 65536;
+// 4 GiB, all a 32-bit memory without a maximum can grow to
+pub const MAX_PAGES: u64 = 65536;
+
+// Enough for any of the small functions passes want to run, low enough that a
+// runaway loop doesn't hang the deobfuscator.
+const DEFAULT_FUEL: u64 = 50_000_000;
+// 64 MiB, `memory.grow` past it traps instead of allocating up to `MAX_PAGES`
+const DEFAULT_MEMORY_LIMIT: u64 = 1024;
+
+// Called instead of the function body, gets the linear memory and the call arguments
+pub type Hook<'a> = Box<dyn FnMut(&mut Vec<u8>, &[Value]) -> Result<Vec<Value>, Trap> + 'a>;
+
+// Interpreter for a walrus module, used to statically evaluate module functions.
+//
+// Imports are stubbed: unless a hook is registered for them, they return zeroed
+// values, so everything runs offline.
+pub struct Emulator<'a> {
+    module: &'a Module,
+
+    pub memory: Vec<u8>,
+    max_pages: u64,
+    globals: HashMap<GlobalId, Value>,
+    tables: HashMap<TableId, Vec<Option<FunctionId>>>,
+    passive_data: HashMap<DataId, Vec<u8>>,
+    hooks: HashMap<FunctionId, Hook<'a>>,
+
+    pub fuel: u64,
+    pub max_call_depth: usize,
+    // pages the memory may grow to, lower than the module's maximum
+    pub memory_limit: u64,
+    call_depth: usize,
+}
+
+pub fn zero_value(ty: ValType) -> Value {
+    match ty {
+        ValType::I32 => Value::I32(0),
+        ValType::I64 => Value::I64(0),
+        ValType::F32 => Value::F32(0.0),
+        ValType::F64 => Value::F64(0.0),
+        ValType::V128 => Value::V128(0),
+        // references are not modeled
+        ValType::Ref(_) => Value::I32(0),
+    }
+}
+
+impl<'a> Emulator<'a> {
+    // Instantiates the module: memory, data segments, globals and tables.
+    // The start function is not run, see `run_start`.
+    pub fn new(module: &'a Module) -> Result<Self, Trap> {
+        let (pages, max_pages) = match module.memories.iter().next() {
+            Some(memory) => (memory.initial, memory.maximum.unwrap_or(MAX_PAGES)),
+            None => (0, 0),
+        };
+
+        let mut emulator = Emulator {
+            module,
+            memory: vec![0; (pages * PAGE_SIZE) as usize],
+            max_pages,
+            globals: HashMap::new(),
+            tables: HashMap::new(),
+            passive_data: HashMap::new(),
+            hooks: HashMap::new(),
+            fuel: DEFAULT_FUEL,
+            max_call_depth: 256,
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+            call_depth: 0,
+        };
+
+        for global in module.globals.iter() {
+            let value = match &global.kind {
+                GlobalKind::Local(init) => emulator.eval_const(init)?,
+                GlobalKind::Import(_) => zero_value(global.ty),
+            };
+            emulator.globals.insert(global.id(), value);
+        }
+
+        for table in module.tables.iter() {
+            emulator
+                .tables
+                .insert(table.id(), vec![None; table.initial as usize]);
+        }
+
+        for element in module.elements.iter() {
+            let ElementKind::Active { table, offset } = &element.kind else {
+                continue;
+            };
+
+            let ElementItems::Functions(functions) = &element.items else {
+                return Err(Trap::Unsupported("element expressions".to_string()));
+            };
+
+            let offset = emulator.eval_const_u32(offset)? as usize;
+            let table = emulator.tables.get_mut(table).unwrap();
+            if offset + functions.len() > table.len() {
+                return Err(Trap::TableOutOfBounds((offset + functions.len()) as u32));
+            }
+
+            for (i, function) in functions.iter().enumerate() {
+                table[offset + i] = Some(*function);
+            }
+        }
+
+        for data in module.data.iter() {
+            match &data.kind {
+                DataKind::Active { offset, .. } => {
+                    let offset = emulator.eval_const_u32(offset)?;
+                    emulator.write(offset, &data.value)?;
+                }
+                DataKind::Passive => {
+                    emulator.passive_data.insert(data.id(), data.value.clone());
+                }
+            }
+        }
+
+        Ok(emulator)
+    }
+
+    pub fn module(&self) -> &'a Module {
+        self.module
+    }
+
+    pub fn run_start(&mut self) -> Result<(), Trap> {
+        if let Some(start) = self.module.start {
+            self.call(start, &[])?;
+        }
+
+        Ok(())
+    }
+
+    // Replaces a function (usually an import) by a host closure
+    pub fn hook(
+        &mut self,
+        func: FunctionId,
+        hook: impl FnMut(&mut Vec<u8>, &[Value]) -> Result<Vec<Value>, Trap> + 'a,
+    ) {
+        self.hooks.insert(func, Box::new(hook));
+    }
+
+    pub fn hook_import(
+        &mut self,
+        module: &str,
+        name: &str,
+        hook: impl FnMut(&mut Vec<u8>, &[Value]) -> Result<Vec<Value>, Trap> + 'a,
+    ) -> Result<(), anyhow::Error> {
+        let import = self
+            .module
+            .imports
+            .find(module, name)
+            .ok_or_else(|| anyhow::anyhow!("no import {}.{}", module, name))?;
+
+        match self.module.imports.get(import).kind {
+            ImportKind::Function(func) => {
+                self.hook(func, hook);
+                Ok(())
+            }
+            _ => anyhow::bail!("{}.{} is not a function import", module, name),
+        }
+    }
+
+    pub fn call(&mut self, func: FunctionId, args: &[Value]) -> Result<Vec<Value>, Trap> {
+        let ty = self.module.types.get(self.module.funcs.get(func).ty());
+        if ty.params().len() != args.len() {
+            return Err(Trap::Host(format!(
+                "expected {} arguments, got {}",
+                ty.params().len(),
+                args.len()
+            )));
+        }
+
+        self.invoke(func, args.to_vec())
+    }
+
+    pub fn call_export(&mut self, name: &str, args: &[Value]) -> Result<Vec<Value>, Trap> {
+        let func = self
+            .module
+            .exports
+            .iter()
+            .find_map(|export| match export.item {
+                ExportItem::Function(func) if export.name == name => Some(func),
+                _ => None,
+            })
+            .ok_or_else(|| Trap::Host(format!("no exported function {}", name)))?;
+
+        self.call(func, args)
+    }
+
+    pub fn global(&self, id: GlobalId) -> Value {
+        self.globals[&id]
+    }
+
+    pub fn set_global(&mut self, id: GlobalId, value: Value) {
+        self.globals.insert(id, value);
+    }
+
+    pub fn table(&self, id: TableId) -> &[Option<FunctionId>] {
+        &self.tables[&id]
+    }
+
+    pub fn read(&self, address: u32, len: usize) -> Result<&[u8], Trap> {
+        let start = self.check_bounds(address as u64, len as u64)?;
+        Ok(&self.memory[start..start + len])
+    }
+
+    pub fn write(&mut self, address: u32, bytes: &[u8]) -> Result<(), Trap> {
+        let start = self.check_bounds(address as u64, bytes.len() as u64)?;
+        self.memory[start..start + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    pub fn read_u8(&self, address: u32) -> Result<u8, Trap> {
+        Ok(self.read(address, 1)?[0])
+    }
+
+    pub fn read_u32(&self, address: u32) -> Result<u32, Trap> {
+        Ok(u32::from_le_bytes(self.read(address, 4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&self, address: u32) -> Result<u64, Trap> {
+        Ok(u64::from_le_bytes(self.read(address, 8)?.try_into().unwrap()))
+    }
+
+    fn check_bounds(&self, address: u64, size: u64) -> Result<usize, Trap> {
+        if address + size > self.memory.len() as u64 {
+            return Err(Trap::MemoryOutOfBounds { address, size });
+        }
+
+        Ok(address as usize)
+    }
+
+    fn eval_const(&self, expr: &ConstExpr) -> Result<Value, Trap> {
+        match expr {
+            ConstExpr::Value(value) => Ok(*value),
+            ConstExpr::Global(global) => Ok(self.globals[global]),
+            _ => Err(Trap::Unsupported(format!("{:?}", expr))),
+        }
+    }
+
+    fn eval_const_u32(&self, expr: &ConstExpr) -> Result<u32, Trap> {
+        match self.eval_const(expr)? {
+            Value::I32(i) => Ok(i as u32),
+            other => Err(Trap::Unsupported(format!("offset {:?}", other))),
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use crate::emulator::trap::Trap;
+use walrus::ir::{BinaryOp, UnaryOp, Value};
+
+fn bool_value(b: bool) -> Value {
+    Value::I32(b as i32)
+}
+
+fn unsupported(op: impl std::fmt::Debug) -> Trap {
+    Trap::Unsupported(format!("{:?}", op))
+}
+
+// wasm min/max propagate NaN and order -0 below +0, unlike f32::min/f32::max
+macro_rules! float_min_max {
+    ($min:ident, $max:ident, $t:ty) => {
+        fn $min(a: $t, b: $t) -> $t {
+            if a.is_nan() || b.is_nan() {
+                <$t>::NAN
+            } else if a == b {
+                if a.is_sign_negative() { a } else { b }
+            } else {
+                a.min(b)
+            }
+        }
+
+        fn $max(a: $t, b: $t) -> $t {
+            if a.is_nan() || b.is_nan() {
+                <$t>::NAN
+            } else if a == b {
+                if a.is_sign_positive() { a } else { b }
+            } else {
+                a.max(b)
+            }
+        }
+    };
+}
+
+float_min_max!(f32_min, f32_max, f32);
+float_min_max!(f64_min, f64_max, f64);
+
+// Trapping float -> int truncation, the range is [min, max) after truncation
+fn truncate(value: f64, min: f64, max: f64) -> Result<f64, Trap> {
+    if value.is_nan() {
+        return Err(Trap::InvalidConversionToInteger);
+    }
+
+    let truncated = value.trunc();
+    if truncated < min || truncated >= max {
+        return Err(Trap::IntegerOverflow);
+    }
+
+    Ok(truncated)
+}
+
+pub fn binop(op: BinaryOp, a: Value, b: Value) -> Result<Value, Trap> {
+    use BinaryOp::*;
+
+    Ok(match (a, b) {
+        (Value::I32(a), Value::I32(b)) => match op {
+            I32Eq => bool_value(a == b),
+            I32Ne => bool_value(a != b),
+            I32LtS => bool_value(a < b),
+            I32LtU => bool_value((a as u32) < (b as u32)),
+            I32GtS => bool_value(a > b),
+            I32GtU => bool_value((a as u32) > (b as u32)),
+            I32LeS => bool_value(a <= b),
+            I32LeU => bool_value((a as u32) <= (b as u32)),
+            I32GeS => bool_value(a >= b),
+            I32GeU => bool_value((a as u32) >= (b as u32)),
+            I32Add => Value::I32(a.wrapping_add(b)),
+            I32Sub => Value::I32(a.wrapping_sub(b)),
+            I32Mul => Value::I32(a.wrapping_mul(b)),
+            I32DivS => {
+                if b == 0 {
+                    return Err(Trap::IntegerDivideByZero);
+                }
+                Value::I32(a.checked_div(b).ok_or(Trap::IntegerOverflow)?)
+            }
+            I32DivU => {
+                if b == 0 {
+                    return Err(Trap::IntegerDivideByZero);
+                }
+                Value::I32(((a as u32) / (b as u32)) as i32)
+            }
+            I32RemS => {
+                if b == 0 {
+                    return Err(Trap::IntegerDivideByZero);
+                }
+                Value::I32(a.wrapping_rem(b))
+            }
+            I32RemU => {
+                if b == 0 {
+                    return Err(Trap::IntegerDivideByZero);
+                }
+                Value::I32(((a as u32) % (b as u32)) as i32)
+            }
+            I32And => Value::I32(a & b),
+            I32Or => Value::I32(a | b),
+            I32Xor => Value::I32(a ^ b),
+            I32Shl => Value::I32(a.wrapping_shl(b as u32)),
+            I32ShrS => Value::I32(a.wrapping_shr(b as u32)),
+            I32ShrU => Value::I32((a as u32).wrapping_shr(b as u32) as i32),
+            I32Rotl => Value::I32(a.rotate_left(b as u32)),
+            I32Rotr => Value::I32(a.rotate_right(b as u32)),
+            _ => return Err(unsupported(op)),
+        },
+        (Value::I64(a), Value::I64(b)) => match op {
+            I64Eq => bool_value(a == b),
+            I64Ne => bool_value(a != b),
+            I64LtS => bool_value(a < b),
+            I64LtU => bool_value((a as u64) < (b as u64)),
+            I64GtS => bool_value(a > b),
+            I64GtU => bool_value((a as u64) > (b as u64)),
+            I64LeS => bool_value(a <= b),
+            I64LeU => bool_value((a as u64) <= (b as u64)),
+            I64GeS => bool_value(a >= b),
+            I64GeU => bool_value((a as u64) >= (b as u64)),
+            I64Add => Value::I64(a.wrapping_add(b)),
+            I64Sub => Value::I64(a.wrapping_sub(b)),
+            I64Mul => Value::I64(a.wrapping_mul(b)),
+            I64DivS => {
+                if b == 0 {
+                    return Err(Trap::IntegerDivideByZero);
+                }
+                Value::I64(a.checked_div(b).ok_or(Trap::IntegerOverflow)?)
+            }
+            I64DivU => {
+                if b == 0 {
+                    return Err(Trap::IntegerDivideByZero);
+                }
+                Value::I64(((a as u64) / (b as u64)) as i64)
+            }
+            I64RemS => {
+                if b == 0 {
+                    return Err(Trap::IntegerDivideByZero);
+                }
+                Value::I64(a.wrapping_rem(b))
+            }
+            I64RemU => {
+                if b == 0 {
+                    return Err(Trap::IntegerDivideByZero);
+                }
+                Value::I64(((a as u64) % (b as u64)) as i64)
+            }
+            I64And => Value::I64(a & b),
+            I64Or => Value::I64(a | b),
+            I64Xor => Value::I64(a ^ b),
+            I64Shl => Value::I64(a.wrapping_shl(b as u32)),
+            I64ShrS => Value::I64(a.wrapping_shr(b as u32)),
+            I64ShrU => Value::I64((a as u64).wrapping_shr(b as u32) as i64),
+            I64Rotl => Value::I64(a.rotate_left((b & 63) as u32)),
+            I64Rotr => Value::I64(a.rotate_right((b & 63) as u32)),
+            _ => return Err(unsupported(op)),
+        },
+        (Value::F32(a), Value::F32(b)) => match op {
+            F32Eq => bool_value(a == b),
+            F32Ne => bool_value(a != b),
+            F32Lt => bool_value(a < b),
+            F32Gt => bool_value(a > b),
+            F32Le => bool_value(a <= b),
+            F32Ge => bool_value(a >= b),
+            F32Add => Value::F32(a + b),
+            F32Sub => Value::F32(a - b),
+            F32Mul => Value::F32(a * b),
+            F32Div => Value::F32(a / b),
+            F32Min => Value::F32(f32_min(a, b)),
+            F32Max => Value::F32(f32_max(a, b)),
+            F32Copysign => Value::F32(a.copysign(b)),
+            _ => return Err(unsupported(op)),
+        },
+        (Value::F64(a), Value::F64(b)) => match op {
+            F64Eq => bool_value(a == b),
+            F64Ne => bool_value(a != b),
+            F64Lt => bool_value(a < b),
+            F64Gt => bool_value(a > b),
+            F64Le => bool_value(a <= b),
+            F64Ge => bool_value(a >= b),
+            F64Add => Value::F64(a + b),
+            F64Sub => Value::F64(a - b),
+            F64Mul => Value::F64(a * b),
+            F64Div => Value::F64(a / b),
+            F64Min => Value::F64(f64_min(a, b)),
+            F64Max => Value::F64(f64_max(a, b)),
+            F64Copysign => Value::F64(a.copysign(b)),
+            _ => return Err(unsupported(op)),
+        },
+        _ => return Err(unsupported(op)),
+    })
+}
+
+pub fn unop(op: UnaryOp, a: Value) -> Result<Value, Trap> {
+    use UnaryOp::*;
+
+    const I32_MIN: f64 = i32::MIN as f64;
+    const I32_END: f64 = 2147483648.0;
+    const U32_END: f64 = 4294967296.0;
+    const I64_MIN: f64 = i64::MIN as f64;
+    const I64_END: f64 = 9223372036854775808.0;
+    const U64_END: f64 = 18446744073709551616.0;
+
+    Ok(match a {
+        Value::I32(a) => match op {
+            I32Eqz => bool_value(a == 0),
+            I32Clz => Value::I32(a.leading_zeros() as i32),
+            I32Ctz => Value::I32(a.trailing_zeros() as i32),
+            I32Popcnt => Value::I32(a.count_ones() as i32),
+            I32Extend8S => Value::I32(a as i8 as i32),
+            I32Extend16S => Value::I32(a as i16 as i32),
+            I64ExtendSI32 => Value::I64(a as i64),
+            I64ExtendUI32 => Value::I64(a as u32 as i64),
+            F32ConvertSI32 => Value::F32(a as f32),
+            F32ConvertUI32 => Value::F32(a as u32 as f32),
+            F64ConvertSI32 => Value::F64(a as f64),
+            F64ConvertUI32 => Value::F64(a as u32 as f64),
+            F32ReinterpretI32 => Value::F32(f32::from_bits(a as u32)),
+            _ => return Err(unsupported(op)),
+        },
+        Value::I64(a) => match op {
+            I64Eqz => bool_value(a == 0),
+            I64Clz => Value::I64(a.leading_zeros() as i64),
+            I64Ctz => Value::I64(a.trailing_zeros() as i64),
+            I64Popcnt => Value::I64(a.count_ones() as i64),
+            I64Extend8S => Value::I64(a as i8 as i64),
+            I64Extend16S => Value::I64(a as i16 as i64),
+            I64Extend32S => Value::I64(a as i32 as i64),
+            I32WrapI64 => Value::I32(a as i32),
+            F32ConvertSI64 => Value::F32(a as f32),
+            F32ConvertUI64 => Value::F32(a as u64 as f32),
+            F64ConvertSI64 => Value::F64(a as f64),
+            F64ConvertUI64 => Value::F64(a as u64 as f64),
+            F64ReinterpretI64 => Value::F64(f64::from_bits(a as u64)),
+            _ => return Err(unsupported(op)),
+        },
+        Value::F32(a) => match op {
+            F32Abs => Value::F32(f32::from_bits(a.to_bits() & 0x7fff_ffff)),
+            F32Neg => Value::F32(f32::from_bits(a.to_bits() ^ 0x8000_0000)),
+            F32Ceil => Value::F32(a.ceil()),
+            F32Floor => Value::F32(a.floor()),
+            F32Trunc => Value::F32(a.trunc()),
+            F32Nearest => Value::F32(a.round_ties_even()),
+            F32Sqrt => Value::F32(a.sqrt()),
+            F64PromoteF32 => Value::F64(a as f64),
+            I32ReinterpretF32 => Value::I32(a.to_bits() as i32),
+            I32TruncSF32 => Value::I32(truncate(a as f64, I32_MIN, I32_END)? as i32),
+            I32TruncUF32 => Value::I32(truncate(a as f64, -0.0, U32_END)? as u32 as i32),
+            I64TruncSF32 => Value::I64(truncate(a as f64, I64_MIN, I64_END)? as i64),
+            I64TruncUF32 => Value::I64(truncate(a as f64, -0.0, U64_END)? as u64 as i64),
+            // `as` saturates and maps NaN to 0, which is exactly the wasm semantic
+            I32TruncSSatF32 => Value::I32(a as i32),
+            I32TruncUSatF32 => Value::I32(a as u32 as i32),
+            I64TruncSSatF32 => Value::I64(a as i64),
+            I64TruncUSatF32 => Value::I64(a as u64 as i64),
+            _ => return Err(unsupported(op)),
+        },
+        Value::F64(a) => match op {
+            F64Abs => Value::F64(f64::from_bits(a.to_bits() & 0x7fff_ffff_ffff_ffff)),
+            F64Neg => Value::F64(f64::from_bits(a.to_bits() ^ 0x8000_0000_0000_0000)),
+            F64Ceil => Value::F64(a.ceil()),
+            F64Floor => Value::F64(a.floor()),
+            F64Trunc => Value::F64(a.trunc()),
+            F64Nearest => Value::F64(a.round_ties_even()),
+            F64Sqrt => Value::F64(a.sqrt()),
+            F32DemoteF64 => Value::F32(a as f32),
+            I64ReinterpretF64 => Value::I64(a.to_bits() as i64),
+            I32TruncSF64 => Value::I32(truncate(a, I32_MIN, I32_END)? as i32),
+            I32TruncUF64 => Value::I32(truncate(a, -0.0, U32_END)? as u32 as i32),
+            I64TruncSF64 => Value::I64(truncate(a, I64_MIN, I64_END)? as i64),
+            I64TruncUF64 => Value::I64(truncate(a, -0.0, U64_END)? as u64 as i64),
+            I32TruncSSatF64 => Value::I32(a as i32),
+            I32TruncUSatF64 => Value::I32(a as u32 as i32),
+            I64TruncSSatF64 => Value::I64(a as i64),
+            I64TruncUSatF64 => Value::I64(a as u64 as i64),
+            _ => return Err(unsupported(op)),
+        },
+        Value::V128(_) => return Err(unsupported(op)),
+    })
+}
@@ -0,0 +1,45 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trap {
+    Unreachable,
+    MemoryOutOfBounds { address: u64, size: u64 },
+    TableOutOfBounds(u32),
+    UninitializedElement(u32),
+    IndirectCallTypeMismatch,
+    IntegerDivideByZero,
+    IntegerOverflow,
+    InvalidConversionToInteger,
+    CallStackExhausted,
+    OutOfFuel,
+    // `memory.grow` past `Emulator::memory_limit` pages
+    MemoryLimit(u64),
+    // The module asked for something the emulator doesn't implement (simd, atomics, references...)
+    Unsupported(String),
+    // Raised by import stubs and hooks
+    Host(String),
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trap::Unreachable => write!(f, "unreachable executed"),
+            Trap::MemoryOutOfBounds { address, size } => {
+                write!(f, "out of bounds memory access ({} bytes at {:#x})", size, address)
+            }
+            Trap::TableOutOfBounds(idx) => write!(f, "undefined table element {}", idx),
+            Trap::UninitializedElement(idx) => write!(f, "uninitialized table element {}", idx),
+            Trap::IndirectCallTypeMismatch => write!(f, "indirect call type mismatch"),
+            Trap::IntegerDivideByZero => write!(f, "integer divide by zero"),
+            Trap::IntegerOverflow => write!(f, "integer overflow"),
+            Trap::InvalidConversionToInteger => write!(f, "invalid conversion to integer"),
+            Trap::CallStackExhausted => write!(f, "call stack exhausted"),
+            Trap::OutOfFuel => write!(f, "out of fuel"),
+            Trap::MemoryLimit(pages) => write!(f, "memory limit of {} pages reached", pages),
+            Trap::Unsupported(what) => write!(f, "unsupported: {}", what),
+            Trap::Host(message) => write!(f, "host: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Trap {}
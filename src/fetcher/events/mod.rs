@@ -1,56 +1,58 @@
 mod visitor;
 
 use crate::fetcher::events::visitor::collect_i32_consts;
-use anyhow::{bail, Context};
-use std::collections::VecDeque;
-use std::ops::Index;
-use walrus::ir::{BinaryOp, Block, Const, IfElse, Instr, Loop, Value};
-use walrus::{ConstExpr, DataKind, GlobalKind, LocalFunction, Module};
-
-const NEEDED_VALUES: [i32; 4] = [-1, 268435455, -2147483648, 0]; 
+use crate::pattern::Pattern;
+use crate::profile::Profile;
+use crate::ssa::Function;
+use anyhow::Context;
+use walrus::ir::Value;
+use walrus::{ConstExpr, DataKind, FunctionId, GlobalKind, LocalFunction, Module};
 
 pub fn fetch_events(module: &mut Module) -> Result<String, anyhow::Error> {
+    fetch_events_with(module, &Profile::default())
+}
+
+pub fn fetch_events_with(module: &mut Module, profile: &Profile) -> Result<String, anyhow::Error> {
     let global = module.globals.iter().next().context("Could not find global")?;
-    let data_segment = module.data.iter().nth(1).context("Could not find memory")?;
+    let data_segment = module.data.iter().nth(profile.data_segment).context("Could not find memory")?;
     let data_start = match &data_segment.kind {
-        DataKind::Active { offset, .. } => match *offset {
-            ConstExpr::Value(v) => match v {
-                Value::I32(i) => i,
-                _ => panic!(),
-            },
-            _ => panic!(),
-        },
+        DataKind::Active {
+            offset: ConstExpr::Value(Value::I32(i)),
+            ..
+        } => *i,
         _ => panic!(),
     } as usize;
     
     
-    'a: for (_, func) in module.funcs.iter_local() {
-        let collected_consts = collect_i32_consts(func);
-        for n in NEEDED_VALUES {
-            if !collected_consts.contains(&n) {
-                continue 'a;
-            }
-        }
-        
-        let (events_idx, events_length) = search_pattern(data_start, func).context("Could not find xor event loc in memory")?;
-        let global_idx = match &global.kind {
-            GlobalKind::Local(c) => match c {
-                ConstExpr::Value(v) => match v {
-                    Value::I32(i) => i,
-                    _ => panic!(),
-                }
-                _ => panic!(),
-            }
-            _ => panic!(),
-        };
-        
-        return read_events(data_start, &data_segment.value, events_idx as usize, *global_idx as usize);
-    }
-    
-    bail!("could not find function that init events")
+    let func = find_events_function_with(module, &profile.events_constants).context("could not find function that init events")?;
+    let func = module.funcs.get(func).kind.unwrap_local();
+
+    let events_idx = search_pattern(data_start, module, func).context("Could not find xor event loc in memory")?;
+    let global_idx = match &global.kind {
+        GlobalKind::Local(ConstExpr::Value(Value::I32(i))) => i,
+        _ => panic!(),
+    };
+
+    read_events(data_start, &data_segment.value, events_idx as usize, *global_idx as usize)
 }
 
-fn read_events(data_start: usize, data: &Vec<u8>, encrypted_event_string_idx: usize, xor_table: usize) -> Result<String, anyhow::Error> {
+// The function decrypting the events, the only one with all the mask constants
+pub fn find_events_function(module: &Module) -> Option<FunctionId> {
+    find_events_function_with(module, &Profile::default().events_constants)
+}
+
+pub fn find_events_function_with(module: &Module, needed_values: &[i32]) -> Option<FunctionId> {
+    module
+        .funcs
+        .iter_local()
+        .find(|(_, func)| {
+            let collected_consts = collect_i32_consts(func);
+            needed_values.iter().all(|n| collected_consts.contains(n))
+        })
+        .map(|(id, _)| id)
+}
+
+fn read_events(data_start: usize, data: &[u8], encrypted_event_string_idx: usize, xor_table: usize) -> Result<String, anyhow::Error> {
     let mut res = String::new();
     let mut offset = 0;
     
@@ -75,46 +77,15 @@ fn read_events(data_start: usize, data: &Vec<u8>, encrypted_event_string_idx: us
     Ok(res)
 }
 
-fn search_pattern(data_segment_start: usize, func: &LocalFunction) -> Option<(i32, i32)> {
-    let mut stack = VecDeque::new();
-    stack.push_front(func.entry_block());
-    while let Some(block_id) = stack.pop_back() {
-        let block = func.block(block_id);
-
-        for (idx, (instr, _)) in block.instrs.iter().enumerate() {
-            match instr {
-                Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => {
-                    stack.push_front(*seq)
-                }
-                Instr::IfElse(IfElse {
-                                  consequent,
-                                  alternative,
-                              }) => {
-                    stack.push_front(*consequent);
-                    stack.push_front(*alternative);
-                }
-                Instr::Binop(op) if matches!(op.op, BinaryOp::I32Xor) => {
-                    if !matches!(block.instrs[idx-1].0, Instr::Load(_)) || !matches!(block.instrs[idx-2].0, Instr::Binop(_)) || !matches!(block.instrs[idx+1].0, Instr::Store(_)) {
-                        continue;
-                    }
+// The events are decrypted by xoring them into memory, the address of the encrypted
+// events is the constant in the address of the xored load
+fn search_pattern(data_segment_start: usize, module: &Module, func: &LocalFunction) -> Option<i32> {
+    let ssa = Function::build(module, func).ok()?;
+    let pattern = Pattern::parse("Store(_, I32Xor(_, Load(Binop(_, events @ i32))))").unwrap();
 
-
-                    if let (
-                        Some(Instr::Const(Const { value: Value::I32(n1), .. })),
-                        Some(Instr::Const(Const { value: Value::I32(n2), .. })),
-                    ) = (
-                        block.instrs.get(idx.wrapping_sub(3)).map(|(i, _)| i),
-                        block.instrs.get(idx + 3).map(|(i, _)| i),
-                    ) {
-                        if *n1 > data_segment_start as i32 {
-                            return Some((*n1, *n2));
-                        }
-                    }
-                }
-                _ => {}
-            }
-        }
-    }
-    
-    None
-}
\ No newline at end of file
+    pattern
+        .find(&ssa)
+        .iter()
+        .filter_map(|(_, captures)| captures.i32(&ssa, "events"))
+        .find(|events| *events > data_segment_start as i32)
+}
@@ -0,0 +1,10 @@
+pub mod analysis;
+pub mod commands;
+pub mod decompiler;
+pub mod emulator;
+pub mod fetcher;
+pub mod pattern;
+pub mod profile;
+pub mod rng;
+pub mod ssa;
+pub mod transformations;
@@ -1,43 +1,66 @@
-mod fetcher;
-mod transformations;
-
-use crate::fetcher::events::fetch_events;
-use crate::transformations::memory::memory_transformer::MemoryTransformer;
-use crate::transformations::Transformer;
+use hcaptcha_wasm_deobfuscator::commands;
+use hcaptcha_wasm_deobfuscator::commands::{check_profile, deobfuscate_with, load_module, select_profile};
+use hcaptcha_wasm_deobfuscator::fetcher::events::fetch_events_with;
+use hcaptcha_wasm_deobfuscator::profile::Profile;
 use std::env;
 use std::path::Path;
 use std::time::Instant;
-use walrus::Module;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = env::args().collect();
 
-    let input = Path::new(
-        args.get(1)
-            .map(|s| s.as_str())
-            .unwrap_or("./assets/input.wasm"),
-    );
-    
-    let output = Path::new(
-        args.get(2)
-            .map(|s| s.as_str())
-            .unwrap_or("./assets/output.wasm"),
-    );
-    
-    if !input.exists() {
-        panic!("Input file does not exist");
+    match args.get(1).map(|s| s.as_str()) {
+        Some("callgraph") => commands::callgraph::run(&args[2..])?,
+        Some("cfg") => commands::cfg::run(&args[2..])?,
+        Some("decompile") => commands::decompile::run(&args[2..])?,
+        Some("diff") => commands::diff::run(&args[2..])?,
+        Some("fingerprint") => commands::fingerprint::run(&args[2..])?,
+        Some("instrument") => commands::instrument::run(&args[2..])?,
+        Some("mba") => commands::mba::run(&args[2..])?,
+        Some("profile") => commands::profile::run(&args[2..])?,
+        Some("sigs") => commands::sigs::run(&args[2..])?,
+        Some("wrappers") => commands::wrappers::run(&args[2..])?,
+        Some("xrefs") => commands::xrefs::run(&args[2..])?,
+        _ => run_deobfuscator(&args)?,
     }
 
-    let t = Instant::now();
-    let mut module = Module::from_file(input).map_err(|e| format!("{:?}", e))?;
+    Ok(())
+}
 
-    let mut transformers = vec![MemoryTransformer {}];
+fn run_deobfuscator(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut paths = Vec::new();
+    let mut profile = None;
+    let mut profiles = "./profiles";
 
-    for transformer in transformers.iter_mut() {
-        transformer.transform(&mut module);
+    let mut rest = args.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--profile" => {
+                let path = rest.next().ok_or("--profile needs a file")?;
+                profile = Some(Profile::load(path)?);
+            }
+            "--profiles" => profiles = rest.next().ok_or("--profiles needs a directory")?,
+            _ => paths.push(arg.as_str()),
+        }
     }
 
-    let events = fetch_events(&mut module)?;
+    let input = paths.first().copied().unwrap_or("./assets/input.wasm");
+    let output = Path::new(paths.get(1).copied().unwrap_or("./assets/output.wasm"));
+
+    let t = Instant::now();
+    let mut module = load_module(input)?;
+
+    // an explicit profile, else the one for the build, else the built-in one, also for
+    // unknown builds
+    let profile = match profile {
+        Some(profile) => profile,
+        None if Path::new(profiles).is_dir() => select_profile(&module, profiles)?,
+        None => Profile::default(),
+    };
+    check_profile(&module, &profile)?;
+    deobfuscate_with(&mut module, &profile)?;
+
+    let events = fetch_events_with(&mut module, &profile)?;
     println!("{:?}", events);
 
     println!("Took {:?}", t.elapsed());
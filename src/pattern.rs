@@ -0,0 +1,372 @@
+// Instruction patterns, written as text:
+//
+//   Store(_, I32Xor(_, Load(Binop(_, events @ i32))))
+//
+// - `_` matches anything
+// - `name @ pattern` binds what `pattern` matched to `name`, a name bound twice has to
+//   match the same thing both times
+// - `i32`, `i64`, `f32` and `f64` match a constant of that type, `i32(96)` only that value
+// - other names match an instruction by variant (`Load`, `Call`, `Binop`) or, for binary
+//   and unary operators, by operator (`I32Xor`, `I64ExtendUI32`). In an expression pattern
+//   the arguments are patterns for its operands, without them the operands aren't looked at.
+//
+// Expression patterns match trees of SSA values, whatever the instruction order and locals
+// in between. Sequence patterns are comma separated instructions, one after the other in
+// the same block.
+use crate::ssa::{Function, InstId, Op};
+use anyhow::{Context, bail};
+use std::collections::VecDeque;
+use walrus::LocalFunction;
+use walrus::ir::{Instr, InstrLocId, InstrSeqId, Value};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    Any,
+    Bind(String, Box<Pattern>),
+    // type name (`i32`) and value, if any
+    Const(&'static str, Option<i64>),
+    // instruction name and operand patterns, if any
+    Op(String, Option<Vec<Pattern>>),
+}
+
+// Instructions a sequence pattern matched, or the defining instructions of the values an
+// expression pattern did, by name
+#[derive(Debug, Clone)]
+pub struct Captures<T> {
+    bound: Vec<(String, T)>,
+}
+
+impl<T: Copy> Captures<T> {
+    fn new() -> Self {
+        Captures { bound: Vec::new() }
+    }
+
+    pub fn get(&self, name: &str) -> Option<T> {
+        self.bound
+            .iter()
+            .find(|(bound, _)| bound == name)
+            .map(|(_, value)| *value)
+    }
+
+    fn bind(&mut self, name: &str, value: T, same: impl Fn(T, T) -> bool) -> bool {
+        match self.get(name) {
+            Some(bound) => same(bound, value),
+            None => {
+                self.bound.push((name.to_string(), value));
+                true
+            }
+        }
+    }
+}
+
+impl Captures<InstId> {
+    // Value of the i32 constant bound to `name`
+    pub fn i32(&self, ssa: &Function, name: &str) -> Option<i32> {
+        match ssa.inst(self.get(name)?).op() {
+            Op::Const(Value::I32(value)) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+impl Captures<&Instr> {
+    // Value of the i32 constant bound to `name`
+    pub fn i32(&self, name: &str) -> Option<i32> {
+        match self.get(name)? {
+            Instr::Const(c) => match c.value {
+                Value::I32(value) => Some(value),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+// Names an instruction answers to: its variant, and its operator for `Binop` and `Unop`
+fn names(instr: &Instr) -> (String, Option<String>) {
+    let debug = format!("{:?}", instr);
+    let variant = debug.split('(').next().unwrap_or_default().to_string();
+    let op = match instr {
+        Instr::Binop(binop) => Some(format!("{:?}", binop.op)),
+        Instr::Unop(unop) => Some(format!("{:?}", unop.op)),
+        _ => None,
+    };
+    (variant, op)
+}
+
+fn const_matches(ty: &str, expected: Option<i64>, value: &Value) -> bool {
+    let actual = match (ty, value) {
+        ("i32", Value::I32(v)) => Some(*v as i64),
+        ("i64", Value::I64(v)) => Some(*v),
+        ("f32", Value::F32(_)) | ("f64", Value::F64(_)) => None,
+        _ => return false,
+    };
+    expected.is_none() || expected == actual
+}
+
+impl Pattern {
+    pub fn parse(text: &str) -> Result<Pattern, anyhow::Error> {
+        let mut parser = Parser::new(text)?;
+        let pattern = parser.pattern()?;
+        parser.end()?;
+        Ok(pattern)
+    }
+
+    // Whether `instr` itself matches, operands aside
+    fn instr_matches(name: &str, instr: &Instr) -> bool {
+        let (variant, op) = names(instr);
+        variant == name || op.is_some_and(|op| op == name)
+    }
+
+    // Matches the tree rooted at `inst`
+    pub fn matches(&self, ssa: &Function, inst: InstId) -> Option<Captures<InstId>> {
+        let mut captures = Captures::new();
+        self.tree(ssa, inst, &mut captures).then_some(captures)
+    }
+
+    fn tree(&self, ssa: &Function, inst: InstId, captures: &mut Captures<InstId>) -> bool {
+        let op = ssa.inst(inst).op();
+        match self {
+            Pattern::Any => true,
+            Pattern::Bind(name, pattern) => {
+                pattern.tree(ssa, inst, captures) && captures.bind(name, inst, |a, b| a == b)
+            }
+            Pattern::Const(ty, value) => matches!(op, Op::Const(c) if const_matches(ty, *value, c)),
+            Pattern::Op(name, operands) => {
+                let matches = match op {
+                    Op::Instr(instr) => Pattern::instr_matches(name, instr),
+                    Op::Const(_) => name == "Const",
+                    Op::Param(_) => name == "Param",
+                    Op::Phi(_) => name == "Phi",
+                    _ => false,
+                };
+                let Some(operands) = operands else {
+                    return matches;
+                };
+                let args = ssa.inst(inst).args();
+                matches
+                    && operands.len() == args.len()
+                    && operands
+                        .iter()
+                        .zip(args.iter())
+                        .all(|(pattern, arg)| pattern.tree(ssa, ssa.def(*arg), captures))
+            }
+        }
+    }
+
+    // Every instruction of `ssa` the pattern matches, in code order
+    pub fn find(&self, ssa: &Function) -> Vec<(InstId, Captures<InstId>)> {
+        ssa.walk()
+            .into_iter()
+            .filter_map(|inst| Some((inst, self.matches(ssa, inst)?)))
+            .collect()
+    }
+
+    fn has_operands(&self) -> bool {
+        match self {
+            Pattern::Bind(_, pattern) => pattern.has_operands(),
+            Pattern::Op(_, operands) => operands.is_some(),
+            _ => false,
+        }
+    }
+
+    fn instr(&self, instr: &Instr) -> bool {
+        match self {
+            Pattern::Any => true,
+            Pattern::Bind(_, pattern) => pattern.instr(instr),
+            Pattern::Const(ty, value) => {
+                matches!(instr, Instr::Const(c) if const_matches(ty, *value, &c.value))
+            }
+            Pattern::Op(name, _) => Pattern::instr_matches(name, instr),
+        }
+    }
+}
+
+// Instructions following each other in a block
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sequence {
+    patterns: Vec<Pattern>,
+}
+
+impl Sequence {
+    pub fn parse(text: &str) -> Result<Sequence, anyhow::Error> {
+        let mut parser = Parser::new(text)?;
+        let mut patterns = vec![parser.pattern()?];
+        while parser.eat(",") {
+            patterns.push(parser.pattern()?);
+        }
+        parser.end()?;
+
+        if let Some(pattern) = patterns.iter().find(|pattern| pattern.has_operands()) {
+            bail!("sequence patterns don't take operands: {:?}", pattern);
+        }
+        Ok(Sequence { patterns })
+    }
+
+    pub fn len(&self) -> usize {
+        self.patterns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    // Matches the instructions from `start`, `None` past the end of the block
+    pub fn matches<'a>(
+        &self,
+        instrs: &'a [(Instr, InstrLocId)],
+        start: usize,
+    ) -> Option<Captures<&'a Instr>> {
+        let instrs = instrs.get(start..start.checked_add(self.len())?)?;
+
+        let mut captures = Captures::new();
+        for (pattern, (instr, _)) in self.patterns.iter().zip(instrs.iter()) {
+            if !pattern.instr(instr) {
+                return None;
+            }
+            if let Pattern::Bind(name, _) = pattern {
+                let same = |a: &Instr, b: &Instr| format!("{:?}", a) == format!("{:?}", b);
+                if !captures.bind(name, instr, same) {
+                    return None;
+                }
+            }
+        }
+        Some(captures)
+    }
+
+    // Every match in `func`, blocks in breadth first order, as the block and position of
+    // the first instruction. Matches may overlap.
+    pub fn find<'a>(
+        &self,
+        func: &'a LocalFunction,
+    ) -> Vec<(InstrSeqId, usize, Captures<&'a Instr>)> {
+        let mut found = Vec::new();
+        let mut stack = VecDeque::new();
+        stack.push_front(func.entry_block());
+        while let Some(seq) = stack.pop_back() {
+            let instrs = &func.block(seq).instrs;
+            for (idx, (instr, _)) in instrs.iter().enumerate() {
+                match instr {
+                    Instr::Block(block) => stack.push_front(block.seq),
+                    Instr::Loop(block) => stack.push_front(block.seq),
+                    Instr::IfElse(if_else) => {
+                        stack.push_front(if_else.consequent);
+                        stack.push_front(if_else.alternative);
+                    }
+                    _ => {}
+                }
+                if let Some(captures) = self.matches(instrs, idx) {
+                    found.push((seq, idx, captures));
+                }
+            }
+        }
+        found
+    }
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(text: &str) -> Result<Parser, anyhow::Error> {
+        let mut tokens = Vec::new();
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                c if c.is_whitespace() => {}
+                '(' | ')' | ',' | '@' => tokens.push(c.to_string()),
+                c if c.is_alphanumeric() || c == '_' || c == '-' => {
+                    let mut token = c.to_string();
+                    while let Some(c) = chars.next_if(|c| c.is_alphanumeric() || *c == '_') {
+                        token.push(c);
+                    }
+                    tokens.push(token);
+                }
+                c => bail!("unexpected {:?} in pattern {:?}", c, text),
+            }
+        }
+        Ok(Parser { tokens, pos: 0 })
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|token| token.as_str())
+    }
+
+    fn next(&mut self) -> Result<String, anyhow::Error> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .context("pattern ends early")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        let found = self.peek() == Some(token);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), anyhow::Error> {
+        match self.next()? {
+            next if next == token => Ok(()),
+            next => bail!("expected {:?} in pattern, found {:?}", token, next),
+        }
+    }
+
+    fn end(&self) -> Result<(), anyhow::Error> {
+        match self.peek() {
+            Some(token) => bail!("unexpected {:?} at the end of pattern", token),
+            None => Ok(()),
+        }
+    }
+
+    fn pattern(&mut self) -> Result<Pattern, anyhow::Error> {
+        let name = self.next()?;
+        if !name.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+            bail!("expected a pattern, found {:?}", name);
+        }
+
+        if self.eat("@") {
+            return Ok(Pattern::Bind(name, Box::new(self.pattern()?)));
+        }
+        if name == "_" {
+            return Ok(Pattern::Any);
+        }
+
+        if let Some(ty) = ["i32", "i64", "f32", "f64"]
+            .into_iter()
+            .find(|ty| *ty == name)
+        {
+            let mut value = None;
+            if self.eat("(") {
+                let literal = self.next()?;
+                let parsed = match literal.strip_prefix("0x") {
+                    Some(hex) => i64::from_str_radix(hex, 16),
+                    None => literal.parse(),
+                };
+                value = Some(parsed.with_context(|| format!("invalid constant {:?}", literal))?);
+                self.expect(")")?;
+            }
+            return Ok(Pattern::Const(ty, value));
+        }
+
+        if !self.eat("(") {
+            return Ok(Pattern::Op(name, None));
+        }
+        let mut operands = Vec::new();
+        if !self.eat(")") {
+            operands.push(self.pattern()?);
+            while self.eat(",") {
+                operands.push(self.pattern()?);
+            }
+            self.expect(")")?;
+        }
+        Ok(Pattern::Op(name, Some(operands)))
+    }
+}
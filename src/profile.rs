@@ -0,0 +1,508 @@
+use crate::analysis::build::{BuildFingerprint, FeatureDiff};
+use crate::fetcher::events::find_events_function_with;
+use crate::pattern::Sequence;
+use anyhow::{Context, bail};
+use std::fs;
+use walrus::ir::Value;
+use walrus::{ConstExpr, DataKind, Module};
+
+// Constants of an hCaptcha build the deobfuscator relies on. Builds change them now and
+// then, a profile file overrides them without recompiling.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    // i32 constants only the events function has all of
+    pub events_constants: Vec<i32>,
+    // data segments holding the xor table and the encrypted data
+    pub table_segment: usize,
+    pub data_segment: usize,
+    // the wrappers index the xor table with `address % table_len`
+    pub table_len: usize,
+    // encrypted memory is in pages of `page_size` bytes, each after a `page_header` bytes
+    // header, the first one at `pages_start`
+    pub page_size: usize,
+    pub page_header: usize,
+    pub pages_start: usize,
+    // how far before its first page the plain data of the segment starts
+    pub start_skew: usize,
+    // deobfuscation passes to run, in order, out of `PASSES`
+    pub passes: Vec<String>,
+    // features of the build the profile is for, see `BuildFingerprint`. Only the features
+    // listed have to match, a profile without any is never picked automatically.
+    pub fingerprint: Vec<(String, String)>,
+}
+
+pub const PASSES: [&str; 5] = ["mba", "memory", "bulk_memory", "globals", "indirect_calls"];
+// `mba` only for builds with MBA obfuscated arithmetic
+pub const DEFAULT_PASSES: [&str; 4] = ["memory", "bulk_memory", "globals", "indirect_calls"];
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            name: "default".to_string(),
+            events_constants: vec![-1, 268435455, -2147483648, 0],
+            table_segment: 0,
+            data_segment: 1,
+            table_len: 96,
+            page_size: 320,
+            page_header: 8,
+            pages_start: 1024,
+            start_skew: 23,
+            passes: DEFAULT_PASSES.iter().map(|pass| pass.to_string()).collect(),
+            fingerprint: Vec::new(),
+        }
+    }
+}
+
+// A profile field the module disagrees with
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub field: &'static str,
+    pub reason: String,
+}
+
+// The known profile closest to a build, it is the build's own without differences
+#[derive(Debug)]
+pub struct Selection<'a> {
+    pub profile: &'a Profile,
+    pub differences: Vec<FeatureDiff>,
+}
+
+#[derive(Debug)]
+enum FieldValue {
+    String(String),
+    Int(i64),
+    Array(Vec<FieldValue>),
+    // JSON object or TOML section
+    Table(Vec<(String, FieldValue)>),
+}
+
+impl Profile {
+    // Header and page, the distance between two page headers
+    pub fn page_stride(&self) -> usize {
+        self.page_size + self.page_header
+    }
+
+    // The part of TOML and JSON profiles need: `key = value` lines and `[section]`s, or
+    // one JSON object. Values are strings, integers (TOML's `0x` and `_` in JSON too),
+    // arrays and tables, there are no floats, booleans, dates or multi-line strings.
+    // Fields left out keep their default.
+    pub fn parse(text: &str) -> Result<Profile, anyhow::Error> {
+        let mut parser = Parser::new(text)?;
+        let fields = match parser.eat("{") {
+            true => parser.object()?,
+            false => parser.toml()?,
+        };
+        if let Some(token) = parser.peek() {
+            bail!("unexpected {:?} in profile", token);
+        }
+
+        let mut profile = Profile::default();
+        for (key, value) in fields {
+            profile.set(&key, value)?;
+        }
+        Ok(profile)
+    }
+
+    pub fn load(path: &str) -> Result<Profile, anyhow::Error> {
+        let text = fs::read_to_string(path).with_context(|| format!("reading {}", path))?;
+        Profile::parse(&text).with_context(|| format!("invalid profile {}", path))
+    }
+
+    // Every `.toml` and `.json` profile in `dir`, by file name
+    pub fn load_dir(dir: &str) -> Result<Vec<Profile>, anyhow::Error> {
+        let mut paths = fs::read_dir(dir)
+            .with_context(|| format!("reading {}", dir))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.retain(|path| {
+            path.extension()
+                .is_some_and(|ext| ext == "toml" || ext == "json")
+        });
+        paths.sort();
+
+        paths
+            .iter()
+            .map(|path| Profile::load(&path.to_string_lossy()))
+            .collect()
+    }
+
+    // The profile with the fewest differing features, the most matching ones on a tie
+    pub fn select<'a>(
+        profiles: &'a [Profile],
+        fingerprint: &BuildFingerprint,
+    ) -> Option<Selection<'a>> {
+        profiles
+            .iter()
+            .filter(|profile| !profile.fingerprint.is_empty())
+            .map(|profile| Selection {
+                profile,
+                differences: fingerprint.diff(&profile.fingerprint),
+            })
+            .min_by_key(|selection| {
+                let matching = selection.profile.fingerprint.len() - selection.differences.len();
+                (selection.differences.len(), usize::MAX - matching)
+            })
+    }
+
+    // `[fingerprint]` section of a profile for the build
+    pub fn fingerprint_section(fingerprint: &BuildFingerprint) -> String {
+        let mut section = "[fingerprint]\n".to_string();
+        for (name, value) in fingerprint.features.iter() {
+            section += &format!("{} = \"{}\"\n", name, value);
+        }
+        section
+    }
+
+    fn set(&mut self, key: &str, value: FieldValue) -> Result<(), anyhow::Error> {
+        let int = |value: &FieldValue| match value {
+            FieldValue::Int(i) if *i >= 0 => Ok(*i as usize),
+            _ => bail!("{} has to be a non-negative integer", key),
+        };
+        // sizes the accessors divide by
+        let nonzero = |value: &FieldValue| match int(value)? {
+            0 => bail!("{} can't be 0", key),
+            i => Ok(i),
+        };
+        let string = |value: FieldValue| match value {
+            FieldValue::String(string) => Ok(string),
+            _ => bail!("{} has to be made of strings", key),
+        };
+
+        match key {
+            "name" => self.name = string(value)?,
+            "events_constants" => match value {
+                FieldValue::Array(values) => {
+                    self.events_constants = values
+                        .iter()
+                        .map(|v| match v {
+                            FieldValue::Int(v) => i32::try_from(*v)
+                                .or_else(|_| u32::try_from(*v).map(|v| v as i32))
+                                .ok(),
+                            _ => None,
+                        })
+                        .collect::<Option<_>>()
+                        .with_context(|| format!("{} has to be i32 integers", key))?
+                }
+                _ => bail!("events_constants has to be an array of integers"),
+            },
+            "passes" => match value {
+                FieldValue::Array(values) => {
+                    self.passes = values.into_iter().map(string).collect::<Result<_, _>>()?;
+                    if let Some(pass) = self
+                        .passes
+                        .iter()
+                        .find(|pass| !PASSES.contains(&pass.as_str()))
+                    {
+                        bail!("unknown pass {}, known are {}", pass, PASSES.join(", "));
+                    }
+                }
+                _ => bail!("passes has to be an array of strings"),
+            },
+            "fingerprint" => match value {
+                FieldValue::Table(features) => {
+                    self.fingerprint = features
+                        .into_iter()
+                        .map(|(name, value)| Ok((name, string(value)?)))
+                        .collect::<Result<_, anyhow::Error>>()?
+                }
+                _ => bail!("fingerprint has to be a table of strings"),
+            },
+            "table_segment" => self.table_segment = int(&value)?,
+            "data_segment" => self.data_segment = int(&value)?,
+            "table_len" => self.table_len = nonzero(&value)?,
+            "page_size" => self.page_size = nonzero(&value)?,
+            "page_header" => self.page_header = nonzero(&value)?,
+            "pages_start" => self.pages_start = int(&value)?,
+            "start_skew" => self.start_skew = int(&value)?,
+            _ => bail!("unknown profile field {}", key),
+        }
+
+        Ok(())
+    }
+
+    // Checks what can be checked on the obfuscated module
+    pub fn validate(&self, module: &Module) -> Vec<Mismatch> {
+        let mut mismatches = Vec::new();
+        let mut check = |field, reason: Option<String>| {
+            if let Some(reason) = reason {
+                mismatches.push(Mismatch { field, reason });
+            }
+        };
+
+        let segments = module.data.iter().collect::<Vec<_>>();
+        for (field, index) in [
+            ("table_segment", self.table_segment),
+            ("data_segment", self.data_segment),
+        ] {
+            let reason = match segments.get(index).map(|segment| &segment.kind) {
+                None => Some(format!("the module has {} data segments", segments.len())),
+                Some(DataKind::Active {
+                    offset: ConstExpr::Value(Value::I32(_)),
+                    ..
+                }) => None,
+                Some(_) => Some(format!("data segment {} has no constant offset", index)),
+            };
+            check(field, reason);
+        }
+
+        // the first decrypted byte has its page flag in the data segment
+        if let Some(DataKind::Active {
+            offset: ConstExpr::Value(Value::I32(start)),
+            ..
+        }) = segments.get(self.data_segment).map(|segment| &segment.kind)
+        {
+            let (start, len) = (*start as usize, segments[self.data_segment].value.len());
+            let flag = start
+                .checked_sub(start / self.page_size * self.page_header + self.page_size + self.start_skew)
+                .map(|pos| pos / self.page_size * self.page_stride() + self.pages_start);
+            check(
+                "start_skew",
+                (!flag.is_some_and(|flag| (start..start + len).contains(&flag)))
+                    .then(|| format!("data segment {} doesn't start on a page", self.data_segment)),
+            );
+        }
+
+        let events = find_events_function_with(module, &self.events_constants);
+        check(
+            "events_constants",
+            events
+                .is_none()
+                .then(|| "no function has all of them".to_string()),
+        );
+
+        let computes = |sequence: String, what: &str| {
+            let sequence = Sequence::parse(&sequence).unwrap();
+            let found = module
+                .funcs
+                .iter_local()
+                .any(|(_, func)| !sequence.find(func).is_empty());
+            (!found).then(|| format!("no function computes {}", what))
+        };
+        check(
+            "table_len",
+            computes(
+                format!("i32({}), I32RemU", self.table_len),
+                &format!("`% {}`", self.table_len),
+            ),
+        );
+        check(
+            "page_size",
+            computes(
+                format!("i32({}), I32DivU", self.page_size),
+                &format!("`/ {}`", self.page_size),
+            ),
+        );
+        check(
+            "page_header",
+            computes(
+                format!("i32({}), I32Mul", self.page_stride()),
+                &format!("`* {}`", self.page_stride()),
+            ),
+        );
+        check(
+            "pages_start",
+            computes(
+                format!("I32Mul, i32({}), I32Add", self.pages_start),
+                &format!("`page * {} + {}`", self.page_stride(), self.pages_start),
+            ),
+        );
+
+        mismatches
+    }
+}
+
+// The character after a backslash in a string, escapes TOML and JSON share plus `\/`
+// (JSON), `\U` (TOML) and JSON's surrogate pairs
+fn escape(chars: &mut impl Iterator<Item = char>) -> Result<char, anyhow::Error> {
+    let digits = match chars.next() {
+        Some('n') => return Ok('\n'),
+        Some('t') => return Ok('\t'),
+        Some('r') => return Ok('\r'),
+        Some('b') => return Ok('\u{8}'),
+        Some('f') => return Ok('\u{c}'),
+        Some(c @ ('"' | '\\' | '/')) => return Ok(c),
+        Some('u') => 4,
+        Some('U') => 8,
+        Some(c) => bail!("unknown escape \\{} in profile", c),
+        None => bail!("unterminated string in profile"),
+    };
+
+    let mut code = hex_digits(chars, digits)?;
+    if digits == 4 && (0xd800..0xdc00).contains(&code) {
+        let low = match (chars.next(), chars.next()) {
+            (Some('\\'), Some('u')) => hex_digits(chars, 4)?,
+            _ => bail!("unpaired surrogate {:x} in profile", code),
+        };
+        if !(0xdc00..0xe000).contains(&low) {
+            bail!("unpaired surrogate {:x} in profile", code);
+        }
+        code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+    }
+    char::from_u32(code).with_context(|| format!("invalid unicode escape {:x} in profile", code))
+}
+
+fn hex_digits(chars: &mut impl Iterator<Item = char>, digits: usize) -> Result<u32, anyhow::Error> {
+    let hex = chars.take(digits).collect::<String>();
+    Some(&hex)
+        .filter(|hex| hex.len() == digits && hex.chars().all(|c| c.is_ascii_hexdigit()))
+        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+        .with_context(|| format!("invalid unicode escape {:?} in profile", hex))
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(text: &str) -> Result<Parser, anyhow::Error> {
+        let mut tokens = Vec::new();
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '#' => while chars.next_if(|c| *c != '\n').is_some() {},
+                c if c.is_whitespace() => {}
+                '{' | '}' | '[' | ']' | ',' | ':' | '=' => tokens.push(c.to_string()),
+                '"' => {
+                    let mut token = String::from("\"");
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => token.push(escape(&mut chars)?),
+                            Some(c) => token.push(c),
+                            None => bail!("unterminated string in profile"),
+                        }
+                    }
+                    tokens.push(token);
+                }
+                c if c.is_alphanumeric() || c == '_' || c == '-' => {
+                    let mut token = c.to_string();
+                    while let Some(c) = chars.next_if(|c| c.is_alphanumeric() || *c == '_') {
+                        token.push(c);
+                    }
+                    tokens.push(token);
+                }
+                c => bail!("unexpected {:?} in profile", c),
+            }
+        }
+        Ok(Parser { tokens, pos: 0 })
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|token| token.as_str())
+    }
+
+    fn next(&mut self) -> Result<String, anyhow::Error> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .context("profile ends early")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        let found = self.peek() == Some(token);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), anyhow::Error> {
+        match self.next()? {
+            next if next == token => Ok(()),
+            next => bail!("expected {:?} in profile, found {:?}", token, next),
+        }
+    }
+
+    // `key = value` pairs, pairs after a `[section]` header go in its table
+    fn toml(&mut self) -> Result<Vec<(String, FieldValue)>, anyhow::Error> {
+        let mut fields = Vec::new();
+        let mut section: Option<(String, Vec<(String, FieldValue)>)> = None;
+        while self.peek().is_some() {
+            if self.eat("[") {
+                let name = self.key()?;
+                self.expect("]")?;
+                if let Some((name, table)) = section.replace((name, Vec::new())) {
+                    fields.push((name, FieldValue::Table(table)));
+                }
+                continue;
+            }
+
+            let key = self.key()?;
+            self.expect("=")?;
+            let value = self.value()?;
+            match &mut section {
+                Some((_, table)) => table.push((key, value)),
+                None => fields.push((key, value)),
+            }
+        }
+        if let Some((name, table)) = section {
+            fields.push((name, FieldValue::Table(table)));
+        }
+        Ok(fields)
+    }
+
+    // After the opening brace
+    fn object(&mut self) -> Result<Vec<(String, FieldValue)>, anyhow::Error> {
+        let mut fields = Vec::new();
+        while !self.eat("}") {
+            let key = self.key()?;
+            self.expect(":")?;
+            fields.push((key, self.value()?));
+            if !self.eat(",") {
+                self.expect("}")?;
+                break;
+            }
+        }
+        Ok(fields)
+    }
+
+    // Bare in TOML, quoted in JSON
+    fn key(&mut self) -> Result<String, anyhow::Error> {
+        let key = self.next()?;
+        Ok(key.strip_prefix('"').unwrap_or(&key).to_string())
+    }
+
+    fn int(&mut self) -> Result<i64, anyhow::Error> {
+        let token = self.next()?;
+        let (negative, digits) = match token.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, token.as_str()),
+        };
+        let digits = digits.replace('_', "");
+        let value = match digits.strip_prefix("0x") {
+            Some(hex) => i64::from_str_radix(hex, 16),
+            None => digits.parse(),
+        }
+        .with_context(|| format!("expected an integer in profile, found {:?}", token))?;
+        Ok(if negative { -value } else { value })
+    }
+
+    fn value(&mut self) -> Result<FieldValue, anyhow::Error> {
+        if let Some(string) = self.peek().and_then(|token| token.strip_prefix('"')) {
+            let string = string.to_string();
+            self.pos += 1;
+            return Ok(FieldValue::String(string));
+        }
+        if self.eat("{") {
+            return Ok(FieldValue::Table(self.object()?));
+        }
+        if !self.eat("[") {
+            return Ok(FieldValue::Int(self.int()?));
+        }
+
+        let mut values = Vec::new();
+        while !self.eat("]") {
+            values.push(self.value()?);
+            if !self.eat(",") {
+                self.expect("]")?;
+                break;
+            }
+        }
+        Ok(FieldValue::Array(values))
+    }
+}
@@ -0,0 +1,524 @@
+use crate::ssa::{
+    Edge, Function, InstId, Op, RegionId, RegionKind, ValueId, is_simd, load_type, op_type,
+    with_stack,
+};
+use anyhow::bail;
+use std::collections::HashMap;
+use walrus::ir::{
+    AtomicWidth, Instr, InstrLocId, InstrSeqId, InstrSeqType, Value, Visitor, dfs_in_order,
+};
+use walrus::{LocalFunction, LocalId, Module, RefType, ValType};
+
+#[derive(Clone, Copy)]
+struct Label {
+    region: RegionId,
+    arity: usize,
+}
+
+// Locals and stack at the end of a region that falls through
+type State = (Vec<ValueId>, Vec<ValueId>);
+
+struct Builder<'a> {
+    module: &'a Module,
+    func: &'a LocalFunction,
+    ssa: Function,
+    // position of every local in the environments
+    locals: HashMap<LocalId, usize>,
+    local_types: Vec<ValType>,
+    labels: HashMap<InstrSeqId, Label>,
+    // loop phis, one per local, branches back add an argument to each
+    loop_phis: HashMap<RegionId, Vec<InstId>>,
+    // locals and values brought by the edges into a block or `if` not merged yet
+    incoming: HashMap<RegionId, Vec<State>>,
+}
+
+#[derive(Default)]
+struct Locals {
+    order: Vec<LocalId>,
+}
+
+impl<'a> Visitor<'a> for Locals {
+    fn visit_local_id(&mut self, local: &LocalId) {
+        if !self.order.contains(local) {
+            self.order.push(*local);
+        }
+    }
+}
+
+fn pop(stack: &mut Vec<ValueId>) -> Result<ValueId, anyhow::Error> {
+    match stack.pop() {
+        Some(value) => Ok(value),
+        None => bail!("stack underflow"),
+    }
+}
+
+fn pop_n(stack: &mut Vec<ValueId>, n: usize) -> Result<Vec<ValueId>, anyhow::Error> {
+    if stack.len() < n {
+        bail!("stack underflow");
+    }
+    Ok(stack.split_off(stack.len() - n))
+}
+
+fn atomic_type(width: AtomicWidth) -> ValType {
+    match width {
+        AtomicWidth::I32 | AtomicWidth::I32_8 | AtomicWidth::I32_16 => ValType::I32,
+        _ => ValType::I64,
+    }
+}
+
+impl Builder<'_> {
+    fn block_results(&self, seq: InstrSeqId) -> Result<Vec<ValType>, anyhow::Error> {
+        match self.func.block(seq).ty {
+            InstrSeqType::Simple(ty) => Ok(ty.into_iter().collect()),
+            InstrSeqType::MultiValue(ty) => {
+                let ty = self.module.types.get(ty);
+                if !ty.params().is_empty() {
+                    bail!("blocks with parameters are not supported");
+                }
+                Ok(ty.results().to_vec())
+            }
+        }
+    }
+
+    // Values an instruction pops and the types of those it pushes
+    fn effects(
+        &self,
+        instr: &Instr,
+        stack: &[ValueId],
+    ) -> Result<(usize, Vec<ValType>), anyhow::Error> {
+        let module = self.module;
+        let call = |ty| {
+            let ty = module.types.get(ty);
+            (ty.params().len(), ty.results().to_vec())
+        };
+
+        Ok(match instr {
+            Instr::Call(call_) => call(module.funcs.get(call_.func).ty()),
+            Instr::ReturnCall(call_) => (call(module.funcs.get(call_.func).ty()).0, vec![]),
+            Instr::CallIndirect(call_) => {
+                let (params, results) = call(call_.ty);
+                (params + 1, results)
+            }
+            Instr::ReturnCallIndirect(call_) => (call(call_.ty).0 + 1, vec![]),
+            Instr::GlobalGet(get) => (0, vec![module.globals.get(get.global).ty]),
+            Instr::GlobalSet(_) => (1, vec![]),
+            Instr::Binop(op) if !is_simd(&format!("{:?}", op.op)) => {
+                (2, vec![op_type(&format!("{:?}", op.op))])
+            }
+            Instr::Unop(op) if !is_simd(&format!("{:?}", op.op)) => {
+                (1, vec![op_type(&format!("{:?}", op.op))])
+            }
+            Instr::Select(select) => {
+                let ty = match (select.ty, stack.len()) {
+                    (Some(ty), _) => ty,
+                    (None, len) if len >= 3 => self.ssa.ty(stack[len - 3]),
+                    _ => bail!("stack underflow"),
+                };
+                (3, vec![ty])
+            }
+            Instr::Unreachable(_) => (0, vec![]),
+            Instr::MemorySize(_) => (0, vec![ValType::I32]),
+            Instr::MemoryGrow(_) => (1, vec![ValType::I32]),
+            Instr::MemoryInit(_) | Instr::MemoryCopy(_) | Instr::MemoryFill(_) => (3, vec![]),
+            Instr::DataDrop(_) | Instr::ElemDrop(_) | Instr::AtomicFence(_) => (0, vec![]),
+            Instr::Load(load) if !matches!(load.kind, walrus::ir::LoadKind::V128) => {
+                (1, vec![load_type(&load.kind)])
+            }
+            Instr::Store(store) if !matches!(store.kind, walrus::ir::StoreKind::V128) => {
+                (2, vec![])
+            }
+            Instr::AtomicRmw(rmw) => (2, vec![atomic_type(rmw.width)]),
+            Instr::Cmpxchg(cmpxchg) => (3, vec![atomic_type(cmpxchg.width)]),
+            Instr::AtomicNotify(_) => (2, vec![ValType::I32]),
+            Instr::AtomicWait(_) => (3, vec![ValType::I32]),
+            Instr::TableGet(get) => (
+                1,
+                vec![ValType::Ref(module.tables.get(get.table).element_ty)],
+            ),
+            Instr::TableSet(_) => (2, vec![]),
+            Instr::TableGrow(_) => (2, vec![ValType::I32]),
+            Instr::TableSize(_) => (0, vec![ValType::I32]),
+            Instr::TableFill(_) | Instr::TableInit(_) | Instr::TableCopy(_) => (3, vec![]),
+            Instr::RefNull(null) => (0, vec![ValType::Ref(null.ty)]),
+            Instr::RefIsNull(_) => (1, vec![ValType::I32]),
+            Instr::RefFunc(_) => (0, vec![ValType::Ref(RefType::Funcref)]),
+            instr => bail!("unsupported instruction {:?}", instr),
+        })
+    }
+
+    // Records `edge` into the merge of `target`, with the locals and values it brings
+    fn edge(&mut self, target: RegionId, edge: Edge, env: &[ValueId], values: Vec<ValueId>) {
+        let preds = &mut self.ssa.regions[target.0].preds;
+        // a `br_table` naming a target twice is one edge
+        if preds.contains(&edge) {
+            return;
+        }
+        preds.push(edge);
+
+        match self.loop_phis.get(&target).cloned() {
+            Some(phis) => {
+                for (phi, value) in phis.into_iter().zip(env.iter()) {
+                    let mut args = self.ssa.inst(phi).args.clone();
+                    args.push(*value);
+                    self.ssa.set_args(phi, args);
+                }
+            }
+            None => self
+                .incoming
+                .entry(target)
+                .or_default()
+                .push((env.to_vec(), values)),
+        }
+    }
+
+    // Locals and values after the block or `if` of `target`, phis go at the end of
+    // `region`. `None` when nothing gets there.
+    fn merge(
+        &mut self,
+        target: RegionId,
+        region: RegionId,
+        results: &[ValType],
+        loc: InstrLocId,
+    ) -> Option<State> {
+        let incoming = self.incoming.remove(&target)?;
+        let local_types = self.local_types.clone();
+
+        let mut merge =
+            |values: Vec<ValueId>, ty: ValType| match values.iter().all(|v| *v == values[0]) {
+                true => values[0],
+                false => {
+                    let phi = self.ssa.append(region, Op::Phi(target), values, &[ty], loc);
+                    self.ssa.inst(phi).results[0]
+                }
+            };
+
+        let env = (0..local_types.len())
+            .map(|i| {
+                merge(
+                    incoming.iter().map(|(env, _)| env[i]).collect(),
+                    local_types[i],
+                )
+            })
+            .collect();
+        let values = (0..results.len())
+            .map(|i| {
+                merge(
+                    incoming.iter().map(|(_, values)| values[i]).collect(),
+                    results[i],
+                )
+            })
+            .collect();
+        Some((env, values))
+    }
+
+    // Marks the end of `region` unreachable after a block, loop or `if` nothing leaves, the
+    // lowered code needs it to validate
+    fn dead_end(&mut self, region: RegionId, loc: InstrLocId) -> Option<State> {
+        let op = Op::Instr(Instr::Unreachable(walrus::ir::Unreachable {}));
+        self.ssa.append(region, op, vec![], &[], loc);
+        None
+    }
+
+    // Builds `seq` into `region`, returns the locals and stack at its end if it falls through
+    fn seq(
+        &mut self,
+        seq: InstrSeqId,
+        region: RegionId,
+        mut env: Vec<ValueId>,
+    ) -> Result<Option<State>, anyhow::Error> {
+        let func = self.func;
+        let mut stack = Vec::new();
+
+        for (instr, loc) in func.block(seq).instrs.iter() {
+            let loc = *loc;
+            match instr {
+                Instr::Const(c) => {
+                    let ty = match c.value {
+                        Value::I32(_) => ValType::I32,
+                        Value::I64(_) => ValType::I64,
+                        Value::F32(_) => ValType::F32,
+                        Value::F64(_) => ValType::F64,
+                        Value::V128(_) => ValType::V128,
+                    };
+                    let inst = self
+                        .ssa
+                        .append(region, Op::Const(c.value), vec![], &[ty], loc);
+                    stack.push(self.ssa.inst(inst).results[0]);
+                }
+                Instr::LocalGet(get) => stack.push(env[self.locals[&get.local]]),
+                Instr::LocalSet(set) => env[self.locals[&set.local]] = pop(&mut stack)?,
+                Instr::LocalTee(tee) => {
+                    let value = pop(&mut stack)?;
+                    env[self.locals[&tee.local]] = value;
+                    stack.push(value);
+                }
+                Instr::Drop(_) => {
+                    pop(&mut stack)?;
+                }
+                Instr::Block(block) => {
+                    let results = self.block_results(block.seq)?;
+                    let inner = self.ssa.add_region(RegionKind::Block, region);
+                    self.ssa.append(region, Op::Block(inner), vec![], &[], loc);
+                    self.labels.insert(
+                        block.seq,
+                        Label {
+                            region: inner,
+                            arity: results.len(),
+                        },
+                    );
+
+                    if let Some((end, mut values)) = self.seq(block.seq, inner, env.clone())? {
+                        let values = pop_n(&mut values, results.len())?;
+                        self.edge(inner, Edge::End(inner), &end, values);
+                    }
+                    let Some((merged, values)) = self.merge(inner, region, &results, loc) else {
+                        return Ok(self.dead_end(region, loc));
+                    };
+                    env = merged;
+                    stack.extend(values);
+                }
+                Instr::Loop(block) => {
+                    let results = self.block_results(block.seq)?;
+                    let inner = self.ssa.add_region(RegionKind::Loop, region);
+                    let inst = self.ssa.append(region, Op::Loop(inner), vec![], &[], loc);
+                    self.labels.insert(
+                        block.seq,
+                        Label {
+                            region: inner,
+                            arity: 0,
+                        },
+                    );
+
+                    // every local gets a phi, those merging a single value are dropped later
+                    let phis = self
+                        .local_types
+                        .clone()
+                        .into_iter()
+                        .map(|ty| self.ssa.append(inner, Op::Phi(inner), vec![], &[ty], loc))
+                        .collect::<Vec<_>>();
+                    let start = phis
+                        .iter()
+                        .map(|phi| self.ssa.inst(*phi).results[0])
+                        .collect();
+                    self.loop_phis.insert(inner, phis);
+                    self.edge(inner, Edge::From(inst), &env, vec![]);
+
+                    let Some((end, mut values)) = self.seq(block.seq, inner, start)? else {
+                        return Ok(self.dead_end(region, loc));
+                    };
+                    env = end;
+                    stack.extend(pop_n(&mut values, results.len())?);
+                }
+                Instr::IfElse(if_else) => {
+                    let cond = pop(&mut stack)?;
+                    let results = self.block_results(if_else.consequent)?;
+                    let then = self.ssa.add_region(RegionKind::Then, region);
+                    let otherwise = self.ssa.add_region(RegionKind::Else, region);
+                    self.ssa
+                        .append(region, Op::If(then, otherwise), vec![cond], &[], loc);
+                    let label = Label {
+                        region: then,
+                        arity: results.len(),
+                    };
+                    self.labels.insert(if_else.consequent, label);
+                    self.labels.insert(if_else.alternative, label);
+
+                    for (seq, arm) in [(if_else.consequent, then), (if_else.alternative, otherwise)]
+                    {
+                        if let Some((end, mut values)) = self.seq(seq, arm, env.clone())? {
+                            let values = pop_n(&mut values, results.len())?;
+                            self.edge(then, Edge::End(arm), &end, values);
+                        }
+                    }
+                    let Some((merged, values)) = self.merge(then, region, &results, loc) else {
+                        return Ok(self.dead_end(region, loc));
+                    };
+                    env = merged;
+                    stack.extend(values);
+                }
+                Instr::Br(br) => {
+                    let label = self.labels[&br.block];
+                    let values = pop_n(&mut stack, label.arity)?;
+                    if label.region == Function::BODY {
+                        self.ssa.append(region, Op::Return, values, &[], loc);
+                    } else {
+                        let inst =
+                            self.ssa
+                                .append(region, Op::Br(label.region), values.clone(), &[], loc);
+                        self.edge(label.region, Edge::From(inst), &env, values);
+                    }
+                    return Ok(None);
+                }
+                Instr::BrIf(br) => {
+                    let label = self.labels[&br.block];
+                    let cond = pop(&mut stack)?;
+                    let values = pop_n(&mut stack.clone(), label.arity)?;
+                    let args = values.iter().copied().chain([cond]).collect();
+                    let inst = self
+                        .ssa
+                        .append(region, Op::BrIf(label.region), args, &[], loc);
+                    if label.region != Function::BODY {
+                        self.edge(label.region, Edge::From(inst), &env, values);
+                    }
+                }
+                Instr::BrTable(table) => {
+                    let index = pop(&mut stack)?;
+                    let default = self.labels[&table.default];
+                    let values = pop_n(&mut stack, default.arity)?;
+                    let targets = table
+                        .blocks
+                        .iter()
+                        .map(|seq| self.labels[seq].region)
+                        .collect::<Vec<_>>();
+
+                    let args = values.iter().copied().chain([index]).collect();
+                    let op = Op::BrTable(targets.clone(), default.region);
+                    let inst = self.ssa.append(region, op, args, &[], loc);
+                    for target in targets.into_iter().chain([default.region]) {
+                        if target != Function::BODY {
+                            self.edge(target, Edge::From(inst), &env, values.clone());
+                        }
+                    }
+                    return Ok(None);
+                }
+                Instr::Return(_) => {
+                    let values = pop_n(&mut stack, self.ssa.results.len())?;
+                    self.ssa.append(region, Op::Return, values, &[], loc);
+                    return Ok(None);
+                }
+                instr => {
+                    let (pops, results) = self.effects(instr, &stack)?;
+                    let args = pop_n(&mut stack, pops)?;
+                    let op = Op::Instr(instr.clone());
+                    let diverges = op.diverges();
+                    let inst = self.ssa.append(region, op, args, &results, loc);
+                    stack.extend(self.ssa.inst(inst).results.iter().copied());
+                    if diverges {
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+
+        Ok(Some((env, stack)))
+    }
+}
+
+impl Function {
+    pub fn build(module: &Module, func: &LocalFunction) -> Result<Self, anyhow::Error> {
+        with_stack(|| Function::build_inner(module, func))
+    }
+
+    fn build_inner(module: &Module, func: &LocalFunction) -> Result<Self, anyhow::Error> {
+        let ty = module.types.get(func.ty());
+        let ssa = Function::new(
+            ty.params().to_vec(),
+            ty.results().to_vec(),
+            func.args.clone(),
+        );
+
+        let mut visitor = Locals::default();
+        dfs_in_order(&mut visitor, func, func.entry_block());
+        let mut order = func.args.clone();
+        order.extend(
+            visitor
+                .order
+                .into_iter()
+                .filter(|local| !func.args.contains(local)),
+        );
+
+        let mut builder = Builder {
+            module,
+            func,
+            ssa,
+            locals: order
+                .iter()
+                .enumerate()
+                .map(|(i, local)| (*local, i))
+                .collect(),
+            local_types: order
+                .iter()
+                .map(|local| module.locals.get(*local).ty())
+                .collect(),
+            labels: HashMap::from([(
+                func.entry_block(),
+                Label {
+                    region: Function::BODY,
+                    arity: ty.results().len(),
+                },
+            )]),
+            loop_phis: HashMap::new(),
+            incoming: HashMap::new(),
+        };
+
+        // parameters, then the other locals start out zeroed
+        let loc = InstrLocId::default();
+        let env = order
+            .iter()
+            .enumerate()
+            .map(|(i, local)| {
+                let ty = module.locals.get(*local).ty();
+                let op = match ty {
+                    _ if i < func.args.len() => Op::Param(i),
+                    ValType::I32 => Op::Const(Value::I32(0)),
+                    ValType::I64 => Op::Const(Value::I64(0)),
+                    ValType::F32 => Op::Const(Value::F32(0.0)),
+                    ValType::F64 => Op::Const(Value::F64(0.0)),
+                    ValType::V128 => Op::Const(Value::V128(0)),
+                    ValType::Ref(ty) => Op::Instr(Instr::RefNull(walrus::ir::RefNull { ty })),
+                };
+                let inst = builder.ssa.append(Function::BODY, op, vec![], &[ty], loc);
+                builder.ssa.inst(inst).results[0]
+            })
+            .collect();
+
+        if let Some((_, mut stack)) = builder.seq(func.entry_block(), Function::BODY, env)? {
+            let values = pop_n(&mut stack, ty.results().len())?;
+            builder
+                .ssa
+                .append(Function::BODY, Op::Return, values, &[], loc);
+        }
+
+        let mut ssa = builder.ssa;
+        ssa.cleanup();
+        Ok(ssa)
+    }
+
+    // Drops phis merging a single value and the phis, constants and null references
+    // nothing uses
+    pub fn cleanup(&mut self) {
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for inst in self.walk() {
+                let data = &self.insts[inst.0];
+                let removable = match &data.op {
+                    Op::Phi(_) => {
+                        let result = data.results[0];
+                        let mut incoming = data.args.iter().filter(|arg| **arg != result);
+                        let first = incoming.next().copied();
+                        let trivial = first.filter(|first| incoming.all(|arg| arg == first));
+                        if let Some(value) = trivial {
+                            self.set_args(inst, Vec::new());
+                            self.replace_uses(result, value);
+                        }
+                        true
+                    }
+                    Op::Const(_) | Op::Instr(Instr::RefNull(_)) => true,
+                    _ => false,
+                };
+
+                let data = &self.insts[inst.0];
+                if removable
+                    && data
+                        .results
+                        .iter()
+                        .all(|r| self.values[r.0].uses.is_empty())
+                {
+                    self.remove(inst);
+                    changed = true;
+                }
+            }
+        }
+    }
+}
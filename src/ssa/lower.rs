@@ -0,0 +1,379 @@
+use crate::ssa::{Edge, Function, InstId, Op, RegionId, RegionKind, ValueId, with_stack};
+use std::collections::{HashMap, HashSet};
+use walrus::ir::{
+    Block, Br, BrIf, BrTable, Const, IfElse, Instr, InstrLocId, InstrSeqId, LocalGet, LocalSet,
+    Loop, Return,
+};
+use walrus::{FunctionBuilder, FunctionId, FunctionKind, LocalId, Module, ModuleLocals};
+
+struct Lowerer<'a> {
+    ssa: &'a Function,
+    module_locals: &'a mut ModuleLocals,
+    builder: FunctionBuilder,
+    locals: HashMap<ValueId, LocalId>,
+    // values left on the stack for their only user instead of going through a local
+    inline: HashSet<ValueId>,
+    // what a branch to a region goes to, the `if` arm being lowered for an `if`
+    seqs: HashMap<RegionId, InstrSeqId>,
+    phis: HashMap<RegionId, Vec<InstId>>,
+}
+
+impl Function {
+    // Arguments an instruction takes from the stack when lowered, the rest is copied to
+    // the phis of the target
+    fn stack_operands(&self, inst: InstId) -> &[ValueId] {
+        let inst = self.inst(inst);
+        match inst.op {
+            Op::Instr(_) | Op::Return | Op::If(..) => &inst.args,
+            Op::BrIf(_) | Op::BrTable(..) => &inst.args[inst.args.len() - 1..],
+            _ => &[],
+        }
+    }
+
+    // Replaces the body of `id` with this function
+    pub fn lower(&self, module: &mut Module, id: FunctionId) {
+        with_stack(|| self.lower_inner(module, id))
+    }
+
+    fn lower_inner(&self, module: &mut Module, id: FunctionId) {
+        let builder = FunctionBuilder::new(&mut module.types, &self.params, &self.results);
+
+        let mut phis = HashMap::<RegionId, Vec<InstId>>::new();
+        for inst in self.walk() {
+            if let Op::Phi(region) = self.inst(inst).op {
+                phis.entry(region).or_default().push(inst);
+            }
+        }
+
+        let mut lowerer = Lowerer {
+            ssa: self,
+            module_locals: &mut module.locals,
+            seqs: HashMap::from([(Function::BODY, builder.func_body_id())]),
+            builder,
+            locals: HashMap::new(),
+            inline: HashSet::new(),
+            phis,
+        };
+        lowerer.find_inline(Function::BODY);
+        let body = lowerer.builder.func_body_id();
+        lowerer.region(Function::BODY, body);
+
+        let func = lowerer.builder.local_func(self.args.clone());
+        module.funcs.get_mut(id).kind = FunctionKind::Local(func);
+    }
+}
+
+impl Lowerer<'_> {
+    // Whether `value` can stay on the stack until its user: used once, as a stack operand,
+    // in the same region
+    fn candidate(&self, value: ValueId) -> bool {
+        let ssa = self.ssa;
+        let def = ssa.inst(ssa.def(value));
+        match ssa.uses(value) {
+            [user] => {
+                !matches!(def.op, Op::Phi(_) | Op::Param(_))
+                    && def.results.len() == 1
+                    && ssa.inst(*user).region == def.region
+                    && ssa.stack_operands(*user).contains(&value)
+            }
+            _ => false,
+        }
+    }
+
+    // Simulates the stack of `region`: a value is inlined when it is still on top when
+    // its user comes, along with the other operands pushed before it
+    fn find_inline(&mut self, region: RegionId) {
+        let ssa = self.ssa;
+        let mut stack = Vec::<ValueId>::new();
+
+        for inst in ssa.region(region).insts.iter() {
+            let operands = ssa.stack_operands(*inst);
+            let matched = (0..=operands.len().min(stack.len()))
+                .rev()
+                .find(|n| stack[stack.len() - n..] == operands[..*n])
+                .unwrap_or(0);
+            stack.truncate(stack.len() - matched);
+            self.inline.extend(operands[..matched].iter().copied());
+            // the others of this user are too deep, they go through locals
+            stack.retain(|value| !operands.contains(value));
+
+            match ssa.inst(*inst).op {
+                Op::Block(inner) | Op::Loop(inner) => self.find_inline(inner),
+                Op::If(then, otherwise) => {
+                    self.find_inline(then);
+                    self.find_inline(otherwise);
+                }
+                _ => {}
+            }
+            stack.extend(
+                ssa.inst(*inst)
+                    .results
+                    .iter()
+                    .filter(|value| self.candidate(**value)),
+            );
+        }
+    }
+
+    fn local(&mut self, value: ValueId) -> LocalId {
+        if let Op::Param(i) = self.ssa.inst(self.ssa.def(value)).op {
+            return self.ssa.args[i];
+        }
+        let ty = self.ssa.ty(value);
+        *self
+            .locals
+            .entry(value)
+            .or_insert_with(|| self.module_locals.add(ty))
+    }
+
+    fn push(&mut self, seq: InstrSeqId, instr: Instr, loc: InstrLocId) {
+        self.builder.instr_seq(seq).instrs_mut().push((instr, loc));
+    }
+
+    // Constants are rematerialized, other values read from their local
+    fn get(&mut self, seq: InstrSeqId, value: ValueId) {
+        let instr = match self.ssa.constant(value) {
+            Some(value) => Instr::Const(Const { value }),
+            None => Instr::LocalGet(LocalGet {
+                local: self.local(value),
+            }),
+        };
+        self.push(seq, instr, InstrLocId::default());
+    }
+
+    fn set(&mut self, seq: InstrSeqId, value: ValueId) {
+        let local = self.local(value);
+        self.push(
+            seq,
+            Instr::LocalSet(LocalSet { local }),
+            InstrLocId::default(),
+        );
+    }
+
+    // Results of `inst` on the stack go to their locals, or are dropped if unused
+    fn results(&mut self, seq: InstrSeqId, inst: InstId) {
+        let results = &self.ssa.inst(inst).results;
+        if let [value] = results[..]
+            && self.inline.contains(&value)
+        {
+            return;
+        }
+        for value in results.iter().rev() {
+            match self.ssa.uses(*value).is_empty() {
+                true => self.push(seq, Instr::Drop(walrus::ir::Drop {}), InstrLocId::default()),
+                false => self.set(seq, *value),
+            }
+        }
+    }
+
+    // Phi assignments for `edge` into `target`, as pairs of phi and incoming value
+    fn copies(&self, target: RegionId, edge: Edge) -> Vec<(ValueId, ValueId)> {
+        let ssa = self.ssa;
+        let Some(index) = ssa
+            .region(target)
+            .preds
+            .iter()
+            .position(|pred| *pred == edge)
+        else {
+            return Vec::new();
+        };
+        let phis = self.phis.get(&target).map_or(&[][..], |phis| &phis[..]);
+        phis.iter()
+            .map(|phi| (ssa.inst(*phi).results[0], ssa.inst(*phi).args[index]))
+            .filter(|(phi, value)| phi != value)
+            .collect()
+    }
+
+    // All incoming values are read before any phi is written, a phi may be the incoming
+    // value of another
+    fn emit_copies(&mut self, seq: InstrSeqId, copies: &[(ValueId, ValueId)]) {
+        for (_, value) in copies.iter() {
+            self.get(seq, *value);
+        }
+        for (phi, _) in copies.iter().rev() {
+            self.set(seq, *phi);
+        }
+    }
+
+    // Leaves for `target`: copies then a branch, or a return with the values
+    fn jump(&mut self, seq: InstrSeqId, target: RegionId, edge: Edge, values: &[ValueId]) {
+        if target == Function::BODY {
+            for value in values.iter() {
+                self.get(seq, *value);
+            }
+            self.push(seq, Instr::Return(Return {}), InstrLocId::default());
+            return;
+        }
+        let copies = self.copies(target, edge);
+        self.emit_copies(seq, &copies);
+        let block = self.seqs[&target];
+        self.push(seq, Instr::Br(Br { block }), InstrLocId::default());
+    }
+
+    fn region(&mut self, region: RegionId, seq: InstrSeqId) {
+        let ssa = self.ssa;
+        let mut falls_through = true;
+
+        for inst in ssa.region(region).insts.iter() {
+            let data = ssa.inst(*inst);
+            let loc = data.loc;
+            for value in ssa.stack_operands(*inst) {
+                if !self.inline.contains(value) {
+                    self.get(seq, *value);
+                }
+            }
+
+            match &data.op {
+                Op::Param(_) | Op::Phi(_) => {}
+                Op::Const(value) => {
+                    if self.inline.contains(&data.results[0]) {
+                        self.push(seq, Instr::Const(Const { value: *value }), loc);
+                    }
+                }
+                Op::Instr(instr) => {
+                    self.push(seq, instr.clone(), loc);
+                    self.results(seq, *inst);
+                }
+                Op::Block(inner) => {
+                    let block = self.builder.dangling_instr_seq(None).id();
+                    self.seqs.insert(*inner, block);
+                    self.region(*inner, block);
+                    self.push(seq, Instr::Block(Block { seq: block }), loc);
+                }
+                Op::Loop(inner) => {
+                    let copies = self.copies(*inner, Edge::From(*inst));
+                    self.emit_copies(seq, &copies);
+                    let body = self.builder.dangling_instr_seq(None).id();
+                    self.seqs.insert(*inner, body);
+                    self.region(*inner, body);
+                    self.push(seq, Instr::Loop(Loop { seq: body }), loc);
+                }
+                Op::If(then, otherwise) => {
+                    let consequent = self.builder.dangling_instr_seq(None).id();
+                    let alternative = self.builder.dangling_instr_seq(None).id();
+                    self.seqs.insert(*then, consequent);
+                    self.region(*then, consequent);
+                    self.seqs.insert(*then, alternative);
+                    self.region(*otherwise, alternative);
+                    let instr = Instr::IfElse(IfElse {
+                        consequent,
+                        alternative,
+                    });
+                    self.push(seq, instr, loc);
+                }
+                Op::Br(target) => self.jump(seq, *target, Edge::From(*inst), &data.args),
+                Op::BrIf(target) => {
+                    let values = &data.args[..data.args.len() - 1];
+                    let copies = self.copies(*target, Edge::From(*inst));
+                    if *target != Function::BODY && copies.is_empty() {
+                        let block = self.seqs[target];
+                        self.push(seq, Instr::BrIf(BrIf { block }), loc);
+                    } else {
+                        // only taken branches pass values along
+                        let consequent = self.builder.dangling_instr_seq(None).id();
+                        let alternative = self.builder.dangling_instr_seq(None).id();
+                        self.jump(consequent, *target, Edge::From(*inst), values);
+                        let instr = Instr::IfElse(IfElse {
+                            consequent,
+                            alternative,
+                        });
+                        self.push(seq, instr, loc);
+                    }
+                }
+                Op::BrTable(targets, default) => self.br_table(seq, *inst, targets, *default),
+                Op::Return => self.push(seq, Instr::Return(Return {}), loc),
+            }
+
+            falls_through = !data.op.diverges();
+        }
+
+        if !falls_through {
+            return;
+        }
+        let merge = match ssa.region(region).kind {
+            RegionKind::Block => region,
+            RegionKind::Then | RegionKind::Else => {
+                let parent = ssa.region(region).parent.unwrap();
+                let owner =
+                    ssa.region(parent)
+                        .insts
+                        .iter()
+                        .find_map(|inst| match ssa.inst(*inst).op {
+                            Op::If(then, otherwise) if then == region || otherwise == region => {
+                                Some(then)
+                            }
+                            _ => None,
+                        });
+                owner.unwrap()
+            }
+            RegionKind::Loop | RegionKind::Body => return,
+        };
+        let copies = self.copies(merge, Edge::End(region));
+        self.emit_copies(seq, &copies);
+    }
+
+    // Targets needing copies or values get a block of their own to land in first:
+    //   block $t1 (block $t0 (local.get $index, br_table $t0 $t1 ..)) copies, br target0) copies, br target1
+    fn br_table(&mut self, seq: InstrSeqId, inst: InstId, targets: &[RegionId], default: RegionId) {
+        let data = self.ssa.inst(inst);
+        let values = &data.args[..data.args.len() - 1];
+        let edge = Edge::From(inst);
+
+        let mut distinct = Vec::new();
+        for target in targets.iter().chain([&default]) {
+            if !distinct.contains(target) {
+                distinct.push(*target);
+            }
+        }
+        let direct = distinct
+            .iter()
+            .all(|target| match *target == Function::BODY {
+                true => values.is_empty(),
+                false => self.copies(*target, edge).is_empty(),
+            });
+        if direct {
+            let blocks = targets.iter().map(|target| self.seqs[target]).collect();
+            let instr = Instr::BrTable(BrTable {
+                blocks,
+                default: self.seqs[&default],
+            });
+            self.push(seq, instr, data.loc);
+            return;
+        }
+
+        let index = self.module_locals.add(walrus::ValType::I32);
+        self.push(
+            seq,
+            Instr::LocalSet(LocalSet { local: index }),
+            InstrLocId::default(),
+        );
+        let landings = distinct
+            .iter()
+            .map(|_| self.builder.dangling_instr_seq(None).id())
+            .collect::<Vec<_>>();
+        let landing =
+            |target: &RegionId| landings[distinct.iter().position(|t| t == target).unwrap()];
+
+        let inner = landings[0];
+        self.push(
+            inner,
+            Instr::LocalGet(LocalGet { local: index }),
+            InstrLocId::default(),
+        );
+        let instr = Instr::BrTable(BrTable {
+            blocks: targets.iter().map(landing).collect(),
+            default: landing(&default),
+        });
+        self.push(inner, instr, data.loc);
+
+        // each landing block sits in the next one, the last in `seq`
+        for (i, target) in distinct.iter().enumerate() {
+            let outer = landings.get(i + 1).copied().unwrap_or(seq);
+            self.push(
+                outer,
+                Instr::Block(Block { seq: landings[i] }),
+                InstrLocId::default(),
+            );
+            self.jump(outer, *target, edge, values);
+        }
+    }
+}
@@ -0,0 +1,448 @@
+pub mod build;
+pub mod lower;
+
+use walrus::ir::{Instr, InstrLocId, LoadKind, Value};
+use walrus::{LocalId, ValType};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ValueId(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct InstId(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RegionId(usize);
+
+impl ValueId {
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+impl InstId {
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+impl RegionId {
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Op {
+    // entry value of the `n`th parameter
+    Param(usize),
+    Const(Value),
+    // value at the start of a loop or after a block/`if` reached from several edges, one
+    // argument per edge of `Region::preds`
+    Phi(RegionId),
+    // any other walrus instruction, the arguments are its stack operands
+    Instr(Instr),
+    Block(RegionId),
+    Loop(RegionId),
+    // condition argument; then and else arm, the merge after it is the then arm's
+    If(RegionId, RegionId),
+    // branches take the values passed along, then the condition or index
+    Br(RegionId),
+    BrIf(RegionId),
+    BrTable(Vec<RegionId>, RegionId),
+    // arguments are the results
+    Return,
+}
+
+impl Op {
+    // Control flow never goes past it
+    pub fn diverges(&self) -> bool {
+        matches!(
+            self,
+            Op::Br(_)
+                | Op::BrTable(..)
+                | Op::Return
+                | Op::Instr(
+                    Instr::Unreachable(_) | Instr::ReturnCall(_) | Instr::ReturnCallIndirect(_)
+                )
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Inst {
+    op: Op,
+    args: Vec<ValueId>,
+    results: Vec<ValueId>,
+    region: RegionId,
+    loc: InstrLocId,
+}
+
+impl Inst {
+    pub fn op(&self) -> &Op {
+        &self.op
+    }
+
+    pub fn args(&self) -> &[ValueId] {
+        &self.args
+    }
+
+    pub fn results(&self) -> &[ValueId] {
+        &self.results
+    }
+
+    pub fn region(&self) -> RegionId {
+        self.region
+    }
+
+    // Where the instruction was in the original code
+    pub fn loc(&self) -> InstrLocId {
+        self.loc
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ValueData {
+    ty: ValType,
+    def: InstId,
+    // an instruction using it twice shows up twice
+    uses: Vec<InstId>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Body,
+    Block,
+    Loop,
+    Then,
+    Else,
+}
+
+// How control gets to a merge
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Edge {
+    // a region falling off its end: a block body or an `if` arm
+    End(RegionId),
+    // a branch, or the `Loop` instruction entering its loop
+    From(InstId),
+}
+
+#[derive(Debug, Clone)]
+pub struct Region {
+    kind: RegionKind,
+    parent: Option<RegionId>,
+    insts: Vec<InstId>,
+    // edges into the merge of this region, in the order of the phi arguments
+    preds: Vec<Edge>,
+}
+
+impl Region {
+    pub fn kind(&self) -> RegionKind {
+        self.kind
+    }
+
+    pub fn parent(&self) -> Option<RegionId> {
+        self.parent
+    }
+
+    pub fn insts(&self) -> &[InstId] {
+        &self.insts
+    }
+
+    pub fn preds(&self) -> &[Edge] {
+        &self.preds
+    }
+}
+
+// A function in SSA form. Locals are gone, every value is defined once by an instruction
+// and knows its users. Control flow keeps the structure of the wasm code: regions are the
+// bodies of blocks, loops and `if` arms, and a value reaching a merge from several edges
+// goes through a phi (after the block or `if`, at the top of the loop).
+#[derive(Debug, Clone)]
+pub struct Function {
+    params: Vec<ValType>,
+    results: Vec<ValType>,
+    // locals of the parameters, kept when lowering
+    args: Vec<LocalId>,
+    values: Vec<ValueData>,
+    insts: Vec<Inst>,
+    regions: Vec<Region>,
+}
+
+// Result type of an operator, walrus names them after it (`I64ExtendUI32` is an i64)
+// except for comparisons which are all i32
+pub fn op_type(name: &str) -> ValType {
+    if is_simd(name) {
+        return ValType::V128;
+    }
+
+    let op = name.get(3..).unwrap_or_default();
+    let comparison = [
+        "Eqz", "Eq", "Ne", "Lt", "LtS", "LtU", "Gt", "GtS", "GtU", "Le", "LeS", "LeU", "Ge", "GeS",
+        "GeU",
+    ]
+    .contains(&op);
+    match name.get(..3) {
+        _ if comparison => ValType::I32,
+        Some("I64") => ValType::I64,
+        Some("F32") => ValType::F32,
+        Some("F64") => ValType::F64,
+        _ => ValType::I32,
+    }
+}
+
+// Vector operators are named after their lanes (`I8x16Add`) or start with `V128`
+pub fn is_simd(name: &str) -> bool {
+    name.starts_with("V128")
+        || ["x16", "x8", "x4", "x2"]
+            .iter()
+            .any(|lanes| name.contains(lanes))
+}
+
+pub fn load_type(kind: &LoadKind) -> ValType {
+    match kind {
+        LoadKind::I32 { .. } | LoadKind::I32_8 { .. } | LoadKind::I32_16 { .. } => ValType::I32,
+        LoadKind::I64 { .. }
+        | LoadKind::I64_8 { .. }
+        | LoadKind::I64_16 { .. }
+        | LoadKind::I64_32 { .. } => ValType::I64,
+        LoadKind::F32 => ValType::F32,
+        LoadKind::F64 => ValType::F64,
+        LoadKind::V128 => ValType::V128,
+    }
+}
+
+// Building and lowering recurse on nested blocks and flattened dispatchers nest them
+// hundreds deep, don't depend on the caller's stack size
+fn with_stack<T: Send>(f: impl FnOnce() -> T + Send) -> T {
+    std::thread::scope(|scope| {
+        let thread = std::thread::Builder::new()
+            .stack_size(256 * 1024 * 1024)
+            .spawn_scoped(scope, f)
+            .expect("could not spawn thread");
+        thread
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
+
+impl Function {
+    pub const BODY: RegionId = RegionId(0);
+
+    fn new(params: Vec<ValType>, results: Vec<ValType>, args: Vec<LocalId>) -> Self {
+        Function {
+            params,
+            results,
+            args,
+            values: Vec::new(),
+            insts: Vec::new(),
+            regions: vec![Region {
+                kind: RegionKind::Body,
+                parent: None,
+                insts: Vec::new(),
+                preds: Vec::new(),
+            }],
+        }
+    }
+
+    pub fn params(&self) -> &[ValType] {
+        &self.params
+    }
+
+    pub fn results(&self) -> &[ValType] {
+        &self.results
+    }
+
+    pub fn inst(&self, id: InstId) -> &Inst {
+        &self.insts[id.0]
+    }
+
+    pub fn region(&self, id: RegionId) -> &Region {
+        &self.regions[id.0]
+    }
+
+    pub fn ty(&self, value: ValueId) -> ValType {
+        self.values[value.0].ty
+    }
+
+    // Instruction defining `value`
+    pub fn def(&self, value: ValueId) -> InstId {
+        self.values[value.0].def
+    }
+
+    pub fn uses(&self, value: ValueId) -> &[InstId] {
+        &self.values[value.0].uses
+    }
+
+    // Instruction defining the `n`th argument of `inst`
+    pub fn operand(&self, inst: InstId, n: usize) -> Option<&Inst> {
+        let value = *self.inst(inst).args.get(n)?;
+        Some(self.inst(self.def(value)))
+    }
+
+    // Constant `value` is, if any
+    pub fn constant(&self, value: ValueId) -> Option<Value> {
+        match self.inst(self.def(value)).op {
+            Op::Const(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    // Every instruction still in the code, in order, nested regions right after the
+    // instruction owning them
+    pub fn walk(&self) -> Vec<InstId> {
+        let mut out = Vec::new();
+        self.walk_region(Function::BODY, &mut out);
+        out
+    }
+
+    fn walk_region(&self, region: RegionId, out: &mut Vec<InstId>) {
+        for inst in self.regions[region.0].insts.iter() {
+            out.push(*inst);
+            match self.insts[inst.0].op {
+                Op::Block(inner) | Op::Loop(inner) => self.walk_region(inner, out),
+                Op::If(then, otherwise) => {
+                    self.walk_region(then, out);
+                    self.walk_region(otherwise, out);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Phis of the merge of `region`, in order
+    pub fn phis(&self, region: RegionId) -> Vec<InstId> {
+        let owner = match self.regions[region.0].kind {
+            RegionKind::Loop => Some(region),
+            _ => self.regions[region.0].parent,
+        };
+        owner
+            .map(|owner| self.regions[owner.0].insts.iter())
+            .into_iter()
+            .flatten()
+            .filter(|inst| matches!(self.insts[inst.0].op, Op::Phi(r) if r == region))
+            .copied()
+            .collect()
+    }
+
+    fn add_region(&mut self, kind: RegionKind, parent: RegionId) -> RegionId {
+        self.regions.push(Region {
+            kind,
+            parent: Some(parent),
+            insts: Vec::new(),
+            preds: Vec::new(),
+        });
+        RegionId(self.regions.len() - 1)
+    }
+
+    fn new_inst(
+        &mut self,
+        region: RegionId,
+        op: Op,
+        args: Vec<ValueId>,
+        results: &[ValType],
+        loc: InstrLocId,
+    ) -> InstId {
+        let id = InstId(self.insts.len());
+        for arg in args.iter() {
+            self.values[arg.0].uses.push(id);
+        }
+        let results = results
+            .iter()
+            .map(|ty| {
+                self.values.push(ValueData {
+                    ty: *ty,
+                    def: id,
+                    uses: Vec::new(),
+                });
+                ValueId(self.values.len() - 1)
+            })
+            .collect();
+        self.insts.push(Inst {
+            op,
+            args,
+            results,
+            region,
+            loc,
+        });
+        id
+    }
+
+    // Adds an instruction at the end of `region`
+    pub fn append(
+        &mut self,
+        region: RegionId,
+        op: Op,
+        args: Vec<ValueId>,
+        results: &[ValType],
+        loc: InstrLocId,
+    ) -> InstId {
+        let id = self.new_inst(region, op, args, results, loc);
+        self.regions[region.0].insts.push(id);
+        id
+    }
+
+    // Adds an instruction right before `before`, in its region
+    pub fn insert_before(
+        &mut self,
+        before: InstId,
+        op: Op,
+        args: Vec<ValueId>,
+        results: &[ValType],
+    ) -> InstId {
+        let region = self.insts[before.0].region;
+        let loc = self.insts[before.0].loc;
+        let id = self.new_inst(region, op, args, results, loc);
+        let insts = &mut self.regions[region.0].insts;
+        let at = insts
+            .iter()
+            .position(|inst| *inst == before)
+            .expect("instruction was removed");
+        insts.insert(at, id);
+        id
+    }
+
+    // The op of `inst`, it must take and produce the same number of values
+    pub fn set_op(&mut self, inst: InstId, op: Op) {
+        self.insts[inst.0].op = op;
+    }
+
+    pub fn set_args(&mut self, inst: InstId, args: Vec<ValueId>) {
+        for arg in self.insts[inst.0].args.iter() {
+            let uses = &mut self.values[arg.0].uses;
+            let at = uses.iter().position(|user| *user == inst).unwrap();
+            uses.swap_remove(at);
+        }
+        for arg in args.iter() {
+            self.values[arg.0].uses.push(inst);
+        }
+        self.insts[inst.0].args = args;
+    }
+
+    // Makes every user of `old` use `new` instead
+    pub fn replace_uses(&mut self, old: ValueId, new: ValueId) {
+        for user in std::mem::take(&mut self.values[old.0].uses) {
+            for arg in self.insts[user.0]
+                .args
+                .iter_mut()
+                .filter(|arg| **arg == old)
+            {
+                *arg = new;
+                self.values[new.0].uses.push(user);
+            }
+        }
+    }
+
+    // Takes `inst` out of the code, its results must not be used anymore
+    pub fn remove(&mut self, inst: InstId) {
+        assert!(
+            self.insts[inst.0]
+                .results
+                .iter()
+                .all(|r| self.values[r.0].uses.is_empty()),
+            "removing an instruction whose results are still used"
+        );
+        self.set_args(inst, Vec::new());
+        let region = self.insts[inst.0].region;
+        self.regions[region.0].insts.retain(|i| *i != inst);
+    }
+}
@@ -0,0 +1,456 @@
+use crate::transformations::Transformer;
+use std::collections::VecDeque;
+use walrus::ir::{
+    BinaryOp, Binop, Block, Const, IfElse, Instr, InstrSeqId, InstrSeqType, LoadKind, LocalGet,
+    LocalSet, Loop, MemoryCopy, MemoryFill, Store, StoreKind, UnaryOp, Unop, Value,
+};
+use walrus::{InstrLocId, LocalFunction, LocalId, MemoryId, Module, ValType};
+
+// Rewrites byte copy and fill loops into `memory.copy` / `memory.fill`. Runs on native
+// loads and stores, after `MemoryTransformer` for the loops built on wrappers.
+//
+// Two shapes are recognized, with a body made of one byte access and `x += 1` / `x -= 1`
+// steps, the addresses only stepped after the access:
+// - `block { n == 0 br_if; loop { body; --n br_if } }`, n bytes
+// - `loop { if p < end (or p != end) { body; br } }`, end - p bytes
+//
+// A range out of bounds traps before anything is written, where the loop would have
+// written up to the bound.
+#[derive(Default)]
+pub struct BulkMemoryTransformer;
+
+impl Transformer for BulkMemoryTransformer {
+    fn transform(&mut self, module: &mut Module) {
+        let ids = module
+            .funcs
+            .iter_local()
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+
+        for id in ids {
+            let func = module.funcs.get_mut(id).kind.unwrap_local_mut();
+            let loops = find_byte_loops(func);
+
+            // back to front, positions in the same sequence stay valid
+            for (seq, idx, byte_loop) in loops.into_iter().rev() {
+                let len = match byte_loop.len {
+                    Len::Counter(n) => n,
+                    Len::Distance { .. } => module.locals.add(ValType::I32),
+                };
+                byte_loop.rewrite(func, seq, idx, len);
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Access {
+    Copy {
+        memory: MemoryId,
+        dst: LocalId,
+        dst_offset: u32,
+        src: LocalId,
+        src_offset: u32,
+    },
+    Fill {
+        memory: MemoryId,
+        dst: LocalId,
+        offset: u32,
+        // `local.get` or `i32.const`
+        value: Instr,
+    },
+}
+
+enum Len {
+    // decremented down to 0
+    Counter(LocalId),
+    // `ptr` is incremented up to `end`
+    Distance { ptr: LocalId, end: LocalId },
+}
+
+struct ByteLoop {
+    access: Access,
+    // locals moved by one every iteration, true for increments
+    steps: Vec<(LocalId, bool)>,
+    // true when the loop runs at least once
+    guard: Vec<Instr>,
+    len: Len,
+}
+
+fn local_get(local: LocalId) -> Instr {
+    Instr::LocalGet(LocalGet { local })
+}
+
+fn local_set(local: LocalId) -> Instr {
+    Instr::LocalSet(LocalSet { local })
+}
+
+fn i32_const(value: i32) -> Instr {
+    Instr::Const(Const {
+        value: Value::I32(value),
+    })
+}
+
+fn binop(op: BinaryOp) -> Instr {
+    Instr::Binop(Binop { op })
+}
+
+fn address(local: LocalId, offset: u32) -> Vec<Instr> {
+    match offset {
+        0 => vec![local_get(local)],
+        _ => vec![
+            local_get(local),
+            i32_const(offset as i32),
+            binop(BinaryOp::I32Add),
+        ],
+    }
+}
+
+fn is_byte_store(store: &Store) -> bool {
+    matches!(store.kind, StoreKind::I32_8 { atomic: false })
+}
+
+fn is_i32_const(instr: &Instr, value: Option<i32>) -> bool {
+    matches!(instr, Instr::Const(Const { value: Value::I32(v) }) if value.is_none_or(|value| value == *v))
+}
+
+fn is_empty_seq(func: &LocalFunction, seq: InstrSeqId) -> bool {
+    matches!(func.block(seq).ty, InstrSeqType::Simple(None))
+}
+
+fn instrs(func: &LocalFunction, seq: InstrSeqId) -> Vec<&Instr> {
+    func.block(seq)
+        .instrs
+        .iter()
+        .map(|(instr, _)| instr)
+        .collect()
+}
+
+// Access and steps of a loop body, None if it does anything else
+fn parse_body(mut instrs: &[&Instr]) -> Option<(Access, Vec<(LocalId, bool)>)> {
+    let mut access = None;
+    let mut steps = Vec::<(LocalId, bool)>::new();
+    // locals stepped before the access, which then uses the moved address
+    let mut early = Vec::new();
+
+    while !instrs.is_empty() {
+        match instrs {
+            [Instr::LocalGet(dst), Instr::LocalGet(src), Instr::Load(load), Instr::Store(store), rest @ ..]
+                if access.is_none()
+                    && matches!(load.kind, LoadKind::I32_8 { .. })
+                    && is_byte_store(store)
+                    && load.memory == store.memory
+                    && dst.local != src.local =>
+            {
+                access = Some(Access::Copy {
+                    memory: store.memory,
+                    dst: dst.local,
+                    dst_offset: store.arg.offset,
+                    src: src.local,
+                    src_offset: load.arg.offset,
+                });
+                instrs = rest;
+            }
+            [Instr::LocalGet(dst), value, Instr::Store(store), rest @ ..]
+                if access.is_none()
+                    && is_byte_store(store)
+                    && (matches!(value, Instr::LocalGet(_)) || is_i32_const(value, None)) =>
+            {
+                access = Some(Access::Fill {
+                    memory: store.memory,
+                    dst: dst.local,
+                    offset: store.arg.offset,
+                    value: (*value).clone(),
+                });
+                instrs = rest;
+            }
+            [Instr::LocalGet(get), one, Instr::Binop(Binop { op }), Instr::LocalSet(set), rest @ ..]
+                if get.local == set.local
+                    && is_i32_const(one, Some(1))
+                    && matches!(op, BinaryOp::I32Add | BinaryOp::I32Sub)
+                    && steps.iter().all(|(l, _)| *l != get.local) =>
+            {
+                steps.push((get.local, matches!(op, BinaryOp::I32Add)));
+                if access.is_none() {
+                    early.push(get.local);
+                }
+                instrs = rest;
+            }
+            _ => return None,
+        }
+    }
+
+    let access = access?;
+    let step = |local: LocalId| steps.iter().find(|(l, _)| *l == local).map(|(_, inc)| *inc);
+    let valid = match &access {
+        Access::Copy { dst, src, .. } => {
+            step(*dst) == Some(true)
+                && step(*src) == Some(true)
+                && !early.contains(dst)
+                && !early.contains(src)
+        }
+        Access::Fill { dst, value, .. } => {
+            step(*dst) == Some(true)
+                && !early.contains(dst)
+                && !matches!(value, Instr::LocalGet(LocalGet { local }) if step(*local).is_some())
+        }
+    };
+
+    valid.then_some((access, steps))
+}
+
+// `block { n == 0 br_if; loop { body; --n br_if } }`
+fn counted_loop(func: &LocalFunction, block: InstrSeqId) -> Option<ByteLoop> {
+    let outer = instrs(func, block);
+    let [Instr::LocalGet(LocalGet { local: n }), Instr::Unop(Unop {
+        op: UnaryOp::I32Eqz,
+    }), Instr::BrIf(exit), Instr::Loop(Loop { seq })] = outer[..]
+    else {
+        return None;
+    };
+    if exit.block != block || !is_empty_seq(func, block) || !is_empty_seq(func, *seq) {
+        return None;
+    }
+
+    let inner = instrs(func, *seq);
+    let (body, mut decrement) = match &inner[..] {
+        [body @ .., Instr::LocalGet(get), one, Instr::Binop(Binop {
+            op: BinaryOp::I32Sub,
+        }), Instr::LocalTee(tee), Instr::BrIf(br)]
+            if get.local == *n
+                && is_i32_const(one, Some(1))
+                && tee.local == *n
+                && br.block == *seq =>
+        {
+            (body, true)
+        }
+        [body @ .., Instr::LocalGet(get), Instr::BrIf(br)]
+            if get.local == *n && br.block == *seq =>
+        {
+            (body, false)
+        }
+        _ => return None,
+    };
+
+    let (access, mut steps) = parse_body(body)?;
+    if let Some(pos) = steps.iter().position(|(l, _)| l == n) {
+        // the decrement is a step of the body
+        if decrement || steps[pos].1 {
+            return None;
+        }
+        steps.remove(pos);
+        decrement = true;
+    }
+
+    let uses_counter = match &access {
+        Access::Copy { dst, src, .. } => dst == n || src == n,
+        Access::Fill { dst, value, .. } => {
+            dst == n || matches!(value, Instr::LocalGet(LocalGet { local }) if local == n)
+        }
+    };
+    (decrement && !uses_counter).then_some(ByteLoop {
+        access,
+        steps,
+        guard: vec![local_get(*n)],
+        len: Len::Counter(*n),
+    })
+}
+
+// `loop { if p < end { body; br } }`
+fn bounded_loop(func: &LocalFunction, seq: InstrSeqId) -> Option<ByteLoop> {
+    let outer = instrs(func, seq);
+    let [Instr::LocalGet(LocalGet { local: ptr }), Instr::LocalGet(LocalGet { local: end }), Instr::Binop(Binop { op }), Instr::IfElse(IfElse {
+        consequent,
+        alternative,
+    })] = outer[..]
+    else {
+        return None;
+    };
+    if !matches!(op, BinaryOp::I32LtU | BinaryOp::I32Ne)
+        || !is_empty_seq(func, seq)
+        || !func.block(*alternative).instrs.is_empty()
+    {
+        return None;
+    }
+
+    let body = instrs(func, *consequent);
+    let [body @ .., Instr::Br(br)] = &body[..] else {
+        return None;
+    };
+    if br.block != seq {
+        return None;
+    }
+
+    let (access, steps) = parse_body(body)?;
+    let moved = |local: &LocalId| steps.iter().find(|(l, _)| l == local).map(|(_, inc)| *inc);
+    let end_is_value = matches!(&access, Access::Fill { value: Instr::LocalGet(LocalGet { local }), .. } if local == end);
+    if moved(ptr) != Some(true) || moved(end).is_some() || end_is_value {
+        return None;
+    }
+
+    Some(ByteLoop {
+        access,
+        steps,
+        guard: vec![local_get(*ptr), local_get(*end), binop(*op)],
+        len: Len::Distance {
+            ptr: *ptr,
+            end: *end,
+        },
+    })
+}
+
+// Byte loops with their position
+fn find_byte_loops(func: &LocalFunction) -> Vec<(InstrSeqId, usize, ByteLoop)> {
+    let mut found = Vec::new();
+    let mut stack = VecDeque::new();
+    stack.push_front(func.entry_block());
+
+    while let Some(seq_id) = stack.pop_back() {
+        for (idx, (instr, _)) in func.block(seq_id).instrs.iter().enumerate() {
+            let byte_loop = match instr {
+                Instr::Block(Block { seq }) => counted_loop(func, *seq),
+                Instr::Loop(Loop { seq }) => bounded_loop(func, *seq),
+                _ => None,
+            };
+
+            if let Some(byte_loop) = byte_loop {
+                found.push((seq_id, idx, byte_loop));
+                continue;
+            }
+
+            match instr {
+                Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => stack.push_front(*seq),
+                Instr::IfElse(IfElse {
+                    consequent,
+                    alternative,
+                }) => {
+                    stack.push_front(*consequent);
+                    stack.push_front(*alternative);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    found.sort_by_key(|(seq, idx, _)| (*seq, *idx));
+    found
+}
+
+fn new_seq(func: &mut LocalFunction, instrs: Vec<Instr>, loc: InstrLocId) -> InstrSeqId {
+    let id = func.builder_mut().dangling_instr_seq(None).id();
+    func.block_mut(id).instrs = instrs.into_iter().map(|instr| (instr, loc)).collect();
+    id
+}
+
+impl ByteLoop {
+    // Replaces the loop at `idx` of `seq`, `len` holds the number of bytes, it is the
+    // counter for counted loops
+    fn rewrite(self, func: &mut LocalFunction, seq: InstrSeqId, idx: usize, len: LocalId) {
+        let (original, loc) = func.block(seq).instrs[idx].clone();
+
+        let mut bulk = match &self.access {
+            Access::Copy {
+                memory,
+                dst,
+                dst_offset,
+                src,
+                src_offset,
+            } => {
+                let mut bulk = address(*dst, *dst_offset);
+                bulk.extend(address(*src, *src_offset));
+                bulk.push(local_get(len));
+                bulk.push(Instr::MemoryCopy(MemoryCopy {
+                    src: *memory,
+                    dst: *memory,
+                }));
+                bulk
+            }
+            Access::Fill {
+                memory,
+                dst,
+                offset,
+                value,
+            } => {
+                let mut bulk = address(*dst, *offset);
+                bulk.push(value.clone());
+                bulk.push(local_get(len));
+                bulk.push(Instr::MemoryFill(MemoryFill { memory: *memory }));
+                bulk
+            }
+        };
+
+        // the locals end up where the loop leaves them
+        for (local, increment) in self.steps.iter() {
+            let op = if *increment {
+                BinaryOp::I32Add
+            } else {
+                BinaryOp::I32Sub
+            };
+            bulk.extend([
+                local_get(*local),
+                local_get(len),
+                binop(op),
+                local_set(*local),
+            ]);
+        }
+        if let Len::Counter(n) = self.len {
+            bulk.extend([i32_const(0), local_set(n)]);
+        }
+
+        let mut consequent = Vec::new();
+        if let Len::Distance { ptr, end } = self.len {
+            consequent.extend([
+                local_get(end),
+                local_get(ptr),
+                binop(BinaryOp::I32Sub),
+                local_set(len),
+            ]);
+        }
+
+        match &self.access {
+            // A forward byte copy into the bytes right after its source repeats them,
+            // memory.copy doesn't, keep the loop for that case
+            Access::Copy {
+                dst,
+                dst_offset,
+                src,
+                src_offset,
+                ..
+            } => {
+                consequent.extend(address(*dst, *dst_offset));
+                consequent.extend(address(*src, *src_offset));
+                consequent.extend([
+                    binop(BinaryOp::I32Sub),
+                    i32_const(1),
+                    binop(BinaryOp::I32Sub),
+                    local_get(len),
+                    i32_const(1),
+                    binop(BinaryOp::I32Sub),
+                    binop(BinaryOp::I32LtU),
+                ]);
+
+                let overlapping = new_seq(func, vec![original], loc);
+                let bulk = new_seq(func, bulk, loc);
+                consequent.push(Instr::IfElse(IfElse {
+                    consequent: overlapping,
+                    alternative: bulk,
+                }));
+            }
+            Access::Fill { .. } => consequent.extend(bulk),
+        }
+
+        let consequent = new_seq(func, consequent, loc);
+        let alternative = new_seq(func, vec![], loc);
+
+        let mut replacement = self.guard;
+        replacement.push(Instr::IfElse(IfElse {
+            consequent,
+            alternative,
+        }));
+
+        func.block_mut(seq).instrs.splice(
+            idx..idx + 1,
+            replacement.into_iter().map(|instr| (instr, loc)),
+        );
+    }
+}
@@ -0,0 +1,107 @@
+use crate::analysis::addresses::{seqs, stack_pointer};
+use crate::transformations::Transformer;
+use std::collections::{HashMap, HashSet};
+use walrus::ir::{Const, Instr, Value};
+use walrus::{ConstExpr, ExportItem, GlobalId, GlobalKind, Module};
+
+// Replaces `global.get` of globals that never change with their value, so folding and
+// patterns see the constant. A global never changes when it is defined in the module
+// with a constant initializer, no function sets it and, if mutable, it isn't exported
+// for the loader to set. The globals themselves stay, `fetch_events` reads the first
+// initializer.
+//
+// Also names the stack pointer global `stack_pointer` when it has no name yet.
+#[derive(Default)]
+pub struct ConstantGlobalTransformer {
+    pub promoted: Vec<PromotedGlobal>,
+    pub stack_pointer: Option<GlobalId>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PromotedGlobal {
+    pub global: GlobalId,
+    pub value: Value,
+    // `global.get`s replaced
+    pub gets: usize,
+}
+
+impl Transformer for ConstantGlobalTransformer {
+    fn transform(&mut self, module: &mut Module) {
+        self.stack_pointer = stack_pointer(module);
+        if let Some(global) = self.stack_pointer {
+            let global = module.globals.get_mut(global);
+            if global.name.is_none() {
+                global.name = Some("stack_pointer".to_string());
+            }
+        }
+
+        let constants = constant_globals(module);
+        if constants.is_empty() {
+            return;
+        }
+
+        let mut gets = HashMap::<GlobalId, usize>::new();
+        let ids = module
+            .funcs
+            .iter_local()
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        for id in ids {
+            let func = module.funcs.get_mut(id).kind.unwrap_local_mut();
+            for seq in seqs(func) {
+                for (instr, _) in func.block_mut(seq).instrs.iter_mut() {
+                    let Instr::GlobalGet(get) = instr else {
+                        continue;
+                    };
+                    let Some(value) = constants.get(&get.global) else {
+                        continue;
+                    };
+                    *gets.entry(get.global).or_default() += 1;
+                    *instr = Instr::Const(Const { value: *value });
+                }
+            }
+        }
+
+        let mut promoted = constants
+            .into_iter()
+            .map(|(global, value)| PromotedGlobal {
+                global,
+                value,
+                gets: gets.get(&global).copied().unwrap_or(0),
+            })
+            .collect::<Vec<_>>();
+        promoted.sort_by_key(|promoted| promoted.global.index());
+        self.promoted = promoted;
+    }
+}
+
+// Value of every global that never changes
+fn constant_globals(module: &Module) -> HashMap<GlobalId, Value> {
+    let mut written = HashSet::new();
+    for (_, func) in module.funcs.iter_local() {
+        for seq in seqs(func) {
+            for (instr, _) in func.block(seq).instrs.iter() {
+                if let Instr::GlobalSet(set) = instr {
+                    written.insert(set.global);
+                }
+            }
+        }
+    }
+    for export in module.exports.iter() {
+        if let ExportItem::Global(global) = export.item
+            && module.globals.get(global).mutable
+        {
+            written.insert(global);
+        }
+    }
+
+    module
+        .globals
+        .iter()
+        .filter(|global| !written.contains(&global.id()))
+        .filter_map(|global| match global.kind {
+            GlobalKind::Local(ConstExpr::Value(value)) => Some((global.id(), value)),
+            _ => None,
+        })
+        .collect()
+}
@@ -0,0 +1,192 @@
+use crate::analysis::xrefs::tables;
+use crate::pattern::Sequence;
+use crate::ssa::{Function, InstId, Op, ValueId};
+use crate::transformations::Transformer;
+use std::collections::{HashMap, HashSet};
+use walrus::ir::{Call, Instr, ReturnCall, Value, Visitor, dfs_in_order};
+use walrus::{ConstExpr, ElementItems, ElementKind, ExportItem, FunctionId, Module, TableId, TypeId};
+
+// Turns `call_indirect` with a known table index into a direct call, so the call graph
+// and xrefs see the callee. The index is known when it is a constant, or a local that
+// holds the same constant on every path (the phis merging it all get that constant).
+//
+// Only tables the module can't change count: defined in it, not exported for the loader
+// to write, filled by active segments of functions at constant offsets only and never
+// written by `table.set`, `table.grow`, `table.fill`, `table.copy` or `table.init`. Calls
+// whose slot is empty or holds a function of another type trap, they stay.
+#[derive(Default)]
+pub struct IndirectCallTransformer {
+    pub resolved: Vec<ResolvedCall>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedCall {
+    pub caller: FunctionId,
+    pub callee: FunctionId,
+    pub index: u32,
+}
+
+impl Transformer for IndirectCallTransformer {
+    fn transform(&mut self, module: &mut Module) {
+        let tables = constant_tables(module);
+        if tables.is_empty() {
+            return;
+        }
+
+        let indirect = Sequence::parse("CallIndirect").unwrap();
+        let return_indirect = Sequence::parse("ReturnCallIndirect").unwrap();
+        let ids = module
+            .funcs
+            .iter_local()
+            .filter(|(_, func)| {
+                !indirect.find(func).is_empty() || !return_indirect.find(func).is_empty()
+            })
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+
+        for id in ids {
+            let local = module.funcs.get(id).kind.unwrap_local();
+            let Ok(mut ssa) = Function::build(module, local) else {
+                continue;
+            };
+
+            let resolved = resolve(module, &mut ssa, &tables);
+            if resolved.is_empty() {
+                continue;
+            }
+
+            ssa.cleanup();
+            ssa.lower(module, id);
+            self.resolved
+                .extend(resolved.into_iter().map(|(callee, index)| ResolvedCall {
+                    caller: id,
+                    callee,
+                    index,
+                }));
+        }
+    }
+}
+
+// Contents of the tables that stay as the element segments left them
+fn constant_tables(module: &Module) -> HashMap<TableId, Vec<Option<FunctionId>>> {
+    let mut written = WrittenTables::default();
+    for (_, func) in module.funcs.iter_local() {
+        dfs_in_order(&mut written, func, func.entry_block());
+    }
+    for export in module.exports.iter() {
+        if let ExportItem::Table(table) = export.item {
+            written.tables.insert(table);
+        }
+    }
+    // `tables` skips the segments it can't place, the slots they fill are unknown
+    for element in module.elements.iter() {
+        if let ElementKind::Active { table, offset } = &element.kind
+            && (!matches!(offset, ConstExpr::Value(Value::I32(_)))
+                || !matches!(element.items, ElementItems::Functions(_)))
+        {
+            written.tables.insert(*table);
+        }
+    }
+
+    let mut tables = tables(module);
+    tables.retain(|table, _| {
+        module.tables.get(*table).import.is_none() && !written.tables.contains(table)
+    });
+    tables
+}
+
+#[derive(Default)]
+struct WrittenTables {
+    tables: HashSet<TableId>,
+}
+
+impl<'a> Visitor<'a> for WrittenTables {
+    fn visit_instr(&mut self, instr: &'a Instr, _: &'a walrus::InstrLocId) {
+        let table = match instr {
+            Instr::TableSet(i) => i.table,
+            Instr::TableGrow(i) => i.table,
+            Instr::TableFill(i) => i.table,
+            Instr::TableCopy(i) => i.dst,
+            Instr::TableInit(i) => i.table,
+            _ => return,
+        };
+        self.tables.insert(table);
+    }
+}
+
+// Rewrites the calls it can resolve, returns their callees and indices
+fn resolve(
+    module: &Module,
+    ssa: &mut Function,
+    tables: &HashMap<TableId, Vec<Option<FunctionId>>>,
+) -> Vec<(FunctionId, u32)> {
+    let mut resolved = Vec::new();
+    for inst in ssa.walk() {
+        let (table, ty, tail) = match ssa.inst(inst).op() {
+            Op::Instr(Instr::CallIndirect(call)) => (call.table, call.ty, false),
+            Op::Instr(Instr::ReturnCallIndirect(call)) => (call.table, call.ty, true),
+            _ => continue,
+        };
+        let Some(slots) = tables.get(&table) else {
+            continue;
+        };
+
+        let args = ssa.inst(inst).args().to_vec();
+        let Some((index, args)) = args.split_last() else {
+            continue;
+        };
+        let Some(index) = constant_index(ssa, *index, &mut HashSet::new()) else {
+            continue;
+        };
+        let Some(Some(callee)) = slots.get(index as usize) else {
+            continue;
+        };
+        if !same_type(module, module.funcs.get(*callee).ty(), ty) {
+            continue;
+        }
+
+        let op = match tail {
+            false => Instr::Call(Call { func: *callee }),
+            true => Instr::ReturnCall(ReturnCall { func: *callee }),
+        };
+        ssa.set_op(inst, Op::Instr(op));
+        ssa.set_args(inst, args.to_vec());
+        resolved.push((*callee, index));
+    }
+    resolved
+}
+
+// The i32 constant `value` always is, through phis. `seen` breaks loops, a phi reached
+// again adds no new value.
+fn constant_index(ssa: &Function, value: ValueId, seen: &mut HashSet<InstId>) -> Option<u32> {
+    if let Some(Value::I32(index)) = ssa.constant(value) {
+        return Some(index as u32);
+    }
+
+    let def = ssa.def(value);
+    if !matches!(ssa.inst(def).op(), Op::Phi(_)) {
+        return None;
+    }
+    if !seen.insert(def) {
+        return None;
+    }
+
+    let mut index = None;
+    for arg in ssa.inst(def).args() {
+        if seen.contains(&ssa.def(*arg)) {
+            continue;
+        }
+        let arg = constant_index(ssa, *arg, seen)?;
+        if index.is_some_and(|index| index != arg) {
+            return None;
+        }
+        index = Some(arg);
+    }
+    index
+}
+
+// Types are compared by shape, a module may have duplicates
+fn same_type(module: &Module, a: TypeId, b: TypeId) -> bool {
+    let (a, b) = (module.types.get(a), module.types.get(b));
+    a.params() == b.params() && a.results() == b.results()
+}
@@ -0,0 +1,624 @@
+use crate::commands::function_label;
+use crate::rng::Rng;
+use crate::ssa::{Function, InstId, Op, ValueId};
+use crate::transformations::Transformer;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::OnceLock;
+use walrus::ir::{BinaryOp, Binop, Instr, Value};
+use walrus::{FunctionId, Module, ValType};
+
+// Simplifies linear mixed boolean-arithmetic expressions, sums of constant multiples of
+// bitwise expressions like `(a ^ b) + 2 * (a & b)` for `a + b`. Runs before
+// `MemoryTransformer`, the wrapper heuristics look for plain constants and operators.
+//
+// A linear MBA expression over n variables is fully determined by its values when every
+// variable is 0 or -1 (all bits equal), the truth table. The simplest of a few forms
+// with the same truth table replaces the expression when it is smaller, after checking it
+// against the original on the truth table and on random inputs.
+//
+// Expressions are trees of SSA values, anything that isn't part of a linear MBA
+// expression (a load, a call, `x & 0xff`) is a variable.
+#[derive(Default)]
+pub struct MbaTransformer {
+    pub rewrites: Vec<MbaRewrite>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MbaRewrite {
+    pub func: FunctionId,
+    pub before: String,
+    pub after: String,
+}
+
+impl fmt::Display for MbaRewrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} => {}", self.before, self.after)
+    }
+}
+
+// More variables are rare and the truth table doubles with each one
+const MAX_VARS: usize = 4;
+const MAX_NODES: usize = 64;
+// Largest bitwise expression looked up by truth table, see `bitwise_table`
+const MAX_BITWISE_COST: usize = 15;
+const RANDOM_CHECKS: usize = 32;
+
+impl Transformer for MbaTransformer {
+    fn transform(&mut self, module: &mut Module) {
+        let ids = module
+            .funcs
+            .iter_local()
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+
+        for id in ids {
+            let local = module.funcs.get(id).kind.unwrap_local();
+            let Ok(mut ssa) = Function::build(module, local) else {
+                continue;
+            };
+
+            let rewrites = simplify(&mut ssa);
+            if rewrites.is_empty() {
+                continue;
+            }
+
+            ssa.cleanup();
+            ssa.lower(module, id);
+            self.rewrites
+                .extend(rewrites.into_iter().map(|(before, after)| MbaRewrite {
+                    func: id,
+                    before,
+                    after,
+                }));
+        }
+    }
+}
+
+impl MbaTransformer {
+    // One line per rewrite, by function
+    pub fn report(&self, module: &Module) -> String {
+        self.rewrites
+            .iter()
+            .map(|rewrite| format!("{}: {}\n", function_label(module, rewrite.func), rewrite))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MbaOp {
+    Add,
+    Sub,
+    Mul,
+    And,
+    Or,
+    Xor,
+}
+
+impl MbaOp {
+    fn of(op: BinaryOp) -> Option<(MbaOp, ValType)> {
+        Some(match op {
+            BinaryOp::I32Add => (MbaOp::Add, ValType::I32),
+            BinaryOp::I32Sub => (MbaOp::Sub, ValType::I32),
+            BinaryOp::I32Mul => (MbaOp::Mul, ValType::I32),
+            BinaryOp::I32And => (MbaOp::And, ValType::I32),
+            BinaryOp::I32Or => (MbaOp::Or, ValType::I32),
+            BinaryOp::I32Xor => (MbaOp::Xor, ValType::I32),
+            BinaryOp::I64Add => (MbaOp::Add, ValType::I64),
+            BinaryOp::I64Sub => (MbaOp::Sub, ValType::I64),
+            BinaryOp::I64Mul => (MbaOp::Mul, ValType::I64),
+            BinaryOp::I64And => (MbaOp::And, ValType::I64),
+            BinaryOp::I64Or => (MbaOp::Or, ValType::I64),
+            BinaryOp::I64Xor => (MbaOp::Xor, ValType::I64),
+            _ => return None,
+        })
+    }
+
+    fn binary_op(self, ty: ValType) -> BinaryOp {
+        match (self, ty) {
+            (MbaOp::Add, ValType::I32) => BinaryOp::I32Add,
+            (MbaOp::Sub, ValType::I32) => BinaryOp::I32Sub,
+            (MbaOp::Mul, ValType::I32) => BinaryOp::I32Mul,
+            (MbaOp::And, ValType::I32) => BinaryOp::I32And,
+            (MbaOp::Or, ValType::I32) => BinaryOp::I32Or,
+            (MbaOp::Xor, ValType::I32) => BinaryOp::I32Xor,
+            (MbaOp::Add, _) => BinaryOp::I64Add,
+            (MbaOp::Sub, _) => BinaryOp::I64Sub,
+            (MbaOp::Mul, _) => BinaryOp::I64Mul,
+            (MbaOp::And, _) => BinaryOp::I64And,
+            (MbaOp::Or, _) => BinaryOp::I64Or,
+            (MbaOp::Xor, _) => BinaryOp::I64Xor,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            MbaOp::Add => "+",
+            MbaOp::Sub => "-",
+            MbaOp::Mul => "*",
+            MbaOp::And => "&",
+            MbaOp::Or => "|",
+            MbaOp::Xor => "^",
+        }
+    }
+
+    fn bitwise(self) -> bool {
+        matches!(self, MbaOp::And | MbaOp::Or | MbaOp::Xor)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    // index into the variables of the expression
+    Var(usize),
+    Const(i64),
+    Not(Box<Expr>),
+    Bin(MbaOp, Box<Expr>, Box<Expr>),
+}
+
+fn bin(op: MbaOp, left: Expr, right: Expr) -> Expr {
+    Expr::Bin(op, Box::new(left), Box::new(right))
+}
+
+impl Expr {
+    // Instructions it lowers to, `~x` is `x ^ -1`
+    fn cost(&self) -> usize {
+        match self {
+            Expr::Var(_) | Expr::Const(_) => 1,
+            Expr::Not(e) => e.cost() + 2,
+            Expr::Bin(_, l, r) => l.cost() + r.cost() + 1,
+        }
+    }
+
+    fn bitwise(&self) -> bool {
+        match self {
+            Expr::Var(_) | Expr::Const(0 | -1) | Expr::Not(_) => true,
+            Expr::Bin(op, _, _) => op.bitwise(),
+            Expr::Const(_) => false,
+        }
+    }
+
+    // Wrapping to `bits` bits, sign extended
+    fn eval(&self, vars: &[i64], bits: u32) -> i64 {
+        let value = match self {
+            Expr::Var(i) => vars[*i],
+            Expr::Const(c) => *c,
+            Expr::Not(e) => !e.eval(vars, bits),
+            Expr::Bin(op, l, r) => {
+                let (l, r) = (l.eval(vars, bits), r.eval(vars, bits));
+                match op {
+                    MbaOp::Add => l.wrapping_add(r),
+                    MbaOp::Sub => l.wrapping_sub(r),
+                    MbaOp::Mul => l.wrapping_mul(r),
+                    MbaOp::And => l & r,
+                    MbaOp::Or => l | r,
+                    MbaOp::Xor => l ^ r,
+                }
+            }
+        };
+        wrap(value, bits)
+    }
+
+    // Whether it has both bitwise and arithmetic operators, plain arithmetic is left alone
+    fn mixed(&self) -> bool {
+        fn ops(expr: &Expr, bitwise: &mut bool, arithmetic: &mut bool) {
+            match expr {
+                Expr::Var(_) | Expr::Const(_) => {}
+                Expr::Not(e) => {
+                    *bitwise = true;
+                    ops(e, bitwise, arithmetic);
+                }
+                Expr::Bin(op, l, r) => {
+                    *bitwise |= op.bitwise();
+                    *arithmetic |= !op.bitwise();
+                    ops(l, bitwise, arithmetic);
+                    ops(r, bitwise, arithmetic);
+                }
+            }
+        }
+
+        let (mut bitwise, mut arithmetic) = (false, false);
+        ops(self, &mut bitwise, &mut arithmetic);
+        bitwise && arithmetic
+    }
+
+    fn fmt_operand(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Bin(..) => write!(f, "({})", self),
+            _ => write!(f, "{}", self),
+        }
+    }
+}
+
+// Variables are named by first appearance
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Var(i) => write!(f, "{}", (b'a' + *i as u8) as char),
+            Expr::Const(c) => write!(f, "{}", c),
+            Expr::Not(e) => {
+                write!(f, "~")?;
+                e.fmt_operand(f)
+            }
+            Expr::Bin(op, l, r) => {
+                l.fmt_operand(f)?;
+                write!(f, " {} ", op.symbol())?;
+                r.fmt_operand(f)
+            }
+        }
+    }
+}
+
+fn wrap(value: i64, bits: u32) -> i64 {
+    if bits == 32 {
+        value as i32 as i64
+    } else {
+        value
+    }
+}
+
+// Reads the expression tree under a value, in SSA
+struct Tree<'a> {
+    ssa: &'a Function,
+    ty: ValType,
+    vars: Vec<ValueId>,
+    nodes: usize,
+}
+
+impl Tree<'_> {
+    fn var(&mut self, value: ValueId) -> Expr {
+        let index = match self.vars.iter().position(|var| *var == value) {
+            Some(index) => index,
+            None => {
+                self.vars.push(value);
+                self.vars.len() - 1
+            }
+        };
+        Expr::Var(index)
+    }
+
+    fn read(&mut self, value: ValueId) -> Expr {
+        // shared values stay, rewriting the expressions using them would duplicate them
+        if self.nodes > 0 && self.ssa.uses(value).len() > 1 && self.ssa.constant(value).is_none() {
+            return self.var(value);
+        }
+        match self.ssa.constant(value) {
+            Some(Value::I32(c)) => return Expr::Const(c as i64),
+            Some(Value::I64(c)) => return Expr::Const(c),
+            _ => {}
+        }
+
+        let inst = self.ssa.inst(self.ssa.def(value));
+        let op = match inst.op() {
+            Op::Instr(Instr::Binop(binop)) => MbaOp::of(binop.op),
+            _ => None,
+        };
+        let Some((op, _)) = op.filter(|(_, ty)| *ty == self.ty) else {
+            return self.var(value);
+        };
+        if self.nodes >= MAX_NODES {
+            return self.var(value);
+        }
+        self.nodes += 1;
+
+        let (vars, nodes) = (self.vars.len(), self.nodes);
+        let left = self.read(inst.args()[0]);
+        let right = self.read(inst.args()[1]);
+        let linear = match op {
+            // bitwise operators only take bitwise operands, `x & 0xff` isn't linear
+            MbaOp::And | MbaOp::Or | MbaOp::Xor => left.bitwise() && right.bitwise(),
+            MbaOp::Mul => matches!(left, Expr::Const(_)) || matches!(right, Expr::Const(_)),
+            MbaOp::Add | MbaOp::Sub => true,
+        };
+        if !linear {
+            // what the operands added is only used by them
+            self.vars.truncate(vars);
+            self.nodes = nodes;
+            return self.var(value);
+        }
+
+        match (op, left, right) {
+            (MbaOp::Xor, e, Expr::Const(-1)) | (MbaOp::Xor, Expr::Const(-1), e) => {
+                Expr::Not(Box::new(e))
+            }
+            (op, left, right) => bin(op, left, right),
+        }
+    }
+}
+
+// Rewrites the expressions it can simplify, returns them before and after
+fn simplify(ssa: &mut Function) -> Vec<(String, String)> {
+    let mut rewrites = Vec::new();
+    let mut removed = HashSet::new();
+
+    // outermost expressions first, their operands go with them
+    for inst in ssa.walk().into_iter().rev() {
+        if removed.contains(&inst) {
+            continue;
+        }
+        let ty = match ssa.inst(inst).op() {
+            Op::Instr(Instr::Binop(binop)) => match MbaOp::of(binop.op) {
+                Some((_, ty)) => ty,
+                None => continue,
+            },
+            _ => continue,
+        };
+
+        let root = ssa.inst(inst).results()[0];
+        let mut tree = Tree {
+            ssa,
+            ty,
+            vars: Vec::new(),
+            nodes: 0,
+        };
+        let expr = tree.read(root);
+        let vars = tree.vars;
+        if !expr.mixed() || vars.len() > MAX_VARS {
+            continue;
+        }
+
+        let bits = if ty == ValType::I32 { 32 } else { 64 };
+        let Some(simplified) = solve(&expr, vars.len(), bits) else {
+            continue;
+        };
+        if simplified.cost() >= expr.cost() {
+            continue;
+        }
+
+        let value = emit(ssa, inst, &simplified, &vars, ty);
+        ssa.replace_uses(root, value);
+        remove_dead(ssa, inst, &mut removed);
+        rewrites.push((expr.to_string(), simplified.to_string()));
+    }
+
+    rewrites
+}
+
+// Inserts `expr` before `before`, returns its value
+fn emit(ssa: &mut Function, before: InstId, expr: &Expr, vars: &[ValueId], ty: ValType) -> ValueId {
+    let constant = |c: i64| match ty {
+        ValType::I32 => Value::I32(c as i32),
+        _ => Value::I64(c),
+    };
+    let inst = match expr {
+        Expr::Var(i) => return vars[*i],
+        Expr::Const(c) => ssa.insert_before(before, Op::Const(constant(*c)), Vec::new(), &[ty]),
+        Expr::Not(e) => {
+            let value = emit(ssa, before, e, vars, ty);
+            let ones = ssa.insert_before(before, Op::Const(constant(-1)), Vec::new(), &[ty]);
+            let ones = ssa.inst(ones).results()[0];
+            let op = Op::Instr(Instr::Binop(Binop {
+                op: MbaOp::Xor.binary_op(ty),
+            }));
+            ssa.insert_before(before, op, vec![value, ones], &[ty])
+        }
+        Expr::Bin(op, l, r) => {
+            let l = emit(ssa, before, l, vars, ty);
+            let r = emit(ssa, before, r, vars, ty);
+            let op = Op::Instr(Instr::Binop(Binop {
+                op: op.binary_op(ty),
+            }));
+            ssa.insert_before(before, op, vec![l, r], &[ty])
+        }
+    };
+    ssa.inst(inst).results()[0]
+}
+
+// Removes `inst` and the operands only it used, as long as they can't trap or have
+// side effects
+fn remove_dead(ssa: &mut Function, inst: InstId, removed: &mut HashSet<InstId>) {
+    let pure = match ssa.inst(inst).op() {
+        Op::Const(_) => true,
+        Op::Instr(Instr::Binop(binop)) => MbaOp::of(binop.op).is_some(),
+        _ => false,
+    };
+    let unused = ssa
+        .inst(inst)
+        .results()
+        .iter()
+        .all(|result| ssa.uses(*result).is_empty());
+    if !pure || !unused || removed.contains(&inst) {
+        return;
+    }
+
+    let args = ssa.inst(inst).args().to_vec();
+    ssa.remove(inst);
+    removed.insert(inst);
+    for arg in args {
+        remove_dead(ssa, ssa.def(arg), removed);
+    }
+}
+
+// The simplest expression with the truth table of `expr`, checked against it
+fn solve(expr: &Expr, vars: usize, bits: u32) -> Option<Expr> {
+    // values with every variable 0 or -1, variable i is -1 where bit i of the index is set
+    let inputs = (0..1usize << vars)
+        .map(|b| {
+            (0..vars)
+                .map(|i| -((b >> i & 1) as i64))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let table = inputs
+        .iter()
+        .map(|input| expr.eval(input, bits))
+        .collect::<Vec<_>>();
+
+    let candidates = [
+        constant(&table),
+        single_term(&table, vars, bits),
+        Some(conjunctions(&table, vars, bits)),
+    ];
+    let best = candidates
+        .into_iter()
+        .flatten()
+        .min_by_key(|candidate| candidate.cost())?;
+
+    let mut rng = Rng::new(0x6d6261);
+    let random = (0..RANDOM_CHECKS).map(|_| {
+        (0..vars)
+            .map(|_| wrap(rng.next_u64() as i64, bits))
+            .collect::<Vec<_>>()
+    });
+    let agrees = inputs
+        .into_iter()
+        .chain(random)
+        .all(|input| expr.eval(&input, bits) == best.eval(&input, bits));
+    agrees.then_some(best)
+}
+
+fn constant(table: &[i64]) -> Option<Expr> {
+    table
+        .iter()
+        .all(|value| *value == table[0])
+        .then_some(Expr::Const(table[0]))
+}
+
+// `k + a * f` for a bitwise `f` with `f(0) == 0`: the table takes at most two values
+fn single_term(table: &[i64], vars: usize, bits: u32) -> Option<Expr> {
+    let k = table[0];
+    let mut diffs = table
+        .iter()
+        .map(|value| wrap(value.wrapping_sub(k), bits))
+        .filter(|d| *d != 0);
+    let d = diffs.next()?;
+    if diffs.any(|other| other != d) {
+        return None;
+    }
+
+    let truth = table
+        .iter()
+        .enumerate()
+        .filter(|(_, value)| **value != k)
+        .fold(0u16, |truth, (b, _)| truth | 1 << b);
+    let f = bitwise_table(vars)?.get(&truth)?.clone();
+
+    // where f is true, variables and f are -1
+    Some(sum(vec![(wrap(d.wrapping_neg(), bits), f)], k, bits))
+}
+
+// Sum of conjunctions of the variables, the coefficients by Möbius inversion of the table
+fn conjunctions(table: &[i64], vars: usize, bits: u32) -> Expr {
+    let mut terms = Vec::new();
+    for s in 1..1usize << vars {
+        let coefficient = (0..1usize << vars)
+            .filter(|t| t & s == *t)
+            .map(|t| {
+                let sign = if (s ^ t).count_ones() % 2 == 0 { -1 } else { 1 };
+                table[t].wrapping_mul(sign)
+            })
+            .fold(0i64, |sum, value| sum.wrapping_add(value));
+        let coefficient = wrap(coefficient, bits);
+        if coefficient == 0 {
+            continue;
+        }
+
+        let conjunction = (0..vars)
+            .filter(|i| s >> i & 1 == 1)
+            .map(Expr::Var)
+            .reduce(|l, r| bin(MbaOp::And, l, r))
+            .unwrap();
+        terms.push((coefficient, conjunction));
+    }
+
+    sum(terms, table[0], bits)
+}
+
+// `k + Σ c * e`, positive terms first and the negative ones subtracted
+fn sum(terms: Vec<(i64, Expr)>, k: i64, bits: u32) -> Expr {
+    let scaled = |c: i64, e: Expr| match c {
+        1 => e,
+        _ => bin(MbaOp::Mul, Expr::Const(c), e),
+    };
+    let (positive, negative): (Vec<_>, Vec<_>) = terms.into_iter().partition(|(c, _)| *c > 0);
+
+    let mut k = k;
+    let mut expr = positive
+        .into_iter()
+        .map(|(c, e)| scaled(c, e))
+        .reduce(|l, r| bin(MbaOp::Add, l, r));
+    if expr.is_none() && k != 0 {
+        expr = Some(Expr::Const(k));
+        k = 0;
+    }
+    for (c, e) in negative {
+        expr = Some(match expr {
+            Some(acc) => bin(MbaOp::Sub, acc, scaled(wrap(c.wrapping_neg(), bits), e)),
+            None => scaled(c, e),
+        });
+    }
+
+    let expr = expr.unwrap_or(Expr::Const(0));
+    match k {
+        0 => expr,
+        k if k > 0 => bin(MbaOp::Add, expr, Expr::Const(k)),
+        k => bin(MbaOp::Sub, expr, Expr::Const(wrap(k.wrapping_neg(), bits))),
+    }
+}
+
+// Smallest bitwise expression for every truth table over `vars` variables (bit b of the
+// table is the value where variable i is set iff bit i of b is), built up by cost.
+// Only for up to 3 variables, 4 would have 65536 tables.
+fn bitwise_table(vars: usize) -> Option<&'static HashMap<u16, Expr>> {
+    static TABLES: OnceLock<Vec<HashMap<u16, Expr>>> = OnceLock::new();
+    let tables = TABLES.get_or_init(|| (0..=3).map(build_bitwise_table).collect());
+    tables.get(vars)
+}
+
+fn build_bitwise_table(vars: usize) -> HashMap<u16, Expr> {
+    let rows = 1usize << vars;
+    let mask = if rows == 16 {
+        u16::MAX
+    } else {
+        (1u16 << rows) - 1
+    };
+    let var_truth = |i: usize| {
+        (0..rows)
+            .filter(|b| b >> i & 1 == 1)
+            .fold(0u16, |t, b| t | 1 << b)
+    };
+
+    // by_cost[c] has the tables first reached with cost c
+    let mut best = HashMap::new();
+    let mut by_cost = vec![Vec::new(); MAX_BITWISE_COST + 1];
+    let add =
+        |best: &mut HashMap<u16, Expr>, by_cost: &mut Vec<Vec<u16>>, truth: u16, expr: Expr| {
+            let cost = expr.cost();
+            if cost <= MAX_BITWISE_COST && !best.contains_key(&truth) {
+                best.insert(truth, expr);
+                by_cost[cost].push(truth);
+            }
+        };
+
+    add(&mut best, &mut by_cost, 0, Expr::Const(0));
+    add(&mut best, &mut by_cost, mask, Expr::Const(-1));
+    for i in 0..vars {
+        add(&mut best, &mut by_cost, var_truth(i), Expr::Var(i));
+    }
+
+    for cost in 2..=MAX_BITWISE_COST {
+        let mut found = Vec::new();
+        if cost >= 3 {
+            for truth in by_cost[cost - 2].iter() {
+                found.push((!truth & mask, Expr::Not(Box::new(best[truth].clone()))));
+            }
+        }
+        for left_cost in 1..cost - 1 {
+            let right_cost = cost - 1 - left_cost;
+            for l in by_cost[left_cost].iter() {
+                for r in by_cost[right_cost].iter() {
+                    for (op, truth) in
+                        [(MbaOp::And, l & r), (MbaOp::Or, l | r), (MbaOp::Xor, l ^ r)]
+                    {
+                        found.push((truth, bin(op, best[l].clone(), best[r].clone())));
+                    }
+                }
+            }
+        }
+        for (truth, expr) in found {
+            add(&mut best, &mut by_cost, truth, expr);
+        }
+    }
+
+    best
+}
@@ -0,0 +1,328 @@
+use crate::emulator::Emulator;
+use crate::profile::Profile;
+use crate::rng::Rng;
+use crate::transformations::memory::memory_transformer::MemoryTransformer;
+use crate::transformations::memory::MemEncFuncType;
+use anyhow::{anyhow, bail, Context};
+use std::collections::HashMap;
+use walrus::ir::Value;
+use walrus::{ConstExpr, DataKind, FunctionId, Module, ValType};
+
+// Every load type, in the order candidates are tried
+const LOAD_TYPES: [MemEncFuncType; 14] = [
+    MemEncFuncType::Unsigned8,
+    MemEncFuncType::Signed8,
+    MemEncFuncType::Unsigned16,
+    MemEncFuncType::Signed16,
+    MemEncFuncType::Signed32,
+    MemEncFuncType::Signed64,
+    MemEncFuncType::I64Unsigned8,
+    MemEncFuncType::I64Signed8,
+    MemEncFuncType::I64Unsigned16,
+    MemEncFuncType::I64Signed16,
+    MemEncFuncType::I64Unsigned32,
+    MemEncFuncType::I64Signed32,
+    MemEncFuncType::Float32,
+    MemEncFuncType::Float64,
+];
+
+// Classifies the wrappers by running them instead of looking at their code. Everything
+// above the raw data is filled with noise, whatever scheme encrypts it the wrappers then
+// read random plain bytes, and the low byte of any load at `a` is the plain byte at `a`.
+// The type is the only one explaining every output.
+pub struct BehavioralMapper {
+    pub seed: u64,
+    // addresses tried per wrapper
+    pub samples: usize,
+    // any function with an inlined accessor is a candidate, some of them run for long
+    pub fuel_per_call: u64,
+    // where the encrypted segment is and the accessor constants candidates are found by
+    pub profile: Profile,
+}
+
+impl Default for BehavioralMapper {
+    fn default() -> Self {
+        BehavioralMapper {
+            seed: 0x5eed,
+            samples: 64,
+            fuel_per_call: 100_000,
+            profile: Profile::default(),
+        }
+    }
+}
+
+// A wrapper both mappers don't agree on, None when one of them doesn't see a wrapper
+#[derive(Debug, PartialEq)]
+pub struct Mismatch {
+    pub func: FunctionId,
+    pub static_type: Option<MemEncFuncType>,
+    pub behavioral_type: Option<MemEncFuncType>,
+}
+
+fn bits(value: &Value) -> u64 {
+    match value {
+        Value::I32(i) => *i as u32 as u64,
+        Value::I64(i) => *i as u64,
+        Value::F32(f) => f.to_bits() as u64,
+        Value::F64(f) => f.to_bits(),
+        Value::V128(v) => *v as u64,
+    }
+}
+
+// What a load of `ty` returns for these plain bytes, as bits
+fn load_bits(ty: MemEncFuncType, bytes: &[u8]) -> u64 {
+    let mut raw = [0u8; 8];
+    raw[..ty.width() as usize].copy_from_slice(&bytes[..ty.width() as usize]);
+    let raw = u64::from_le_bytes(raw);
+
+    match ty {
+        MemEncFuncType::Signed8 => raw as i8 as i32 as u32 as u64,
+        MemEncFuncType::Signed16 => raw as i16 as i32 as u32 as u64,
+        MemEncFuncType::I64Signed8 => raw as i8 as u64,
+        MemEncFuncType::I64Signed16 => raw as i16 as u64,
+        MemEncFuncType::I64Signed32 => raw as i32 as u64,
+        _ => raw,
+    }
+}
+
+// Random value of `ty`, floats are kept away from NaNs so their bits survive
+fn random_value(rng: &mut Rng, ty: ValType) -> Option<Value> {
+    let bits = rng.next_u64() & !(1 << 62) & !(1 << 30);
+    match ty {
+        ValType::I32 => Some(Value::I32(bits as i32)),
+        ValType::I64 => Some(Value::I64(bits as i64)),
+        ValType::F32 => Some(Value::F32(f32::from_bits(bits as u32))),
+        ValType::F64 => Some(Value::F64(f64::from_bits(bits))),
+        _ => None,
+    }
+}
+
+fn store_type(value: ValType, width: u32) -> Option<MemEncFuncType> {
+    match (value, width) {
+        (ValType::I32, 1) => Some(MemEncFuncType::Signed8),
+        (ValType::I32, 2) => Some(MemEncFuncType::Signed16),
+        (ValType::I32, 4) => Some(MemEncFuncType::Signed32),
+        (ValType::I64, 1) => Some(MemEncFuncType::I64Signed8),
+        (ValType::I64, 2) => Some(MemEncFuncType::I64Signed16),
+        (ValType::I64, 4) => Some(MemEncFuncType::I64Signed32),
+        (ValType::I64, 8) => Some(MemEncFuncType::Signed64),
+        (ValType::F32, 4) => Some(MemEncFuncType::Float32),
+        (ValType::F64, 8) => Some(MemEncFuncType::Float64),
+        _ => None,
+    }
+}
+
+impl BehavioralMapper {
+    pub fn with_profile(mut self, profile: Profile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    // None when the function traps
+    fn call(&self, emulator: &mut Emulator, id: FunctionId, args: &[Value]) -> Option<Vec<Value>> {
+        emulator.fuel = self.fuel_per_call;
+        emulator.call(id, args).ok()
+    }
+
+    // Plain bytes through a load wrapper, one call per byte
+    fn read_plain(&self, emulator: &mut Emulator, load: FunctionId, address: u32, len: u32) -> Option<Vec<u8>> {
+        (address..address + len)
+            .map(|a| {
+                let result = self.call(emulator, load, &[Value::I32(a as i32), Value::I32(0)])?;
+                result.first().map(|value| bits(value) as u8)
+            })
+            .collect()
+    }
+
+    // Emulator with noise above the raw data and where to sample logical addresses
+    fn instantiate<'a>(&self, module: &'a Module) -> Result<(Emulator<'a>, Vec<u32>), anyhow::Error> {
+        let mut emulator = Emulator::new(module).map_err(|trap| anyhow!("could not instantiate module: {}", trap))?;
+        let mut rng = Rng::new(self.seed);
+
+        let raw_end = module
+            .data
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != self.profile.data_segment)
+            .filter_map(|(_, data)| match &data.kind {
+                DataKind::Active {
+                    offset: ConstExpr::Value(Value::I32(offset)),
+                    ..
+                } => Some(*offset as usize + data.value.len()),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+
+        if raw_end >= emulator.memory.len() {
+            bail!("no memory left above the raw data");
+        }
+        for byte in emulator.memory[raw_end..].iter_mut() {
+            *byte = rng.next_u32() as u8;
+        }
+
+        // far enough from the raw data, whatever the layout of the encrypted pages
+        let base = (emulator.memory.len() / 4).max(raw_end * 2) as u64;
+        let addresses = (0..self.samples)
+            .map(|_| rng.range(base, base + 4096) as u32)
+            .collect();
+
+        Ok((emulator, addresses))
+    }
+
+    pub fn map_load_functions(&self, module: &Module) -> Result<HashMap<FunctionId, MemEncFuncType>, anyhow::Error> {
+        let (mut emulator, addresses) = self.instantiate(module)?;
+
+        let noise = emulator.memory.clone();
+
+        let mut outputs = Vec::new();
+        for id in MemoryTransformer::with_profile(self.profile.clone()).find_mem_load_functions(module) {
+            // a candidate that isn't a wrapper may write anywhere
+            emulator.memory.clone_from(&noise);
+
+            let values = addresses
+                .iter()
+                .map(|a| {
+                    let result = self.call(&mut emulator, id, &[Value::I32(*a as i32), Value::I32(0)])?;
+                    result.first().map(bits)
+                })
+                .collect::<Option<Vec<_>>>();
+
+            // trapping isn't something a wrapper does
+            if let Some(values) = values {
+                outputs.push((id, values));
+            }
+        }
+
+        // The plain byte at every address is the low byte most candidates agree on,
+        // candidates that don't aren't wrappers
+        let mut votes = HashMap::<Vec<u8>, usize>::new();
+        for (_, values) in outputs.iter() {
+            *votes.entry(values.iter().map(|v| *v as u8).collect()).or_default() += 1;
+        }
+        let Some((low_bytes, _)) = votes.into_iter().filter(|(_, n)| *n > 1).max_by_key(|(_, n)| *n) else {
+            return Ok(HashMap::new());
+        };
+        let (reference, _) = outputs
+            .iter()
+            .find(|(_, values)| values.iter().map(|v| *v as u8).eq(low_bytes.iter().copied()))
+            .unwrap();
+
+        emulator.memory.clone_from(&noise);
+        let plain = addresses
+            .iter()
+            .map(|a| self.read_plain(&mut emulator, *reference, *a, 8).context("reference load trapped"))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut mapped = HashMap::new();
+        for (id, values) in outputs.iter() {
+            if !values.iter().map(|v| *v as u8).eq(low_bytes.iter().copied()) {
+                continue;
+            }
+
+            let result = module.types.get(module.funcs.get(*id).ty()).results()[0];
+            let candidates = LOAD_TYPES
+                .iter()
+                .filter(|ty| ty.val_type() == result)
+                .filter(|ty| values.iter().zip(plain.iter()).all(|(v, bytes)| *v == load_bits(**ty, bytes)))
+                .collect::<Vec<_>>();
+
+            match candidates[..] {
+                [ty] => {
+                    mapped.insert(*id, *ty);
+                }
+                [] => bail!("load function {:?} doesn't behave like any load", id),
+                _ => bail!("load function {:?} behaves like {:?}", id, candidates),
+            }
+        }
+
+        Ok(mapped)
+    }
+
+    // `loads` are needed to read the stored bytes back
+    pub fn map_store_functions(
+        &self,
+        module: &Module,
+        loads: &HashMap<FunctionId, MemEncFuncType>,
+    ) -> Result<HashMap<FunctionId, MemEncFuncType>, anyhow::Error> {
+        let (mut emulator, addresses) = self.instantiate(module)?;
+        let mut rng = Rng::new(self.seed ^ 0x5707e);
+
+        let mut loads = loads.keys().copied().collect::<Vec<_>>();
+        loads.sort_by_key(|id| id.index());
+        let load = *loads.first().context("no load function to read the stores back")?;
+
+        let noise = emulator.memory.clone();
+
+        let mut mapped = HashMap::new();
+        'candidates: for id in MemoryTransformer::with_profile(self.profile.clone()).find_mem_store_functions(module) {
+            emulator.memory.clone_from(&noise);
+            let value_type = module.types.get(module.funcs.get(id).ty()).params()[1];
+
+            // Bytes always holding the stored value. Wider is fine once in a while, the
+            // 16 bits wrapper of the bundled builds writes the whole i32 across pages.
+            let mut width = 8;
+            let mut written = false;
+            for address in addresses.iter() {
+                let value = random_value(&mut rng, value_type).context("unsupported store value type")?;
+                // the load wrapper may not work anymore after a function that isn't a store
+                let Some(before) = self.read_plain(&mut emulator, load, address - 8, 16) else {
+                    continue 'candidates;
+                };
+                if self.call(&mut emulator, id, &[Value::I32(*address as i32), value, Value::I32(0)]).is_none() {
+                    continue 'candidates;
+                }
+
+                let Some(after) = self.read_plain(&mut emulator, load, address - 8, 16) else {
+                    continue 'candidates;
+                };
+                written |= before != after;
+                // functions with inlined accessors are candidates too
+                if after[..8] != before[..8] {
+                    continue 'candidates;
+                }
+
+                let value = bits(&value).to_le_bytes();
+                width = [8, 4, 2, 1, 0]
+                    .into_iter()
+                    .filter(|w| *w <= width)
+                    .find(|w| after[8..8 + w] == value[..*w])
+                    .unwrap();
+            }
+
+            if !written || width == 0 {
+                continue;
+            }
+
+            match store_type(value_type, width as u32) {
+                Some(ty) => {
+                    mapped.insert(id, ty);
+                }
+                None => bail!("store function {:?} stores {} bytes of {}", id, width, value_type),
+            }
+        }
+
+        Ok(mapped)
+    }
+}
+
+// Functions the two classifications disagree on, by index
+pub fn cross_check(
+    static_types: &HashMap<FunctionId, MemEncFuncType>,
+    behavioral_types: &HashMap<FunctionId, MemEncFuncType>,
+) -> Vec<Mismatch> {
+    let mut mismatches = static_types
+        .keys()
+        .chain(behavioral_types.keys())
+        .filter(|id| static_types.get(id) != behavioral_types.get(id))
+        .map(|id| Mismatch {
+            func: *id,
+            static_type: static_types.get(id).copied(),
+            behavioral_type: behavioral_types.get(id).copied(),
+        })
+        .collect::<Vec<_>>();
+
+    mismatches.sort_by_key(|m| m.func.index());
+    mismatches.dedup();
+    mismatches
+}
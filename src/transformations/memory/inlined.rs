@@ -0,0 +1,284 @@
+use crate::transformations::memory::MemEncFuncType;
+use std::collections::{HashMap, VecDeque};
+use walrus::ir::{
+    dfs_in_order, BinaryOp, Binop, Block, IfElse, Instr, InstrSeq, InstrSeqId, Load, LocalGet, LocalSet, Loop, MemArg, Store,
+    Visitor,
+};
+use walrus::{FunctionId, LocalFunction, LocalId, Module, ModuleLocals};
+
+// A wrapper body spliced into a function by an inliner: the arguments are popped into
+// locals, last one first, then the body runs on those locals
+struct Inlined {
+    seq: InstrSeqId,
+    start: usize,
+    // from the first `local.set` to the end of the body
+    len: usize,
+    func_type: MemEncFuncType,
+    is_store: bool,
+    // locals holding the wrapper arguments
+    args: Vec<LocalId>,
+}
+
+#[derive(Default)]
+struct LocalUses {
+    uses: HashMap<LocalId, usize>,
+}
+
+impl<'a> Visitor<'a> for LocalUses {
+    fn visit_local_id(&mut self, local: &LocalId) {
+        *self.uses.entry(*local).or_default() += 1;
+    }
+}
+
+// Structural comparison of a wrapper body with function code, locals and blocks of the
+// wrapper are mapped one to one on those of the function
+struct Matcher<'a> {
+    locals: &'a ModuleLocals,
+    template: &'a LocalFunction,
+    func: &'a LocalFunction,
+    local_map: HashMap<LocalId, LocalId>,
+    seq_map: HashMap<InstrSeqId, InstrSeqId>,
+    uses: HashMap<LocalId, usize>,
+}
+
+impl Matcher<'_> {
+    fn local(&mut self, template: LocalId, func: LocalId) -> bool {
+        match self.local_map.get(&template) {
+            Some(mapped) if *mapped != func => return false,
+            Some(_) => {}
+            None => {
+                if self.local_map.values().any(|mapped| *mapped == func)
+                    || self.locals.get(template).ty() != self.locals.get(func).ty()
+                {
+                    return false;
+                }
+                self.local_map.insert(template, func);
+            }
+        }
+
+        *self.uses.entry(func).or_default() += 1;
+        true
+    }
+
+    fn seq(&mut self, template: InstrSeqId, func: InstrSeqId) -> bool {
+        let (t, f) = (self.template.block(template), self.func.block(func));
+        if format!("{:?}", t.ty) != format!("{:?}", f.ty) || t.instrs.len() != f.instrs.len() {
+            return false;
+        }
+
+        self.seq_map.insert(template, func);
+        self.instrs(t, f, 0)
+    }
+
+    fn instrs(&mut self, template: &InstrSeq, func: &InstrSeq, start: usize) -> bool {
+        template
+            .instrs
+            .iter()
+            .zip(func.instrs[start..].iter())
+            .all(|((t, _), (f, _))| self.instr(t, f))
+    }
+
+    fn target(&self, template: InstrSeqId, func: InstrSeqId) -> bool {
+        self.seq_map.get(&template) == Some(&func)
+    }
+
+    fn instr(&mut self, template: &Instr, func: &Instr) -> bool {
+        match (template, func) {
+            (Instr::LocalGet(t), Instr::LocalGet(f)) => self.local(t.local, f.local),
+            (Instr::LocalSet(t), Instr::LocalSet(f)) => self.local(t.local, f.local),
+            (Instr::LocalTee(t), Instr::LocalTee(f)) => self.local(t.local, f.local),
+            (Instr::Block(t), Instr::Block(f)) => self.seq(t.seq, f.seq),
+            (Instr::Loop(t), Instr::Loop(f)) => self.seq(t.seq, f.seq),
+            (Instr::IfElse(t), Instr::IfElse(f)) => {
+                self.seq(t.consequent, f.consequent) && self.seq(t.alternative, f.alternative)
+            }
+            (Instr::Br(t), Instr::Br(f)) => self.target(t.block, f.block),
+            (Instr::BrIf(t), Instr::BrIf(f)) => self.target(t.block, f.block),
+            (Instr::BrTable(t), Instr::BrTable(f)) => {
+                t.blocks.len() == f.blocks.len()
+                    && t.blocks.iter().zip(f.blocks.iter()).all(|(t, f)| self.target(*t, *f))
+                    && self.target(t.default, f.default)
+            }
+            // an inlined return doesn't return from the caller
+            (Instr::Return(_), _) => false,
+            (Instr::Call(t), Instr::Call(f)) => t.func == f.func,
+            (Instr::LocalGet(_) | Instr::LocalSet(_) | Instr::LocalTee(_), _)
+            | (Instr::Block(_) | Instr::Loop(_) | Instr::IfElse(_), _)
+            | (Instr::Br(_) | Instr::BrIf(_) | Instr::BrTable(_), _) => false,
+            (t, f) => format!("{:?}", t) == format!("{:?}", f),
+        }
+    }
+}
+
+// Locals set by `len` instructions from `start`, last argument first
+fn arg_locals(seq: &InstrSeq, start: usize, len: usize) -> Option<Vec<LocalId>> {
+    let mut args = seq
+        .instrs
+        .get(start..start + len)?
+        .iter()
+        .map(|(instr, _)| match instr {
+            Instr::LocalSet(LocalSet { local }) => Some(*local),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    args.reverse();
+    Some(args)
+}
+
+fn find_inlined(
+    module: &Module,
+    func: &LocalFunction,
+    wrappers: &[(FunctionId, MemEncFuncType, bool)],
+) -> Vec<Inlined> {
+    let mut uses = LocalUses::default();
+    dfs_in_order(&mut uses, func, func.entry_block());
+
+    let mut found = Vec::new();
+    let mut stack = VecDeque::new();
+    stack.push_front(func.entry_block());
+    while let Some(seq_id) = stack.pop_back() {
+        let seq = func.block(seq_id);
+
+        let mut idx = 0;
+        'instrs: while idx < seq.instrs.len() {
+            match &seq.instrs[idx].0 {
+                Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => stack.push_front(*seq),
+                Instr::IfElse(IfElse {
+                    consequent,
+                    alternative,
+                }) => {
+                    stack.push_front(*consequent);
+                    stack.push_front(*alternative);
+                }
+                _ => {}
+            }
+
+            for (wrapper, func_type, is_store) in wrappers.iter() {
+                let template = module.funcs.get(*wrapper).kind.unwrap_local();
+                let body = template.block(template.entry_block());
+                let arity = template.args.len();
+
+                let Some(args) = arg_locals(seq, idx, arity) else {
+                    continue;
+                };
+                if idx + arity + body.instrs.len() > seq.instrs.len() {
+                    continue;
+                }
+
+                let mut matcher = Matcher {
+                    locals: &module.locals,
+                    template,
+                    func,
+                    local_map: HashMap::new(),
+                    seq_map: HashMap::new(),
+                    uses: HashMap::new(),
+                };
+                if !template.args.iter().zip(args.iter()).all(|(t, f)| matcher.local(*t, *f))
+                    || !matcher.instrs(body, seq, idx + arity)
+                {
+                    continue;
+                }
+
+                // every local of the copy has to be dead outside of it
+                if matcher.uses.iter().any(|(local, n)| uses.uses[local] != *n) {
+                    continue;
+                }
+
+                found.push(Inlined {
+                    seq: seq_id,
+                    start: idx,
+                    len: arity + body.instrs.len(),
+                    func_type: *func_type,
+                    is_store: *is_store,
+                    args,
+                });
+                idx += arity + body.instrs.len();
+                continue 'instrs;
+            }
+
+            idx += 1;
+        }
+    }
+
+    found
+}
+
+// Rewrites inlined copies of the load and store wrappers into plain memory accesses,
+// returns how many were rewritten
+pub fn revert_inlined_accessors(
+    module: &mut Module,
+    loads: &HashMap<FunctionId, MemEncFuncType>,
+    stores: &HashMap<FunctionId, MemEncFuncType>,
+) -> usize {
+    let Some(memory) = module.memories.iter().next().map(|m| m.id()) else {
+        return 0;
+    };
+
+    let wrappers = loads
+        .iter()
+        .map(|(id, ty)| (*id, *ty, false))
+        .chain(stores.iter().map(|(id, ty)| (*id, *ty, true)))
+        .collect::<Vec<_>>();
+
+    let found = module
+        .funcs
+        .iter_local()
+        .filter(|(id, _)| !loads.contains_key(id) && !stores.contains_key(id))
+        .map(|(id, func)| (id, find_inlined(module, func, &wrappers)))
+        .filter(|(_, found)| !found.is_empty())
+        .collect::<Vec<_>>();
+
+    let mut count = 0;
+    for (id, found) in found {
+        let func = module.funcs.get_mut(id).kind.unwrap_local_mut();
+
+        // back to front, positions in the same sequence stay valid
+        for inlined in found.into_iter().rev() {
+            let seq = func.block_mut(inlined.seq);
+            let loc = seq.instrs[inlined.start].1;
+
+            let (idx, value, offset) = match inlined.args[..] {
+                [idx, value, offset] => (idx, Some(value), offset),
+                [idx, offset] => (idx, None, offset),
+                _ => unreachable!(),
+            };
+            let arg = MemArg {
+                align: inlined.func_type.width(),
+                offset: 0,
+            };
+
+            // the arguments stay in their locals
+            let mut access = vec![
+                Instr::LocalGet(LocalGet { local: idx }),
+                Instr::LocalGet(LocalGet { local: offset }),
+                Instr::Binop(Binop { op: BinaryOp::I32Add }),
+            ];
+            if inlined.is_store {
+                access.push(Instr::LocalGet(LocalGet {
+                    local: value.unwrap(),
+                }));
+                access.push(Instr::Store(Store {
+                    memory,
+                    kind: inlined.func_type.store_kind(),
+                    arg,
+                }));
+            } else {
+                access.push(Instr::Load(Load {
+                    memory,
+                    kind: inlined.func_type.load_kind(),
+                    arg,
+                }));
+            }
+
+            let arity = inlined.args.len();
+            seq.instrs.splice(
+                inlined.start + arity..inlined.start + inlined.len,
+                access.into_iter().map(|instr| (instr, loc)),
+            );
+            count += 1;
+        }
+    }
+
+    count
+}
@@ -0,0 +1,40 @@
+use std::collections::HashMap;
+use anyhow::bail;
+use walrus::ir::Instr;
+use walrus::{FunctionId, Module};
+use crate::transformations::memory::memory_encryption::{u8_load_function, MemoryEncryptionScheme};
+use crate::transformations::memory::MemEncFuncType;
+
+// Newer builds, the u8 load wrapper calls into the cipher instead of indexing a table.
+// Only detected for now.
+#[derive(Debug)]
+pub struct Chacha20MemoryEncryption;
+
+impl MemoryEncryptionScheme for Chacha20MemoryEncryption {
+    fn name(&self) -> &'static str {
+        "Chacha20"
+    }
+
+    fn detect(module: &Module, wrappers: &HashMap<FunctionId, MemEncFuncType>) -> Option<Self> {
+        let u8_load_func = u8_load_function(module, wrappers)?;
+
+        u8_load_func
+            .block(u8_load_func.entry_block())
+            .instrs
+            .iter()
+            .any(|(instr, _)| matches!(instr, Instr::Call(_)))
+            .then_some(Chacha20MemoryEncryption)
+    }
+
+    fn decrypt_segment(&self, _: &Module, _: usize, _: &[u8]) -> Result<(usize, Vec<u8>), anyhow::Error> {
+        bail!("Chacha20 is not supported yet")
+    }
+
+    fn encrypt_segment(&self, _: &Module, _: usize, _: &[u8]) -> Result<(usize, Vec<u8>), anyhow::Error> {
+        bail!("Chacha20 is not supported yet")
+    }
+
+    fn view(&self, _: &Module, _: &[u8], _: usize, _: usize) -> Result<Vec<u8>, anyhow::Error> {
+        bail!("Chacha20 is not supported yet")
+    }
+}
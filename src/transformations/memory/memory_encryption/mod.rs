@@ -0,0 +1,100 @@
+pub mod chacha20;
+pub mod xor;
+
+pub use chacha20::Chacha20MemoryEncryption;
+pub use xor::XorMemoryEncryption;
+
+use std::collections::HashMap;
+use std::fmt;
+use anyhow::Context;
+use walrus::{FunctionId, LocalFunction, Module};
+use crate::profile::Profile;
+use crate::transformations::memory::MemEncFuncType;
+
+// How a build encrypts its linear memory. Addresses are logical (what the wrappers take),
+// segments and memory images are physical.
+pub trait MemoryEncryptionScheme: fmt::Debug + Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // `wrappers` are the mapped load wrappers
+    fn detect(module: &Module, wrappers: &HashMap<FunctionId, MemEncFuncType>) -> Option<Self>
+    where
+        Self: Sized;
+
+    // Detection with the constants of a build profile, for schemes that have any
+    fn detect_with_profile(module: &Module, wrappers: &HashMap<FunctionId, MemEncFuncType>, _profile: &Profile) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        Self::detect(module, wrappers)
+    }
+
+    // Decrypts a data segment loaded at `start`, returns the logical address of the plain data
+    fn decrypt_segment(&self, module: &Module, start: usize, data: &[u8]) -> Result<(usize, Vec<u8>), anyhow::Error>;
+
+    // Inverse of `decrypt_segment`, returns where the encrypted segment has to be loaded
+    fn encrypt_segment(&self, module: &Module, start: usize, data: &[u8]) -> Result<(usize, Vec<u8>), anyhow::Error>;
+
+    // Plain bytes at `address` of a full memory image, may be short at the end of the memory
+    fn view(&self, module: &Module, memory: &[u8], address: usize, len: usize) -> Result<Vec<u8>, anyhow::Error>;
+}
+
+type Detector = fn(&Module, &HashMap<FunctionId, MemEncFuncType>, &Profile) -> Option<Box<dyn MemoryEncryptionScheme>>;
+
+// Schemes are tried in registration order, the first one detected wins
+pub struct SchemeRegistry {
+    detectors: Vec<Detector>,
+    // Constants of the build, the built-in ones by default
+    pub profile: Profile,
+}
+
+impl SchemeRegistry {
+    pub fn empty() -> Self {
+        SchemeRegistry {
+            detectors: Vec::new(),
+            profile: Profile::default(),
+        }
+    }
+
+    pub fn with_profile(mut self, profile: Profile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    pub fn register<S: MemoryEncryptionScheme + 'static>(&mut self) -> &mut Self {
+        self.detectors.push(|module, wrappers, profile| {
+            S::detect_with_profile(module, wrappers, profile).map(|scheme| Box::new(scheme) as Box<dyn MemoryEncryptionScheme>)
+        });
+        self
+    }
+
+    pub fn detect(&self, module: &Module, wrappers: &HashMap<FunctionId, MemEncFuncType>) -> Result<Box<dyn MemoryEncryptionScheme>, anyhow::Error> {
+        self.detectors
+            .iter()
+            .find_map(|detect| detect(module, wrappers, &self.profile))
+            .context("Failed to map memory encryption mode")
+    }
+}
+
+impl Default for SchemeRegistry {
+    fn default() -> Self {
+        let mut registry = SchemeRegistry::empty();
+        registry
+            .register::<XorMemoryEncryption>()
+            .register::<Chacha20MemoryEncryption>();
+        registry
+    }
+}
+
+// Most schemes are recognizable from the u8 load wrapper
+pub fn u8_load_function<'a>(module: &'a Module, wrappers: &HashMap<FunctionId, MemEncFuncType>) -> Option<&'a LocalFunction> {
+    wrappers
+        .iter()
+        .find(|(_, func_type)| matches!(func_type, MemEncFuncType::Unsigned8))
+        .map(|(id, _)| module.funcs.get(*id).kind.unwrap_local())
+}
+
+pub fn map_memory_encryption_mode(module: &Module, mapped_loads: &HashMap<FunctionId, MemEncFuncType>) -> Result<Box<dyn MemoryEncryptionScheme>, anyhow::Error> {
+    u8_load_function(module, mapped_loads).context("could not find u8 load func")?;
+    SchemeRegistry::default().detect(module, mapped_loads)
+}
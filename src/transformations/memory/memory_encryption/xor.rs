@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use anyhow::{bail, Context};
+use std::fmt;
+use walrus::{ConstExpr, DataKind, FunctionId, Module};
+use walrus::ir::Value;
+use crate::pattern::Pattern;
+use crate::profile::Profile;
+use crate::ssa::Function;
+use crate::transformations::memory::memory_encryption::{u8_load_function, MemoryEncryptionScheme};
+use crate::transformations::memory::MemEncFuncType;
+
+pub struct XorMemoryEncryption {
+    xor_table_start: usize,
+    profile: Profile,
+}
+
+// The profile is the same for every scheme of a run, it would only be noise
+impl fmt::Debug for XorMemoryEncryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("XorMemoryEncryption")
+            .field("xor_table_start", &self.xor_table_start)
+            .finish()
+    }
+}
+
+impl XorMemoryEncryption {
+    pub fn xor_table_start(&self) -> usize {
+        self.xor_table_start
+    }
+
+    // Retrieves xor table
+    // Needs a function that loads a primitive from the memory (preferably unsigned byte)
+    fn get_xor_table(&self, module: &Module) -> Result<Vec<u8>, anyhow::Error> {
+        let segment = self.profile.table_segment;
+        let data_segment = module
+            .data
+            .iter()
+            .nth(segment)
+            .with_context(|| format!("no table segment {}", segment))?;
+        let data_start = match &data_segment.kind {
+            DataKind::Active {
+                offset: ConstExpr::Value(Value::I32(i)),
+                ..
+            } => *i,
+            _ => bail!("table segment {} isn't at a constant address", segment),
+        } as usize;
+
+        self.xor_table_start
+            .checked_sub(data_start)
+            .and_then(|start| data_segment.value.get(start..start + self.profile.table_len))
+            .map(|xors| xors.to_vec())
+            .with_context(|| format!("xor table at {:#x} is outside of table segment {}", self.xor_table_start, segment))
+    }
+
+    fn read_byte(
+        &self,
+        data_start: usize,
+        data: &[u8],
+        xor_table: &[u8],
+        pos: usize,
+    ) -> Option<u8> {
+        let profile = &self.profile;
+        let var0 = pos;
+        let var1 = var0 / profile.page_size;
+        let var2 = var1 * profile.page_header + var0 + profile.pages_start + profile.page_header;
+
+        let v = xor_table[var0 % profile.table_len];
+        let flag = (var1 * profile.page_stride() + profile.pages_start).checked_sub(data_start)?;
+        let result = if *data.get(flag)? > 0 {
+            *data.get(var2.checked_sub(data_start)?)?
+        } else {
+            v
+        };
+
+        Some(result ^ v)
+    }
+}
+
+impl MemoryEncryptionScheme for XorMemoryEncryption {
+    fn name(&self) -> &'static str {
+        "Xor"
+    }
+
+    fn detect(module: &Module, wrappers: &HashMap<FunctionId, MemEncFuncType>) -> Option<Self> {
+        Self::detect_with_profile(module, wrappers, &Profile::default())
+    }
+
+    // The u8 load wrapper indexes the table with `address % table_len`
+    fn detect_with_profile(module: &Module, wrappers: &HashMap<FunctionId, MemEncFuncType>, profile: &Profile) -> Option<Self> {
+        let u8_load_func = u8_load_function(module, wrappers)?;
+
+        let ssa = Function::build(module, u8_load_func).ok()?;
+
+        // calls into something else (chacha20)
+        if !Pattern::parse("Call").unwrap().find(&ssa).is_empty() {
+            return None;
+        }
+
+        let pattern = Pattern::parse("I32Add(I32RemU(_, _), table @ i32)").unwrap();
+        let (_, captures) = pattern.find(&ssa).into_iter().next()?;
+        Some(XorMemoryEncryption {
+            xor_table_start: captures.i32(&ssa, "table")? as usize,
+            profile: profile.clone(),
+        })
+    }
+
+    fn decrypt_segment(&self, module: &Module, start: usize, data: &[u8]) -> Result<(usize, Vec<u8>), anyhow::Error> {
+        let profile = &self.profile;
+        let start_pos = start
+            .checked_sub((start / profile.page_size) * profile.page_header + profile.page_size + profile.start_skew)
+            .with_context(|| format!("data segment at {:#x} starts before the first page", start))?;
+        let mut new_data = Vec::<u8>::with_capacity(data.len());
+
+        let xor_table = self.get_xor_table(module)?;
+        for (i, _) in data.iter().enumerate() {
+            let pos = start_pos + i;
+
+            let res = self.read_byte(start, data, &xor_table, pos);
+            if let Some(res) = res {
+                new_data.push(res);
+            } else {
+                break;
+            }
+        }
+        if new_data.is_empty() && !data.is_empty() {
+            bail!("data segment at {:#x} doesn't hold the page of {:#x}", start, start_pos);
+        }
+
+        Ok((start_pos, new_data))
+    }
+
+    // Every page holding `data` is written out whole: flag, header padding and the
+    // encrypted page, bytes around `data` are encrypted zeros
+    fn encrypt_segment(&self, module: &Module, start: usize, data: &[u8]) -> Result<(usize, Vec<u8>), anyhow::Error> {
+        let profile = &self.profile;
+        let xor_table = self.get_xor_table(module)?;
+        let first_page = start / profile.page_size;
+        let last_page = (start + data.len()).saturating_sub(1).max(start) / profile.page_size;
+
+        let mut new_data = Vec::with_capacity((last_page - first_page + 1) * profile.page_stride());
+        for page in first_page..=last_page {
+            new_data.push(1);
+            new_data.extend(vec![0; profile.page_header - 1]);
+
+            for pos in page * profile.page_size..(page + 1) * profile.page_size {
+                let plain = pos
+                    .checked_sub(start)
+                    .and_then(|i| data.get(i))
+                    .copied()
+                    .unwrap_or(0);
+                new_data.push(plain ^ xor_table[pos % profile.table_len]);
+            }
+        }
+
+        Ok((first_page * profile.page_stride() + profile.pages_start, new_data))
+    }
+
+    // What the load wrappers would read byte by byte, stops at the end of the memory
+    fn view(&self, module: &Module, memory: &[u8], address: usize, len: usize) -> Result<Vec<u8>, anyhow::Error> {
+        let xor_table = self.get_xor_table(module)?;
+
+        Ok((address..address + len)
+            .map_while(|pos| self.read_byte(0, memory, &xor_table, pos))
+            .collect())
+    }
+}
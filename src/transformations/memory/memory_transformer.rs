@@ -1,39 +1,62 @@
-use crate::transformations::memory::visitors::{LoadMemoryFuncMapper, StoreMemoryFuncMapper};
+use crate::transformations::memory::inlined::revert_inlined_accessors;
+use crate::transformations::memory::visitors::{AccessorPatternFinder, LoadMemoryFuncMapper, StoreMemoryFuncMapper};
 use crate::transformations::memory::MemEncFuncType;
 use crate::transformations::Transformer;
-use std::collections::{HashMap, VecDeque};
-use walrus::ir::{
-    BinaryOp, Block, ExtendedLoad, IfElse, Instr, Load, LoadKind, Loop, MemArg, Store, StoreKind,
-    Value,
-};
-use walrus::{ConstExpr, DataKind, FunctionId, FunctionKind, InstrLocId, Module, ValType};
-use crate::transformations::memory::memory_encryption::map_memory_encryption_mode;
-
-pub struct MemoryTransformer {}
+use crate::pattern::Sequence;
+use crate::profile::Profile;
+use std::collections::HashMap;
+use anyhow::{bail, Context};
+use walrus::ir::{BinaryOp, Instr, Load, MemArg, Store, Value};
+use walrus::{ConstExpr, DataKind, FunctionId, FunctionKind, LocalFunction, Module, ValType};
+use crate::transformations::memory::memory_encryption::SchemeRegistry;
+
+#[derive(Default)]
+pub struct MemoryTransformer {
+    // Memory encryption schemes to try, the built-in ones by default
+    pub schemes: SchemeRegistry,
+}
 
+// Without an error channel a build it can't handle is reported and left as it is, the
+// module is only changed once everything is known
 impl Transformer for MemoryTransformer {
     fn transform(&mut self, module: &mut Module) {
-        let mapped_load_functions = self.map_load_functions(module);
-        let mapped_store_functions = self.map_store_functions(module);
-        let memory_encryption_mode = map_memory_encryption_mode(&module, &mapped_load_functions).unwrap();
+        if let Err(err) = self.try_transform(module) {
+            eprintln!("memory transformer skipped: {:#}", err);
+        }
+    }
+}
 
-        let wasm_data = module.data.iter().nth(1).unwrap();
+impl MemoryTransformer {
+    pub fn with_profile(profile: Profile) -> Self {
+        MemoryTransformer {
+            schemes: SchemeRegistry::default().with_profile(profile),
+        }
+    }
+
+    pub fn try_transform(&mut self, module: &mut Module) -> Result<(), anyhow::Error> {
+        let mapped_load_functions = self.map_load_functions(module)?;
+        let mapped_store_functions = self.map_store_functions(module)?;
+        let memory_encryption_mode = self.schemes.detect(module, &mapped_load_functions)?;
+
+        let data_segment = self.schemes.profile.data_segment;
+        let wasm_data = module
+            .data
+            .iter()
+            .nth(data_segment)
+            .with_context(|| format!("no data segment {}", data_segment))?;
         let data_start = match &wasm_data.kind {
-            DataKind::Active { offset, .. } => match *offset {
-                ConstExpr::Value(v) => match v {
-                    Value::I32(i) => i,
-                    _ => panic!(),
-                },
-                _ => panic!(),
-            },
-            _ => panic!(),
+            DataKind::Active {
+                offset: ConstExpr::Value(Value::I32(i)),
+                ..
+            } => *i,
+            _ => bail!("data segment {} isn't at a constant address", data_segment),
         } as usize;
         
-        let (start_pos, new_data) = memory_encryption_mode.decrypt(&module, data_start, &wasm_data.value);
+        let (start_pos, new_data) = memory_encryption_mode.decrypt_segment(module, data_start, &wasm_data.value)?;
 
         // replace data with our new decrypted data
         {
-            let mem_id = module.get_memory_id().unwrap();
+            let mem_id = module.get_memory_id()?;
             let data = module.data.get_mut(wasm_data.id());
 
             data.value = new_data;
@@ -45,15 +68,42 @@ impl Transformer for MemoryTransformer {
 
         self.revert_memory_loads(module, &mapped_load_functions);
         self.revert_memory_stores(module, &mapped_store_functions);
+        // before the wrapper bodies are rewritten, they are the templates
+        revert_inlined_accessors(module, &mapped_load_functions, &mapped_store_functions);
         self.rewrite_loads(module, &mapped_load_functions);
         self.rewrite_stores(module, &mapped_store_functions);
+
+        Ok(())
+    }
+
+    // Builds may call internal wrappers, what makes one is the page header check and the
+    // table lookup in its own body. Stores leave the header to a page init function they
+    // call, one with no result that has both itself. Other callees don't count, callers of
+    // a wrapper aren't wrappers.
+    fn is_accessor(&self, module: &Module, local: &LocalFunction, store: bool) -> bool {
+        let mut finder = AccessorPatternFinder::new(&self.schemes.profile);
+        if finder.find(local).found() {
+            return true;
+        }
+        if !store || !finder.has_table_lookup {
+            return false;
+        }
+
+        finder.callees.iter().any(|callee| {
+            let callee = module.funcs.get(*callee);
+            match &callee.kind {
+                FunctionKind::Local(local) => {
+                    module.types.get(callee.ty()).results().is_empty()
+                        && AccessorPatternFinder::new(&self.schemes.profile).find(local).found()
+                }
+                _ => false,
+            }
+        })
     }
-}
 
-impl MemoryTransformer {
     // Finds every function that could possibly match with mem load funcs.
-    // 2 params + 1 result + exported
-    fn find_mem_load_functions(&self, module: &Module) -> Vec<FunctionId> {
+    // 2 params + 1 result + accessor body
+    pub fn find_mem_load_functions(&self, module: &Module) -> Vec<FunctionId> {
         let mut functions = Vec::new();
 
         'a: for function in module.funcs.iter() {
@@ -75,7 +125,7 @@ impl MemoryTransformer {
                     continue 'a;
                 }
 
-                if !module.exports.get_exported_func(function_id).is_some() {
+                if !self.is_accessor(module, local, false) {
                     continue 'a;
                 }
 
@@ -86,7 +136,7 @@ impl MemoryTransformer {
         functions
     }
 
-    fn map_load_functions(&self, module: &mut Module) -> HashMap<FunctionId, MemEncFuncType> {
+    pub fn map_load_functions(&self, module: &Module) -> Result<HashMap<FunctionId, MemEncFuncType>, anyhow::Error> {
         let mut mapped_load_functions = HashMap::new();
         let load_functions = self.find_mem_load_functions(module);
 
@@ -96,9 +146,12 @@ impl MemoryTransformer {
             let t = module.types.get(local.ty());
 
             match t.results()[0] {
-                ValType::I32 => {
+                result @ (ValType::I32 | ValType::I64) => {
                     let mut visitor = LoadMemoryFuncMapper::default();
-                    if let Some(load_type) = visitor.map(&local) {
+                    if let Some(load_type) = visitor
+                        .map(local, result)
+                        .with_context(|| format!("Failed to map load function {:?}", id))?
+                    {
                         mapped_load_functions.insert(id, load_type);
                     }
                 }
@@ -108,14 +161,11 @@ impl MemoryTransformer {
                 ValType::F64 => {
                     mapped_load_functions.insert(id, MemEncFuncType::Float64);
                 }
-                ValType::I64 => {
-                    mapped_load_functions.insert(id, MemEncFuncType::Signed64);
-                }
                 _ => unreachable!(), // what the flip
             };
         }
 
-        mapped_load_functions
+        Ok(mapped_load_functions)
     }
 
     fn revert_memory_loads(
@@ -125,132 +175,12 @@ impl MemoryTransformer {
     ) {
         let memory_id = module.memories.iter().next().unwrap().id();
 
-        module.funcs.iter_local_mut().for_each(|(_, f)| {
-            let mut stack = VecDeque::new();
-            stack.push_front(f.entry_block());
-            while let Some(block_id) = stack.pop_back() {
-                let block = f.block_mut(block_id);
-
-                let mut replacements = Vec::<(usize, (Instr, InstrLocId))>::new();
-
-                for (idx, (instr, instr_id)) in block.instrs.iter().enumerate() {
-                    match instr {
-                        Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => {
-                            stack.push_front(*seq)
-                        }
-                        Instr::IfElse(IfElse {
-                            consequent,
-                            alternative,
-                        }) => {
-                            stack.push_front(*consequent);
-                            stack.push_front(*alternative);
-                        }
-                        Instr::Const(c) => match c.value {
-                            Value::I32(i) => {
-                                let next_instruction = block.instrs.get(idx + 1);
-                                if let Some((next_instruction, _)) = next_instruction {
-                                    if let Instr::Call(call) = next_instruction {
-                                        if let Some(func_type) = functions.get(&call.func) {
-                                            replacements.push((
-                                                idx,
-                                                (
-                                                    Instr::Load(match func_type {
-                                                        MemEncFuncType::Signed64 => Load {
-                                                            memory: memory_id,
-                                                            kind: LoadKind::I64 {
-                                                                atomic: false,
-                                                            },
-                                                            arg: MemArg {
-                                                                align: 8,
-                                                                offset: i as u32,
-                                                            },
-                                                        },
-                                                        MemEncFuncType::Signed32 => Load {
-                                                            memory: memory_id,
-                                                            kind: LoadKind::I32 {
-                                                                atomic: false,
-                                                            },
-                                                            arg: MemArg {
-                                                                align: 4,
-                                                                offset: i as u32,
-                                                            },
-                                                        },
-                                                        MemEncFuncType::Signed16 => Load {
-                                                            memory: memory_id,
-                                                            kind: LoadKind::I32_16 {
-                                                                kind: ExtendedLoad::SignExtend,
-                                                            },
-                                                            arg: MemArg {
-                                                                align: 2,
-                                                                offset: i as u32,
-                                                            },
-                                                        },
-                                                        MemEncFuncType::Unsigned16 => Load {
-                                                            memory: memory_id,
-                                                            kind: LoadKind::I32_16 {
-                                                                kind: ExtendedLoad::ZeroExtend,
-                                                            },
-                                                            arg: MemArg {
-                                                                align: 2,
-                                                                offset: i as u32,
-                                                            },
-                                                        },
-                                                        MemEncFuncType::Unsigned8 => Load {
-                                                            memory: memory_id,
-                                                            kind: LoadKind::I32_8 {
-                                                                kind: ExtendedLoad::ZeroExtend,
-                                                            },
-                                                            arg: MemArg {
-                                                                align: 1,
-                                                                offset: i as u32,
-                                                            },
-                                                        },
-                                                        MemEncFuncType::Signed8 => Load {
-                                                            memory: memory_id,
-                                                            kind: LoadKind::I32_8 {
-                                                                kind: ExtendedLoad::SignExtend,
-                                                            },
-                                                            arg: MemArg {
-                                                                align: 1,
-                                                                offset: i as u32,
-                                                            },
-                                                        },
-                                                        MemEncFuncType::Float32 => Load {
-                                                            memory: memory_id,
-                                                            kind: LoadKind::F32,
-                                                            arg: MemArg {
-                                                                align: 4,
-                                                                offset: i as u32,
-                                                            },
-                                                        },
-                                                        MemEncFuncType::Float64 => Load {
-                                                            memory: memory_id,
-                                                            kind: LoadKind::F64,
-                                                            arg: MemArg {
-                                                                align: 8,
-                                                                offset: i as u32,
-                                                            },
-                                                        },
-                                                    }),
-                                                    *instr_id,
-                                                ),
-                                            ));
-                                        }
-                                    }
-                                }
-                            }
-                            _ => {}
-                        },
-                        _ => {}
-                    };
-                }
-
-                replacements.reverse();
-                for (idx, (instr, seq)) in replacements {
-                    block.instrs[idx] = (instr, seq);
-                    block.instrs.remove(idx + 1);
-                }
-            }
+        revert_wrapper_calls(module, functions, |func_type, arg| {
+            Instr::Load(Load {
+                memory: memory_id,
+                kind: func_type.load_kind(),
+                arg,
+            })
         });
     }
 
@@ -261,114 +191,16 @@ impl MemoryTransformer {
     ) {
         let memory_id = module.memories.iter().next().unwrap().id();
 
-        module.funcs.iter_local_mut().for_each(|(_, f)| {
-            let mut stack = VecDeque::new();
-            stack.push_front(f.entry_block());
-            while let Some(block_id) = stack.pop_back() {
-                let block = f.block_mut(block_id);
-
-                let mut replacements = Vec::<(usize, (Instr, InstrLocId))>::new();
-
-                for (idx, (instr, instr_id)) in block.instrs.iter().enumerate() {
-                    match instr {
-                        Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => {
-                            stack.push_front(*seq)
-                        }
-                        Instr::IfElse(IfElse {
-                            consequent,
-                            alternative,
-                        }) => {
-                            stack.push_front(*consequent);
-                            stack.push_front(*alternative);
-                        }
-                        Instr::Const(c) => match c.value {
-                            Value::I32(i) => {
-                                let next_instruction = block.instrs.get(idx + 1);
-                                if let Some((next_instruction, _)) = next_instruction {
-                                    if let Instr::Call(call) = next_instruction {
-                                        if let Some(func_type) = functions.get(&call.func) {
-                                            replacements.push((
-                                                idx,
-                                                (
-                                                    Instr::Store(match func_type {
-                                                        MemEncFuncType::Signed64 => Store {
-                                                            memory: memory_id,
-                                                            kind: StoreKind::I64 { atomic: false },
-                                                            arg: MemArg {
-                                                                align: 8,
-                                                                offset: i as u32,
-                                                            },
-                                                        },
-                                                        MemEncFuncType::Signed32 => Store {
-                                                            memory: memory_id,
-                                                            kind: StoreKind::I32 { atomic: false },
-                                                            arg: MemArg {
-                                                                align: 4,
-                                                                offset: i as u32,
-                                                            },
-                                                        },
-                                                        MemEncFuncType::Signed16
-                                                        | MemEncFuncType::Unsigned16 => Store {
-                                                            memory: memory_id,
-                                                            kind: StoreKind::I32_16 {
-                                                                atomic: false,
-                                                            },
-                                                            arg: MemArg {
-                                                                align: 2,
-                                                                offset: i as u32,
-                                                            },
-                                                        },
-                                                        MemEncFuncType::Signed8
-                                                        | MemEncFuncType::Unsigned8 => Store {
-                                                            memory: memory_id,
-                                                            kind: StoreKind::I32_8 {
-                                                                atomic: false,
-                                                            },
-                                                            arg: MemArg {
-                                                                align: 1,
-                                                                offset: i as u32,
-                                                            },
-                                                        },
-                                                        MemEncFuncType::Float32 => Store {
-                                                            memory: memory_id,
-                                                            kind: StoreKind::F32,
-                                                            arg: MemArg {
-                                                                align: 4,
-                                                                offset: i as u32,
-                                                            },
-                                                        },
-                                                        MemEncFuncType::Float64 => Store {
-                                                            memory: memory_id,
-                                                            kind: StoreKind::F64,
-                                                            arg: MemArg {
-                                                                align: 8,
-                                                                offset: i as u32,
-                                                            },
-                                                        },
-                                                    }),
-                                                    *instr_id,
-                                                ),
-                                            ));
-                                        }
-                                    }
-                                }
-                            }
-                            _ => {}
-                        },
-                        _ => {}
-                    };
-                }
-
-                replacements.reverse(); // hell yeah
-                for (idx, (instr, seq)) in replacements {
-                    block.instrs[idx] = (instr, seq);
-                    block.instrs.remove(idx + 1);
-                }
-            }
+        revert_wrapper_calls(module, functions, |func_type, arg| {
+            Instr::Store(Store {
+                memory: memory_id,
+                kind: func_type.store_kind(),
+                arg,
+            })
         });
     }
 
-    fn find_mem_store_functions(&self, module: &Module) -> Vec<FunctionId> {
+    pub fn find_mem_store_functions(&self, module: &Module) -> Vec<FunctionId> {
         let mut functions = Vec::new();
 
         'a: for function in module.funcs.iter() {
@@ -386,11 +218,11 @@ impl MemoryTransformer {
                     }
                 }
 
-                if t.results().len() != 0 {
+                if !t.results().is_empty() {
                     continue 'a;
                 }
 
-                if !module.exports.get_exported_func(function_id).is_some() {
+                if !self.is_accessor(module, local, true) {
                     continue 'a;
                 }
 
@@ -401,7 +233,7 @@ impl MemoryTransformer {
         functions
     }
 
-    fn map_store_functions(&self, module: &mut Module) -> HashMap<FunctionId, MemEncFuncType> {
+    pub fn map_store_functions(&self, module: &Module) -> Result<HashMap<FunctionId, MemEncFuncType>, anyhow::Error> {
         let mut mapped_store_functions = HashMap::new();
         let store_functions = self.find_mem_store_functions(module);
 
@@ -410,35 +242,25 @@ impl MemoryTransformer {
             let local = func.kind.unwrap_local();
             let t = module.types.get(local.ty());
 
-            match &t.params()[1] {
-                ValType::I64 => {
-                    mapped_store_functions.insert(id, MemEncFuncType::Signed64);
-                }
-                ValType::F32 => {
-                    mapped_store_functions.insert(id, MemEncFuncType::Float32);
-                }
-                ValType::F64 => {
-                    mapped_store_functions.insert(id, MemEncFuncType::Float64);
-                }
-                _ => {
-                    let mut visitor = StoreMemoryFuncMapper::default();
-                    if let Some(load_type) = visitor.map(&local) {
-                        mapped_store_functions.insert(id, load_type);
-                    }
-                }
+            let mut visitor = StoreMemoryFuncMapper::default();
+            if let Some(store_type) = visitor
+                .map(local, t.params()[1])
+                .with_context(|| format!("Failed to map store function {:?}", id))?
+            {
+                mapped_store_functions.insert(id, store_type);
             }
         }
 
-        mapped_store_functions
+        Ok(mapped_store_functions)
     }
 
     fn rewrite_loads(&self, module: &mut Module, functions: &HashMap<FunctionId, MemEncFuncType>) {
         let memory_id = module.memories.iter().next().unwrap().id();
 
-        for (id, func_type) in functions.into_iter() {
+        for (id, func_type) in functions.iter() {
             let func = module.funcs.get_mut(*id).kind.unwrap_local_mut();
 
-            let idx_local = *func.args.get(0).unwrap();
+            let idx_local = *func.args.first().unwrap();
             let offset_local = *func.args.get(1).unwrap();
 
             func.builder_mut()
@@ -447,104 +269,15 @@ impl MemoryTransformer {
                 .local_get_at(1, offset_local)
                 .binop_at(2, BinaryOp::I32Add);
 
-            match func_type {
-                MemEncFuncType::Unsigned8 => {
-                    func.builder_mut().func_body().load_at(
-                        3,
-                        memory_id,
-                        LoadKind::I32_8 {
-                            kind: ExtendedLoad::ZeroExtend,
-                        },
-                        MemArg {
-                            align: 1,
-                            offset: 0,
-                        },
-                    );
-                }
-                MemEncFuncType::Signed8 => {
-                    func.builder_mut().func_body().load_at(
-                        3,
-                        memory_id,
-                        LoadKind::I32_8 {
-                            kind: ExtendedLoad::SignExtend,
-                        },
-                        MemArg {
-                            align: 1,
-                            offset: 0,
-                        },
-                    );
-                }
-                MemEncFuncType::Unsigned16 => {
-                    func.builder_mut().func_body().load_at(
-                        3,
-                        memory_id,
-                        LoadKind::I32_16 {
-                            kind: ExtendedLoad::ZeroExtend,
-                        },
-                        MemArg {
-                            align: 2,
-                            offset: 0,
-                        },
-                    );
-                }
-                MemEncFuncType::Signed16 => {
-                    func.builder_mut().func_body().load_at(
-                        3,
-                        memory_id,
-                        LoadKind::I32_16 {
-                            kind: ExtendedLoad::SignExtend,
-                        },
-                        MemArg {
-                            align: 2,
-                            offset: 0,
-                        },
-                    );
-                }
-                MemEncFuncType::Signed32 => {
-                    func.builder_mut().func_body().load_at(
-                        3,
-                        memory_id,
-                        LoadKind::I32 { atomic: false },
-                        MemArg {
-                            align: 4,
-                            offset: 0,
-                        },
-                    );
-                }
-                MemEncFuncType::Signed64 => {
-                    func.builder_mut().func_body().load_at(
-                        3,
-                        memory_id,
-                        LoadKind::I64 { atomic: false },
-                        MemArg {
-                            align: 8,
-                            offset: 0,
-                        },
-                    );
-                }
-                MemEncFuncType::Float32 => {
-                    func.builder_mut().func_body().load_at(
-                        3,
-                        memory_id,
-                        LoadKind::F32,
-                        MemArg {
-                            align: 4,
-                            offset: 0,
-                        },
-                    );
-                }
-                MemEncFuncType::Float64 => {
-                    func.builder_mut().func_body().load_at(
-                        3,
-                        memory_id,
-                        LoadKind::F64,
-                        MemArg {
-                            align: 8,
-                            offset: 0,
-                        },
-                    );
-                }
-            }
+            func.builder_mut().func_body().load_at(
+                3,
+                memory_id,
+                func_type.load_kind(),
+                MemArg {
+                    align: func_type.width(),
+                    offset: 0,
+                },
+            );
 
             func.builder_mut().func_body().return_at(4);
         }
@@ -553,10 +286,10 @@ impl MemoryTransformer {
     fn rewrite_stores(&self, module: &mut Module, functions: &HashMap<FunctionId, MemEncFuncType>) {
         let memory_id = module.memories.iter().next().unwrap().id();
 
-        for (id, func_type) in functions.into_iter() {
+        for (id, func_type) in functions.iter() {
             let func = module.funcs.get_mut(*id).kind.unwrap_local_mut();
 
-            let idx_local = *func.args.get(0).unwrap();
+            let idx_local = *func.args.first().unwrap();
             let value_local = *func.args.get(1).unwrap();
             let offset_local = *func.args.get(2).unwrap();
 
@@ -567,76 +300,52 @@ impl MemoryTransformer {
                 .binop_at(2, BinaryOp::I32Add)
                 .local_get_at(3, value_local);
 
-            match func_type {
-                MemEncFuncType::Unsigned8 | MemEncFuncType::Signed8 => {
-                    func.builder_mut().func_body().store_at(
-                        4,
-                        memory_id,
-                        StoreKind::I32_8 { atomic: false },
-                        MemArg {
-                            align: 1,
-                            offset: 0,
-                        },
-                    );
-                }
-                MemEncFuncType::Unsigned16 | MemEncFuncType::Signed16 => {
-                    func.builder_mut().func_body().store_at(
-                        4,
-                        memory_id,
-                        StoreKind::I32_16 { atomic: false },
-                        MemArg {
-                            align: 2,
-                            offset: 0,
-                        },
-                    );
-                }
-                MemEncFuncType::Signed32 => {
-                    func.builder_mut().func_body().store_at(
-                        4,
-                        memory_id,
-                        StoreKind::I32 { atomic: false },
-                        MemArg {
-                            align: 4,
-                            offset: 0,
-                        },
-                    );
-                }
-                MemEncFuncType::Signed64 => {
-                    func.builder_mut().func_body().store_at(
-                        4,
-                        memory_id,
-                        StoreKind::I64 { atomic: false },
-                        MemArg {
-                            align: 8,
-                            offset: 0,
-                        },
-                    );
-                }
-                MemEncFuncType::Float32 => {
-                    func.builder_mut().func_body().store_at(
-                        4,
-                        memory_id,
-                        StoreKind::F32,
-                        MemArg {
-                            align: 4,
-                            offset: 0,
-                        },
-                    );
-                }
-                MemEncFuncType::Float64 => {
-                    func.builder_mut().func_body().store_at(
-                        4,
-                        memory_id,
-                        StoreKind::F64,
-                        MemArg {
-                            align: 8,
-                            offset: 0,
-                        },
-                    );
-                }
-            }
+            func.builder_mut().func_body().store_at(
+                4,
+                memory_id,
+                func_type.store_kind(),
+                MemArg {
+                    align: func_type.width(),
+                    offset: 0,
+                },
+            );
 
             func.builder_mut().func_body().return_at(5);
         }
     }
-}
\ No newline at end of file
+}
+
+// `i32.const offset; call wrapper` becomes the access `access` builds for the wrapper type
+// and the offset, the other arguments are already on the stack
+fn revert_wrapper_calls(
+    module: &mut Module,
+    functions: &HashMap<FunctionId, MemEncFuncType>,
+    access: impl Fn(MemEncFuncType, MemArg) -> Instr,
+) {
+    let pattern = Sequence::parse("offset @ i32, wrapper @ Call").unwrap();
+
+    module.funcs.iter_local_mut().for_each(|(_, f)| {
+        let replacements = pattern
+            .find(f)
+            .into_iter()
+            .filter_map(|(seq, idx, captures)| {
+                let Some(Instr::Call(call)) = captures.get("wrapper") else {
+                    return None;
+                };
+                let func_type = functions.get(&call.func)?;
+                let arg = MemArg {
+                    align: func_type.width(),
+                    offset: captures.i32("offset")? as u32,
+                };
+                Some((seq, idx, access(*func_type, arg)))
+            })
+            .collect::<Vec<_>>();
+
+        // back to front, positions in the same block stay valid
+        for (seq, idx, instr) in replacements.into_iter().rev() {
+            let block = f.block_mut(seq);
+            block.instrs[idx].0 = instr;
+            block.instrs.remove(idx + 1);
+        }
+    });
+}
@@ -1,18 +1,187 @@
 pub mod memory_transformer;
 mod visitors;
-mod memory_encryption;
+pub mod memory_encryption;
+pub mod reencrypt;
+pub mod behavior;
+pub mod inlined;
 
-#[derive(Eq, PartialEq, Hash, Debug)]
-enum MemEncFuncType {
+use walrus::ir::{ExtendedLoad, LoadKind, StoreKind};
+use walrus::ValType;
+
+#[derive(Eq, PartialEq, Hash, Debug, Clone, Copy)]
+pub enum MemEncFuncType {
     Unsigned8,  // 1 byte
     Unsigned16, // 2 bytes
-    // Unsigned32, // 4 bytes
-    // Unsigned64, // don't think it exists
+    // no Unsigned32/Unsigned64, a full width load has no sign
     Signed8,  // 1 byte
     Signed16, // 2 bytes
     Signed32, // 4 bytes
     Signed64, // 8 bytes
 
+    // i64 results of narrower reads (i64.load8_s ... i64.load32_u)
+    I64Unsigned8,
+    I64Unsigned16,
+    I64Unsigned32,
+    I64Signed8,
+    I64Signed16,
+    I64Signed32,
+
     Float32,
     Float64,
-}
\ No newline at end of file
+}
+
+impl MemEncFuncType {
+    // Bytes read or written by the wrapper
+    pub fn width(&self) -> u32 {
+        match self {
+            MemEncFuncType::Unsigned8
+            | MemEncFuncType::Signed8
+            | MemEncFuncType::I64Unsigned8
+            | MemEncFuncType::I64Signed8 => 1,
+            MemEncFuncType::Unsigned16
+            | MemEncFuncType::Signed16
+            | MemEncFuncType::I64Unsigned16
+            | MemEncFuncType::I64Signed16 => 2,
+            MemEncFuncType::Signed32
+            | MemEncFuncType::I64Unsigned32
+            | MemEncFuncType::I64Signed32
+            | MemEncFuncType::Float32 => 4,
+            MemEncFuncType::Signed64 | MemEncFuncType::Float64 => 8,
+        }
+    }
+
+    // Type of the loaded (or stored) value
+    pub fn val_type(&self) -> ValType {
+        match self {
+            MemEncFuncType::Unsigned8
+            | MemEncFuncType::Unsigned16
+            | MemEncFuncType::Signed8
+            | MemEncFuncType::Signed16
+            | MemEncFuncType::Signed32 => ValType::I32,
+            MemEncFuncType::Signed64
+            | MemEncFuncType::I64Unsigned8
+            | MemEncFuncType::I64Unsigned16
+            | MemEncFuncType::I64Unsigned32
+            | MemEncFuncType::I64Signed8
+            | MemEncFuncType::I64Signed16
+            | MemEncFuncType::I64Signed32 => ValType::I64,
+            MemEncFuncType::Float32 => ValType::F32,
+            MemEncFuncType::Float64 => ValType::F64,
+        }
+    }
+
+    pub fn load_kind(&self) -> LoadKind {
+        match self {
+            MemEncFuncType::Unsigned8 => LoadKind::I32_8 {
+                kind: ExtendedLoad::ZeroExtend,
+            },
+            MemEncFuncType::Signed8 => LoadKind::I32_8 {
+                kind: ExtendedLoad::SignExtend,
+            },
+            MemEncFuncType::Unsigned16 => LoadKind::I32_16 {
+                kind: ExtendedLoad::ZeroExtend,
+            },
+            MemEncFuncType::Signed16 => LoadKind::I32_16 {
+                kind: ExtendedLoad::SignExtend,
+            },
+            MemEncFuncType::Signed32 => LoadKind::I32 { atomic: false },
+            MemEncFuncType::Signed64 => LoadKind::I64 { atomic: false },
+            MemEncFuncType::I64Unsigned8 => LoadKind::I64_8 {
+                kind: ExtendedLoad::ZeroExtend,
+            },
+            MemEncFuncType::I64Signed8 => LoadKind::I64_8 {
+                kind: ExtendedLoad::SignExtend,
+            },
+            MemEncFuncType::I64Unsigned16 => LoadKind::I64_16 {
+                kind: ExtendedLoad::ZeroExtend,
+            },
+            MemEncFuncType::I64Signed16 => LoadKind::I64_16 {
+                kind: ExtendedLoad::SignExtend,
+            },
+            MemEncFuncType::I64Unsigned32 => LoadKind::I64_32 {
+                kind: ExtendedLoad::ZeroExtend,
+            },
+            MemEncFuncType::I64Signed32 => LoadKind::I64_32 {
+                kind: ExtendedLoad::SignExtend,
+            },
+            MemEncFuncType::Float32 => LoadKind::F32,
+            MemEncFuncType::Float64 => LoadKind::F64,
+        }
+    }
+
+    // Stores only truncate, signed and unsigned types share one
+    pub fn store_kind(&self) -> StoreKind {
+        match self {
+            MemEncFuncType::Unsigned8 | MemEncFuncType::Signed8 => StoreKind::I32_8 { atomic: false },
+            MemEncFuncType::Unsigned16 | MemEncFuncType::Signed16 => StoreKind::I32_16 { atomic: false },
+            MemEncFuncType::Signed32 => StoreKind::I32 { atomic: false },
+            MemEncFuncType::Signed64 => StoreKind::I64 { atomic: false },
+            MemEncFuncType::I64Unsigned8 | MemEncFuncType::I64Signed8 => StoreKind::I64_8 { atomic: false },
+            MemEncFuncType::I64Unsigned16 | MemEncFuncType::I64Signed16 => StoreKind::I64_16 { atomic: false },
+            MemEncFuncType::I64Unsigned32 | MemEncFuncType::I64Signed32 => StoreKind::I64_32 { atomic: false },
+            MemEncFuncType::Float32 => StoreKind::F32,
+            MemEncFuncType::Float64 => StoreKind::F64,
+        }
+    }
+
+    // None for atomic and SIMD loads, the wrappers never stand for them
+    pub fn from_load_kind(kind: &LoadKind) -> Option<Self> {
+        let ty = match kind {
+            LoadKind::I32_8 {
+                kind: ExtendedLoad::ZeroExtend,
+            } => MemEncFuncType::Unsigned8,
+            LoadKind::I32_8 {
+                kind: ExtendedLoad::SignExtend,
+            } => MemEncFuncType::Signed8,
+            LoadKind::I32_16 {
+                kind: ExtendedLoad::ZeroExtend,
+            } => MemEncFuncType::Unsigned16,
+            LoadKind::I32_16 {
+                kind: ExtendedLoad::SignExtend,
+            } => MemEncFuncType::Signed16,
+            LoadKind::I32 { atomic: false } => MemEncFuncType::Signed32,
+            LoadKind::I64 { atomic: false } => MemEncFuncType::Signed64,
+            LoadKind::I64_8 {
+                kind: ExtendedLoad::ZeroExtend,
+            } => MemEncFuncType::I64Unsigned8,
+            LoadKind::I64_8 {
+                kind: ExtendedLoad::SignExtend,
+            } => MemEncFuncType::I64Signed8,
+            LoadKind::I64_16 {
+                kind: ExtendedLoad::ZeroExtend,
+            } => MemEncFuncType::I64Unsigned16,
+            LoadKind::I64_16 {
+                kind: ExtendedLoad::SignExtend,
+            } => MemEncFuncType::I64Signed16,
+            LoadKind::I64_32 {
+                kind: ExtendedLoad::ZeroExtend,
+            } => MemEncFuncType::I64Unsigned32,
+            LoadKind::I64_32 {
+                kind: ExtendedLoad::SignExtend,
+            } => MemEncFuncType::I64Signed32,
+            LoadKind::F32 => MemEncFuncType::Float32,
+            LoadKind::F64 => MemEncFuncType::Float64,
+            _ => return None,
+        };
+
+        Some(ty)
+    }
+
+    // The signed type for integer stores
+    pub fn from_store_kind(kind: &StoreKind) -> Option<Self> {
+        let ty = match kind {
+            StoreKind::I32_8 { atomic: false } => MemEncFuncType::Signed8,
+            StoreKind::I32_16 { atomic: false } => MemEncFuncType::Signed16,
+            StoreKind::I32 { atomic: false } => MemEncFuncType::Signed32,
+            StoreKind::I64 { atomic: false } => MemEncFuncType::Signed64,
+            StoreKind::I64_8 { atomic: false } => MemEncFuncType::I64Signed8,
+            StoreKind::I64_16 { atomic: false } => MemEncFuncType::I64Signed16,
+            StoreKind::I64_32 { atomic: false } => MemEncFuncType::I64Signed32,
+            StoreKind::F32 => MemEncFuncType::Float32,
+            StoreKind::F64 => MemEncFuncType::Float64,
+            _ => return None,
+        };
+
+        Some(ty)
+    }
+}
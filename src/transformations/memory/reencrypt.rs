@@ -0,0 +1,74 @@
+use crate::transformations::memory::memory_encryption::{MemoryEncryptionScheme, SchemeRegistry};
+use crate::transformations::memory::memory_transformer::MemoryTransformer;
+use anyhow::{bail, Context};
+use walrus::ir::Value;
+use walrus::{ConstExpr, DataId, DataKind, Module};
+
+// The encrypted data segment of a module, decrypted in place while the code still goes
+// through the wrappers. `encrypt` puts it back where the loader expects it.
+pub struct DecryptedMemory {
+    scheme: Box<dyn MemoryEncryptionScheme>,
+    segment: DataId,
+    // physical offset of the encrypted segment
+    offset: usize,
+}
+
+fn segment_offset(module: &Module, id: DataId) -> Result<usize, anyhow::Error> {
+    match &module.data.get(id).kind {
+        DataKind::Active {
+            offset: ConstExpr::Value(Value::I32(i)),
+            ..
+        } => Ok(*i as usize),
+        _ => bail!("data segment has no constant offset"),
+    }
+}
+
+fn replace_segment(module: &mut Module, id: DataId, offset: usize, value: Vec<u8>) -> Result<(), anyhow::Error> {
+    let memory = module.get_memory_id()?;
+    let segment = module.data.get_mut(id);
+
+    segment.value = value;
+    segment.kind = DataKind::Active {
+        memory,
+        offset: ConstExpr::Value(Value::I32(offset as i32)),
+    };
+
+    Ok(())
+}
+
+impl DecryptedMemory {
+    pub fn decrypt(module: &mut Module, schemes: &SchemeRegistry) -> Result<Self, anyhow::Error> {
+        let mapped_loads = MemoryTransformer::with_profile(schemes.profile.clone()).map_load_functions(module)?;
+        let scheme = schemes.detect(module, &mapped_loads)?;
+
+        let segment = module.data.iter().nth(schemes.profile.data_segment).context("Could not find memory")?.id();
+        let offset = segment_offset(module, segment)?;
+        let (start, data) = scheme.decrypt_segment(module, offset, &module.data.get(segment).value)?;
+        replace_segment(module, segment, start, data)?;
+
+        Ok(DecryptedMemory {
+            scheme,
+            segment,
+            offset,
+        })
+    }
+
+    // Passes may have changed the plain data but not moved it, the encrypted segment
+    // has to land at its original offset
+    pub fn encrypt(self, module: &mut Module) -> Result<(), anyhow::Error> {
+        let start = segment_offset(module, self.segment)?;
+        let (offset, data) = self
+            .scheme
+            .encrypt_segment(module, start, &module.data.get(self.segment).value)?;
+
+        if offset != self.offset {
+            bail!(
+                "re-encrypted segment would be loaded at {:#x} instead of {:#x}",
+                offset,
+                self.offset
+            );
+        }
+
+        replace_segment(module, self.segment, offset, data)
+    }
+}
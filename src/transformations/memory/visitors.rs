@@ -1,110 +1,271 @@
-use walrus::ir::{dfs_in_order, BinaryOp, Binop, Const, Load, Store, StoreKind, Value, Visitor};
-use walrus::LocalFunction;
+use crate::profile::Profile;
 use crate::transformations::memory::MemEncFuncType;
+use anyhow::{bail, Context};
+use std::collections::VecDeque;
+use walrus::ir::{
+    dfs_in_order, BinaryOp, Binop, Call, Const, Instr, Load, Store, StoreKind, UnaryOp, Unop, Value,
+    Visitor,
+};
+use walrus::{FunctionId, InstrLocId, LocalFunction, ValType};
 
 #[derive(Default)]
 pub struct LoadMemoryFuncMapper {
     has_load: bool,
-
-    has_16_bits_mask: bool,
-    has_8_bits_mask: bool,
-    has_24_bits_shl: bool,
-    has_right_shift_signed: bool,
-    has_left_shift: bool,
 }
 
 impl LoadMemoryFuncMapper {
-    pub fn map(&mut self, local: &LocalFunction) -> Option<MemEncFuncType> {
+    // The type comes from how the decrypted value is narrowed at the very end of the
+    // wrapper (mask, shift pair or extension). Functions without loads aren't wrappers,
+    // anything else that ends in an unknown way is an error.
+    pub fn map(
+        &mut self,
+        local: &LocalFunction,
+        result: ValType,
+    ) -> Result<Option<MemEncFuncType>, anyhow::Error> {
         dfs_in_order(self, local, local.entry_block());
         if !self.has_load {
-            return None;
-        }
-
-        if self.has_left_shift && self.has_24_bits_shl {
-            return Some(MemEncFuncType::Signed8);
+            return Ok(None);
         }
 
-        if self.has_right_shift_signed {
-            if self.has_16_bits_mask {
-                return Some(MemEncFuncType::Signed16);
-            } else if self.has_8_bits_mask {
-                return Some(MemEncFuncType::Signed8);
-            }
-        } else {
-            if self.has_16_bits_mask {
-                return Some(MemEncFuncType::Unsigned16);
-            } else if self.has_8_bits_mask {
-                return Some(MemEncFuncType::Unsigned8);
-            }
-        }
+        let instrs = local
+            .block(local.entry_block())
+            .instrs
+            .iter()
+            .map(|(instr, _)| instr)
+            .collect::<Vec<_>>();
+        let load_type = match result {
+            ValType::I32 => map_i32_tail(&instrs),
+            ValType::I64 => map_i64_tail(&instrs),
+            _ => None,
+        };
 
-        Some(MemEncFuncType::Signed32)
+        load_type.map(Some).with_context(|| {
+            format!(
+                "unrecognized {} load wrapper ending with {:?}",
+                result,
+                instrs.last()
+            )
+        })
     }
 }
 
 impl<'a> Visitor<'a> for LoadMemoryFuncMapper {
-    fn visit_const(&mut self, instr: &Const) {
-        match instr.value {
-            Value::I32(i) => {
-                if i == 65535 {
-                    self.has_16_bits_mask = true;
-                }
-
-                if i == 24 {
-                    self.has_24_bits_shl = true;
-                }
-
-                if i == 255 {
-                    self.has_8_bits_mask = true;
-                }
-            }
-            _ => {}
-        }
+    fn visit_load(&mut self, _: &Load) {
+        self.has_load = true;
     }
+}
 
-    fn visit_binop(&mut self, instr: &Binop) {
-        match instr.op {
-            BinaryOp::I32ShrS => {
-                self.has_right_shift_signed = true;
-            }
-            BinaryOp::I32Shl => {
-                self.has_left_shift = true;
+fn i32_const(instr: &Instr) -> Option<i32> {
+    match instr {
+        Instr::Const(Const {
+            value: Value::I32(i),
+        }) => Some(*i),
+        _ => None,
+    }
+}
+
+fn i64_const(instr: &Instr) -> Option<i64> {
+    match instr {
+        Instr::Const(Const {
+            value: Value::I64(i),
+        }) => Some(*i),
+        _ => None,
+    }
+}
+
+fn map_i32_tail(instrs: &[&Instr]) -> Option<MemEncFuncType> {
+    match instrs {
+        // (x << n) >> n
+        [.., a, Instr::Binop(Binop { op: BinaryOp::I32Shl }), b, Instr::Binop(Binop { op: BinaryOp::I32ShrS })] => {
+            match (i32_const(a)?, i32_const(b)?) {
+                (24, 24) => Some(MemEncFuncType::Signed8),
+                (16, 16) => Some(MemEncFuncType::Signed16),
+                _ => None,
             }
-            _ => {}
         }
+        [.., Instr::Unop(Unop { op: UnaryOp::I32Extend8S })] => Some(MemEncFuncType::Signed8),
+        [.., Instr::Unop(Unop { op: UnaryOp::I32Extend16S })] => Some(MemEncFuncType::Signed16),
+        [.., mask, Instr::Binop(Binop { op: BinaryOp::I32And })] => match i32_const(mask)? {
+            255 => Some(MemEncFuncType::Unsigned8),
+            65535 => Some(MemEncFuncType::Unsigned16),
+            _ => None,
+        },
+        // the decryption xor or merging bytes, nothing narrowed
+        [.., Instr::Binop(Binop { op: BinaryOp::I32Xor | BinaryOp::I32Or }) | Instr::Load(_)] => {
+            Some(MemEncFuncType::Signed32)
+        }
+        _ => None,
     }
+}
 
-    fn visit_load(&mut self, _: &Load) {
-        self.has_load = true;
+fn map_i64_tail(instrs: &[&Instr]) -> Option<MemEncFuncType> {
+    match instrs {
+        // narrowed as an i32 then extended
+        [rest @ .., Instr::Unop(Unop { op: UnaryOp::I64ExtendSI32 })] => match map_i32_tail(rest)? {
+            MemEncFuncType::Unsigned8 => Some(MemEncFuncType::I64Unsigned8),
+            MemEncFuncType::Unsigned16 => Some(MemEncFuncType::I64Unsigned16),
+            MemEncFuncType::Signed8 => Some(MemEncFuncType::I64Signed8),
+            MemEncFuncType::Signed16 => Some(MemEncFuncType::I64Signed16),
+            MemEncFuncType::Signed32 => Some(MemEncFuncType::I64Signed32),
+            _ => None,
+        },
+        // zero extending a sign extended value is no load
+        [rest @ .., Instr::Unop(Unop { op: UnaryOp::I64ExtendUI32 })] => match map_i32_tail(rest)? {
+            MemEncFuncType::Unsigned8 => Some(MemEncFuncType::I64Unsigned8),
+            MemEncFuncType::Unsigned16 => Some(MemEncFuncType::I64Unsigned16),
+            MemEncFuncType::Signed32 => Some(MemEncFuncType::I64Unsigned32),
+            _ => None,
+        },
+        [.., a, Instr::Binop(Binop { op: BinaryOp::I64Shl }), b, Instr::Binop(Binop { op: BinaryOp::I64ShrS })] => {
+            match (i64_const(a)?, i64_const(b)?) {
+                (56, 56) => Some(MemEncFuncType::I64Signed8),
+                (48, 48) => Some(MemEncFuncType::I64Signed16),
+                (32, 32) => Some(MemEncFuncType::I64Signed32),
+                _ => None,
+            }
+        }
+        [.., Instr::Unop(Unop { op: UnaryOp::I64Extend8S })] => Some(MemEncFuncType::I64Signed8),
+        [.., Instr::Unop(Unop { op: UnaryOp::I64Extend16S })] => Some(MemEncFuncType::I64Signed16),
+        [.., Instr::Unop(Unop { op: UnaryOp::I64Extend32S })] => Some(MemEncFuncType::I64Signed32),
+        [.., mask, Instr::Binop(Binop { op: BinaryOp::I64And })] => match i64_const(mask)? {
+            0xff => Some(MemEncFuncType::I64Unsigned8),
+            0xffff => Some(MemEncFuncType::I64Unsigned16),
+            0xffff_ffff => Some(MemEncFuncType::I64Unsigned32),
+            _ => None,
+        },
+        [.., Instr::Binop(Binop { op: BinaryOp::I64Xor | BinaryOp::I64Or }) | Instr::Load(_)] => {
+            Some(MemEncFuncType::Signed64)
+        }
+        _ => None,
     }
 }
 
 #[derive(Default)]
 pub struct StoreMemoryFuncMapper {
-    has_store: bool,
-    store_kind: Option<StoreKind>,
+    // in visiting order, the wide store comes after any byte by byte fallback
+    store_kinds: Vec<StoreKind>,
+    has_f32_reinterpret: bool,
+    has_f64_reinterpret: bool,
+    has_int_reinterpret: bool,
+}
+
+// Bytes written by a store, None for atomic and SIMD stores
+fn store_width(kind: &StoreKind) -> Option<u32> {
+    match kind {
+        StoreKind::I32_8 { atomic: false } | StoreKind::I64_8 { atomic: false } => Some(1),
+        StoreKind::I32_16 { atomic: false } | StoreKind::I64_16 { atomic: false } => Some(2),
+        StoreKind::I32 { atomic: false } | StoreKind::I64_32 { atomic: false } | StoreKind::F32 => Some(4),
+        StoreKind::I64 { atomic: false } | StoreKind::F64 => Some(8),
+        _ => None,
+    }
 }
 
 impl StoreMemoryFuncMapper {
-    pub fn map(&mut self, local: &LocalFunction) -> Option<MemEncFuncType> {
+    // `value` is the type of the stored value parameter. The last store gives the width,
+    // floats have to be stored as they are or reinterpreted as integers of the same size.
+    pub fn map(&mut self, local: &LocalFunction, value: ValType) -> Result<Option<MemEncFuncType>, anyhow::Error> {
         dfs_in_order(self, local, local.entry_block());
-        if !self.has_store {
-            return None;
-        }
-        
-        match self.store_kind.as_ref().unwrap() {
-            StoreKind::I32_8 {..} => Some(MemEncFuncType::Signed8),
-            StoreKind::I32_16 {..} => Some(MemEncFuncType::Signed16),
-            StoreKind::I32 { .. } => Some(MemEncFuncType::Signed32),
-            StoreKind::I64 { .. } => Some(MemEncFuncType::Float64),
-            _ => unreachable!(),
-        }
+        let Some(last) = self.store_kinds.last() else {
+            return Ok(None);
+        };
+
+        let width = store_width(last).with_context(|| format!("unsupported store {:?} in store wrapper", last))?;
+        let float_store = matches!(last, StoreKind::F32 | StoreKind::F64);
+
+        let store_type = match (value, width) {
+            (ValType::F32, 4) if float_store || self.has_f32_reinterpret => MemEncFuncType::Float32,
+            (ValType::F64, 8) if float_store || self.has_f64_reinterpret => MemEncFuncType::Float64,
+            (ValType::I32 | ValType::I64, _)
+                if float_store || self.has_f32_reinterpret || self.has_f64_reinterpret || self.has_int_reinterpret =>
+            {
+                bail!("{} store wrapper goes through floats", value)
+            }
+            (ValType::I32, 1) => MemEncFuncType::Signed8,
+            (ValType::I32, 2) => MemEncFuncType::Signed16,
+            (ValType::I32, 4) => MemEncFuncType::Signed32,
+            (ValType::I64, 1) => MemEncFuncType::I64Signed8,
+            (ValType::I64, 2) => MemEncFuncType::I64Signed16,
+            (ValType::I64, 4) => MemEncFuncType::I64Signed32,
+            (ValType::I64, 8) => MemEncFuncType::Signed64,
+            _ => bail!("unrecognized {} store wrapper storing {} bytes last ({:?})", value, width, last),
+        };
+
+        Ok(Some(store_type))
     }
 }
 
 impl<'a> Visitor<'a> for StoreMemoryFuncMapper {
     fn visit_store(&mut self, instr: &Store) {
-        self.has_store = true;
-        self.store_kind = Some(instr.kind.clone());
+        self.store_kinds.push(instr.kind);
+    }
+
+    fn visit_unop(&mut self, instr: &Unop) {
+        match instr.op {
+            UnaryOp::I32ReinterpretF32 => self.has_f32_reinterpret = true,
+            UnaryOp::I64ReinterpretF64 => self.has_f64_reinterpret = true,
+            UnaryOp::F32ReinterpretI32 | UnaryOp::F64ReinterpretI64 => self.has_int_reinterpret = true,
+            _ => {}
+        }
+    }
+}
+
+// The page header address (`page * 328 + 1024`) and the table index (`% 96`) that every
+// accessor computes, see `read_byte`. The constants come from the build profile.
+pub struct AccessorPatternFinder {
+    recent: VecDeque<Instr>,
+    page_stride: i32,
+    pages_start: i32,
+    table_len: i32,
+    pub has_header: bool,
+    pub has_table_lookup: bool,
+    pub callees: Vec<FunctionId>,
+}
+
+impl AccessorPatternFinder {
+    pub fn new(profile: &Profile) -> Self {
+        AccessorPatternFinder {
+            recent: VecDeque::new(),
+            page_stride: profile.page_stride() as i32,
+            pages_start: profile.pages_start as i32,
+            table_len: profile.table_len as i32,
+            has_header: false,
+            has_table_lookup: false,
+            callees: Vec::new(),
+        }
+    }
+
+    pub fn find(&mut self, local: &LocalFunction) -> &mut Self {
+        dfs_in_order(self, local, local.entry_block());
+        self
+    }
+
+    pub fn found(&self) -> bool {
+        self.has_header && self.has_table_lookup
+    }
+}
+
+impl<'a> Visitor<'a> for AccessorPatternFinder {
+    fn visit_instr(&mut self, instr: &'a Instr, _: &'a InstrLocId) {
+        if self.recent.len() == 4 {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(instr.clone());
+
+        let recent = self.recent.iter().collect::<Vec<_>>();
+        match recent[..] {
+            [a, Instr::Binop(Binop { op: BinaryOp::I32Mul }), b, Instr::Binop(Binop { op: BinaryOp::I32Add })]
+                if i32_const(a) == Some(self.page_stride) && i32_const(b) == Some(self.pages_start) =>
+            {
+                self.has_header = true
+            }
+            [.., a, Instr::Binop(Binop { op: BinaryOp::I32RemU })] if i32_const(a) == Some(self.table_len) => {
+                self.has_table_lookup = true
+            }
+            _ => {}
+        }
+    }
+
+    fn visit_call(&mut self, instr: &Call) {
+        self.callees.push(instr.func);
     }
 }
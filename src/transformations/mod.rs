@@ -1,4 +1,9 @@
+pub mod bulk_memory;
+pub mod globals;
+pub mod indirect_calls;
+pub mod mba;
 pub mod memory;
+pub mod signatures;
 
 use walrus::Module;
 
@@ -0,0 +1,30 @@
+use crate::analysis::fingerprint::fingerprint_module;
+use crate::analysis::signatures::SignatureDb;
+use crate::transformations::Transformer;
+use walrus::Module;
+
+// Names every local function whose normalized body is in the signature db
+pub struct SignatureTransformer {
+    pub db: SignatureDb,
+    pub applied: usize,
+}
+
+impl SignatureTransformer {
+    pub fn new(db: SignatureDb) -> Self {
+        SignatureTransformer { db, applied: 0 }
+    }
+}
+
+impl Transformer for SignatureTransformer {
+    fn transform(&mut self, module: &mut Module) {
+        let named = fingerprint_module(module)
+            .into_iter()
+            .filter_map(|fingerprint| Some((fingerprint.id, self.db.get(&fingerprint)?.clone())))
+            .collect::<Vec<_>>();
+
+        self.applied = named.len();
+        for (id, name) in named {
+            module.funcs.get_mut(id).name = Some(name);
+        }
+    }
+}
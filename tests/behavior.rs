@@ -0,0 +1,93 @@
+mod support;
+
+use hcaptcha_wasm_deobfuscator::commands::load_module;
+use hcaptcha_wasm_deobfuscator::profile::Profile;
+use hcaptcha_wasm_deobfuscator::rng::Rng;
+use hcaptcha_wasm_deobfuscator::transformations::memory::behavior::{cross_check, BehavioralMapper, Mismatch};
+use hcaptcha_wasm_deobfuscator::transformations::memory::memory_transformer::MemoryTransformer;
+use hcaptcha_wasm_deobfuscator::transformations::memory::MemEncFuncType;
+use support::obfuscator::{obfuscate, plain_module, Obfuscation};
+use walrus::ir::BinaryOp;
+use walrus::Module;
+
+fn generate(seed: u64) -> (Module, Obfuscation) {
+    let mut rng = Rng::new(seed);
+    let mut module = plain_module(&mut rng).module;
+    let obfuscation = obfuscate(&mut module, &mut rng).unwrap();
+
+    (Module::from_buffer(&module.emit_wasm()).unwrap(), obfuscation)
+}
+
+fn mismatches(module: &Module) -> Vec<Mismatch> {
+    let transformer = MemoryTransformer::default();
+    let behavioral = BehavioralMapper::default();
+
+    let loads = behavioral.map_load_functions(module).unwrap();
+    let stores = behavioral.map_store_functions(module, &loads).unwrap();
+
+    let mut mismatches = cross_check(&transformer.map_load_functions(module).unwrap(), &loads);
+    mismatches.extend(cross_check(&transformer.map_store_functions(module).unwrap(), &stores));
+    mismatches
+}
+
+#[test]
+fn bundled_assets_agree_with_static_mapper() {
+    for path in ["assets/input.wasm", "assets/vm_input.wasm"] {
+        let module = load_module(path).unwrap();
+        assert_eq!(mismatches(&module), vec![], "{}", path);
+    }
+}
+
+#[test]
+fn synthetic_wrappers_agree_with_static_mapper() {
+    for seed in 0..8 {
+        let (module, obfuscation) = generate(seed);
+        assert_eq!(mismatches(&module), vec![], "seed {}", seed);
+
+        let loads = BehavioralMapper::default().map_load_functions(&module).unwrap();
+        assert_eq!(loads.len(), obfuscation.loads.len(), "seed {}", seed);
+    }
+}
+
+#[test]
+fn idiom_changes_show_up_as_mismatches() {
+    let (mut module, obfuscation) = generate(0);
+    let (name, _) = obfuscation
+        .loads
+        .iter()
+        .find(|(_, ty)| *ty == MemEncFuncType::Unsigned8)
+        .unwrap();
+    let id = module.exports.get_func(name).unwrap();
+
+    // `| 0` after the mask, the static mapper only looks at the end of the wrapper
+    module
+        .funcs
+        .get_mut(id)
+        .kind
+        .unwrap_local_mut()
+        .builder_mut()
+        .func_body()
+        .i32_const(0)
+        .binop(BinaryOp::I32Or);
+
+    assert_eq!(
+        mismatches(&module),
+        vec![Mismatch {
+            func: id,
+            static_type: Some(MemEncFuncType::Signed32),
+            behavioral_type: Some(MemEncFuncType::Unsigned8),
+        }]
+    );
+}
+
+#[test]
+fn candidates_come_from_the_profile() {
+    let (module, obfuscation) = generate(1);
+    let profile = Profile::parse("table_len = 100").unwrap();
+
+    let mapper = BehavioralMapper::default().with_profile(profile);
+    assert!(mapper.map_load_functions(&module).unwrap().is_empty());
+
+    let mapper = BehavioralMapper::default().with_profile(Profile::default());
+    assert_eq!(mapper.map_load_functions(&module).unwrap().len(), obfuscation.loads.len());
+}
@@ -0,0 +1,302 @@
+mod support;
+
+use hcaptcha_wasm_deobfuscator::commands::deobfuscate;
+use hcaptcha_wasm_deobfuscator::emulator::Emulator;
+use hcaptcha_wasm_deobfuscator::rng::Rng;
+use hcaptcha_wasm_deobfuscator::transformations::bulk_memory::BulkMemoryTransformer;
+use hcaptcha_wasm_deobfuscator::transformations::memory::MemEncFuncType;
+use hcaptcha_wasm_deobfuscator::transformations::Transformer;
+use support::obfuscator::{obfuscate, plain_module};
+use walrus::ir::{
+    dfs_in_order, BinaryOp, ExtendedLoad, Instr, LoadKind, MemArg, StoreKind, UnaryOp, Value, Visitor,
+};
+use walrus::{FunctionBuilder, InstrLocId, LocalId, MemoryId, Module, ValType};
+
+fn u8_load() -> LoadKind {
+    LoadKind::I32_8 {
+        kind: ExtendedLoad::ZeroExtend,
+    }
+}
+
+fn arg(offset: u32) -> MemArg {
+    MemArg { align: 1, offset }
+}
+
+fn sum(builder: &mut FunctionBuilder, locals: &[LocalId]) {
+    let mut body = builder.func_body();
+    body.local_get(locals[0]);
+    for local in &locals[1..] {
+        body.local_get(*local).binop(BinaryOp::I32Add);
+    }
+}
+
+// Exported byte loops, every one returns the sum of its locals once done:
+// - copy_counted(d, s, n), `--n` as the loop condition
+// - fill_counted(d, v, n), `n -= 1` in the body, stores at d + 3
+// - copy_bounded(d, s, end), `d < end`, loads at s + 5
+// - fill_bounded(d, end), `d != end`, stores 0xab
+// - copy_strided(d, s, n), every other byte, not a bulk copy
+// - fill_stepped_first(d, v, n), `d += 1` before the store, writes d + 1..=d + n
+fn add_byte_loops(module: &mut Module, memory: MemoryId) {
+    let ty = [ValType::I32; 3];
+    let locals = |module: &mut Module, n: usize| (0..n).map(|_| module.locals.add(ValType::I32)).collect::<Vec<_>>();
+
+    let p = locals(module, 3);
+    let mut builder = FunctionBuilder::new(&mut module.types, &ty, &[ValType::I32]);
+    builder.func_body().block(None, |block| {
+        let exit = block.id();
+        block.local_get(p[2]).unop(UnaryOp::I32Eqz).br_if(exit);
+        block.loop_(None, |body| {
+            let head = body.id();
+            body.local_get(p[0])
+                .local_get(p[1])
+                .load(memory, u8_load(), arg(0))
+                .store(memory, StoreKind::I32_8 { atomic: false }, arg(0));
+            for local in [p[0], p[1]] {
+                body.local_get(local).i32_const(1).binop(BinaryOp::I32Add).local_set(local);
+            }
+            body.local_get(p[2]).i32_const(1).binop(BinaryOp::I32Sub).local_tee(p[2]).br_if(head);
+        });
+    });
+    sum(&mut builder, &p);
+    let id = builder.finish(p, &mut module.funcs);
+    module.exports.add("copy_counted", id);
+
+    let p = locals(module, 3);
+    let mut builder = FunctionBuilder::new(&mut module.types, &ty, &[ValType::I32]);
+    builder.func_body().block(None, |block| {
+        let exit = block.id();
+        block.local_get(p[2]).unop(UnaryOp::I32Eqz).br_if(exit);
+        block.loop_(None, |body| {
+            let head = body.id();
+            body.local_get(p[0]).local_get(p[1]).store(memory, StoreKind::I32_8 { atomic: false }, arg(3));
+            body.local_get(p[2]).i32_const(1).binop(BinaryOp::I32Sub).local_set(p[2]);
+            body.local_get(p[0]).i32_const(1).binop(BinaryOp::I32Add).local_set(p[0]);
+            body.local_get(p[2]).br_if(head);
+        });
+    });
+    sum(&mut builder, &p);
+    let id = builder.finish(p, &mut module.funcs);
+    module.exports.add("fill_counted", id);
+
+    let p = locals(module, 3);
+    let mut builder = FunctionBuilder::new(&mut module.types, &ty, &[ValType::I32]);
+    builder.func_body().loop_(None, |head| {
+        let head_id = head.id();
+        head.local_get(p[0]).local_get(p[2]).binop(BinaryOp::I32LtU).if_else(
+            None,
+            |body| {
+                body.local_get(p[0])
+                    .local_get(p[1])
+                    .load(memory, u8_load(), arg(5))
+                    .store(memory, StoreKind::I32_8 { atomic: false }, arg(0));
+                for local in [p[1], p[0]] {
+                    body.local_get(local).i32_const(1).binop(BinaryOp::I32Add).local_set(local);
+                }
+                body.br(head_id);
+            },
+            |_| {},
+        );
+    });
+    sum(&mut builder, &p);
+    let id = builder.finish(p, &mut module.funcs);
+    module.exports.add("copy_bounded", id);
+
+    let p = locals(module, 2);
+    let mut builder = FunctionBuilder::new(&mut module.types, &ty[..2], &[ValType::I32]);
+    builder.func_body().loop_(None, |head| {
+        let head_id = head.id();
+        head.local_get(p[0]).local_get(p[1]).binop(BinaryOp::I32Ne).if_else(
+            None,
+            |body| {
+                body.local_get(p[0]).i32_const(0xab).store(memory, StoreKind::I32_8 { atomic: false }, arg(0));
+                body.local_get(p[0]).i32_const(1).binop(BinaryOp::I32Add).local_set(p[0]);
+                body.br(head_id);
+            },
+            |_| {},
+        );
+    });
+    sum(&mut builder, &p);
+    let id = builder.finish(p, &mut module.funcs);
+    module.exports.add("fill_bounded", id);
+
+    let p = locals(module, 3);
+    let mut builder = FunctionBuilder::new(&mut module.types, &ty, &[ValType::I32]);
+    builder.func_body().block(None, |block| {
+        let exit = block.id();
+        block.local_get(p[2]).unop(UnaryOp::I32Eqz).br_if(exit);
+        block.loop_(None, |body| {
+            let head = body.id();
+            body.local_get(p[0])
+                .local_get(p[1])
+                .load(memory, u8_load(), arg(0))
+                .store(memory, StoreKind::I32_8 { atomic: false }, arg(0));
+            body.local_get(p[0]).i32_const(2).binop(BinaryOp::I32Add).local_set(p[0]);
+            body.local_get(p[1]).i32_const(1).binop(BinaryOp::I32Add).local_set(p[1]);
+            body.local_get(p[2]).i32_const(1).binop(BinaryOp::I32Sub).local_tee(p[2]).br_if(head);
+        });
+    });
+    sum(&mut builder, &p);
+    let id = builder.finish(p, &mut module.funcs);
+    module.exports.add("copy_strided", id);
+
+    let p = locals(module, 3);
+    let mut builder = FunctionBuilder::new(&mut module.types, &ty, &[ValType::I32]);
+    builder.func_body().block(None, |block| {
+        let exit = block.id();
+        block.local_get(p[2]).unop(UnaryOp::I32Eqz).br_if(exit);
+        block.loop_(None, |body| {
+            let head = body.id();
+            body.local_get(p[0]).i32_const(1).binop(BinaryOp::I32Add).local_set(p[0]);
+            body.local_get(p[0]).local_get(p[1]).store(memory, StoreKind::I32_8 { atomic: false }, arg(0));
+            body.local_get(p[2]).i32_const(1).binop(BinaryOp::I32Sub).local_set(p[2]);
+            body.local_get(p[2]).br_if(head);
+        });
+    });
+    sum(&mut builder, &p);
+    let id = builder.finish(p, &mut module.funcs);
+    module.exports.add("fill_stepped_first", id);
+}
+
+#[derive(Default)]
+struct BulkOps {
+    copies: usize,
+    fills: usize,
+}
+
+impl<'a> Visitor<'a> for BulkOps {
+    fn visit_instr(&mut self, instr: &'a Instr, _: &'a InstrLocId) {
+        match instr {
+            Instr::MemoryCopy(_) => self.copies += 1,
+            Instr::MemoryFill(_) => self.fills += 1,
+            _ => {}
+        }
+    }
+}
+
+fn bulk_ops(module: &Module) -> (usize, usize) {
+    let mut ops = BulkOps::default();
+    for (_, func) in module.funcs.iter_local() {
+        dfs_in_order(&mut ops, func, func.entry_block());
+    }
+    (ops.copies, ops.fills)
+}
+
+// Arguments of the byte loop `name` in [start, start + size), ranges overlap every
+// other call and are empty once in a while
+fn loop_args(rng: &mut Rng, name: &str, start: u32, size: u32) -> Vec<Value> {
+    let len = match rng.range(0, 4) {
+        0 => 0,
+        _ => rng.range(1, 64) as u32,
+    };
+    let dst = start + 8 + rng.range(0, (size - 160) as u64) as u32;
+    let src = match rng.bool() {
+        true => dst.wrapping_add(rng.range(0, 16) as u32).wrapping_sub(8),
+        false => start + 8 + rng.range(0, (size - 160) as u64) as u32,
+    };
+
+    let args = match name {
+        "copy_counted" | "copy_strided" => vec![dst, src, len],
+        "fill_counted" | "fill_stepped_first" => vec![dst, rng.next_u32(), len],
+        "copy_bounded" => vec![dst, src, dst + len],
+        "fill_bounded" => vec![dst, dst + len],
+        _ => unreachable!(),
+    };
+    args.into_iter().map(|a| Value::I32(a as i32)).collect()
+}
+
+const LOOPS: [&str; 6] = [
+    "copy_counted",
+    "fill_counted",
+    "copy_bounded",
+    "fill_bounded",
+    "copy_strided",
+    "fill_stepped_first",
+];
+
+#[test]
+fn native_byte_loops_become_bulk_ops() {
+    let mut module = Module::default();
+    let memory = module.memories.add_local(false, false, 1, None, None);
+    add_byte_loops(&mut module, memory);
+
+    let original = Module::from_buffer(&module.emit_wasm()).unwrap();
+    let mut rewritten = Module::from_buffer(&module.emit_wasm()).unwrap();
+    BulkMemoryTransformer.transform(&mut rewritten);
+    let rewritten = Module::from_buffer(&rewritten.emit_wasm()).unwrap();
+
+    assert_eq!(bulk_ops(&rewritten), (2, 2));
+
+    let mut rng = Rng::new(0xb1c);
+    let mut a = Emulator::new(&original).unwrap();
+    let mut b = Emulator::new(&rewritten).unwrap();
+    let noise = (0..a.memory.len()).map(|_| rng.next_u32() as u8).collect::<Vec<_>>();
+    a.memory.copy_from_slice(&noise);
+    b.memory.copy_from_slice(&noise);
+
+    for name in LOOPS {
+        for _ in 0..256 {
+            let args = loop_args(&mut rng, name, 0, 4096);
+            let expected = a.call_export(name, &args).unwrap();
+            let result = b.call_export(name, &args).unwrap();
+
+            assert!(format!("{:?}", expected) == format!("{:?}", result), "{} {:?}", name, args);
+            assert!(a.memory == b.memory, "{} {:?}: memory differs", name, args);
+        }
+    }
+}
+
+#[test]
+fn wrapper_byte_loops_become_bulk_ops() {
+    for seed in 0..4 {
+        let mut rng = Rng::new(seed);
+        let plain = plain_module(&mut rng);
+        let mut module = plain.module;
+        let memory = module.get_memory_id().unwrap();
+        add_byte_loops(&mut module, memory);
+
+        let obfuscation = obfuscate(&mut module, &mut rng).unwrap();
+        let original = Module::from_buffer(&module.emit_wasm()).unwrap();
+        let mut rewritten = Module::from_buffer(&module.emit_wasm()).unwrap();
+        deobfuscate(&mut rewritten).unwrap();
+        let rewritten = Module::from_buffer(&rewritten.emit_wasm()).unwrap();
+
+        assert_eq!(bulk_ops(&rewritten), (2, 2), "seed {}", seed);
+
+        let (load, _) = obfuscation
+            .loads
+            .iter()
+            .find(|(_, ty)| *ty == MemEncFuncType::Unsigned8)
+            .unwrap();
+        let mut a = Emulator::new(&original).unwrap();
+        let mut b = Emulator::new(&rewritten).unwrap();
+
+        let (start, size) = (plain.base as u32, plain.data.len() as u32);
+        for name in LOOPS {
+            for _ in 0..16 {
+                let args = loop_args(&mut rng, name, start, size);
+                let expected = a.call_export(name, &args).unwrap();
+                let result = b.call_export(name, &args).unwrap();
+                assert!(
+                    format!("{:?}", expected) == format!("{:?}", result),
+                    "seed {}: {} {:?}",
+                    seed,
+                    name,
+                    args
+                );
+            }
+
+            // the original only shows plain bytes through its wrappers
+            for address in start..start + size {
+                let byte = a.call_export(load, &[Value::I32(address as i32), Value::I32(0)]).unwrap();
+                assert!(
+                    matches!(byte[..], [Value::I32(v)] if v == b.memory[address as usize] as i32),
+                    "seed {}: {} differs at {:#x}",
+                    seed,
+                    name,
+                    address
+                );
+            }
+        }
+    }
+}
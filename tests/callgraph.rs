@@ -0,0 +1,104 @@
+mod support;
+
+use hcaptcha_wasm_deobfuscator::analysis::callgraph::CallGraph;
+use hcaptcha_wasm_deobfuscator::analysis::xrefs::XrefDb;
+use hcaptcha_wasm_deobfuscator::commands::callgraph::{map_wrappers, render, Format};
+use hcaptcha_wasm_deobfuscator::commands::{deobfuscate, find_function};
+use hcaptcha_wasm_deobfuscator::rng::Rng;
+use hcaptcha_wasm_deobfuscator::transformations::memory::MemEncFuncType;
+use support::obfuscator::{obfuscate, plain_module};
+use walrus::{FunctionBuilder, Module, ValType};
+
+// An obfuscated synthetic module with a `log` export calling the `a.b` import
+fn module(seed: u64) -> (Module, Vec<(String, MemEncFuncType, bool)>) {
+    let mut rng = Rng::new(seed);
+    let mut module = plain_module(&mut rng).module;
+
+    let ty = module.types.add(&[ValType::I32], &[]);
+    let (import, _) = module.add_import_func("a", "b", ty);
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+    builder.func_body().i32_const(7).call(import);
+    let log = builder.finish(vec![], &mut module.funcs);
+    module.exports.add("log", log);
+
+    let obfuscation = obfuscate(&mut module, &mut rng).unwrap();
+    let wrappers = obfuscation
+        .loads
+        .iter()
+        .map(|(name, ty)| (name.clone(), *ty, false))
+        .chain(
+            obfuscation
+                .stores
+                .iter()
+                .map(|(name, ty)| (name.clone(), MemEncFuncType::from_store_kind(&ty.store_kind()).unwrap(), true)),
+        )
+        .collect();
+
+    (Module::from_buffer(&module.emit_wasm()).unwrap(), wrappers)
+}
+
+#[test]
+fn nodes_are_labelled() {
+    for seed in 0..4 {
+        let (module, expected) = module(seed);
+        let wrappers = map_wrappers(&module);
+        let graph = CallGraph::build(&module, &XrefDb::build(&module), &wrappers);
+
+        assert_eq!(graph.nodes.iter().filter(|n| n.wrapper.is_some()).count(), expected.len(), "seed {}", seed);
+        for (name, ty, is_store) in expected {
+            let id = find_function(&module, &name).unwrap();
+            let node = graph.nodes.iter().find(|n| n.id == id).unwrap();
+            assert_eq!(node.wrapper, Some((ty, is_store)), "seed {}: {}", seed, name);
+        }
+
+        let import = find_function(&module, "a.b").unwrap();
+        assert_eq!(find_function(&module, "b").unwrap(), import);
+        let node = graph.nodes.iter().find(|n| n.id == import).unwrap();
+        assert_eq!(node.import.as_deref(), Some("a.b"));
+    }
+}
+
+#[test]
+fn root_and_depth() {
+    let (mut module, _) = module(0);
+    let wrappers = map_wrappers(&module);
+    let build = || CallGraph::build(&module, &XrefDb::build(&module), &wrappers);
+
+    let log = find_function(&module, "log").unwrap();
+    let import = find_function(&module, "a.b").unwrap();
+    let graph = build().reachable(log, Some(0));
+    assert_eq!(graph.nodes.iter().map(|n| n.id).collect::<Vec<_>>(), [log]);
+    assert!(graph.edges.is_empty());
+
+    let graph = build().reachable(log, None);
+    let mut nodes = graph.nodes.iter().map(|n| n.id).collect::<Vec<_>>();
+    nodes.sort_by_key(|id| id.index());
+    assert_eq!(nodes, [import, log]);
+
+    let dot = render(&module, &graph, Format::Dot);
+    assert!(dot.starts_with("digraph callgraph {\n"), "{}", dot);
+    assert!(dot.contains(&format!("f{} [label=\"func[{}]\\nimport a.b\"];", import.index(), import.index())), "{}", dot);
+    assert!(dot.contains(&format!("f{} -> f{};", log.index(), import.index())), "{}", dot);
+
+    let graphml = render(&module, &graph, Format::GraphMl);
+    let edge = format!("<edge source=\"f{}\" target=\"f{}\"><data key=\"indirect\">false</data></edge>", log.index(), import.index());
+    assert!(graphml.contains(&edge), "{}", graphml);
+
+    let json = render(&module, &graph, Format::Json);
+    assert!(json.contains(r#""import": "a.b", "wrapper": null"#), "{}", json);
+
+    // the plain u8 accessor calls its wrapper until the module is deobfuscated
+    let load_u8 = find_function(&module, "load_u8").unwrap();
+    let graph = build().reachable(load_u8, Some(1));
+    assert!(graph.nodes.iter().any(|n| n.wrapper == Some((MemEncFuncType::Unsigned8, false))));
+    let json = render(&module, &graph, Format::Json);
+    assert!(json.contains(r#""wrapper": {"type": "Unsigned8", "store": false}"#), "{}", json);
+
+    let mut deobfuscated = Module::from_buffer(&module.emit_wasm()).unwrap();
+    let wrappers = map_wrappers(&deobfuscated);
+    deobfuscate(&mut deobfuscated).unwrap();
+    let load_u8 = find_function(&deobfuscated, "load_u8").unwrap();
+    let graph = CallGraph::build(&deobfuscated, &XrefDb::build(&deobfuscated), &wrappers).reachable(load_u8, None);
+    assert_eq!(graph.nodes.len(), 1);
+    assert_eq!(find_function(&deobfuscated, "events").unwrap(), find_function(&deobfuscated, "init_events").unwrap());
+}
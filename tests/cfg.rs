@@ -0,0 +1,169 @@
+mod support;
+
+use hcaptcha_wasm_deobfuscator::analysis::cfg::{Cfg, EdgeKind, ENTRY, EXIT};
+use hcaptcha_wasm_deobfuscator::commands::cfg::render;
+use hcaptcha_wasm_deobfuscator::commands::{deobfuscate, find_function};
+use hcaptcha_wasm_deobfuscator::rng::Rng;
+use support::obfuscator::{obfuscate, plain_module};
+use walrus::ir::{dfs_in_order, BinaryOp, Instr, InstrLocId, LoadKind, MemArg, Visitor};
+use walrus::{FunctionBuilder, LocalFunction, Module, ValType};
+
+fn func<'a>(module: &'a Module, name: &str) -> &'a LocalFunction {
+    let id = find_function(module, name).unwrap();
+    module.funcs.get(id).kind.unwrap_local()
+}
+
+fn succs(cfg: &Cfg, block: usize) -> Vec<(usize, EdgeKind)> {
+    cfg.blocks[block].succs.clone()
+}
+
+// - diamond(p), if/else
+// - countdown(n), loop with a br_if back edge
+// - dispatch(i), br_table over three nested blocks, dead code after a br
+fn cfg_module() -> Module {
+    let mut module = Module::default();
+    let memory = module.memories.add_local(false, false, 1, None, None);
+
+    let p = module.locals.add(ValType::I32);
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+    builder.func_body().local_get(p).if_else(
+        ValType::I32,
+        |then| {
+            then.i32_const(1);
+        },
+        |otherwise| {
+            otherwise
+                .i32_const(2)
+                .load(memory, LoadKind::I32 { atomic: false }, MemArg { align: 4, offset: 8 });
+        },
+    );
+    let id = builder.finish(vec![p], &mut module.funcs);
+    module.exports.add("diamond", id);
+
+    let n = module.locals.add(ValType::I32);
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[]);
+    builder.func_body().loop_(None, |body| {
+        let head = body.id();
+        body.local_get(n).i32_const(1).binop(BinaryOp::I32ShrU).local_tee(n).br_if(head);
+    });
+    let id = builder.finish(vec![n], &mut module.funcs);
+    module.exports.add("countdown", id);
+
+    let i = module.locals.add(ValType::I32);
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+    builder.func_body().block(None, |outer| {
+        let outer_id = outer.id();
+        outer.block(None, |inner| {
+            let inner_id = inner.id();
+            inner.local_get(i).br_table(Box::new([inner_id, outer_id]), outer_id);
+            inner.i32_const(9).drop();
+        });
+        outer.i32_const(10).local_set(i).br(outer_id);
+        outer.i32_const(11).drop();
+    });
+    builder.func_body().local_get(i);
+    let id = builder.finish(vec![i], &mut module.funcs);
+    module.exports.add("dispatch", id);
+
+    Module::from_buffer(&module.emit_wasm()).unwrap()
+}
+
+#[test]
+fn structured_code_becomes_blocks() {
+    let module = cfg_module();
+
+    let cfg = Cfg::build(func(&module, "diamond"));
+    assert_eq!(cfg.blocks.len(), 4);
+    assert_eq!(cfg.blocks[ENTRY].instrs.len(), 2);
+    let [(then, EdgeKind::True), (otherwise, EdgeKind::False)] = succs(&cfg, ENTRY)[..] else {
+        panic!("{:?}", cfg);
+    };
+    assert_eq!(cfg.blocks[then].instrs.len(), 1);
+    assert_eq!(cfg.blocks[otherwise].instrs.len(), 2);
+    assert_eq!(succs(&cfg, then), [(EXIT, EdgeKind::Fallthrough)]);
+    assert_eq!(succs(&cfg, otherwise), [(EXIT, EdgeKind::Fallthrough)]);
+
+    let cfg = Cfg::build(func(&module, "countdown"));
+    assert_eq!(cfg.blocks.len(), 3, "{:?}", cfg);
+    let [(head, EdgeKind::Fallthrough)] = succs(&cfg, ENTRY)[..] else {
+        panic!("{:?}", cfg);
+    };
+    assert_eq!(succs(&cfg, head), [(head, EdgeKind::True), (EXIT, EdgeKind::False)]);
+    assert_eq!(cfg.preds(head), [ENTRY, head]);
+
+    // the dead `i32.const 9; drop` and `i32.const 11; drop` are gone
+    let cfg = Cfg::build(func(&module, "dispatch"));
+    assert_eq!(cfg.blocks.len(), 4, "{:?}", cfg);
+    let [(set, EdgeKind::Case(0)), (tail, EdgeKind::Case(1)), (default, EdgeKind::Jump)] = succs(&cfg, ENTRY)[..] else {
+        panic!("{:?}", cfg);
+    };
+    assert_eq!(tail, default);
+    assert_eq!(cfg.blocks[set].instrs.len(), 3);
+    assert_eq!(succs(&cfg, set), [(tail, EdgeKind::Jump)]);
+    assert_eq!(cfg.blocks[tail].instrs.len(), 1);
+    assert_eq!(succs(&cfg, tail), [(EXIT, EdgeKind::Fallthrough)]);
+}
+
+#[test]
+fn dot_output() {
+    let module = cfg_module();
+    let diamond = func(&module, "diamond");
+    let dot = render(&module, diamond, &Cfg::build(diamond));
+
+    assert!(dot.starts_with("digraph cfg {\n"), "{}", dot);
+    assert!(dot.contains(r#"bb0 [label="bb0 (entry)\l  local.get p0\l  if\l"];"#), "{}", dot);
+    assert!(dot.contains(r#"\l  i32.const 2\l  i32.load offset=8\l"#), "{}", dot);
+    assert!(dot.contains(r#"bb0 -> bb2 [label="true"];"#), "{}", dot);
+    assert!(dot.contains(r#"bb0 -> bb3 [label="false"];"#), "{}", dot);
+
+    let countdown = func(&module, "countdown");
+    let dot = render(&module, countdown, &Cfg::build(countdown));
+    assert!(dot.contains(r#"  i32.shr_u\l  local.tee p0\l  br_if\l"#), "{}", dot);
+}
+
+#[derive(Default)]
+struct Count {
+    instrs: usize,
+    returns: bool,
+}
+
+impl<'a> Visitor<'a> for Count {
+    fn visit_instr(&mut self, instr: &'a Instr, _: &'a InstrLocId) {
+        if !matches!(instr, Instr::Block(_) | Instr::Loop(_)) {
+            self.instrs += 1;
+        }
+        self.returns |= matches!(instr, Instr::Return(_));
+    }
+}
+
+#[test]
+fn every_instruction_lands_in_one_block() {
+    for seed in 0..4 {
+        let mut rng = Rng::new(seed);
+        let mut module = plain_module(&mut rng).module;
+        obfuscate(&mut module, &mut rng).unwrap();
+        let mut module = Module::from_buffer(&module.emit_wasm()).unwrap();
+        deobfuscate(&mut module).unwrap();
+
+        for (id, func) in module.funcs.iter_local() {
+            let mut count = Count::default();
+            dfs_in_order(&mut count, func, func.entry_block());
+
+            let cfg = Cfg::build(func);
+            let mut positions = cfg.blocks.iter().flat_map(|b| b.instrs.iter().copied()).collect::<Vec<_>>();
+            let len = positions.len();
+            positions.sort_by_key(|(seq, idx)| (seq.index(), *idx));
+            positions.dedup();
+
+            assert_eq!(positions.len(), len, "seed {}: {:?}", seed, id);
+            // the rewritten wrappers return early, the old body after it is dead
+            if count.returns {
+                assert!(len < count.instrs, "seed {}: {:?}", seed, id);
+            } else {
+                assert_eq!(len, count.instrs, "seed {}: {:?}", seed, id);
+            }
+            assert!(cfg.blocks[EXIT].succs.is_empty());
+        }
+    }
+}
+
@@ -0,0 +1,192 @@
+mod support;
+
+use hcaptcha_wasm_deobfuscator::commands::decompile::write_split;
+use hcaptcha_wasm_deobfuscator::commands::{deobfuscate, find_function};
+use hcaptcha_wasm_deobfuscator::decompiler::Decompiler;
+use hcaptcha_wasm_deobfuscator::rng::Rng;
+use hcaptcha_wasm_deobfuscator::transformations::memory::MemEncFuncType;
+use support::obfuscator::{obfuscate, plain_module};
+use walrus::ir::{BinaryOp, ExtendedLoad, LoadKind, MemArg, StoreKind, Value};
+use walrus::{ConstExpr, DataKind, FunctionBuilder, Module, ValType};
+
+fn arg(align: u32, offset: u32) -> MemArg {
+    MemArg { align, offset }
+}
+
+fn decompile(module: &Module, name: &str) -> String {
+    let id = find_function(module, name).unwrap();
+    Decompiler::new(module).function(id).unwrap()
+}
+
+// "hello world\0" at 0x1000 and:
+// - deref(p), u16 load at p + 8
+// - poke(p, v), byte store at p + 3
+// - greet(), calls log(ptr, len) and puts(ptr) with the string
+// - countdown(n), loop with a br_if back edge
+// - max(a, b), signed compare in an if/else with a result
+fn decompiler_module() -> Module {
+    let mut module = Module::default();
+    let memory = module.memories.add_local(false, false, 1, None, None);
+    module.data.add(
+        DataKind::Active {
+            memory,
+            offset: ConstExpr::Value(Value::I32(0x1000)),
+        },
+        b"hello world\0".to_vec(),
+    );
+
+    let ty = module.types.add(&[ValType::I32, ValType::I32], &[]);
+    let (log, _) = module.add_import_func("env", "log", ty);
+    let ty = module.types.add(&[ValType::I32], &[]);
+    let (puts, _) = module.add_import_func("env", "puts", ty);
+
+    let p = module.locals.add(ValType::I32);
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+    builder.func_body().local_get(p).load(
+        memory,
+        LoadKind::I32_16 {
+            kind: ExtendedLoad::ZeroExtend,
+        },
+        arg(1, 8),
+    );
+    let id = builder.finish(vec![p], &mut module.funcs);
+    module.exports.add("deref", id);
+
+    let (p, v) = (module.locals.add(ValType::I32), module.locals.add(ValType::I32));
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32, ValType::I32], &[]);
+    builder
+        .func_body()
+        .local_get(p)
+        .local_get(v)
+        .store(memory, StoreKind::I32_8 { atomic: false }, arg(0, 3));
+    let id = builder.finish(vec![p, v], &mut module.funcs);
+    module.exports.add("poke", id);
+
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+    builder
+        .func_body()
+        .i32_const(0x1000)
+        .i32_const(11)
+        .call(log)
+        .i32_const(0x1000)
+        .call(puts);
+    let id = builder.finish(vec![], &mut module.funcs);
+    module.exports.add("greet", id);
+
+    let n = module.locals.add(ValType::I32);
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[]);
+    builder.func_body().loop_(None, |body| {
+        let head = body.id();
+        body.local_get(n).i32_const(1).binop(BinaryOp::I32Sub).local_tee(n).br_if(head);
+    });
+    let id = builder.finish(vec![n], &mut module.funcs);
+    module.exports.add("countdown", id);
+
+    let (a, b) = (module.locals.add(ValType::I32), module.locals.add(ValType::I32));
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32, ValType::I32], &[ValType::I32]);
+    builder
+        .func_body()
+        .local_get(a)
+        .local_get(b)
+        .binop(BinaryOp::I32GtS)
+        .if_else(
+            ValType::I32,
+            |then| {
+                then.local_get(a);
+            },
+            |otherwise| {
+                otherwise.local_get(b);
+            },
+        );
+    let id = builder.finish(vec![a, b], &mut module.funcs);
+    module.exports.add("max", id);
+
+    Module::from_buffer(&module.emit_wasm()).unwrap()
+}
+
+#[test]
+fn memory_accesses_are_derefs() {
+    let module = decompiler_module();
+
+    assert_eq!(decompile(&module, "deref"), "i32 deref(i32 p0) {\n    return *(u16*)(p0 + 8);\n}\n");
+    assert_eq!(decompile(&module, "poke"), "void poke(i32 p0, i32 p1) {\n    *(u8*)(p0 + 3) = p1;\n}\n");
+}
+
+#[test]
+fn strings_are_inlined() {
+    let module = decompiler_module();
+
+    let text = decompile(&module, "greet");
+    assert!(text.contains("    log(\"hello world\", 11);\n"), "{}", text);
+    assert!(text.contains("    puts(\"hello world\");\n"), "{}", text);
+}
+
+#[test]
+fn control_flow_is_structured() {
+    let module = decompiler_module();
+
+    let text = decompile(&module, "countdown");
+    assert_eq!(
+        text,
+        "void countdown(i32 p0) {\n    L0: while (1) {\n        p0 = p0 - 1;\n        if (p0) {\n            continue L0;\n        }\n        break;\n    }\n}\n"
+    );
+
+    let text = decompile(&module, "max");
+    assert!(text.contains("    i32 t0;\n"), "{}", text);
+    assert!(text.contains("    if ((s32)p0 > (s32)p1) {\n        t0 = p0;\n    } else {\n        t0 = p1;\n    }\n"), "{}", text);
+    assert!(text.ends_with("    return t0;\n}\n"), "{}", text);
+}
+
+#[test]
+fn split_output() {
+    let module = decompiler_module();
+    let decompiler = Decompiler::new(&module);
+    let dir = std::env::temp_dir().join(format!("decompiler-{}", std::process::id()));
+
+    let files = write_split(&module, &decompiler, &dir).unwrap();
+    // the imports have no file
+    assert_eq!(files.len(), 5);
+    for func in module.funcs.iter() {
+        let Some(text) = decompiler.function(func.id()) else {
+            continue;
+        };
+        let path = dir.join(format!("func_{}.c", func.id().index()));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), text);
+    }
+
+    // the combined output has every function after the imports
+    let text = decompiler.module();
+    assert!(text.starts_with("extern void log(i32 p0, i32 p1); // env.log\n"), "{}", text);
+    assert!(files.iter().all(|path| text.contains(&std::fs::read_to_string(path).unwrap())));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn deobfuscated_wrappers_are_derefs() {
+    for seed in 0..4 {
+        let mut rng = Rng::new(seed);
+        let mut module = plain_module(&mut rng).module;
+        let obfuscation = obfuscate(&mut module, &mut rng).unwrap();
+
+        let mut module = Module::from_buffer(&module.emit_wasm()).unwrap();
+        deobfuscate(&mut module).unwrap();
+
+        for (name, ty) in obfuscation.loads.iter() {
+            let pointer = match ty {
+                MemEncFuncType::Unsigned8 | MemEncFuncType::I64Unsigned8 => "u8",
+                MemEncFuncType::Unsigned16 | MemEncFuncType::I64Unsigned16 => "u16",
+                MemEncFuncType::I64Unsigned32 => "u32",
+                MemEncFuncType::Signed8 | MemEncFuncType::I64Signed8 => "s8",
+                MemEncFuncType::Signed16 | MemEncFuncType::I64Signed16 => "s16",
+                MemEncFuncType::I64Signed32 => "s32",
+                MemEncFuncType::Signed32 => "i32",
+                MemEncFuncType::Signed64 => "i64",
+                MemEncFuncType::Float32 => "f32",
+                MemEncFuncType::Float64 => "f64",
+            };
+            let text = decompile(&module, name);
+            assert!(text.contains(&format!("    return *({}*)(", pointer)), "seed {}: {}", seed, text);
+        }
+        assert!(!Decompiler::new(&module).module().contains('?'), "seed {}", seed);
+    }
+}
@@ -0,0 +1,38 @@
+use hcaptcha_wasm_deobfuscator::commands::{load_deobfuscated, load_module};
+use hcaptcha_wasm_deobfuscator::emulator::differential::DifferentialHarness;
+
+// Both assets return from about a third of the 108 calls with the fixed seed
+const MIN_RETURNED: usize = 24;
+
+fn check(path: &str) {
+    let original = load_module(path).unwrap();
+    let rewritten = load_deobfuscated(path).unwrap();
+
+    let mut harness = DifferentialHarness::new(&original, &rewritten).unwrap();
+    harness.seed = 0x5eed;
+
+    let report = harness.run().unwrap();
+    if let Some(divergence) = &report.divergence {
+        panic!("{}: {} ({} calls)", path, divergence, report.calls);
+    }
+
+    assert!(report.calls > report.inconclusive, "every call ran out of fuel");
+    // calls trapping the same way agree too, enough of them have to get through
+    assert!(
+        report.returned >= MIN_RETURNED,
+        "{}: only {} of {} calls returned",
+        path,
+        report.returned,
+        report.calls
+    );
+}
+
+#[test]
+fn input_memory_transformer_preserves_semantics() {
+    check("assets/input.wasm");
+}
+
+#[test]
+fn vm_input_memory_transformer_preserves_semantics() {
+    check("assets/vm_input.wasm");
+}
@@ -0,0 +1,384 @@
+use hcaptcha_wasm_deobfuscator::emulator::numeric::{binop, unop};
+use hcaptcha_wasm_deobfuscator::emulator::trap::Trap;
+use hcaptcha_wasm_deobfuscator::emulator::{Emulator, MAX_PAGES};
+use walrus::ir::{BinaryOp, LoadKind, MemArg, UnaryOp, Value};
+use walrus::{
+    ConstExpr, ElementItems, ElementKind, FunctionBuilder, FunctionKind, Module, RefType, ValType,
+};
+
+fn i32_result(result: Result<Vec<Value>, Trap>) -> i32 {
+    match result.unwrap()[..] {
+        [Value::I32(value)] => value,
+        ref other => panic!("expected an i32, got {:?}", other),
+    }
+}
+
+// One page of memory growing up to `maximum`, a table of [one, empty slot] and:
+// - unreachable(), div(a, b) = a / b, load(address)
+// - indirect(slot) and wide(slot), calling the table as () -> i32 and (i32) -> i32
+// - spin(), looping forever
+// - depth(n), recursing n times
+// - grow(delta) and size()
+fn emulator_module(maximum: Option<u64>) -> Module {
+    let mut module = Module::default();
+    let memory = module.memories.add_local(false, false, 1, maximum, None);
+
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+    builder.func_body().unreachable();
+    let id = builder.finish(vec![], &mut module.funcs);
+    module.exports.add("unreachable", id);
+
+    let (a, b) = (
+        module.locals.add(ValType::I32),
+        module.locals.add(ValType::I32),
+    );
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32; 2], &[ValType::I32]);
+    builder
+        .func_body()
+        .local_get(a)
+        .local_get(b)
+        .binop(BinaryOp::I32DivS);
+    let id = builder.finish(vec![a, b], &mut module.funcs);
+    module.exports.add("div", id);
+
+    let address = module.locals.add(ValType::I32);
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+    builder.func_body().local_get(address).load(
+        memory,
+        LoadKind::I32 { atomic: false },
+        MemArg {
+            align: 4,
+            offset: 0,
+        },
+    );
+    let id = builder.finish(vec![address], &mut module.funcs);
+    module.exports.add("load", id);
+
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+    builder.func_body().i32_const(1);
+    let one = builder.finish(vec![], &mut module.funcs);
+    let table = module.tables.add_local(false, 2, None, RefType::Funcref);
+    module.elements.add(
+        ElementKind::Active {
+            table,
+            offset: ConstExpr::Value(Value::I32(0)),
+        },
+        ElementItems::Functions(vec![one]),
+    );
+    let nullary = module.types.find(&[], &[ValType::I32]).unwrap();
+    let unary = module.types.add(&[ValType::I32], &[ValType::I32]);
+    for (name, ty) in [("indirect", nullary), ("wide", unary)] {
+        let slot = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+        let mut body = builder.func_body();
+        if ty == unary {
+            body.i32_const(0);
+        }
+        body.local_get(slot).call_indirect(ty, table);
+        let id = builder.finish(vec![slot], &mut module.funcs);
+        module.exports.add(name, id);
+    }
+
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+    builder.func_body().loop_(None, |body| {
+        let head = body.id();
+        body.br(head);
+    });
+    let id = builder.finish(vec![], &mut module.funcs);
+    module.exports.add("spin", id);
+
+    // depth(n) = n == 0 ? 0 : depth(n - 1) + 1, the body needs its own id
+    let n = module.locals.add(ValType::I32);
+    let builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+    let depth = builder.finish(vec![n], &mut module.funcs);
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+    builder
+        .func_body()
+        .local_get(n)
+        .unop(UnaryOp::I32Eqz)
+        .if_else(
+            ValType::I32,
+            |then| {
+                then.i32_const(0);
+            },
+            |otherwise| {
+                otherwise
+                    .local_get(n)
+                    .i32_const(1)
+                    .binop(BinaryOp::I32Sub)
+                    .call(depth)
+                    .i32_const(1)
+                    .binop(BinaryOp::I32Add);
+            },
+        );
+    module.funcs.get_mut(depth).kind = FunctionKind::Local(builder.local_func(vec![n]));
+    module.exports.add("depth", depth);
+
+    let delta = module.locals.add(ValType::I32);
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+    builder.func_body().local_get(delta).memory_grow(memory);
+    let id = builder.finish(vec![delta], &mut module.funcs);
+    module.exports.add("grow", id);
+
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+    builder.func_body().memory_size(memory);
+    let id = builder.finish(vec![], &mut module.funcs);
+    module.exports.add("size", id);
+
+    Module::from_buffer(&module.emit_wasm()).unwrap()
+}
+
+#[test]
+fn traps() {
+    let module = emulator_module(None);
+    let mut emulator = Emulator::new(&module).unwrap();
+
+    let mut trap = |name: &str, args: &[i32]| {
+        let args = args.iter().map(|arg| Value::I32(*arg)).collect::<Vec<_>>();
+        emulator.call_export(name, &args).unwrap_err()
+    };
+    assert_eq!(trap("unreachable", &[]), Trap::Unreachable);
+    assert_eq!(trap("div", &[1, 0]), Trap::IntegerDivideByZero);
+    assert_eq!(trap("div", &[i32::MIN, -1]), Trap::IntegerOverflow);
+    assert_eq!(
+        trap("load", &[0xfffe]),
+        Trap::MemoryOutOfBounds {
+            address: 0xfffe,
+            size: 4
+        }
+    );
+    assert_eq!(trap("indirect", &[1]), Trap::UninitializedElement(1));
+    assert_eq!(trap("indirect", &[2]), Trap::TableOutOfBounds(2));
+    assert_eq!(trap("wide", &[0]), Trap::IndirectCallTypeMismatch);
+
+    // the emulator stays usable after a trap
+    assert_eq!(
+        i32_result(emulator.call_export("indirect", &[Value::I32(0)])),
+        1
+    );
+    assert_eq!(
+        i32_result(emulator.call_export("div", &[Value::I32(-7), Value::I32(2)])),
+        -3
+    );
+}
+
+#[test]
+fn fuel_and_call_depth() {
+    let module = emulator_module(None);
+
+    let mut emulator = Emulator::new(&module).unwrap();
+    emulator.fuel = 10_000;
+    assert_eq!(
+        emulator.call_export("spin", &[]).unwrap_err(),
+        Trap::OutOfFuel
+    );
+    assert_eq!(emulator.fuel, 0);
+
+    // one instruction short
+    let mut emulator = Emulator::new(&module).unwrap();
+    emulator.fuel = 3;
+    assert_eq!(
+        i32_result(emulator.call_export("div", &[Value::I32(6), Value::I32(3)])),
+        2
+    );
+    emulator.fuel = 2;
+    assert_eq!(
+        emulator
+            .call_export("div", &[Value::I32(6), Value::I32(3)])
+            .unwrap_err(),
+        Trap::OutOfFuel
+    );
+
+    // depth(n) takes n + 1 frames
+    let mut emulator = Emulator::new(&module).unwrap();
+    emulator.max_call_depth = 10;
+    assert_eq!(
+        i32_result(emulator.call_export("depth", &[Value::I32(9)])),
+        9
+    );
+    assert_eq!(
+        emulator
+            .call_export("depth", &[Value::I32(10)])
+            .unwrap_err(),
+        Trap::CallStackExhausted
+    );
+    // the depth unwinds after a trap
+    assert_eq!(
+        i32_result(emulator.call_export("depth", &[Value::I32(9)])),
+        9
+    );
+}
+
+#[test]
+fn memory_grows_up_to_the_maximum() {
+    let module = emulator_module(Some(3));
+    let mut emulator = Emulator::new(&module).unwrap();
+    let mut call = |name: &str, args: &[Value]| i32_result(emulator.call_export(name, args));
+
+    assert_eq!(call("grow", &[Value::I32(1)]), 1);
+    assert_eq!(call("grow", &[Value::I32(2)]), -1);
+    assert_eq!(call("size", &[]), 2);
+    assert_eq!(call("grow", &[Value::I32(1)]), 2);
+    assert_eq!(call("grow", &[Value::I32(0)]), 3);
+    assert_eq!(call("grow", &[Value::I32(1)]), -1);
+    assert_eq!(emulator.memory.len(), 3 * 65536);
+
+    // without a maximum, up to what a 32-bit memory can address
+    let module = emulator_module(None);
+    let mut emulator = Emulator::new(&module).unwrap();
+    let result = emulator.call_export("grow", &[Value::I32(MAX_PAGES as i32)]);
+    assert_eq!(i32_result(result), -1);
+    assert_eq!(emulator.memory.len(), 65536);
+    assert_eq!(
+        i32_result(emulator.call_export("grow", &[Value::I32(1)])),
+        1
+    );
+
+    // the emulator's own limit traps before allocating
+    emulator.memory_limit = 4;
+    assert_eq!(
+        i32_result(emulator.call_export("grow", &[Value::I32(2)])),
+        2
+    );
+    assert_eq!(
+        emulator.call_export("grow", &[Value::I32(1)]).unwrap_err(),
+        Trap::MemoryLimit(4)
+    );
+    assert_eq!(emulator.memory.len(), 4 * 65536);
+}
+
+#[test]
+fn stack_underflow_traps() {
+    // a block taking an i32 nothing pushed, walrus only validates when parsing
+    let mut module = Module::default();
+    let ty = module.types.add(&[ValType::I32], &[]);
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+    builder.func_body().block(ty, |block| {
+        block.drop();
+    });
+    let id = builder.finish(vec![], &mut module.funcs);
+    module.exports.add("underflow", id);
+
+    let mut emulator = Emulator::new(&module).unwrap();
+    assert_eq!(
+        emulator.call_export("underflow", &[]).unwrap_err(),
+        Trap::Unsupported("value stack underflow".to_string())
+    );
+}
+
+#[test]
+fn float_min_max() {
+    let f32_bits = |value: Value| match value {
+        Value::F32(value) => value.to_bits(),
+        other => panic!("expected an f32, got {:?}", other),
+    };
+    let f64_bits = |value: Value| match value {
+        Value::F64(value) => value.to_bits(),
+        other => panic!("expected an f64, got {:?}", other),
+    };
+    let f32_op = |op, a: f32, b: f32| f32_bits(binop(op, Value::F32(a), Value::F32(b)).unwrap());
+    let f64_op = |op, a: f64, b: f64| f64_bits(binop(op, Value::F64(a), Value::F64(b)).unwrap());
+
+    // -0 orders below +0 either way round
+    for (a, b) in [(-0.0, 0.0), (0.0, -0.0)] {
+        assert_eq!(f32_op(BinaryOp::F32Min, a, b), (-0.0f32).to_bits());
+        assert_eq!(f32_op(BinaryOp::F32Max, a, b), 0.0f32.to_bits());
+        assert_eq!(
+            f64_op(BinaryOp::F64Min, a as f64, b as f64),
+            (-0.0f64).to_bits()
+        );
+        assert_eq!(
+            f64_op(BinaryOp::F64Max, a as f64, b as f64),
+            0.0f64.to_bits()
+        );
+    }
+
+    // NaN wins on either side
+    for (a, b) in [(f32::NAN, 1.0), (1.0, f32::NAN)] {
+        assert!(f32::from_bits(f32_op(BinaryOp::F32Min, a, b)).is_nan());
+        assert!(f32::from_bits(f32_op(BinaryOp::F32Max, a, b)).is_nan());
+        assert!(f64::from_bits(f64_op(BinaryOp::F64Min, a as f64, b as f64)).is_nan());
+        assert!(f64::from_bits(f64_op(BinaryOp::F64Max, a as f64, b as f64)).is_nan());
+    }
+
+    assert_eq!(f64_op(BinaryOp::F64Min, -1.5, 2.0), (-1.5f64).to_bits());
+    assert_eq!(
+        f64_op(BinaryOp::F64Max, f64::NEG_INFINITY, -1e300),
+        (-1e300f64).to_bits()
+    );
+}
+
+#[test]
+fn float_truncation() {
+    let trunc = |op, value: Value| unop(op, value).map(|value| format!("{:?}", value));
+
+    // the range is checked after truncation
+    assert_eq!(
+        trunc(UnaryOp::I32TruncSF64, Value::F64(-2147483648.9)),
+        Ok("I32(-2147483648)".to_string())
+    );
+    assert_eq!(
+        trunc(UnaryOp::I32TruncSF64, Value::F64(2147483647.9)),
+        Ok("I32(2147483647)".to_string())
+    );
+    assert_eq!(
+        trunc(UnaryOp::I32TruncSF64, Value::F64(2147483648.0)),
+        Err(Trap::IntegerOverflow)
+    );
+    assert_eq!(
+        trunc(UnaryOp::I32TruncSF64, Value::F64(-2147483649.0)),
+        Err(Trap::IntegerOverflow)
+    );
+    assert_eq!(
+        trunc(UnaryOp::I32TruncUF64, Value::F64(-0.9)),
+        Ok("I32(0)".to_string())
+    );
+    assert_eq!(
+        trunc(UnaryOp::I32TruncUF64, Value::F64(4294967295.5)),
+        Ok("I32(-1)".to_string())
+    );
+    assert_eq!(
+        trunc(UnaryOp::I32TruncUF64, Value::F64(-1.0)),
+        Err(Trap::IntegerOverflow)
+    );
+    assert_eq!(
+        trunc(UnaryOp::I64TruncSF32, Value::F32(9.223372e18)),
+        Err(Trap::IntegerOverflow)
+    );
+    assert_eq!(
+        trunc(UnaryOp::I64TruncUF32, Value::F32(f32::INFINITY)),
+        Err(Trap::IntegerOverflow)
+    );
+
+    for op in [
+        UnaryOp::I32TruncSF32,
+        UnaryOp::I32TruncUF32,
+        UnaryOp::I64TruncSF32,
+        UnaryOp::I64TruncUF32,
+    ] {
+        assert_eq!(
+            trunc(op, Value::F32(f32::NAN)),
+            Err(Trap::InvalidConversionToInteger),
+            "{:?}",
+            op
+        );
+    }
+
+    // saturating conversions clamp and turn NaN into 0
+    assert_eq!(
+        trunc(UnaryOp::I32TruncSSatF32, Value::F32(f32::NAN)),
+        Ok("I32(0)".to_string())
+    );
+    assert_eq!(
+        trunc(UnaryOp::I32TruncSSatF32, Value::F32(1e10)),
+        Ok("I32(2147483647)".to_string())
+    );
+    assert_eq!(
+        trunc(UnaryOp::I32TruncUSatF64, Value::F64(-5.0)),
+        Ok("I32(0)".to_string())
+    );
+    assert_eq!(
+        trunc(UnaryOp::I64TruncSSatF64, Value::F64(f64::NEG_INFINITY)),
+        Ok(format!("I64({})", i64::MIN))
+    );
+}
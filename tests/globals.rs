@@ -0,0 +1,132 @@
+use hcaptcha_wasm_deobfuscator::emulator::Emulator;
+use hcaptcha_wasm_deobfuscator::pattern::Sequence;
+use hcaptcha_wasm_deobfuscator::transformations::Transformer;
+use hcaptcha_wasm_deobfuscator::transformations::globals::ConstantGlobalTransformer;
+use walrus::ir::{BinaryOp, Value};
+use walrus::{ConstExpr, ExportItem, FunctionBuilder, GlobalId, LocalFunction, Module, ValType};
+
+fn local<'a>(module: &'a Module, name: &str) -> &'a LocalFunction {
+    match module.exports.iter().find(|e| e.name == name).unwrap().item {
+        ExportItem::Function(id) => module.funcs.get(id).kind.unwrap_local(),
+        _ => unreachable!(),
+    }
+}
+
+fn count(func: &LocalFunction, sequence: &str) -> usize {
+    Sequence::parse(sequence).unwrap().find(func).len()
+}
+
+// Globals:
+// - sp, mutable, a frame of 16 taken and given back in frame()
+// - key, immutable 0x1234
+// - wide, mutable i64 never set
+// - shared, mutable and exported, the loader may set it
+// Functions:
+// - frame(), returns sp minus 16 and restores it
+// - read(), key + shared
+// - read_wide(), wide
+fn globals_module() -> (Module, [GlobalId; 4]) {
+    let mut module = Module::default();
+    let value = |value: Value| ConstExpr::Value(value);
+    let sp = module.globals.add_local(ValType::I32, true, false, value(Value::I32(0x10000)));
+    let key = module.globals.add_local(ValType::I32, false, false, value(Value::I32(0x1234)));
+    let wide = module.globals.add_local(ValType::I64, true, false, value(Value::I64(-7)));
+    let shared = module.globals.add_local(ValType::I32, true, false, value(Value::I32(5)));
+    module.exports.add("shared", shared);
+
+    let frame = module.locals.add(ValType::I32);
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+    builder
+        .func_body()
+        .global_get(sp)
+        .i32_const(16)
+        .binop(BinaryOp::I32Sub)
+        .local_tee(frame)
+        .global_set(sp)
+        .local_get(frame)
+        .i32_const(16)
+        .binop(BinaryOp::I32Add)
+        .global_set(sp)
+        .local_get(frame);
+    let id = builder.finish(vec![], &mut module.funcs);
+    module.exports.add("frame", id);
+
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+    builder
+        .func_body()
+        .global_get(key)
+        .global_get(shared)
+        .binop(BinaryOp::I32Add);
+    let id = builder.finish(vec![], &mut module.funcs);
+    module.exports.add("read", id);
+
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I64]);
+    builder.func_body().global_get(wide);
+    let id = builder.finish(vec![], &mut module.funcs);
+    module.exports.add("read_wide", id);
+
+    (module, [sp, key, wide, shared])
+}
+
+#[test]
+fn promotes_unwritten_globals() {
+    let (original, _) = globals_module();
+    let (mut module, [_, key, wide, _]) = globals_module();
+    let mut transformer = ConstantGlobalTransformer::default();
+    transformer.transform(&mut module);
+
+    let promoted = transformer
+        .promoted
+        .iter()
+        .map(|promoted| (promoted.global, format!("{:?}", promoted.value), promoted.gets))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        promoted,
+        [
+            (key, "I32(4660)".to_string(), 1),
+            (wide, "I64(-7)".to_string(), 1),
+        ]
+    );
+
+    assert_eq!(count(local(&module, "read"), "GlobalGet"), 1);
+    assert_eq!(count(local(&module, "read"), "i32(4660), GlobalGet, I32Add"), 1);
+    assert_eq!(count(local(&module, "read_wide"), "GlobalGet"), 0);
+    assert_eq!(count(local(&module, "frame"), "GlobalGet"), 1);
+
+    // The globals stay, only their reads go
+    assert_eq!(module.globals.iter().count(), 4);
+
+    let module = Module::from_buffer(&module.emit_wasm()).unwrap();
+    let mut before = Emulator::new(&original).unwrap();
+    let mut after = Emulator::new(&module).unwrap();
+    for name in ["frame", "read", "read_wide"] {
+        assert_eq!(
+            format!("{:?}", before.call_export(name, &[]).unwrap()),
+            format!("{:?}", after.call_export(name, &[]).unwrap()),
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn labels_stack_pointer() {
+    let (mut module, [sp, ..]) = globals_module();
+    let mut transformer = ConstantGlobalTransformer::default();
+    transformer.transform(&mut module);
+    assert_eq!(transformer.stack_pointer, Some(sp));
+
+    let module = Module::from_buffer(&module.emit_wasm()).unwrap();
+    let names = module
+        .globals
+        .iter()
+        .map(|global| global.name.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(names, [Some("stack_pointer".to_string()), None, None, None]);
+
+    // A name the module already has is kept
+    let (mut module, [sp, ..]) = globals_module();
+    module.globals.get_mut(sp).name = Some("__stack_pointer".to_string());
+    ConstantGlobalTransformer::default().transform(&mut module);
+    assert_eq!(module.globals.get(sp).name.as_deref(), Some("__stack_pointer"));
+}
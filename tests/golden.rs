@@ -0,0 +1,109 @@
+use hcaptcha_wasm_deobfuscator::analysis::fingerprint::Fnv64;
+use hcaptcha_wasm_deobfuscator::commands::{deobfuscate, function_label, load_module};
+use hcaptcha_wasm_deobfuscator::fetcher::events::fetch_events;
+use hcaptcha_wasm_deobfuscator::transformations::memory::memory_encryption::map_memory_encryption_mode;
+use hcaptcha_wasm_deobfuscator::transformations::memory::memory_transformer::MemoryTransformer;
+use std::fmt::Write;
+use std::hash::Hasher;
+use std::path::Path;
+
+// Golden files live in tests/golden, run with UPDATE_GOLDEN=1 to rewrite them
+// after an intended change.
+fn render(path: &str) -> String {
+    let mut module = load_module(path).unwrap();
+    let mut out = String::new();
+
+    let transformer = MemoryTransformer::default();
+    let loads = transformer.map_load_functions(&module).unwrap();
+    let stores = transformer.map_store_functions(&module).unwrap();
+    let mode = map_memory_encryption_mode(&module, &loads).unwrap();
+    writeln!(out, "encryption: {}({:?})", mode.name(), mode).unwrap();
+
+    for (kind, mapped) in [("load", &loads), ("store", &stores)] {
+        let mut mapped = mapped.iter().collect::<Vec<_>>();
+        mapped.sort_by_key(|(id, _)| id.index());
+
+        for (id, ty) in mapped {
+            writeln!(out, "{} {} {:?}", kind, function_label(&module, *id), ty).unwrap();
+        }
+    }
+
+    deobfuscate(&mut module).unwrap();
+
+    let events = fetch_events(&mut module).unwrap();
+    for event in events.lines() {
+        writeln!(out, "event {}", event).unwrap();
+    }
+
+    let mut hasher = Fnv64::default();
+    hasher.write(&module.emit_wasm());
+    writeln!(out, "module {:016x}", hasher.finish()).unwrap();
+
+    out
+}
+
+// Line diff (LCS) of expected vs actual, `-` lines are expected only, `+` actual only
+fn diff(expected: &str, actual: &str) -> String {
+    let a = expected.lines().collect::<Vec<_>>();
+    let b = actual.lines().collect::<Vec<_>>();
+
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            writeln!(out, "{:>5} - {}", i + 1, a[i]).unwrap();
+            i += 1;
+        } else {
+            writeln!(out, "{:>5} + {}", j + 1, b[j]).unwrap();
+            j += 1;
+        }
+    }
+
+    out
+}
+
+fn check(asset: &str, golden: &str) {
+    let actual = render(asset);
+    let golden = Path::new("tests/golden").join(golden);
+
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(&golden, &actual).unwrap();
+        return;
+    }
+
+    let expected = std::fs::read_to_string(&golden)
+        .unwrap_or_else(|_| panic!("missing {}, run with UPDATE_GOLDEN=1", golden.display()));
+
+    if expected != actual {
+        panic!(
+            "{} does not match {}:\n{}",
+            asset,
+            golden.display(),
+            diff(&expected, &actual)
+        );
+    }
+}
+
+#[test]
+fn input_golden() {
+    check("assets/input.wasm", "input.txt");
+}
+
+#[test]
+fn vm_input_golden() {
+    check("assets/vm_input.wasm", "vm_input.txt");
+}
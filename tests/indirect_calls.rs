@@ -0,0 +1,193 @@
+use hcaptcha_wasm_deobfuscator::emulator::Emulator;
+use hcaptcha_wasm_deobfuscator::transformations::indirect_calls::IndirectCallTransformer;
+use hcaptcha_wasm_deobfuscator::transformations::Transformer;
+use hcaptcha_wasm_deobfuscator::pattern::Sequence;
+use walrus::ir::{BinaryOp, Value};
+use walrus::{
+    ConstExpr, ElementItems, ElementKind, ExportItem, FunctionBuilder, FunctionId, LocalFunction, Module, RefType,
+    TableId, ValType,
+};
+
+fn export(module: &Module, name: &str) -> FunctionId {
+    match module.exports.iter().find(|e| e.name == name).unwrap().item {
+        ExportItem::Function(id) => id,
+        _ => unreachable!(),
+    }
+}
+
+fn local<'a>(module: &'a Module, name: &str) -> &'a LocalFunction {
+    module.funcs.get(export(module, name)).kind.unwrap_local()
+}
+
+fn count(func: &LocalFunction, sequence: &str) -> usize {
+    Sequence::parse(sequence).unwrap().find(func).len()
+}
+
+// A table of [one, two, add], one() = 1, two() = 2, add(a, b) = a + b, and callers:
+// - constant(), slot 1
+// - local(c), slot 0 stored in a local on both arms of an if
+// - differing(c), slot 0 or 1 depending on `c`
+// - mismatch(), slot 2 called as () -> i32
+// - empty(), slot 5 past the segment
+fn table_module(extra: impl FnOnce(&mut Module, TableId)) -> Module {
+    let mut module = Module::default();
+
+    for (name, value) in [("one", 1), ("two", 2)] {
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder.func_body().i32_const(value);
+        let id = builder.finish(vec![], &mut module.funcs);
+        module.exports.add(name, id);
+    }
+
+    let (a, b) = (module.locals.add(ValType::I32), module.locals.add(ValType::I32));
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32; 2], &[ValType::I32]);
+    builder.func_body().local_get(a).local_get(b).binop(BinaryOp::I32Add);
+    let id = builder.finish(vec![a, b], &mut module.funcs);
+    module.exports.add("add", id);
+
+    let table = module.tables.add_local(false, 8, None, RefType::Funcref);
+    let slots = ["one", "two", "add"].map(|name| export(&module, name));
+    module.elements.add(
+        ElementKind::Active {
+            table,
+            offset: ConstExpr::Value(Value::I32(0)),
+        },
+        ElementItems::Functions(slots.to_vec()),
+    );
+    let nullary = module.types.find(&[], &[ValType::I32]).unwrap();
+
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+    builder.func_body().i32_const(1).call_indirect(nullary, table);
+    let id = builder.finish(vec![], &mut module.funcs);
+    module.exports.add("constant", id);
+
+    for (name, other) in [("local", 0), ("differing", 1)] {
+        let (c, slot) = (module.locals.add(ValType::I32), module.locals.add(ValType::I32));
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+        let mut body = builder.func_body();
+        body.local_get(c).if_else(
+            None,
+            |then| {
+                then.i32_const(0).local_set(slot);
+            },
+            |otherwise| {
+                otherwise.i32_const(other).local_set(slot);
+            },
+        );
+        body.local_get(slot).call_indirect(nullary, table);
+        let id = builder.finish(vec![c], &mut module.funcs);
+        module.exports.add(name, id);
+    }
+
+    for (name, slot) in [("mismatch", 2), ("empty", 5)] {
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+        builder.func_body().i32_const(slot).call_indirect(nullary, table);
+        let id = builder.finish(vec![], &mut module.funcs);
+        module.exports.add(name, id);
+    }
+
+    extra(&mut module, table);
+    Module::from_buffer(&module.emit_wasm()).unwrap()
+}
+
+#[test]
+fn resolves_known_slots() {
+    let original = table_module(|_, _| {});
+    let mut module = table_module(|_, _| {});
+    let mut transformer = IndirectCallTransformer::default();
+    transformer.transform(&mut module);
+
+    let mut resolved = transformer
+        .resolved
+        .iter()
+        .map(|call| (call.caller, call.callee, call.index))
+        .collect::<Vec<_>>();
+    let mut expected = vec![
+        (export(&module, "constant"), export(&module, "two"), 1),
+        (export(&module, "local"), export(&module, "one"), 0),
+    ];
+    resolved.sort();
+    expected.sort();
+    assert_eq!(resolved, expected);
+
+    for name in ["constant", "local"] {
+        assert_eq!(count(local(&module, name), "CallIndirect"), 0, "{}", name);
+        assert_eq!(count(local(&module, name), "Call"), 1, "{}", name);
+    }
+    for name in ["differing", "mismatch", "empty"] {
+        assert_eq!(count(local(&module, name), "CallIndirect"), 1, "{}", name);
+    }
+
+    let module = Module::from_buffer(&module.emit_wasm()).unwrap();
+    let mut before = Emulator::new(&original).unwrap();
+    let mut after = Emulator::new(&module).unwrap();
+    for name in ["constant", "local", "differing"] {
+        for c in [0, 1] {
+            let args: &[Value] = if name == "constant" { &[] } else { &[Value::I32(c)] };
+            assert_eq!(
+                format!("{:?}", before.call_export(name, args).unwrap()),
+                format!("{:?}", after.call_export(name, args).unwrap()),
+                "{}({})",
+                name,
+                c
+            );
+        }
+    }
+}
+
+#[test]
+fn leaves_writable_tables() {
+    let written = |module: &mut Module, table: TableId| {
+        let one = export(module, "one");
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().i32_const(1).ref_func(one).table_set(table);
+        let id = builder.finish(vec![], &mut module.funcs);
+        module.exports.add("patch", id);
+    };
+
+    let mut module = table_module(written);
+    let mut transformer = IndirectCallTransformer::default();
+    transformer.transform(&mut module);
+    assert!(transformer.resolved.is_empty());
+    assert_eq!(count(local(&module, "constant"), "CallIndirect"), 1);
+
+    // A segment at an offset only known when instantiating may overwrite any slot, and so
+    // may one made of expressions
+    let offset = |module: &mut Module, table: TableId| {
+        let (base, _) = module.add_import_global("env", "base", ValType::I32, false, false);
+        let two = export(module, "two");
+        module.elements.add(
+            ElementKind::Active {
+                table,
+                offset: ConstExpr::Global(base),
+            },
+            ElementItems::Functions(vec![two]),
+        );
+    };
+    let expressions = |module: &mut Module, table: TableId| {
+        let two = export(module, "two");
+        module.elements.add(
+            ElementKind::Active {
+                table,
+                offset: ConstExpr::Value(Value::I32(0)),
+            },
+            ElementItems::Expressions(RefType::Funcref, vec![ConstExpr::RefFunc(two)]),
+        );
+    };
+    for extra in [offset, expressions] {
+        let mut module = table_module(extra);
+        let mut transformer = IndirectCallTransformer::default();
+        transformer.transform(&mut module);
+        assert!(transformer.resolved.is_empty());
+        assert_eq!(count(local(&module, "local"), "CallIndirect"), 1);
+    }
+
+    // The loader may write an exported table
+    let mut module = table_module(|module, table| {
+        module.exports.add("table", table);
+    });
+    let mut transformer = IndirectCallTransformer::default();
+    transformer.transform(&mut module);
+    assert!(transformer.resolved.is_empty());
+    assert_eq!(count(local(&module, "constant"), "CallIndirect"), 1);
+}
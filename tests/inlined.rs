@@ -0,0 +1,182 @@
+mod support;
+
+use hcaptcha_wasm_deobfuscator::commands::deobfuscate;
+use hcaptcha_wasm_deobfuscator::emulator::differential::DifferentialHarness;
+use hcaptcha_wasm_deobfuscator::rng::Rng;
+use hcaptcha_wasm_deobfuscator::transformations::memory::memory_transformer::MemoryTransformer;
+use hcaptcha_wasm_deobfuscator::transformations::memory::MemEncFuncType;
+use support::obfuscator::{inline_wrappers, obfuscate, plain_module, unexport_wrappers, Obfuscation};
+use walrus::ir::{dfs_in_order, BinaryOp, Call, Const, Value, Visitor};
+use std::collections::HashMap;
+use walrus::{ExportItem, FunctionBuilder, FunctionId, Module, ValType};
+
+const SEEDS: u64 = 8;
+
+fn generate(seed: u64) -> (Module, Obfuscation) {
+    let mut rng = Rng::new(seed);
+    let mut module = plain_module(&mut rng).module;
+    let obfuscation = obfuscate(&mut module, &mut rng).unwrap();
+
+    (module, obfuscation)
+}
+
+fn round_trip(module: &mut Module) -> Module {
+    Module::from_buffer(&module.emit_wasm()).unwrap()
+}
+
+fn sorted(module: &Module, mapped: HashMap<FunctionId, MemEncFuncType>) -> Vec<(String, MemEncFuncType)> {
+    let mut mapped = mapped
+        .into_iter()
+        .map(|(id, ty)| (module.funcs.get(id).name.clone().unwrap_or_default(), ty))
+        .collect::<Vec<_>>();
+    mapped.sort_by(|a, b| a.0.cmp(&b.0));
+    mapped
+}
+
+// What is left of the memory scheme in a function
+#[derive(Default)]
+struct Leftovers {
+    calls: Vec<FunctionId>,
+    page_stride: bool,
+}
+
+impl<'a> Visitor<'a> for Leftovers {
+    fn visit_call(&mut self, instr: &Call) {
+        self.calls.push(instr.func);
+    }
+
+    fn visit_const(&mut self, instr: &Const) {
+        self.page_stride |= matches!(instr.value, Value::I32(328));
+    }
+}
+
+#[test]
+fn internal_wrappers_are_mapped() {
+    for seed in 0..SEEDS {
+        let (mut module, mut obfuscation) = generate(seed);
+        unexport_wrappers(&mut module, &obfuscation);
+        let module = round_trip(&mut module);
+
+        let transformer = MemoryTransformer::default();
+        let loads = sorted(&module, transformer.map_load_functions(&module).unwrap());
+        let stores = sorted(&module, transformer.map_store_functions(&module).unwrap());
+
+        obfuscation.loads.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut expected = obfuscation
+            .stores
+            .iter()
+            .map(|(name, ty)| (name.clone(), MemEncFuncType::from_store_kind(&ty.store_kind()).unwrap()))
+            .collect::<Vec<_>>();
+        expected.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(loads, obfuscation.loads, "seed {}", seed);
+        assert_eq!(stores, expected, "seed {}", seed);
+    }
+}
+
+#[test]
+fn inlined_wrappers_are_rewritten() {
+    for seed in 0..SEEDS {
+        let (mut module, obfuscation) = generate(seed);
+        let wrappers = obfuscation
+            .loads
+            .iter()
+            .chain(obfuscation.stores.iter())
+            .map(|(name, _)| name.clone())
+            .chain(std::iter::once(obfuscation.page_init.clone()))
+            .collect::<Vec<_>>();
+
+        assert!(inline_wrappers(&mut module, &obfuscation).unwrap() > 0, "seed {}", seed);
+        unexport_wrappers(&mut module, &obfuscation);
+        let original = round_trip(&mut module);
+
+        let mut rewritten = round_trip(&mut module);
+        deobfuscate(&mut rewritten).unwrap();
+
+        let is_wrapper = |id: FunctionId| rewritten.funcs.get(id).name.as_ref().is_some_and(|name| wrappers.contains(name));
+        for (id, func) in rewritten.funcs.iter_local().filter(|(id, _)| !is_wrapper(*id)) {
+            let mut leftovers = Leftovers::default();
+            dfs_in_order(&mut leftovers, func, func.entry_block());
+
+            assert!(
+                leftovers.calls.iter().all(|callee| !is_wrapper(*callee)),
+                "seed {}: {:?} still calls a wrapper",
+                seed,
+                id
+            );
+            assert!(!leftovers.page_stride, "seed {}: {:?} still reads pages", seed, id);
+        }
+
+        let mut harness = DifferentialHarness::new(&original, &rewritten).unwrap();
+        harness.seed = seed;
+
+        let report = harness.run().unwrap();
+        if let Some(divergence) = &report.divergence {
+            panic!("seed {}: {}", seed, divergence);
+        }
+    }
+}
+
+fn exported(module: &Module, name: &str) -> FunctionId {
+    match module.exports.iter().find(|e| e.name == name).unwrap().item {
+        ExportItem::Function(id) => id,
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn wrapper_callers_are_not_wrappers() {
+    for seed in 0..SEEDS {
+        let (mut module, obfuscation) = generate(seed);
+
+        // (i32, i32) -> i32 adding one to what an i32 wrapper reads
+        let load = obfuscation
+            .loads
+            .iter()
+            .map(|(name, _)| exported(&module, name))
+            .find(|id| module.types.get(module.funcs.get(*id).ty()).results() == [ValType::I32])
+            .unwrap();
+        let (idx, offset) = (module.locals.add(ValType::I32), module.locals.add(ValType::I32));
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32; 2], &[ValType::I32]);
+        builder
+            .func_body()
+            .local_get(idx)
+            .local_get(offset)
+            .call(load)
+            .i32_const(1)
+            .binop(BinaryOp::I32Add);
+        let load_caller = builder.finish(vec![idx, offset], &mut module.funcs);
+        module.exports.add("load_caller", load_caller);
+
+        // (i32, i32, i32) -> () initializing the page, then storing through a wrapper
+        let (name, ty) = &obfuscation.stores[0];
+        let store = exported(&module, name);
+        let page_init = exported(&module, &obfuscation.page_init);
+        let value_ty = ty.val_type();
+        let (idx, value, offset) = (
+            module.locals.add(ValType::I32),
+            module.locals.add(value_ty),
+            module.locals.add(ValType::I32),
+        );
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32, value_ty, ValType::I32], &[]);
+        builder
+            .func_body()
+            .local_get(idx)
+            .call(page_init)
+            .local_get(idx)
+            .local_get(value)
+            .local_get(offset)
+            .call(store);
+        let store_caller = builder.finish(vec![idx, value, offset], &mut module.funcs);
+        module.exports.add("store_caller", store_caller);
+
+        let module = round_trip(&mut module);
+        let transformer = MemoryTransformer::default();
+        let loads = transformer.find_mem_load_functions(&module);
+        let stores = transformer.find_mem_store_functions(&module);
+
+        assert!(!loads.contains(&exported(&module, "load_caller")), "seed {}", seed);
+        assert!(!stores.contains(&exported(&module, "store_caller")), "seed {}", seed);
+        assert_eq!(loads.len(), obfuscation.loads.len(), "seed {}", seed);
+        assert_eq!(stores.len(), obfuscation.stores.len(), "seed {}", seed);
+    }
+}
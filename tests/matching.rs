@@ -0,0 +1,141 @@
+use hcaptcha_wasm_deobfuscator::analysis::fingerprint::{fingerprint_module, FunctionFingerprint};
+use hcaptcha_wasm_deobfuscator::analysis::matching::match_functions;
+use hcaptcha_wasm_deobfuscator::commands::load_module;
+use walrus::ir::BinaryOp;
+use walrus::{ExportItem, FunctionBuilder, FunctionId, Module, ValType};
+
+type Body = fn(&mut Module) -> FunctionId;
+
+fn export(module: &Module, name: &str) -> FunctionId {
+    match module.exports.iter().find(|e| e.name == name).unwrap().item {
+        ExportItem::Function(id) => id,
+        _ => unreachable!(),
+    }
+}
+
+fn fingerprint<'a>(fps: &'a [FunctionFingerprint], module: &Module, name: &str) -> &'a FunctionFingerprint {
+    fps.iter().find(|f| f.id == export(module, name)).unwrap()
+}
+
+fn binop(module: &mut Module, swap: bool, op: BinaryOp) -> FunctionId {
+    let (a, b) = (module.locals.add(ValType::I32), module.locals.add(ValType::I32));
+    let (first, second) = if swap { (b, a) } else { (a, b) };
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32; 2], &[ValType::I32]);
+    builder.func_body().local_get(first).local_get(second).binop(op);
+    builder.finish(vec![a, b], &mut module.funcs)
+}
+
+// a - b
+fn sub(module: &mut Module) -> FunctionId {
+    binop(module, false, BinaryOp::I32Sub)
+}
+
+// b - a
+fn rsub(module: &mut Module) -> FunctionId {
+    binop(module, true, BinaryOp::I32Sub)
+}
+
+// a * b
+fn mul(module: &mut Module) -> FunctionId {
+    binop(module, false, BinaryOp::I32Mul)
+}
+
+// (x + step) * 2 doubled through two scratch locals, in declaration order or swapped
+fn scaled(module: &mut Module, step: i32, swapped_locals: bool) -> FunctionId {
+    let x = module.locals.add(ValType::I32);
+    let (t, u) = (module.locals.add(ValType::I32), module.locals.add(ValType::I32));
+    let (t, u) = if swapped_locals { (u, t) } else { (t, u) };
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+    builder
+        .func_body()
+        .local_get(x)
+        .i32_const(step)
+        .binop(BinaryOp::I32Add)
+        .local_set(t)
+        .local_get(t)
+        .i32_const(2)
+        .binop(BinaryOp::I32Mul)
+        .local_tee(u)
+        .local_get(u)
+        .binop(BinaryOp::I32Add);
+    builder.finish(vec![x], &mut module.funcs)
+}
+
+fn build(functions: &[(&str, Body)]) -> Module {
+    let mut module = Module::default();
+    for (name, body) in functions {
+        let id = body(&mut module);
+        module.exports.add(name, id);
+    }
+    Module::from_buffer(&module.emit_wasm()).unwrap()
+}
+
+#[test]
+fn exact_hash_tells_operand_order_apart() {
+    let module = build(&[
+        ("sub", sub),
+        ("rsub", rsub),
+        ("scaled", |m| scaled(m, 3, false)),
+        ("scaled_swapped", |m| scaled(m, 3, true)),
+    ]);
+    let fps = fingerprint_module(&module);
+
+    let (a, b) = (fingerprint(&fps, &module, "sub"), fingerprint(&fps, &module, "rsub"));
+    assert_eq!(a.structure, b.structure);
+    assert_ne!(a.exact, b.exact);
+
+    // which local ids a body got doesn't matter, only how it uses them
+    let (a, b) = (
+        fingerprint(&fps, &module, "scaled"),
+        fingerprint(&fps, &module, "scaled_swapped"),
+    );
+    assert_eq!(a.exact, b.exact);
+}
+
+#[test]
+fn matches_functions_across_builds() {
+    let old = build(&[
+        ("sub", sub),
+        ("rsub", rsub),
+        ("scaled", |m| scaled(m, 3, false)),
+        ("gone", mul),
+    ]);
+    // reordered, a constant changed, one removed and one added
+    let new = build(&[
+        ("scaled", |m| scaled(m, 5, true)),
+        ("rsub", rsub),
+        ("sub", sub),
+        ("fresh", |m| {
+            let mut builder = FunctionBuilder::new(&mut m.types, &[], &[ValType::F64]);
+            builder.func_body().f64_const(1.5);
+            builder.finish(vec![], &mut m.funcs)
+        }),
+    ]);
+
+    let matching = match_functions(&fingerprint_module(&old), &fingerprint_module(&new));
+    let mut matched = matching
+        .matched
+        .iter()
+        .map(|m| (m.old, m.new, m.unchanged))
+        .collect::<Vec<_>>();
+    matched.sort();
+
+    let mut expected = ["sub", "rsub", "scaled"]
+        .map(|name| (export(&old, name), export(&new, name), name != "scaled"))
+        .to_vec();
+    expected.sort();
+    assert_eq!(matched, expected);
+    assert_eq!(matching.removed, [export(&old, "gone")]);
+    assert_eq!(matching.added, [export(&new, "fresh")]);
+}
+
+#[test]
+fn a_build_matches_itself() {
+    let module = load_module("assets/input.wasm").unwrap();
+    let fps = fingerprint_module(&module);
+    let matching = match_functions(&fps, &fps);
+
+    assert_eq!(matching.matched.len(), fps.len());
+    assert!(matching.matched.iter().all(|m| m.old == m.new && m.unchanged));
+    assert!(matching.added.is_empty() && matching.removed.is_empty());
+}
@@ -0,0 +1,267 @@
+use hcaptcha_wasm_deobfuscator::commands::{deobfuscate_with, find_function, load_module};
+use hcaptcha_wasm_deobfuscator::fetcher::events::fetch_events_with;
+use hcaptcha_wasm_deobfuscator::profile::Profile;
+use hcaptcha_wasm_deobfuscator::emulator::Emulator;
+use hcaptcha_wasm_deobfuscator::rng::Rng;
+use hcaptcha_wasm_deobfuscator::transformations::mba::MbaTransformer;
+use hcaptcha_wasm_deobfuscator::transformations::Transformer;
+use walrus::ir::{BinaryOp, Value};
+use walrus::{FunctionBuilder, InstrSeqBuilder, LocalId, Module, ValType};
+
+type Body = fn(&mut InstrSeqBuilder, LocalId, LocalId, ValType);
+
+fn op(ty: ValType, i32_op: BinaryOp, i64_op: BinaryOp) -> BinaryOp {
+    if ty == ValType::I32 { i32_op } else { i64_op }
+}
+
+fn constant(body: &mut InstrSeqBuilder, ty: ValType, value: i64) {
+    if ty == ValType::I32 {
+        body.i32_const(value as i32);
+    } else {
+        body.i64_const(value);
+    }
+}
+
+// (a ^ b) + 2 * (a & b)
+fn add(body: &mut InstrSeqBuilder, a: LocalId, b: LocalId, ty: ValType) {
+    body.local_get(a).local_get(b).binop(op(ty, BinaryOp::I32Xor, BinaryOp::I64Xor));
+    constant(body, ty, 2);
+    body.local_get(a)
+        .local_get(b)
+        .binop(op(ty, BinaryOp::I32And, BinaryOp::I64And))
+        .binop(op(ty, BinaryOp::I32Mul, BinaryOp::I64Mul))
+        .binop(op(ty, BinaryOp::I32Add, BinaryOp::I64Add));
+}
+
+// (a | b) - (a & b)
+fn xor(body: &mut InstrSeqBuilder, a: LocalId, b: LocalId, ty: ValType) {
+    body.local_get(a)
+        .local_get(b)
+        .binop(op(ty, BinaryOp::I32Or, BinaryOp::I64Or))
+        .local_get(a)
+        .local_get(b)
+        .binop(op(ty, BinaryOp::I32And, BinaryOp::I64And))
+        .binop(op(ty, BinaryOp::I32Sub, BinaryOp::I64Sub));
+}
+
+// ((a & ~b) + b) + 7, through a local
+fn or_plus(body: &mut InstrSeqBuilder, a: LocalId, b: LocalId, ty: ValType) {
+    body.local_get(a).local_get(b);
+    constant(body, ty, -1);
+    body.binop(op(ty, BinaryOp::I32Xor, BinaryOp::I64Xor))
+        .binop(op(ty, BinaryOp::I32And, BinaryOp::I64And))
+        .local_set(a)
+        .local_get(a)
+        .local_get(b)
+        .binop(op(ty, BinaryOp::I32Add, BinaryOp::I64Add));
+    constant(body, ty, 7);
+    body.binop(op(ty, BinaryOp::I32Add, BinaryOp::I64Add));
+}
+
+// (a ^ b) - (a | b) + (a & b)
+fn zero(body: &mut InstrSeqBuilder, a: LocalId, b: LocalId, ty: ValType) {
+    body.local_get(a)
+        .local_get(b)
+        .binop(op(ty, BinaryOp::I32Xor, BinaryOp::I64Xor))
+        .local_get(a)
+        .local_get(b)
+        .binop(op(ty, BinaryOp::I32Or, BinaryOp::I64Or))
+        .binop(op(ty, BinaryOp::I32Sub, BinaryOp::I64Sub))
+        .local_get(a)
+        .local_get(b)
+        .binop(op(ty, BinaryOp::I32And, BinaryOp::I64And))
+        .binop(op(ty, BinaryOp::I32Add, BinaryOp::I64Add));
+}
+
+// a * b + (m ^ b) + 2 * (m & b) for m = a & 0xff, only the part after the product is linear
+fn masked(body: &mut InstrSeqBuilder, a: LocalId, b: LocalId, ty: ValType) {
+    body.local_get(a).local_get(b).binop(op(ty, BinaryOp::I32Mul, BinaryOp::I64Mul));
+    body.local_get(a);
+    constant(body, ty, 0xff);
+    body.binop(op(ty, BinaryOp::I32And, BinaryOp::I64And))
+        .local_set(a)
+        .local_get(a)
+        .local_get(b)
+        .binop(op(ty, BinaryOp::I32Xor, BinaryOp::I64Xor))
+        .binop(op(ty, BinaryOp::I32Add, BinaryOp::I64Add));
+    constant(body, ty, 2);
+    body.local_get(a)
+        .local_get(b)
+        .binop(op(ty, BinaryOp::I32And, BinaryOp::I64And))
+        .binop(op(ty, BinaryOp::I32Mul, BinaryOp::I64Mul))
+        .binop(op(ty, BinaryOp::I32Add, BinaryOp::I64Add));
+}
+
+// a + 1 + 2, plain arithmetic
+fn plain(body: &mut InstrSeqBuilder, a: LocalId, _: LocalId, ty: ValType) {
+    body.local_get(a);
+    constant(body, ty, 1);
+    body.binop(op(ty, BinaryOp::I32Add, BinaryOp::I64Add));
+    constant(body, ty, 2);
+    body.binop(op(ty, BinaryOp::I32Add, BinaryOp::I64Add));
+}
+
+const BODIES: [(&str, Body); 6] = [
+    ("add", add),
+    ("xor", xor),
+    ("or_plus", or_plus),
+    ("zero", zero),
+    ("masked", masked),
+    ("plain", plain),
+];
+
+// Every body as `<name>32(a, b)` and `<name>64(a, b)`
+fn mba_module() -> Module {
+    let mut module = Module::default();
+    for (name, body) in BODIES {
+        for ty in [ValType::I32, ValType::I64] {
+            let (a, b) = (module.locals.add(ty), module.locals.add(ty));
+            let mut builder = FunctionBuilder::new(&mut module.types, &[ty, ty], &[ty]);
+            body(&mut builder.func_body(), a, b, ty);
+            let id = builder.finish(vec![a, b], &mut module.funcs);
+            let bits = if ty == ValType::I32 { 32 } else { 64 };
+            module.exports.add(&format!("{}{}", name, bits), id);
+        }
+    }
+    Module::from_buffer(&module.emit_wasm()).unwrap()
+}
+
+fn rewrites(transformer: &MbaTransformer, module: &Module, name: &str) -> Vec<String> {
+    let id = find_function(module, name).unwrap();
+    transformer
+        .rewrites
+        .iter()
+        .filter(|rewrite| rewrite.func == id)
+        .map(|rewrite| rewrite.to_string())
+        .collect()
+}
+
+#[test]
+fn simplifies_linear_mba() {
+    let mut module = mba_module();
+    let mut transformer = MbaTransformer::default();
+    transformer.transform(&mut module);
+
+    for bits in [32, 64] {
+        let rewrites = |name: &str| rewrites(&transformer, &module, &format!("{}{}", name, bits));
+        assert_eq!(rewrites("add"), ["(a ^ b) + (2 * (a & b)) => a + b"]);
+        assert_eq!(rewrites("xor"), ["(a | b) - (a & b) => a ^ b"]);
+        assert_eq!(rewrites("or_plus"), ["((a & ~b) + b) + 7 => (a | b) + 7"]);
+        assert_eq!(rewrites("zero"), ["((a ^ b) - (a | b)) + (a & b) => 0"]);
+        // the product and the mask are variables
+        assert_eq!(
+            rewrites("masked"),
+            ["(a + (b ^ c)) + (2 * (b & c)) => (a + b) + c"]
+        );
+        assert!(rewrites("plain").is_empty());
+    }
+
+    let report = transformer.report(&module);
+    assert_eq!(report.lines().count(), transformer.rewrites.len());
+    assert!(report.contains("(add32): (a ^ b) + (2 * (a & b)) => a + b\n"), "{}", report);
+}
+
+#[test]
+fn rewrites_keep_results() {
+    let original = mba_module();
+    let mut module = mba_module();
+    MbaTransformer::default().transform(&mut module);
+    let module = Module::from_buffer(&module.emit_wasm()).unwrap();
+
+    let mut before = Emulator::new(&original).unwrap();
+    let mut after = Emulator::new(&module).unwrap();
+    let mut rng = Rng::new(48);
+    for (name, _) in BODIES {
+        for i in 0..64 {
+            let (a, b) = match i {
+                0 => (0, 0),
+                1 => (-1, -1),
+                2 => (i64::MIN, i64::MAX),
+                _ => (rng.next_u64() as i64, rng.next_u64() as i64),
+            };
+            for (export, args) in [
+                (format!("{}32", name), [Value::I32(a as i32), Value::I32(b as i32)]),
+                (format!("{}64", name), [Value::I64(a), Value::I64(b)]),
+            ] {
+                assert_eq!(
+                    format!("{:?}", before.call_export(&export, &args).unwrap()),
+                    format!("{:?}", after.call_export(&export, &args).unwrap()),
+                    "{}({}, {})",
+                    export,
+                    a,
+                    b
+                );
+            }
+        }
+    }
+}
+
+// ((a | b) - (a & b)) + i64::MIN and ((a | b) - (a & b)) + i64::MIN * (a & b), constants
+// with no positive counterpart
+fn min_module() -> Module {
+    let mut module = Module::default();
+    for (name, scaled) in [("offset", false), ("scaled", true)] {
+        let ty = ValType::I64;
+        let (a, b) = (module.locals.add(ty), module.locals.add(ty));
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ty, ty], &[ty]);
+        let mut body = builder.func_body();
+        xor(&mut body, a, b, ty);
+        body.i64_const(i64::MIN);
+        if scaled {
+            body.local_get(a).local_get(b).binop(BinaryOp::I64And).binop(BinaryOp::I64Mul);
+        }
+        body.binop(BinaryOp::I64Add);
+        let id = builder.finish(vec![a, b], &mut module.funcs);
+        module.exports.add(name, id);
+    }
+    Module::from_buffer(&module.emit_wasm()).unwrap()
+}
+
+#[test]
+fn min_constants_wrap() {
+    let original = min_module();
+    let mut module = min_module();
+    let mut transformer = MbaTransformer::default();
+    transformer.transform(&mut module);
+    assert_eq!(
+        rewrites(&transformer, &module, "offset"),
+        ["((a | b) - (a & b)) + -9223372036854775808 => (a ^ b) - -9223372036854775808"]
+    );
+    assert_eq!(
+        rewrites(&transformer, &module, "scaled"),
+        ["((a | b) - (a & b)) + (-9223372036854775808 * (a & b)) => (a + b) + (9223372036854775806 * (a & b))"]
+    );
+    let module = Module::from_buffer(&module.emit_wasm()).unwrap();
+
+    let mut before = Emulator::new(&original).unwrap();
+    let mut after = Emulator::new(&module).unwrap();
+    let mut rng = Rng::new(64);
+    for name in ["offset", "scaled"] {
+        for i in 0..32 {
+            let (a, b) = match i {
+                0 => (i64::MIN, i64::MIN),
+                1 => (-1, i64::MAX),
+                _ => (rng.next_u64() as i64, rng.next_u64() as i64),
+            };
+            let args = [Value::I64(a), Value::I64(b)];
+            assert_eq!(
+                format!("{:?}", before.call_export(name, &args).unwrap()),
+                format!("{:?}", after.call_export(name, &args).unwrap()),
+                "{}({}, {})",
+                name,
+                a,
+                b
+            );
+        }
+    }
+}
+
+#[test]
+fn runs_before_the_memory_transformer() {
+    let profile = Profile::parse("passes = [\"mba\", \"memory\", \"bulk_memory\"]").unwrap();
+    let mut module = load_module("assets/input.wasm").unwrap();
+
+    deobfuscate_with(&mut module, &profile).unwrap();
+    let events = fetch_events_with(&mut module, &profile).unwrap();
+    assert!(events.starts_with("19j0,b8990fa9,1\n"), "{}", events);
+}
@@ -0,0 +1,429 @@
+mod support;
+
+use hcaptcha_wasm_deobfuscator::commands::{deobfuscate, deobfuscate_with};
+use hcaptcha_wasm_deobfuscator::emulator::differential::DifferentialHarness;
+use hcaptcha_wasm_deobfuscator::emulator::Emulator;
+use hcaptcha_wasm_deobfuscator::fetcher::events::fetch_events;
+use hcaptcha_wasm_deobfuscator::profile::Profile;
+use hcaptcha_wasm_deobfuscator::rng::Rng;
+use hcaptcha_wasm_deobfuscator::transformations::memory::memory_encryption::{
+    map_memory_encryption_mode, MemoryEncryptionScheme, SchemeRegistry, XorMemoryEncryption,
+};
+use hcaptcha_wasm_deobfuscator::transformations::memory::memory_transformer::MemoryTransformer;
+use hcaptcha_wasm_deobfuscator::transformations::memory::MemEncFuncType;
+use std::collections::HashMap;
+use support::obfuscator::{obfuscate, plain_module, Obfuscation};
+use walrus::ir::{BinaryOp, MemArg, StoreKind, UnaryOp, Value};
+use walrus::{DataKind, ConstExpr, FunctionBuilder, FunctionId, Module, ValType};
+
+const SEEDS: u64 = 32;
+
+struct Generated {
+    module: Module,
+    base: usize,
+    data: Vec<u8>,
+    events: String,
+    obfuscation: Obfuscation,
+}
+
+fn generate(seed: u64) -> Generated {
+    let mut rng = Rng::new(seed);
+    let plain = plain_module(&mut rng);
+    let mut module = plain.module;
+    let obfuscation = obfuscate(&mut module, &mut rng).unwrap();
+
+    Generated {
+        // go through the binary format like a real module would
+        module: Module::from_buffer(&module.emit_wasm()).unwrap(),
+        base: plain.base,
+        data: plain.data,
+        events: plain.events,
+        obfuscation,
+    }
+}
+
+fn named(module: &Module, mapped: &HashMap<FunctionId, MemEncFuncType>) -> Vec<(String, MemEncFuncType)> {
+    let mut named = mapped
+        .iter()
+        .map(|(id, ty)| (module.exports.get_exported_func(*id).unwrap().name.clone(), *ty))
+        .collect::<Vec<_>>();
+    named.sort_by(|a, b| a.0.cmp(&b.0));
+    named
+}
+
+fn sorted(wrappers: &[(String, MemEncFuncType)]) -> Vec<(String, MemEncFuncType)> {
+    let mut wrappers = wrappers.to_vec();
+    wrappers.sort_by(|a, b| a.0.cmp(&b.0));
+    wrappers
+}
+
+// Plain value the load wrapper of `ty` should return, as bits
+fn expected(ty: MemEncFuncType, bytes: &[u8]) -> u64 {
+    let mut raw = [0u8; 8];
+    raw[..bytes.len()].copy_from_slice(bytes);
+    let raw = u64::from_le_bytes(raw);
+
+    match ty {
+        MemEncFuncType::Signed8 => raw as i8 as i32 as u32 as u64,
+        MemEncFuncType::Signed16 => raw as i16 as i32 as u32 as u64,
+        MemEncFuncType::I64Signed8 => raw as i8 as u64,
+        MemEncFuncType::I64Signed16 => raw as i16 as u64,
+        MemEncFuncType::I64Signed32 => raw as i32 as u64,
+        _ => raw,
+    }
+}
+
+fn bits(value: &Value) -> u64 {
+    match value {
+        Value::I32(i) => *i as u32 as u64,
+        Value::I64(i) => *i as u64,
+        Value::F32(f) => f.to_bits() as u64,
+        Value::F64(f) => f.to_bits(),
+        Value::V128(_) => unreachable!(),
+    }
+}
+
+fn value(ty: MemEncFuncType, bits: u64) -> Value {
+    match ty.val_type() {
+        ValType::I64 => Value::I64(bits as i64),
+        ValType::F32 => Value::F32(f32::from_bits(bits as u32)),
+        ValType::F64 => Value::F64(f64::from_bits(bits)),
+        _ => Value::I32(bits as i32),
+    }
+}
+
+#[test]
+fn wrappers_are_mapped() {
+    for seed in 0..SEEDS {
+        let generated = generate(seed);
+        let transformer = MemoryTransformer::default();
+
+        let loads = transformer.map_load_functions(&generated.module).unwrap();
+        assert_eq!(named(&generated.module, &loads), sorted(&generated.obfuscation.loads), "seed {}", seed);
+
+        // store wrappers only differ by width, the signed type is reported
+        let expected_stores = generated
+            .obfuscation
+            .stores
+            .iter()
+            .map(|(name, ty)| {
+                let ty = match ty {
+                    MemEncFuncType::Unsigned8 => MemEncFuncType::Signed8,
+                    MemEncFuncType::Unsigned16 => MemEncFuncType::Signed16,
+                    ty => *ty,
+                };
+                (name.clone(), ty)
+            })
+            .collect::<Vec<_>>();
+        let stores = transformer.map_store_functions(&generated.module).unwrap();
+        assert_eq!(named(&generated.module, &stores), sorted(&expected_stores), "seed {}", seed);
+    }
+}
+
+#[test]
+fn unknown_wrapper_shape_is_an_error() {
+    let mut generated = generate(0);
+    let (name, _) = generated
+        .obfuscation
+        .loads
+        .iter()
+        .find(|(_, ty)| *ty == MemEncFuncType::Unsigned8)
+        .unwrap();
+    let id = generated.module.exports.get_func(name).unwrap();
+
+    // a 3 bits wide load doesn't exist
+    generated
+        .module
+        .funcs
+        .get_mut(id)
+        .kind
+        .unwrap_local_mut()
+        .builder_mut()
+        .func_body()
+        .i32_const(7)
+        .binop(BinaryOp::I32And);
+
+    assert!(MemoryTransformer::default().map_load_functions(&generated.module).is_err());
+}
+
+// Map of a lone `(idx, value, offset)` function storing `value` after `ops`, with the
+// accessor patterns the mapper looks for computed and dropped
+fn map_store(value: ValType, ops: &[UnaryOp], kind: StoreKind) -> Result<Option<MemEncFuncType>, anyhow::Error> {
+    let mut module = Module::default();
+    let memory = module.memories.add_local(false, false, 1, None, None);
+    let params = [ValType::I32, value, ValType::I32].map(|ty| module.locals.add(ty));
+
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32, value, ValType::I32], &[]);
+    let mut body = builder.func_body();
+    body.local_get(params[0])
+        .i32_const(328)
+        .binop(BinaryOp::I32Mul)
+        .i32_const(1024)
+        .binop(BinaryOp::I32Add)
+        .i32_const(96)
+        .binop(BinaryOp::I32RemU)
+        .drop();
+    body.local_get(params[0]).local_get(params[2]).binop(BinaryOp::I32Add).local_get(params[1]);
+    for op in ops {
+        body.unop(*op);
+    }
+    body.store(memory, kind, MemArg { align: 1, offset: 0 });
+    let id = builder.finish(params.to_vec(), &mut module.funcs);
+
+    let mapped = MemoryTransformer::default().map_store_functions(&module)?;
+    Ok(mapped.get(&id).copied())
+}
+
+#[test]
+fn store_wrappers_are_mapped_by_value_and_width() {
+    let cases = [
+        (ValType::I32, vec![], StoreKind::I32_8 { atomic: false }, MemEncFuncType::Signed8),
+        (ValType::I32, vec![], StoreKind::I32_16 { atomic: false }, MemEncFuncType::Signed16),
+        (ValType::I32, vec![], StoreKind::I32 { atomic: false }, MemEncFuncType::Signed32),
+        (ValType::I32, vec![UnaryOp::I64ExtendUI32], StoreKind::I64_8 { atomic: false }, MemEncFuncType::Signed8),
+        (ValType::I32, vec![UnaryOp::I64ExtendUI32], StoreKind::I64_16 { atomic: false }, MemEncFuncType::Signed16),
+        (ValType::I32, vec![UnaryOp::I64ExtendUI32], StoreKind::I64_32 { atomic: false }, MemEncFuncType::Signed32),
+        (ValType::I64, vec![UnaryOp::I32WrapI64], StoreKind::I32_8 { atomic: false }, MemEncFuncType::I64Signed8),
+        (ValType::I64, vec![], StoreKind::I64_8 { atomic: false }, MemEncFuncType::I64Signed8),
+        (ValType::I64, vec![], StoreKind::I64_16 { atomic: false }, MemEncFuncType::I64Signed16),
+        (ValType::I64, vec![], StoreKind::I64_32 { atomic: false }, MemEncFuncType::I64Signed32),
+        (ValType::I64, vec![], StoreKind::I64 { atomic: false }, MemEncFuncType::Signed64),
+        (ValType::F32, vec![], StoreKind::F32, MemEncFuncType::Float32),
+        (ValType::F32, vec![UnaryOp::I32ReinterpretF32], StoreKind::I32 { atomic: false }, MemEncFuncType::Float32),
+        (ValType::F64, vec![], StoreKind::F64, MemEncFuncType::Float64),
+        (ValType::F64, vec![UnaryOp::I64ReinterpretF64], StoreKind::I64 { atomic: false }, MemEncFuncType::Float64),
+    ];
+
+    for (value, ops, kind, expected) in cases {
+        let mapped = map_store(value, &ops, kind).unwrap();
+        assert_eq!(mapped, Some(expected), "{} {:?} {:?}", value, ops, kind);
+    }
+}
+
+#[test]
+fn mismatched_store_wrappers_are_errors() {
+    let cases = [
+        // 8 bytes out of an i32
+        (ValType::I32, vec![UnaryOp::I64ExtendUI32], StoreKind::I64 { atomic: false }),
+        // a float converted, not reinterpreted
+        (ValType::F32, vec![UnaryOp::I32TruncSF32], StoreKind::I32 { atomic: false }),
+        (ValType::F32, vec![UnaryOp::I32ReinterpretF32], StoreKind::I32_16 { atomic: false }),
+        (ValType::I32, vec![UnaryOp::F32ConvertSI32], StoreKind::F32),
+        (ValType::I64, vec![UnaryOp::F64ReinterpretI64, UnaryOp::I64ReinterpretF64], StoreKind::I64 { atomic: false }),
+        (ValType::I32, vec![], StoreKind::I32 { atomic: true }),
+    ];
+
+    for (value, ops, kind) in cases {
+        assert!(map_store(value, &ops, kind).is_err(), "{} {:?} {:?}", value, ops, kind);
+    }
+}
+
+#[test]
+fn encryption_round_trip() {
+    for seed in 0..SEEDS {
+        let generated = generate(seed);
+        let module = &generated.module;
+        let loads = MemoryTransformer::default().map_load_functions(module).unwrap();
+
+        let mode = map_memory_encryption_mode(module, &loads).unwrap();
+        assert_eq!(mode.name(), "Xor", "seed {}", seed);
+        let xor = XorMemoryEncryption::detect(module, &loads).unwrap();
+        assert_eq!(xor.xor_table_start(), generated.obfuscation.table_start, "seed {}", seed);
+
+        let segment = module.data.iter().nth(1).unwrap();
+        let DataKind::Active {
+            offset: ConstExpr::Value(Value::I32(start)),
+            ..
+        } = segment.kind
+        else {
+            panic!("seed {}: encrypted segment is not active", seed);
+        };
+
+        let (address, plain) = mode.decrypt_segment(module, start as usize, &segment.value).unwrap();
+        assert!(address <= generated.base, "seed {}: decrypted from {} after {}", seed, address, generated.base);
+
+        let skipped = generated.base - address;
+        assert!(plain[..skipped].iter().all(|b| *b == 0), "seed {}", seed);
+        assert!(plain.len() >= skipped + generated.data.len(), "seed {}: decrypted {} bytes", seed, plain.len());
+        assert!(plain[skipped..skipped + generated.data.len()] == generated.data[..], "seed {}", seed);
+    }
+}
+
+#[test]
+fn events_round_trip() {
+    for seed in 0..SEEDS {
+        let mut generated = generate(seed);
+        deobfuscate(&mut generated.module).unwrap();
+
+        let events = fetch_events(&mut generated.module).unwrap();
+        assert_eq!(events, generated.events, "seed {}", seed);
+    }
+}
+
+#[test]
+fn wrappers_access_plain_memory() {
+    for seed in 0..SEEDS {
+        let generated = generate(seed);
+        let mut emulator = Emulator::new(&generated.module).unwrap();
+        let mut rng = Rng::new(seed);
+
+        for (name, ty) in generated.obfuscation.loads.iter() {
+            for _ in 0..16 {
+                let width = ty.width() as usize;
+                let i = rng.range(0, (generated.data.len() - width) as u64) as usize;
+                let offset = rng.range(0, 16).min(i as u64) as i32;
+                let args = [Value::I32((generated.base + i) as i32 - offset), Value::I32(offset)];
+
+                let result = emulator.call_export(name, &args).unwrap();
+                assert_eq!(
+                    bits(&result[0]),
+                    expected(*ty, &generated.data[i..i + width]),
+                    "seed {}: {} {:?} at {:#x}",
+                    seed,
+                    name,
+                    ty,
+                    generated.base + i
+                );
+            }
+        }
+
+        // stores anywhere in the logical memory, including pages that were never written
+        for (name, ty) in generated.obfuscation.stores.iter() {
+            let (load, _) = generated
+                .obfuscation
+                .loads
+                .iter()
+                .find(|(_, t)| t == ty)
+                .unwrap();
+
+            for _ in 0..16 {
+                let address = rng.range(0, 1048576) as i32;
+                let mut bytes = rng.next_u64().to_le_bytes();
+                let width = ty.width() as usize;
+                bytes[width..].fill(0);
+                let stored = expected(*ty, &bytes[..width]);
+
+                emulator
+                    .call_export(name, &[Value::I32(address), value(*ty, stored), Value::I32(0)])
+                    .unwrap();
+                let result = emulator.call_export(load, &[Value::I32(address), Value::I32(0)]).unwrap();
+                assert_eq!(bits(&result[0]), stored, "seed {}: {} {:?} at {:#x}", seed, name, ty, address);
+            }
+        }
+    }
+}
+
+#[test]
+fn deobfuscated_module_preserves_semantics() {
+    for seed in 0..4 {
+        let mut generated = generate(seed);
+        let mut rewritten = Module::from_buffer(&generated.module.emit_wasm()).unwrap();
+        deobfuscate(&mut rewritten).unwrap();
+
+        let mut harness = DifferentialHarness::new(&generated.module, &rewritten).unwrap();
+        harness.seed = seed;
+
+        let report = harness.run().unwrap();
+        if let Some(divergence) = &report.divergence {
+            panic!("seed {}: {}", seed, divergence);
+        }
+    }
+}
+
+#[test]
+fn encrypted_segment_reads_back() {
+    for seed in 0..SEEDS {
+        let generated = generate(seed);
+        let module = &generated.module;
+        let loads = MemoryTransformer::default().map_load_functions(module).unwrap();
+        let mode = map_memory_encryption_mode(module, &loads).unwrap();
+
+        let (start, encrypted) = mode.encrypt_segment(module, generated.base, &generated.data).unwrap();
+        let mut memory = vec![0; start + encrypted.len()];
+        memory[start..].copy_from_slice(&encrypted);
+
+        let plain = mode.view(module, &memory, generated.base, generated.data.len()).unwrap();
+        assert!(plain == generated.data, "seed {}", seed);
+    }
+}
+
+#[derive(Debug)]
+struct Identity;
+
+impl MemoryEncryptionScheme for Identity {
+    fn name(&self) -> &'static str {
+        "Identity"
+    }
+
+    fn detect(_: &Module, _: &HashMap<FunctionId, MemEncFuncType>) -> Option<Self> {
+        Some(Identity)
+    }
+
+    fn decrypt_segment(&self, _: &Module, start: usize, data: &[u8]) -> Result<(usize, Vec<u8>), anyhow::Error> {
+        Ok((start, data.to_vec()))
+    }
+
+    fn encrypt_segment(&self, _: &Module, start: usize, data: &[u8]) -> Result<(usize, Vec<u8>), anyhow::Error> {
+        Ok((start, data.to_vec()))
+    }
+
+    fn view(&self, _: &Module, memory: &[u8], address: usize, len: usize) -> Result<Vec<u8>, anyhow::Error> {
+        Ok(memory[address..address + len].to_vec())
+    }
+}
+
+#[test]
+fn registry_tries_schemes_in_order() {
+    let generated = generate(0);
+    let module = &generated.module;
+    let loads = MemoryTransformer::default().map_load_functions(module).unwrap();
+
+    assert!(SchemeRegistry::empty().detect(module, &loads).is_err());
+
+    let mut registry = SchemeRegistry::empty();
+    registry.register::<XorMemoryEncryption>().register::<Identity>();
+    assert_eq!(registry.detect(module, &loads).unwrap().name(), "Xor");
+
+    let mut registry = SchemeRegistry::empty();
+    registry.register::<Identity>().register::<XorMemoryEncryption>();
+    assert_eq!(registry.detect(module, &loads).unwrap().name(), "Identity");
+}
+
+// The segments moved one up behind a passive one, found through the profile
+#[test]
+fn harness_follows_the_profile_data_segment() {
+    let mut generated = generate(0);
+    let module = &mut generated.module;
+    let segments = module
+        .data
+        .iter()
+        .map(|data| match data.kind {
+            DataKind::Active { memory, offset } => (data.id(), Some((memory, offset))),
+            DataKind::Passive => (data.id(), None),
+        })
+        .collect::<Vec<_>>();
+    module.data.add(DataKind::Passive, vec![1, 2, 3]);
+    for (id, active) in segments {
+        let value = std::mem::take(&mut module.data.get_mut(id).value);
+        module.data.delete(id);
+        let kind = match active {
+            Some((memory, offset)) => DataKind::Active { memory, offset },
+            None => DataKind::Passive,
+        };
+        module.data.add(kind, value);
+    }
+    let bytes = module.emit_wasm();
+    let original = Module::from_buffer(&bytes).unwrap();
+
+    let profile = Profile::parse("table_segment = 1\ndata_segment = 2").unwrap();
+    let mut rewritten = Module::from_buffer(&bytes).unwrap();
+    deobfuscate_with(&mut rewritten, &profile).unwrap();
+
+    let mut harness = DifferentialHarness::with_profile(&original, &rewritten, &profile).unwrap();
+    harness.seed = 2;
+    let report = harness.run().unwrap();
+    if let Some(divergence) = &report.divergence {
+        panic!("{}", divergence);
+    }
+    assert!(report.calls > report.inconclusive, "every call ran out of fuel");
+}
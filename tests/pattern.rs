@@ -0,0 +1,155 @@
+use hcaptcha_wasm_deobfuscator::commands::find_function;
+use hcaptcha_wasm_deobfuscator::pattern::{Pattern, Sequence};
+use hcaptcha_wasm_deobfuscator::ssa::Function;
+use walrus::ir::{BinaryOp, Instr, LoadKind, MemArg, StoreKind};
+use walrus::{FunctionBuilder, Module, ValType};
+
+const WORD: MemArg = MemArg {
+    align: 4,
+    offset: 0,
+};
+
+// - xor(p, q), `*p ^= *(q + 0x2000)` with the load address computed first into a local
+// - double(x), `x + x`
+// - calls(), `call f(1)` in the body and in a block
+fn pattern_module() -> Module {
+    let mut module = Module::default();
+    let memory = module.memories.add_local(false, false, 1, None, None);
+
+    let (p, q, address) = (
+        module.locals.add(ValType::I32),
+        module.locals.add(ValType::I32),
+        module.locals.add(ValType::I32),
+    );
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32, ValType::I32], &[]);
+    builder
+        .func_body()
+        .local_get(q)
+        .i32_const(0x2000)
+        .binop(BinaryOp::I32Add)
+        .local_set(address)
+        .local_get(p)
+        .local_get(p)
+        .load(memory, LoadKind::I32 { atomic: false }, WORD)
+        .local_get(address)
+        .load(memory, LoadKind::I32 { atomic: false }, WORD)
+        .binop(BinaryOp::I32Xor)
+        .store(memory, StoreKind::I32 { atomic: false }, WORD);
+    let id = builder.finish(vec![p, q], &mut module.funcs);
+    module.exports.add("xor", id);
+
+    let x = module.locals.add(ValType::I32);
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+    builder.func_body().local_get(x).local_get(x).binop(BinaryOp::I32Add);
+    let double = builder.finish(vec![x], &mut module.funcs);
+    module.exports.add("double", double);
+
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+    builder
+        .func_body()
+        .i32_const(1)
+        .call(double)
+        .drop()
+        .block(None, |block| {
+            block.i32_const(1).call(double).drop();
+        });
+    let id = builder.finish(vec![], &mut module.funcs);
+    module.exports.add("calls", id);
+
+    Module::from_buffer(&module.emit_wasm()).unwrap()
+}
+
+fn build(module: &Module, name: &str) -> Function {
+    let id = find_function(module, name).unwrap();
+    Function::build(module, module.funcs.get(id).kind.unwrap_local()).unwrap()
+}
+
+#[test]
+fn parse() {
+    let pattern = Pattern::parse("Store(_, x @ I32Xor(_, i32(-1)))").unwrap();
+    let expected = Pattern::Op(
+        "Store".to_string(),
+        Some(vec![
+            Pattern::Any,
+            Pattern::Bind(
+                "x".to_string(),
+                Box::new(Pattern::Op(
+                    "I32Xor".to_string(),
+                    Some(vec![Pattern::Any, Pattern::Const("i32", Some(-1))]),
+                )),
+            ),
+        ]),
+    );
+    assert_eq!(pattern, expected);
+    assert_eq!(Pattern::parse("i64(0x10)").unwrap(), Pattern::Const("i64", Some(16)));
+    assert_eq!(Pattern::parse("Load").unwrap(), Pattern::Op("Load".to_string(), None));
+
+    for bad in ["", "Load(", "Load(_,)", "Load) ", "i32(x)", "-1", "a @", "Load Store", "Load + 1"] {
+        assert!(Pattern::parse(bad).is_err(), "{:?}", bad);
+    }
+    assert!(Sequence::parse("i32, Call").is_ok());
+    assert!(Sequence::parse("i32, Call(_)").is_err());
+    assert!(Sequence::parse("i32,").is_err());
+}
+
+#[test]
+fn expressions_follow_data_flow() {
+    let module = pattern_module();
+    let ssa = build(&module, "xor");
+
+    // the address went through a local and was computed before the other load
+    let pattern = Pattern::parse("Store(p @ Param, I32Xor(Load(Param), Load(I32Add(Param, key @ i32))))").unwrap();
+    let found = pattern.find(&ssa);
+    assert_eq!(found.len(), 1);
+    let captures = &found[0].1;
+    assert_eq!(captures.i32(&ssa, "key"), Some(0x2000));
+    assert_eq!(captures.i32(&ssa, "p"), None);
+    assert!(captures.get("missing").is_none());
+
+    // operand count and order matter
+    for pattern in ["Store(_)", "Store(I32Xor, _)", "Store(_, I32Xor(_, Load(I32Add(i32, _))))"] {
+        assert!(Pattern::parse(pattern).unwrap().find(&ssa).is_empty(), "{}", pattern);
+    }
+    // variant names match any operator
+    assert_eq!(Pattern::parse("Binop(Load, Load)").unwrap().find(&ssa).len(), 1);
+    assert_eq!(Pattern::parse("Load").unwrap().find(&ssa).len(), 2);
+}
+
+#[test]
+fn bindings_must_agree() {
+    let module = pattern_module();
+
+    let ssa = build(&module, "double");
+    assert_eq!(Pattern::parse("I32Add(x @ _, x @ _)").unwrap().find(&ssa).len(), 1);
+
+    let ssa = build(&module, "xor");
+    assert!(Pattern::parse("Store(x @ _, I32Xor(Load(x @ _), Load(x @ _)))").unwrap().find(&ssa).is_empty());
+    assert_eq!(Pattern::parse("Store(x @ _, I32Xor(Load(x @ _), _))").unwrap().find(&ssa).len(), 1);
+}
+
+#[test]
+fn sequences() {
+    let module = pattern_module();
+    let id = find_function(&module, "calls").unwrap();
+    let func = module.funcs.get(id).kind.unwrap_local();
+
+    let sequence = Sequence::parse("arg @ i32(1), Call, Drop").unwrap();
+    let found = sequence.find(func);
+    // the body, then the block
+    assert_eq!(found.len(), 2);
+    assert_eq!(found[0].0, func.entry_block());
+    assert_eq!(found[0].1, 0);
+    assert_ne!(found[1].0, func.entry_block());
+    assert_eq!(found[1].2.i32("arg"), Some(1));
+    assert!(matches!(found[1].2.get("arg"), Some(Instr::Const(_))));
+
+    // runs off neither end of the block
+    let instrs = &func.block(func.entry_block()).instrs;
+    assert!(sequence.matches(instrs, instrs.len()).is_none());
+    assert!(sequence.matches(instrs, usize::MAX).is_none());
+    assert!(Sequence::parse("Block, _").unwrap().matches(instrs, 3).is_none());
+    assert!(Sequence::parse("_, Block").unwrap().matches(instrs, 2).is_some());
+
+    // the same name binds the same instruction
+    assert_eq!(Sequence::parse("x @ _, x @ _").unwrap().find(func).len(), 0);
+}
@@ -0,0 +1,221 @@
+use hcaptcha_wasm_deobfuscator::analysis::build::BuildFingerprint;
+use hcaptcha_wasm_deobfuscator::commands::{check_profile, deobfuscate_with, load_module, select_profile};
+use hcaptcha_wasm_deobfuscator::fetcher::events::fetch_events_with;
+use hcaptcha_wasm_deobfuscator::profile::Profile;
+
+const TOML: &str = r#"
+# a build with a bigger table
+name = "bigger table"
+table_len = 0x80
+events_constants = [-1, 0xfffffff, 0x80000000, 0]
+"#;
+
+const JSON: &str = r#"{
+    "name": "bigger table",
+    "table_len": 128,
+    "events_constants": [-1, 268435455, -2147483648, 0]
+}"#;
+
+#[test]
+fn parse() {
+    let toml = Profile::parse(TOML).unwrap();
+    assert_eq!(toml, Profile::parse(JSON).unwrap());
+    assert_eq!(toml.name, "bigger table");
+    assert_eq!(toml.table_len, 128);
+    assert_eq!(toml.events_constants, Profile::default().events_constants);
+    // the rest keeps the built-in values
+    assert_eq!(toml.page_size, 320);
+    assert_eq!(toml.page_stride(), 328);
+
+    assert_eq!(Profile::parse("").unwrap(), Profile::default());
+    assert_eq!(Profile::parse("{}").unwrap(), Profile::default());
+
+    for bad in [
+        "table_size = 96",
+        "table_len = -1",
+        "table_len = 0",
+        "table_len = \"96\"",
+        "name = 1",
+        "events_constants = [0x100000000]",
+        "page_size = 320 }",
+        "{ \"page_size\": 320",
+        "{ \"page_size\" = 320 }",
+        "name = \"unterminated",
+        "name = \"unknown \\q escape\"",
+        "name = \"short \\u12\"",
+        "name = \"surrogate \\ud800\"",
+        "name = \"surrogate \\ud800\\u0041\"",
+        "name = 1.5",
+        "name = true",
+    ] {
+        assert!(Profile::parse(bad).is_err(), "{:?}", bad);
+    }
+
+    // escapes are decoded, JSON's `\/` and TOML's `\U` included
+    let toml = Profile::parse(r#"name = "a\"b\\c\n\td\u00e9\U0001F600""#).unwrap();
+    let json = Profile::parse(r#"{ "name": "a\"b\\c\n\td\u00E9\ud83d\ude00" }"#).unwrap();
+    assert_eq!(toml.name, "a\"b\\c\n\td\u{e9}\u{1f600}");
+    assert_eq!(json, toml);
+    assert_eq!(Profile::parse(r#"{ "name": "a\/b" }"#).unwrap().name, "a/b");
+
+    // the error names the zero field, not the last one parsed
+    for field in ["table_len", "page_size", "page_header"] {
+        let error = Profile::parse(&format!("{} = 0\nname = \"zero\"", field)).unwrap_err();
+        assert!(format!("{:#}", error).contains(&format!("{} can't be 0", field)), "{:#}", error);
+    }
+}
+
+#[test]
+fn validation_reports_mismatched_fields() {
+    let module = load_module("assets/input.wasm").unwrap();
+    assert!(Profile::default().validate(&module).is_empty());
+
+    let profile = Profile {
+        data_segment: 5,
+        table_len: 100,
+        page_header: 16,
+        events_constants: vec![0x1234567],
+        ..Profile::default()
+    };
+    let fields = profile
+        .validate(&module)
+        .into_iter()
+        .map(|mismatch| mismatch.field)
+        .collect::<Vec<_>>();
+    assert_eq!(fields, ["data_segment", "events_constants", "table_len", "page_header"]);
+
+    let error = check_profile(&module, &profile).unwrap_err().to_string();
+    assert!(error.contains("table_len: no function computes `% 100`"), "{}", error);
+
+    let profile = Profile::parse("start_skew = 5000").unwrap();
+    let fields = profile
+        .validate(&module)
+        .into_iter()
+        .map(|mismatch| mismatch.field)
+        .collect::<Vec<_>>();
+    assert_eq!(fields, ["start_skew"]);
+}
+
+#[test]
+fn deobfuscates_with_a_loaded_profile() {
+    let profile = Profile::parse("name = \"input\"\ndata_segment = 1\npages_start = 1024").unwrap();
+    let mut module = load_module("assets/input.wasm").unwrap();
+
+    check_profile(&module, &profile).unwrap();
+    deobfuscate_with(&mut module, &profile).unwrap();
+    let events = fetch_events_with(&mut module, &profile).unwrap();
+    assert!(events.starts_with("19j0,b8990fa9,1\n"), "{}", events);
+}
+
+#[test]
+fn wrong_profile_fails_without_changes() {
+    for (profile, expected) in [
+        ("data_segment = 7", "no data segment 7"),
+        ("table_segment = 1", "is outside of table segment 1"),
+        ("start_skew = 5000", "doesn't hold the page of"),
+    ] {
+        let profile = Profile::parse(profile).unwrap();
+        let mut module = load_module("assets/input.wasm").unwrap();
+        let before = module.emit_wasm();
+
+        let error = deobfuscate_with(&mut module, &profile).unwrap_err();
+        assert!(format!("{:#}", error).contains(expected), "{:#}", error);
+        assert_eq!(module.emit_wasm(), before);
+    }
+
+    // a profile built in code isn't checked by `parse`
+    let profile = Profile {
+        passes: vec!["globals".to_string(), "inline".to_string()],
+        ..Profile::default()
+    };
+    let mut module = load_module("assets/input.wasm").unwrap();
+    let before = module.emit_wasm();
+    let error = deobfuscate_with(&mut module, &profile).unwrap_err();
+    assert!(error.to_string().starts_with("unknown pass inline"), "{}", error);
+    assert_eq!(module.emit_wasm(), before);
+}
+
+#[test]
+fn sections_and_passes() {
+    let toml = r#"
+passes = ["memory"]
+
+[fingerprint]
+imports = "123:02039dd4069c8b19"
+table_len = "96"
+"#;
+    let json = r#"{
+    "passes": ["memory"],
+    "fingerprint": { "imports": "123:02039dd4069c8b19", "table_len": "96" }
+}"#;
+    let profile = Profile::parse(toml).unwrap();
+    assert_eq!(profile, Profile::parse(json).unwrap());
+    assert_eq!(profile.passes, ["memory"]);
+    assert_eq!(
+        profile.fingerprint,
+        [
+            ("imports".to_string(), "123:02039dd4069c8b19".to_string()),
+            ("table_len".to_string(), "96".to_string()),
+        ]
+    );
+    assert_eq!(
+        Profile::default().passes,
+        ["memory", "bulk_memory", "globals", "indirect_calls"]
+    );
+
+    for bad in [
+        "passes = [\"inline\"]",
+        "passes = [1]",
+        "[fingerprint]\ntable_len = 96",
+        "fingerprint = \"x\"",
+        "[fingerprint\n",
+    ] {
+        assert!(Profile::parse(bad).is_err(), "{:?}", bad);
+    }
+}
+
+#[test]
+fn fingerprints_pick_the_build_profile() {
+    let profiles = Profile::load_dir("profiles").unwrap();
+    let names = profiles.iter().map(|profile| profile.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["input", "vm_input"]);
+
+    for name in names {
+        let module = load_module(&format!("assets/{}.wasm", name)).unwrap();
+        let fingerprint = BuildFingerprint::of(&module);
+        assert_eq!(fingerprint, BuildFingerprint::of(&module));
+        assert_eq!(fingerprint.get("table_len"), Some("96"));
+        assert_eq!(fingerprint.get("page_size"), Some("320"));
+        assert_eq!(fingerprint.get("page_stride"), Some("328"));
+        assert_eq!(fingerprint.get("pages_start"), Some("1024"));
+
+        let selection = Profile::select(&profiles, &fingerprint).unwrap();
+        assert_eq!(selection.profile.name, name);
+        assert!(selection.differences.is_empty());
+    }
+
+    // an unknown build gets the closest profile and what differs
+    let module = load_module("assets/input.wasm").unwrap();
+    let known = profiles.iter().filter(|profile| profile.name != "input").cloned().collect::<Vec<_>>();
+    let selection = Profile::select(&known, &BuildFingerprint::of(&module)).unwrap();
+    assert_eq!(selection.profile.name, "vm_input");
+    let features = selection.differences.iter().map(|diff| diff.feature.as_str()).collect::<Vec<_>>();
+    assert_eq!(features, ["imports", "exports"]);
+
+    // without a fingerprint a profile is never picked
+    assert!(Profile::select(&[Profile::default()], &BuildFingerprint::of(&module)).is_none());
+}
+
+#[test]
+fn unknown_builds_get_the_default_profile() {
+    let module = load_module("assets/input.wasm").unwrap();
+    assert_eq!(select_profile(&module, "profiles").unwrap().name, "input");
+
+    // only the other build's profile, it is the closest but not used
+    let dir = std::env::temp_dir().join(format!("profiles-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::copy("profiles/vm_input.toml", dir.join("vm_input.toml")).unwrap();
+    let profile = select_profile(&module, dir.to_str().unwrap());
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(profile.unwrap(), Profile::default());
+}
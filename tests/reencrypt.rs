@@ -0,0 +1,121 @@
+mod support;
+
+use hcaptcha_wasm_deobfuscator::commands::{self, instrument, load_module};
+use hcaptcha_wasm_deobfuscator::emulator::Emulator;
+use hcaptcha_wasm_deobfuscator::profile::Profile;
+use hcaptcha_wasm_deobfuscator::rng::Rng;
+use hcaptcha_wasm_deobfuscator::transformations::memory::MemEncFuncType;
+use hcaptcha_wasm_deobfuscator::transformations::Transformer;
+use support::obfuscator::{obfuscate, plain_module};
+use walrus::ir::Value;
+use walrus::{ConstExpr, DataKind, Module};
+
+// Writes a byte of the decrypted data segment
+struct PatchByte {
+    address: usize,
+    value: u8,
+}
+
+impl Transformer for PatchByte {
+    fn transform(&mut self, module: &mut Module) {
+        let id = module.data.iter().nth(1).unwrap().id();
+        let segment = module.data.get_mut(id);
+        let DataKind::Active {
+            offset: ConstExpr::Value(Value::I32(start)),
+            ..
+        } = segment.kind
+        else {
+            panic!("data segment has no constant offset");
+        };
+
+        segment.value[self.address - start as usize] = self.value;
+    }
+}
+
+#[test]
+fn bundled_assets_are_unchanged_without_passes() {
+    for path in ["assets/input.wasm", "assets/vm_input.wasm"] {
+        let mut module = load_module(path).unwrap();
+        instrument(&mut module, &Profile::default(), &mut []).unwrap();
+
+        let original = load_module(path).unwrap().emit_wasm();
+        assert!(module.emit_wasm() == original, "{} changed", path);
+    }
+}
+
+#[test]
+fn segments_come_from_the_profile() {
+    let original = load_module("assets/input.wasm").unwrap().emit_wasm();
+
+    let mut module = load_module("assets/input.wasm").unwrap();
+    instrument(&mut module, &Profile::load("profiles/input.toml").unwrap(), &mut []).unwrap();
+    assert!(module.emit_wasm() == original);
+
+    let mut module = load_module("assets/input.wasm").unwrap();
+    let profile = Profile::parse("data_segment = 7").unwrap();
+    assert!(instrument(&mut module, &profile, &mut []).is_err());
+}
+
+#[test]
+fn passes_see_plain_memory() {
+    for seed in 0..8 {
+        let mut rng = Rng::new(seed);
+        let plain = plain_module(&mut rng);
+        let mut module = plain.module;
+        let obfuscation = obfuscate(&mut module, &mut rng).unwrap();
+        let mut module = Module::from_buffer(&module.emit_wasm()).unwrap();
+
+        let i = rng.range(0, plain.data.len() as u64) as usize;
+        let value = plain.data[i] ^ 0x5a;
+        let mut passes: Vec<Box<dyn Transformer>> = vec![Box::new(PatchByte {
+            address: plain.base + i,
+            value,
+        })];
+        instrument(&mut module, &Profile::default(), &mut passes).unwrap();
+
+        // the wrappers still decrypt everything
+        let (load, _) = obfuscation
+            .loads
+            .iter()
+            .find(|(_, ty)| *ty == MemEncFuncType::Unsigned8)
+            .unwrap();
+        let mut emulator = Emulator::new(&module).unwrap();
+        for (j, expected) in plain.data.iter().enumerate() {
+            let expected = if j == i { value } else { *expected };
+            let result = emulator
+                .call_export(load, &[Value::I32((plain.base + j) as i32), Value::I32(0)])
+                .unwrap();
+            assert!(
+                matches!(result[..], [Value::I32(v)] if v == expected as i32),
+                "seed {}: {:?} at {:#x}, expected {}",
+                seed,
+                result,
+                plain.base + j,
+                expected
+            );
+        }
+    }
+}
+
+#[test]
+fn passes_from_the_command_line() {
+    let output = std::env::temp_dir().join(format!("instrument-{}.wasm", std::process::id()));
+    let args = |passes: &str| {
+        ["assets/input.wasm", output.to_str().unwrap(), "--passes", passes].map(String::from)
+    };
+
+    commands::instrument::run(&args("globals,indirect_calls")).unwrap();
+    let module = load_module(output.to_str().unwrap()).unwrap();
+    std::fs::remove_file(&output).unwrap();
+
+    // the data stays encrypted, the globals pass named the stack pointer
+    let original = load_module("assets/input.wasm").unwrap();
+    let data = |module: &Module| module.data.iter().map(|data| data.value.clone()).collect::<Vec<_>>();
+    assert!(data(&module) == data(&original));
+    assert!(module.globals.iter().any(|global| global.name.as_deref() == Some("stack_pointer")));
+
+    for pass in ["memory", "mba,inline"] {
+        let error = commands::instrument::run(&args(pass)).unwrap_err();
+        assert!(error.to_string().contains("can't run in instrument mode"), "{}", error);
+    }
+}
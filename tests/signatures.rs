@@ -0,0 +1,87 @@
+use hcaptcha_wasm_deobfuscator::analysis::signatures::SignatureDb;
+use hcaptcha_wasm_deobfuscator::transformations::signatures::SignatureTransformer;
+use hcaptcha_wasm_deobfuscator::transformations::Transformer;
+use walrus::ir::BinaryOp;
+use walrus::{FunctionBuilder, FunctionId, Module, ValType};
+
+// ((a - b) * k) ^ (a + b) >> 3, or with `b - a` when `swap`, long enough to be learned
+fn mix(module: &mut Module, name: &str, k: i32, swap: bool) -> FunctionId {
+    let (a, b) = (module.locals.add(ValType::I32), module.locals.add(ValType::I32));
+    let (first, second) = if swap { (b, a) } else { (a, b) };
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32; 2], &[ValType::I32]);
+    builder
+        .name(name.to_string())
+        .func_body()
+        .local_get(first)
+        .local_get(second)
+        .binop(BinaryOp::I32Sub)
+        .i32_const(k)
+        .binop(BinaryOp::I32Mul)
+        .local_get(a)
+        .local_get(b)
+        .binop(BinaryOp::I32Add)
+        .i32_const(3)
+        .binop(BinaryOp::I32ShrU)
+        .binop(BinaryOp::I32Xor);
+    builder.finish(vec![a, b], &mut module.funcs)
+}
+
+// Forward and backward `mix` with k = 7, and two identical k = 9 bodies under different names
+fn annotated() -> Module {
+    let mut module = Module::default();
+    mix(&mut module, "mix_forward", 7, false);
+    mix(&mut module, "mix_backward", 7, true);
+    mix(&mut module, "mix_other", 9, false);
+    mix(&mut module, "mix_again", 9, false);
+    Module::from_buffer(&module.emit_wasm()).unwrap()
+}
+
+fn names(module: &Module) -> Vec<Option<String>> {
+    module.funcs.iter().map(|func| func.name.clone()).collect()
+}
+
+#[test]
+fn learned_names_apply_to_a_stripped_build() {
+    let mut db = SignatureDb::default();
+    let stats = db.learn(&annotated());
+    assert_eq!((stats.learned, stats.conflicts), (2, 1));
+
+    let path = std::env::temp_dir().join(format!("signatures-{}.sigs", std::process::id()));
+    db.save(&path).unwrap();
+    let db = SignatureDb::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let mut module = annotated();
+    for id in module.funcs.iter().map(|func| func.id()).collect::<Vec<_>>() {
+        module.funcs.get_mut(id).name = None;
+    }
+
+    let mut transformer = SignatureTransformer::new(db);
+    transformer.transform(&mut module);
+    assert_eq!(transformer.applied, 2);
+
+    // the bodies differing only in operand order keep their own names, the ambiguous
+    // ones stay unnamed
+    let module = Module::from_buffer(&module.emit_wasm()).unwrap();
+    assert_eq!(
+        names(&module),
+        [Some("mix_forward".to_string()), Some("mix_backward".to_string()), None, None]
+    );
+}
+
+#[test]
+fn operand_order_is_not_the_same_function() {
+    let mut module = Module::default();
+    mix(&mut module, "mix_forward", 7, false);
+    let mut db = SignatureDb::default();
+    db.learn(&Module::from_buffer(&module.emit_wasm()).unwrap());
+
+    let mut module = Module::default();
+    mix(&mut module, "unknown", 7, true);
+    let mut module = Module::from_buffer(&module.emit_wasm()).unwrap();
+
+    let mut transformer = SignatureTransformer::new(db);
+    transformer.transform(&mut module);
+    assert_eq!(transformer.applied, 0);
+    assert_eq!(names(&module), [Some("unknown".to_string())]);
+}
@@ -0,0 +1,170 @@
+use hcaptcha_wasm_deobfuscator::commands::{find_function, load_deobfuscated, load_module};
+use hcaptcha_wasm_deobfuscator::emulator::differential::DifferentialHarness;
+use hcaptcha_wasm_deobfuscator::ssa::{Edge, Function, InstId, Op, RegionKind};
+use walrus::ir::{BinaryOp, Instr};
+use walrus::{FunctionBuilder, FunctionKind, Module, ValType};
+
+fn build(module: &Module, name: &str) -> Function {
+    let id = find_function(module, name).unwrap();
+    Function::build(module, module.funcs.get(id).kind.unwrap_local()).unwrap()
+}
+
+fn find(ssa: &Function, pred: impl Fn(&Op) -> bool) -> Vec<InstId> {
+    ssa.walk().into_iter().filter(|inst| pred(ssa.inst(*inst).op())).collect()
+}
+
+// - countdown(n), loop with a br_if back edge
+// - max(a, b), if/else with a result
+// - same(a, c), sets a local to the same value in both arms of an if
+fn ssa_module() -> Module {
+    let mut module = Module::default();
+
+    let n = module.locals.add(ValType::I32);
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[]);
+    builder.func_body().loop_(None, |body| {
+        let head = body.id();
+        body.local_get(n).i32_const(1).binop(BinaryOp::I32Sub).local_tee(n).br_if(head);
+    });
+    let id = builder.finish(vec![n], &mut module.funcs);
+    module.exports.add("countdown", id);
+
+    let (a, b) = (module.locals.add(ValType::I32), module.locals.add(ValType::I32));
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32, ValType::I32], &[ValType::I32]);
+    builder
+        .func_body()
+        .local_get(a)
+        .local_get(b)
+        .binop(BinaryOp::I32GtS)
+        .if_else(
+            ValType::I32,
+            |then| {
+                then.local_get(a);
+            },
+            |otherwise| {
+                otherwise.local_get(b);
+            },
+        );
+    let id = builder.finish(vec![a, b], &mut module.funcs);
+    module.exports.add("max", id);
+
+    let (a, c, x) = (
+        module.locals.add(ValType::I32),
+        module.locals.add(ValType::I32),
+        module.locals.add(ValType::I32),
+    );
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32, ValType::I32], &[ValType::I32]);
+    builder.func_body().local_get(c).if_else(
+        None,
+        |then| {
+            then.local_get(a).local_set(x);
+        },
+        |otherwise| {
+            otherwise.local_get(a).local_set(x);
+        },
+    );
+    builder.func_body().local_get(x);
+    let id = builder.finish(vec![a, c], &mut module.funcs);
+    module.exports.add("same", id);
+
+    Module::from_buffer(&module.emit_wasm()).unwrap()
+}
+
+#[test]
+fn loop_values_go_through_phis() {
+    let module = ssa_module();
+    let ssa = build(&module, "countdown");
+
+    let phis = find(&ssa, |op| matches!(op, Op::Phi(_)));
+    assert_eq!(phis.len(), 1);
+    let phi = ssa.inst(phis[0]);
+    let Op::Phi(region) = *phi.op() else { unreachable!() };
+    assert_eq!(ssa.region(region).kind(), RegionKind::Loop);
+    assert_eq!(ssa.region(region).preds().len(), 2);
+
+    // entered with the parameter, then the decremented value from the back edge
+    assert!(matches!(ssa.operand(phis[0], 0).unwrap().op(), Op::Param(0)));
+    let sub = ssa.def(phi.args()[1]);
+    assert!(matches!(ssa.inst(sub).op(), Op::Instr(Instr::Binop(op)) if matches!(op.op, BinaryOp::I32Sub)));
+    assert_eq!(ssa.operand(sub, 0).unwrap().results(), phi.results());
+    assert!(matches!(ssa.constant(ssa.inst(sub).args()[1]), Some(walrus::ir::Value::I32(1))));
+
+    // the difference feeds the back edge and its condition
+    let users = ssa.uses(phi.args()[1]);
+    assert_eq!(users.len(), 2);
+    let br_if = users.iter().find(|user| matches!(ssa.inst(**user).op(), Op::BrIf(_))).unwrap();
+    assert_eq!(ssa.region(region).preds()[1], Edge::From(*br_if));
+    assert!(users.contains(&phis[0]));
+}
+
+#[test]
+fn if_results_merge_in_phis() {
+    let module = ssa_module();
+    let ssa = build(&module, "max");
+
+    let phis = find(&ssa, |op| matches!(op, Op::Phi(_)));
+    assert_eq!(phis.len(), 1);
+    let phi = ssa.inst(phis[0]);
+    assert!(matches!(ssa.operand(phis[0], 0).unwrap().op(), Op::Param(0)));
+    assert!(matches!(ssa.operand(phis[0], 1).unwrap().op(), Op::Param(1)));
+
+    let returns = find(&ssa, |op| matches!(op, Op::Return));
+    assert_eq!(returns.len(), 1);
+    assert_eq!(ssa.inst(returns[0]).args(), phi.results());
+    assert_eq!(ssa.uses(phi.results()[0]), &returns[..]);
+}
+
+#[test]
+fn single_value_merges_have_no_phi() {
+    let module = ssa_module();
+    let ssa = build(&module, "same");
+
+    assert!(find(&ssa, |op| matches!(op, Op::Phi(_))).is_empty());
+    let returns = find(&ssa, |op| matches!(op, Op::Return));
+    assert!(matches!(ssa.operand(returns[0], 0).unwrap().op(), Op::Param(0)));
+    // the zero `x` starts with is never read
+    assert!(find(&ssa, |op| matches!(op, Op::Const(_))).is_empty());
+}
+
+#[test]
+fn lowering_round_trips() {
+    let mut module = ssa_module();
+    let ids = module.funcs.iter().map(|func| func.id()).collect::<Vec<_>>();
+    for id in ids {
+        let ssa = Function::build(&module, module.funcs.get(id).kind.unwrap_local()).unwrap();
+        ssa.lower(&mut module, id);
+    }
+    let module = Module::from_buffer(&module.emit_wasm()).unwrap();
+
+    // the lowered code builds into the same form
+    let ssa = build(&module, "countdown");
+    assert_eq!(find(&ssa, |op| matches!(op, Op::Phi(_))).len(), 1);
+    let ssa = build(&module, "max");
+    assert_eq!(find(&ssa, |op| matches!(op, Op::Phi(_))).len(), 1);
+}
+
+#[test]
+fn lowered_asset_preserves_semantics() {
+    let path = "assets/input.wasm";
+    let original = load_module(path).unwrap();
+    let mut rewritten = load_deobfuscated(path).unwrap();
+
+    let ids = rewritten
+        .funcs
+        .iter()
+        .filter(|func| matches!(func.kind, FunctionKind::Local(_)))
+        .map(|func| func.id())
+        .collect::<Vec<_>>();
+    for id in ids {
+        let ssa = Function::build(&rewritten, rewritten.funcs.get(id).kind.unwrap_local()).unwrap();
+        ssa.lower(&mut rewritten, id);
+    }
+    let rewritten = Module::from_buffer(&rewritten.emit_wasm()).unwrap();
+
+    let mut harness = DifferentialHarness::new(&original, &rewritten).unwrap();
+    harness.seed = 0x5eed;
+    let report = harness.run().unwrap();
+    if let Some(divergence) = &report.divergence {
+        panic!("{} ({} calls)", divergence, report.calls);
+    }
+    assert!(report.calls > report.inconclusive, "every call ran out of fuel");
+}
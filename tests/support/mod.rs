@@ -0,0 +1,4 @@
+// Shared by the integration tests, not every test uses everything
+#![allow(dead_code)]
+
+pub mod obfuscator;
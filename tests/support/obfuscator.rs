@@ -0,0 +1,1044 @@
+// Applies the hCaptcha memory scheme to a plain module, so the deobfuscator can be
+// tested without the proprietary binaries:
+// - logical memory is split in 320 bytes pages, page `p` has a flag byte at
+//   `p * 328 + 1024` and its data at `p * 328 + 1032`, xored with a 96 bytes table
+// - every load/store goes through an exported wrapper, `(idx, offset)` for loads and
+//   `(idx, value, offset)` for stores
+// - stores on a page that was never written call an exported page init first
+//
+// Every constant the deobfuscator should not depend on is randomized.
+
+use anyhow::{bail, Context};
+use hcaptcha_wasm_deobfuscator::rng::Rng;
+use hcaptcha_wasm_deobfuscator::transformations::memory::MemEncFuncType;
+use std::collections::{HashMap, VecDeque};
+use walrus::ir::{
+    BinaryOp, Block, Br, BrIf, Call, Const, ExtendedLoad, IfElse, Instr, InstrSeqId, InstrSeqType,
+    LoadKind, LocalGet, LocalSet, LocalTee, Loop, MemArg, StoreKind, UnaryOp, Value,
+};
+use walrus::{
+    ConstExpr, DataKind, ExportItem, FunctionBuilder, FunctionId, InstrLocId, InstrSeqBuilder, LocalFunction,
+    LocalId, MemoryId, Module, ValType,
+};
+
+pub const PAGE_SIZE: usize = 320;
+const PAGE_STRIDE: usize = 328;
+const PAGES_START: usize = 1024;
+const TABLE_SIZE: usize = 96;
+
+// The deobfuscator only guesses where the plain data starts for data in these pages
+const FIRST_PAGES: (usize, usize) = (1712, 3312);
+
+pub const MEM_ENC_FUNC_TYPES: [MemEncFuncType; 14] = [
+    MemEncFuncType::Unsigned8,
+    MemEncFuncType::Unsigned16,
+    MemEncFuncType::Signed8,
+    MemEncFuncType::Signed16,
+    MemEncFuncType::Signed32,
+    MemEncFuncType::Signed64,
+    MemEncFuncType::I64Unsigned8,
+    MemEncFuncType::I64Unsigned16,
+    MemEncFuncType::I64Unsigned32,
+    MemEncFuncType::I64Signed8,
+    MemEncFuncType::I64Signed16,
+    MemEncFuncType::I64Signed32,
+    MemEncFuncType::Float32,
+    MemEncFuncType::Float64,
+];
+
+// Types that get a store wrapper
+pub const STORE_FUNC_TYPES: [MemEncFuncType; 11] = [
+    MemEncFuncType::Unsigned8,
+    MemEncFuncType::Unsigned16,
+    MemEncFuncType::Signed8,
+    MemEncFuncType::Signed16,
+    MemEncFuncType::Signed32,
+    MemEncFuncType::Signed64,
+    MemEncFuncType::I64Signed8,
+    MemEncFuncType::I64Signed16,
+    MemEncFuncType::I64Signed32,
+    MemEncFuncType::Float32,
+    MemEncFuncType::Float64,
+];
+
+pub struct PlainModule {
+    pub module: Module,
+    // Logical address and contents of its only data segment
+    pub base: usize,
+    pub data: Vec<u8>,
+    pub events: String,
+}
+
+pub struct Obfuscation {
+    pub table_start: usize,
+    pub table: Vec<u8>,
+    // Export name of every wrapper
+    pub loads: Vec<(String, MemEncFuncType)>,
+    pub stores: Vec<(String, MemEncFuncType)>,
+    pub page_init: String,
+}
+
+struct Layout {
+    memory: MemoryId,
+    table_start: i32,
+}
+
+fn shuffle<T>(rng: &mut Rng, items: &mut [T]) {
+    for i in (1..items.len()).rev() {
+        items.swap(i, rng.range(0, i as u64 + 1) as usize);
+    }
+}
+
+fn u8_arg() -> (LoadKind, MemArg) {
+    (
+        LoadKind::I32_8 {
+            kind: ExtendedLoad::ZeroExtend,
+        },
+        MemArg {
+            align: 1,
+            offset: 0,
+        },
+    )
+}
+
+// Random plain module with one data segment, the events init function and an
+// exported accessor for every load and store kind
+pub fn plain_module(rng: &mut Rng) -> PlainModule {
+    let mut module = Module::default();
+
+    let base = rng.range(FIRST_PAGES.0 as u64 + 1, 3200) as usize * PAGE_SIZE
+        + rng.range(0, PAGE_SIZE as u64) as usize;
+    let len = rng.range(2048, 8192) as usize;
+    let mut data = (0..len).map(|_| rng.next_u32() as u8).collect::<Vec<_>>();
+
+    // leave whole pages empty once in a while, those may never be initialized
+    if rng.bool() {
+        let start = rng.range(1024, (len - 700) as u64) as usize;
+        data[start..start + 700].fill(0);
+    }
+
+    let lines = rng.range(8, 40);
+    let events = (0..lines)
+        .map(|_| {
+            let name_len = rng.range(1, 5);
+            let name = (0..name_len)
+                .map(|_| char::from_digit(rng.range(0, 36) as u32, 36).unwrap())
+                .collect::<String>();
+            format!("{},{:x},{}", name, rng.next_u32(), rng.range(0, 2))
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    // The events are xored with the bytes at the initial stack pointer, both live in the
+    // data segment, the byte after the last event decrypts to 0.
+    let padded_len = (events.len() + 1 + 3) & !3;
+    let key = (base + rng.range(16, 256) as usize + 3) & !3;
+    let encrypted = key + padded_len + rng.range(0, 64) as usize * 4;
+    for (i, c) in events.bytes().chain([0]).enumerate() {
+        data[encrypted - base + i] = c ^ data[key - base + i];
+    }
+
+    let memory = module.memories.add_local(false, false, 18, None, None);
+    module.data.add(
+        DataKind::Active {
+            memory,
+            offset: ConstExpr::Value(Value::I32(base as i32)),
+        },
+        data.clone(),
+    );
+    module.globals.add_local(
+        ValType::I32,
+        true,
+        false,
+        ConstExpr::Value(Value::I32(key as i32)),
+    );
+
+    let init_events = events_function(&mut module, memory, key, encrypted, padded_len);
+    module.exports.add("init_events", init_events);
+
+    let loads = [
+        ("load_u8", LoadKind::I32_8 { kind: ExtendedLoad::ZeroExtend }, ValType::I32, 1),
+        ("load_s8", LoadKind::I32_8 { kind: ExtendedLoad::SignExtend }, ValType::I32, 1),
+        ("load_u16", LoadKind::I32_16 { kind: ExtendedLoad::ZeroExtend }, ValType::I32, 2),
+        ("load_s16", LoadKind::I32_16 { kind: ExtendedLoad::SignExtend }, ValType::I32, 2),
+        ("load_i32", LoadKind::I32 { atomic: false }, ValType::I32, 4),
+        ("load_i64", LoadKind::I64 { atomic: false }, ValType::I64, 8),
+        ("load_i64_u8", LoadKind::I64_8 { kind: ExtendedLoad::ZeroExtend }, ValType::I64, 1),
+        ("load_i64_s8", LoadKind::I64_8 { kind: ExtendedLoad::SignExtend }, ValType::I64, 1),
+        ("load_i64_u16", LoadKind::I64_16 { kind: ExtendedLoad::ZeroExtend }, ValType::I64, 2),
+        ("load_i64_s16", LoadKind::I64_16 { kind: ExtendedLoad::SignExtend }, ValType::I64, 2),
+        ("load_i64_u32", LoadKind::I64_32 { kind: ExtendedLoad::ZeroExtend }, ValType::I64, 4),
+        ("load_i64_s32", LoadKind::I64_32 { kind: ExtendedLoad::SignExtend }, ValType::I64, 4),
+        ("load_f32", LoadKind::F32, ValType::F32, 4),
+        ("load_f64", LoadKind::F64, ValType::F64, 8),
+    ];
+    for (name, kind, ty, align) in loads {
+        let address = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ty]);
+        builder.func_body().local_get(address).load(
+            memory,
+            kind,
+            MemArg {
+                align,
+                offset: rng.range(0, 32) as u32,
+            },
+        );
+        let id = builder.finish(vec![address], &mut module.funcs);
+        module.exports.add(name, id);
+    }
+
+    let stores = [
+        ("store_i8", StoreKind::I32_8 { atomic: false }, ValType::I32, 1),
+        ("store_i16", StoreKind::I32_16 { atomic: false }, ValType::I32, 2),
+        ("store_i32", StoreKind::I32 { atomic: false }, ValType::I32, 4),
+        ("store_i64", StoreKind::I64 { atomic: false }, ValType::I64, 8),
+        ("store_i64_8", StoreKind::I64_8 { atomic: false }, ValType::I64, 1),
+        ("store_i64_16", StoreKind::I64_16 { atomic: false }, ValType::I64, 2),
+        ("store_i64_32", StoreKind::I64_32 { atomic: false }, ValType::I64, 4),
+        ("store_f32", StoreKind::F32, ValType::F32, 4),
+        ("store_f64", StoreKind::F64, ValType::F64, 8),
+    ];
+    for (name, kind, ty, align) in stores {
+        let address = module.locals.add(ValType::I32);
+        let value = module.locals.add(ty);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32, ty], &[]);
+        builder.func_body().local_get(address).local_get(value).store(
+            memory,
+            kind,
+            MemArg {
+                align,
+                offset: rng.range(0, 32) as u32,
+            },
+        );
+        let id = builder.finish(vec![address, value], &mut module.funcs);
+        module.exports.add(name, id);
+    }
+
+    PlainModule {
+        module,
+        base,
+        data,
+        events,
+    }
+}
+
+// Decrypts the events in place, word by word, the way `fetch_events` expects to find it
+fn events_function(
+    module: &mut Module,
+    memory: MemoryId,
+    key: usize,
+    encrypted: usize,
+    len: usize,
+) -> FunctionId {
+    let mask = module.locals.add(ValType::I32);
+    let i = module.locals.add(ValType::I32);
+    let more = module.locals.add(ValType::I32);
+    let word = MemArg {
+        align: 4,
+        offset: 0,
+    };
+
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+    let mut body = builder.func_body();
+    body.i32_const(-1)
+        .i32_const(268435455)
+        .binop(BinaryOp::I32And)
+        .i32_const(i32::MIN)
+        .binop(BinaryOp::I32Or)
+        .local_set(mask)
+        .i32_const(0)
+        .local_set(i);
+
+    body.loop_(None, |body| {
+        let start = body.id();
+        body.local_get(i)
+            .i32_const(key as i32)
+            .binop(BinaryOp::I32Add)
+            .local_get(i)
+            .i32_const(key as i32)
+            .binop(BinaryOp::I32Add)
+            .load(memory, LoadKind::I32 { atomic: false }, word)
+            .local_get(i)
+            .i32_const(encrypted as i32)
+            .binop(BinaryOp::I32Add)
+            .load(memory, LoadKind::I32 { atomic: false }, word)
+            .binop(BinaryOp::I32Xor)
+            .store(memory, StoreKind::I32 { atomic: false }, word)
+            .local_get(i)
+            .i32_const(len as i32 - 4)
+            .binop(BinaryOp::I32LtU)
+            .local_set(more)
+            .local_get(i)
+            .i32_const(4)
+            .binop(BinaryOp::I32Add)
+            .local_set(i)
+            .local_get(more)
+            .br_if(start);
+    });
+
+    body.local_get(mask);
+    builder.finish(vec![], &mut module.funcs)
+}
+
+pub fn obfuscate(module: &mut Module, rng: &mut Rng) -> Result<Obfuscation, anyhow::Error> {
+    if module.memories.iter().count() != 1 {
+        bail!("expected a single memory");
+    }
+    let memory = module.get_memory_id()?;
+
+    if module.data.iter().count() != 1 {
+        bail!("expected a single data segment");
+    }
+    let (data_id, base) = match module.data.iter().next().map(|d| (d.id(), &d.kind)) {
+        Some((
+            id,
+            DataKind::Active {
+                offset: ConstExpr::Value(Value::I32(base)),
+                ..
+            },
+        )) => (id, *base as usize),
+        _ => bail!("expected an active data segment"),
+    };
+
+    // Start a page early, the deobfuscator only estimates where the plain data starts
+    let first_page = (base / PAGE_SIZE).checked_sub(1).context("data segment too low")?;
+    if !(FIRST_PAGES.0..FIRST_PAGES.1).contains(&first_page) {
+        bail!("data segment must start in pages {:?}", FIRST_PAGES);
+    }
+
+    let plain_functions = module
+        .funcs
+        .iter_local()
+        .map(|(id, _)| id)
+        .collect::<Vec<_>>();
+
+    let table_start = rng.range(256, (PAGES_START - TABLE_SIZE - 8) as u64) as usize;
+    let table = (0..TABLE_SIZE)
+        .map(|_| rng.next_u32() as u8)
+        .collect::<Vec<_>>();
+    let layout = Layout {
+        memory,
+        table_start: table_start as i32,
+    };
+
+    let mut names = ('A'..='Z')
+        .chain('a'..='z')
+        .map(|c| format!("{}b", c))
+        .collect::<Vec<_>>();
+    shuffle(rng, &mut names);
+
+    let page_init = page_init_function(module, &layout, rng.range(1, 256) as i32);
+    let page_init_name = names.pop().unwrap();
+    module.exports.add(&page_init_name, page_init);
+
+    let mut types = MEM_ENC_FUNC_TYPES;
+    shuffle(rng, &mut types);
+
+    let mut loads = Vec::new();
+    let mut load_ids = Vec::new();
+    for ty in types {
+        let id = load_wrapper(module, &layout, ty);
+        let name = names.pop().unwrap();
+        module.exports.add(&name, id);
+        loads.push((name, ty));
+        load_ids.push((ty, id));
+    }
+
+    let mut types = STORE_FUNC_TYPES;
+    shuffle(rng, &mut types);
+
+    let mut stores = Vec::new();
+    let mut store_ids = Vec::new();
+    for ty in types {
+        let id = store_wrapper(module, &layout, page_init, ty);
+        let name = names.pop().unwrap();
+        module.exports.add(&name, id);
+        stores.push((name, ty));
+        store_ids.push((ty, id));
+    }
+
+    for id in plain_functions {
+        wrap_memory_accesses(module, id, &load_ids, &store_ids)?;
+    }
+
+    // the table goes in the raw area, followed by its first bytes so wide reads can wrap
+    let raw_start = rng.range(0, 64) as usize;
+    let mut raw = (raw_start..table_start)
+        .map(|_| rng.next_u32() as u8)
+        .collect::<Vec<_>>();
+    raw.extend_from_slice(&table);
+    raw.extend_from_slice(&table[..8]);
+
+    let plain = std::mem::take(&mut module.data.get_mut(data_id).value);
+    let encrypted = encrypt(rng, &table, first_page, base, &plain);
+    let encrypted_start = first_page * PAGE_STRIDE + PAGES_START;
+
+    let raw_segment = module.data.get_mut(data_id);
+    raw_segment.value = raw;
+    raw_segment.kind = DataKind::Active {
+        memory,
+        offset: ConstExpr::Value(Value::I32(raw_start as i32)),
+    };
+
+    let end = encrypted_start + encrypted.len();
+    module.data.add(
+        DataKind::Active {
+            memory,
+            offset: ConstExpr::Value(Value::I32(encrypted_start as i32)),
+        },
+        encrypted,
+    );
+
+    let memory = module.memories.get_mut(memory);
+    memory.initial = memory.initial.max(end.div_ceil(65536) as u64);
+
+    Ok(Obfuscation {
+        table_start,
+        table,
+        loads,
+        stores,
+        page_init: page_init_name,
+    })
+}
+
+// Physical image of the pages holding `plain`, starting with the flag of `first_page`
+fn encrypt(rng: &mut Rng, table: &[u8], first_page: usize, base: usize, plain: &[u8]) -> Vec<u8> {
+    let last_page = (base + plain.len() - 1) / PAGE_SIZE;
+    let mut out = Vec::with_capacity((last_page - first_page + 1) * PAGE_STRIDE);
+
+    for page in first_page..=last_page {
+        let bytes = (page * PAGE_SIZE..(page + 1) * PAGE_SIZE)
+            .map(|address| match address.checked_sub(base) {
+                Some(i) if i < plain.len() => plain[i],
+                _ => 0,
+            })
+            .collect::<Vec<_>>();
+
+        // an uninitialized page reads as zeros whatever it holds
+        if bytes.iter().all(|b| *b == 0) && rng.bool() {
+            out.push(0);
+            out.extend((1..PAGE_STRIDE).map(|_| rng.next_u32() as u8));
+            continue;
+        }
+
+        out.push(rng.range(1, 256) as u8);
+        out.extend((1..PAGE_STRIDE - PAGE_SIZE).map(|_| rng.next_u32() as u8));
+        out.extend(
+            bytes
+                .iter()
+                .enumerate()
+                .map(|(i, b)| b ^ table[(page * PAGE_SIZE + i) % TABLE_SIZE]),
+        );
+    }
+
+    out
+}
+
+// `i32.load offset=o` becomes `i32.const o; call wrapper`, same for stores
+fn wrap_memory_accesses(
+    module: &mut Module,
+    id: FunctionId,
+    loads: &[(MemEncFuncType, FunctionId)],
+    stores: &[(MemEncFuncType, FunctionId)],
+) -> Result<(), anyhow::Error> {
+    let wrapper = |wrappers: &[(MemEncFuncType, FunctionId)], ty: Option<MemEncFuncType>| {
+        ty.and_then(|ty| wrappers.iter().find(|(t, _)| *t == ty).map(|(_, id)| *id))
+    };
+
+    let func = module.funcs.get_mut(id).kind.unwrap_local_mut();
+    let mut stack = VecDeque::<InstrSeqId>::new();
+    stack.push_front(func.entry_block());
+
+    while let Some(block_id) = stack.pop_back() {
+        let block = func.block_mut(block_id);
+        let mut replacements = Vec::new();
+
+        for (idx, (instr, _)) in block.instrs.iter().enumerate() {
+            match instr {
+                Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => stack.push_front(*seq),
+                Instr::IfElse(IfElse {
+                    consequent,
+                    alternative,
+                }) => {
+                    stack.push_front(*consequent);
+                    stack.push_front(*alternative);
+                }
+                Instr::Load(load) => {
+                    let func = wrapper(loads, MemEncFuncType::from_load_kind(&load.kind))
+                        .with_context(|| format!("unsupported load {:?}", load.kind))?;
+                    replacements.push((idx, load.arg.offset, func));
+                }
+                Instr::Store(store) => {
+                    let func = wrapper(stores, MemEncFuncType::from_store_kind(&store.kind))
+                        .with_context(|| format!("unsupported store {:?}", store.kind))?;
+                    replacements.push((idx, store.arg.offset, func));
+                }
+                _ => {}
+            }
+        }
+
+        for (idx, offset, func) in replacements.into_iter().rev() {
+            let loc = block.instrs[idx].1;
+            block.instrs[idx] = (
+                Instr::Const(Const {
+                    value: Value::I32(offset as i32),
+                }),
+                loc,
+            );
+            block.instrs.insert(idx + 1, (Instr::Call(Call { func }), loc));
+        }
+    }
+
+    Ok(())
+}
+
+fn flag_address(body: &mut InstrSeqBuilder, page: LocalId) {
+    body.local_get(page)
+        .i32_const(PAGE_STRIDE as i32)
+        .binop(BinaryOp::I32Mul)
+        .i32_const(PAGES_START as i32)
+        .binop(BinaryOp::I32Add);
+}
+
+fn data_address(body: &mut InstrSeqBuilder, address: LocalId, page: LocalId) {
+    body.local_get(page)
+        .i32_const(3)
+        .binop(BinaryOp::I32Shl)
+        .local_get(address)
+        .binop(BinaryOp::I32Add)
+        .i32_const((PAGES_START + 8) as i32)
+        .binop(BinaryOp::I32Add);
+}
+
+fn table_address(body: &mut InstrSeqBuilder, layout: &Layout, address: LocalId) {
+    body.local_get(address)
+        .i32_const(TABLE_SIZE as i32)
+        .binop(BinaryOp::I32RemU)
+        .i32_const(layout.table_start)
+        .binop(BinaryOp::I32Add);
+}
+
+fn set_page(body: &mut InstrSeqBuilder, address: LocalId, page: LocalId) {
+    body.local_get(address)
+        .i32_const(PAGE_SIZE as i32)
+        .binop(BinaryOp::I32DivU)
+        .local_set(page);
+}
+
+// Plain byte at `address`, 0 if its page was never initialized
+fn read_byte(body: &mut InstrSeqBuilder, layout: &Layout, address: LocalId, page: LocalId) {
+    let (kind, arg) = u8_arg();
+
+    set_page(body, address, page);
+    flag_address(body, page);
+    body.load(layout.memory, kind, arg);
+    body.if_else(
+        ValType::I32,
+        |then| {
+            data_address(then, address, page);
+            then.load(layout.memory, kind, arg);
+        },
+        |else_| {
+            table_address(else_, layout, address);
+            else_.load(layout.memory, kind, arg);
+        },
+    );
+    table_address(body, layout, address);
+    body.load(layout.memory, kind, arg).binop(BinaryOp::I32Xor);
+}
+
+// Little endian i32 of the `width` plain bytes at `address + k`. Bytes are merged in
+// halves, a shift by 24 would make the value look like a sign extension.
+fn read_bytes(
+    body: &mut InstrSeqBuilder,
+    layout: &Layout,
+    address: LocalId,
+    scratch: LocalId,
+    page: LocalId,
+    k: i32,
+    width: i32,
+) {
+    if width == 1 {
+        if k == 0 {
+            read_byte(body, layout, address, page);
+        } else {
+            body.local_get(address)
+                .i32_const(k)
+                .binop(BinaryOp::I32Add)
+                .local_set(scratch);
+            read_byte(body, layout, scratch, page);
+        }
+        return;
+    }
+
+    let half = width / 2;
+    read_bytes(body, layout, address, scratch, page, k, half);
+    read_bytes(body, layout, address, scratch, page, k + half, half);
+    body.i32_const(half * 8)
+        .binop(BinaryOp::I32Shl)
+        .binop(BinaryOp::I32Or);
+}
+
+fn load_wrapper(module: &mut Module, layout: &Layout, ty: MemEncFuncType) -> FunctionId {
+    let idx = module.locals.add(ValType::I32);
+    let offset = module.locals.add(ValType::I32);
+    let address = module.locals.add(ValType::I32);
+    let scratch = module.locals.add(ValType::I32);
+    let page = module.locals.add(ValType::I32);
+
+    let mut builder = FunctionBuilder::new(
+        &mut module.types,
+        &[ValType::I32, ValType::I32],
+        &[ty.val_type()],
+    );
+    let mut body = builder.func_body();
+    body.local_get(idx)
+        .local_get(offset)
+        .binop(BinaryOp::I32Add)
+        .local_set(address);
+
+    let read = |body: &mut InstrSeqBuilder, k, width| {
+        read_bytes(body, layout, address, scratch, page, k, width)
+    };
+
+    match ty {
+        MemEncFuncType::Unsigned8 => {
+            read(&mut body, 0, 1);
+            body.i32_const(255).binop(BinaryOp::I32And);
+        }
+        MemEncFuncType::Signed8 => {
+            read(&mut body, 0, 1);
+            body.i32_const(24)
+                .binop(BinaryOp::I32Shl)
+                .i32_const(24)
+                .binop(BinaryOp::I32ShrS);
+        }
+        MemEncFuncType::Unsigned16 => {
+            read(&mut body, 0, 2);
+            body.i32_const(65535).binop(BinaryOp::I32And);
+        }
+        MemEncFuncType::Signed16 => {
+            read(&mut body, 0, 2);
+            body.i32_const(65535)
+                .binop(BinaryOp::I32And)
+                .i32_const(16)
+                .binop(BinaryOp::I32Shl)
+                .i32_const(16)
+                .binop(BinaryOp::I32ShrS);
+        }
+        MemEncFuncType::Signed32 => read(&mut body, 0, 4),
+        // every way of getting an i64 out of a narrower read the mapper knows
+        MemEncFuncType::I64Unsigned8 => {
+            read(&mut body, 0, 1);
+            body.i32_const(255)
+                .binop(BinaryOp::I32And)
+                .unop(UnaryOp::I64ExtendUI32);
+        }
+        MemEncFuncType::I64Signed8 => {
+            read(&mut body, 0, 1);
+            body.i32_const(24)
+                .binop(BinaryOp::I32Shl)
+                .i32_const(24)
+                .binop(BinaryOp::I32ShrS)
+                .unop(UnaryOp::I64ExtendSI32);
+        }
+        MemEncFuncType::I64Unsigned16 => {
+            read(&mut body, 0, 2);
+            body.unop(UnaryOp::I64ExtendUI32)
+                .i64_const(0xffff)
+                .binop(BinaryOp::I64And);
+        }
+        MemEncFuncType::I64Signed16 => {
+            read(&mut body, 0, 2);
+            body.unop(UnaryOp::I64ExtendUI32)
+                .i64_const(48)
+                .binop(BinaryOp::I64Shl)
+                .i64_const(48)
+                .binop(BinaryOp::I64ShrS);
+        }
+        MemEncFuncType::I64Unsigned32 => {
+            read(&mut body, 0, 4);
+            body.unop(UnaryOp::I64ExtendUI32);
+        }
+        MemEncFuncType::I64Signed32 => {
+            read(&mut body, 0, 4);
+            body.unop(UnaryOp::I64ExtendUI32)
+                .unop(UnaryOp::I64Extend32S);
+        }
+        MemEncFuncType::Float32 => {
+            read(&mut body, 0, 4);
+            body.unop(UnaryOp::F32ReinterpretI32);
+        }
+        MemEncFuncType::Signed64 | MemEncFuncType::Float64 => {
+            read(&mut body, 0, 4);
+            body.unop(UnaryOp::I64ExtendUI32);
+            read(&mut body, 4, 4);
+            body.unop(UnaryOp::I64ExtendUI32)
+                .i64_const(32)
+                .binop(BinaryOp::I64Shl)
+                .binop(BinaryOp::I64Or);
+
+            if ty == MemEncFuncType::Float64 {
+                body.unop(UnaryOp::F64ReinterpretI64);
+            }
+        }
+    }
+
+    builder.finish(vec![idx, offset], &mut module.funcs)
+}
+
+// Calls the page init if the page holding `address` was never written
+fn ensure_page(body: &mut InstrSeqBuilder, layout: &Layout, page_init: FunctionId, address: LocalId, page: LocalId) {
+    let (kind, arg) = u8_arg();
+
+    set_page(body, address, page);
+    flag_address(body, page);
+    body.load(layout.memory, kind, arg)
+        .unop(UnaryOp::I32Eqz)
+        .if_else(
+            None,
+            |then| {
+                then.local_get(page).call(page_init);
+            },
+            |_| {},
+        );
+}
+
+fn store_wrapper(
+    module: &mut Module,
+    layout: &Layout,
+    page_init: FunctionId,
+    ty: MemEncFuncType,
+) -> FunctionId {
+    let width = ty.width() as i32;
+    let value_type = ty.val_type();
+
+    let idx = module.locals.add(ValType::I32);
+    let value = module.locals.add(value_type);
+    let offset = module.locals.add(ValType::I32);
+    let address = module.locals.add(ValType::I32);
+    let scratch = module.locals.add(ValType::I32);
+    let page = module.locals.add(ValType::I32);
+    let bits = module.locals.add(ValType::I64);
+
+    let mut builder = FunctionBuilder::new(
+        &mut module.types,
+        &[ValType::I32, value_type, ValType::I32],
+        &[],
+    );
+    let mut body = builder.func_body();
+    body.local_get(idx)
+        .local_get(offset)
+        .binop(BinaryOp::I32Add)
+        .local_set(address)
+        .local_get(value);
+
+    match value_type {
+        ValType::I32 => body.unop(UnaryOp::I64ExtendUI32),
+        ValType::F32 => body
+            .unop(UnaryOp::I32ReinterpretF32)
+            .unop(UnaryOp::I64ExtendUI32),
+        ValType::F64 => body.unop(UnaryOp::I64ReinterpretF64),
+        _ => &mut body,
+    };
+    body.local_set(bits);
+
+    ensure_page(&mut body, layout, page_init, address, page);
+    body.local_get(address)
+        .i32_const(width - 1)
+        .binop(BinaryOp::I32Add)
+        .local_set(scratch);
+    ensure_page(&mut body, layout, page_init, scratch, page);
+
+    // Byte by byte when crossing a page, a single store otherwise. The wide store
+    // comes last, the store wrapper mapper looks at the last one.
+    let (kind, arg) = u8_arg();
+    body.local_get(address)
+        .i32_const(PAGE_SIZE as i32)
+        .binop(BinaryOp::I32RemU)
+        .i32_const(PAGE_SIZE as i32 - width)
+        .binop(BinaryOp::I32GtU)
+        .if_else(
+            None,
+            |then| {
+                for k in 0..width {
+                    then.local_get(address)
+                        .i32_const(k)
+                        .binop(BinaryOp::I32Add)
+                        .local_set(scratch);
+                    set_page(then, scratch, page);
+                    data_address(then, scratch, page);
+                    then.local_get(bits)
+                        .i64_const(k as i64 * 8)
+                        .binop(BinaryOp::I64ShrU)
+                        .unop(UnaryOp::I32WrapI64);
+                    table_address(then, layout, scratch);
+                    then.load(layout.memory, kind, arg)
+                        .binop(BinaryOp::I32Xor)
+                        .store(
+                            layout.memory,
+                            StoreKind::I32_8 { atomic: false },
+                            MemArg {
+                                align: 1,
+                                offset: 0,
+                            },
+                        );
+                }
+            },
+            |else_| {
+                set_page(else_, address, page);
+                data_address(else_, address, page);
+                else_.local_get(bits);
+                table_address(else_, layout, address);
+                else_
+                    .load(
+                        layout.memory,
+                        LoadKind::I64 { atomic: false },
+                        MemArg {
+                            align: 1,
+                            offset: 0,
+                        },
+                    )
+                    .binop(BinaryOp::I64Xor);
+
+                // i64 values are stored as they are, everything else as an i32
+                let store = match (value_type, width) {
+                    (ValType::I64, _) => ty.store_kind(),
+                    (_, 1) => StoreKind::I32_8 { atomic: false },
+                    (_, 2) => StoreKind::I32_16 { atomic: false },
+                    (_, 4) => StoreKind::I32 { atomic: false },
+                    _ => StoreKind::I64 { atomic: false },
+                };
+                if value_type != ValType::I64 && width < 8 {
+                    else_.unop(UnaryOp::I32WrapI64);
+                }
+                else_.store(
+                    layout.memory,
+                    store,
+                    MemArg {
+                        align: 1,
+                        offset: 0,
+                    },
+                );
+            },
+        );
+
+    builder.finish(vec![idx, value, offset], &mut module.funcs)
+}
+
+// Marks the page as written and fills it with encrypted zeros
+fn page_init_function(module: &mut Module, layout: &Layout, flag: i32) -> FunctionId {
+    let page = module.locals.add(ValType::I32);
+    let i = module.locals.add(ValType::I32);
+    let (kind, arg) = u8_arg();
+    let byte = StoreKind::I32_8 { atomic: false };
+
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[]);
+    let mut body = builder.func_body();
+    flag_address(&mut body, page);
+    body.i32_const(flag).store(layout.memory, byte, arg);
+
+    body.loop_(None, |body| {
+        let start = body.id();
+        flag_address(body, page);
+        body.i32_const(8)
+            .binop(BinaryOp::I32Add)
+            .local_get(i)
+            .binop(BinaryOp::I32Add)
+            .local_get(page)
+            .i32_const(PAGE_SIZE as i32)
+            .binop(BinaryOp::I32Mul)
+            .local_get(i)
+            .binop(BinaryOp::I32Add)
+            .i32_const(TABLE_SIZE as i32)
+            .binop(BinaryOp::I32RemU)
+            .i32_const(layout.table_start)
+            .binop(BinaryOp::I32Add)
+            .load(layout.memory, kind, arg)
+            .store(layout.memory, byte, arg)
+            .local_get(i)
+            .i32_const(1)
+            .binop(BinaryOp::I32Add)
+            .local_tee(i)
+            .i32_const(PAGE_SIZE as i32)
+            .binop(BinaryOp::I32LtU)
+            .br_if(start);
+    });
+
+    builder.finish(vec![page], &mut module.funcs)
+}
+
+// Drops the exports of the wrappers and the page init, like a build keeping them internal.
+// The export names stay in the name section.
+pub fn unexport_wrappers(module: &mut Module, obfuscation: &Obfuscation) {
+    let names = obfuscation
+        .loads
+        .iter()
+        .chain(obfuscation.stores.iter())
+        .map(|(name, _)| name)
+        .chain(std::iter::once(&obfuscation.page_init))
+        .collect::<Vec<_>>();
+
+    let exports = module
+        .exports
+        .iter()
+        .filter(|export| names.contains(&&export.name))
+        .map(|export| (export.id(), export.item, export.name.clone()))
+        .collect::<Vec<_>>();
+    for (id, item, name) in exports {
+        if let ExportItem::Function(func) = item {
+            module.funcs.get_mut(func).name = Some(name);
+        }
+        module.exports.delete(id);
+    }
+}
+
+// Body of a function, every sequence reachable from the entry
+struct Snapshot {
+    args: Vec<LocalId>,
+    entry: InstrSeqId,
+    seqs: Vec<(InstrSeqId, InstrSeqType, Vec<Instr>)>,
+}
+
+fn snapshot(func: &LocalFunction) -> Snapshot {
+    let mut seqs = Vec::new();
+    let mut stack = VecDeque::new();
+    stack.push_front(func.entry_block());
+
+    while let Some(seq_id) = stack.pop_back() {
+        let seq = func.block(seq_id);
+        for (instr, _) in seq.instrs.iter() {
+            match instr {
+                Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => stack.push_front(*seq),
+                Instr::IfElse(IfElse {
+                    consequent,
+                    alternative,
+                }) => {
+                    stack.push_front(*consequent);
+                    stack.push_front(*alternative);
+                }
+                _ => {}
+            }
+        }
+        seqs.push((seq_id, seq.ty, seq.instrs.iter().map(|(instr, _)| instr.clone()).collect()));
+    }
+
+    Snapshot {
+        args: func.args.clone(),
+        entry: func.entry_block(),
+        seqs,
+    }
+}
+
+// Replaces every `call wrapper` of the plain functions with the wrapper body, the
+// arguments go in fresh locals like a regular inliner would do. Returns the number of
+// inlined calls.
+pub fn inline_wrappers(module: &mut Module, obfuscation: &Obfuscation) -> Result<usize, anyhow::Error> {
+    let mut wrappers = HashMap::new();
+    for (name, _) in obfuscation.loads.iter().chain(obfuscation.stores.iter()) {
+        let id = module.exports.get_func(name)?;
+        wrappers.insert(id, snapshot(module.funcs.get(id).kind.unwrap_local()));
+    }
+    let page_init = module.exports.get_func(&obfuscation.page_init)?;
+
+    let callers = module
+        .funcs
+        .iter_local()
+        .map(|(id, _)| id)
+        .filter(|id| !wrappers.contains_key(id) && *id != page_init)
+        .collect::<Vec<_>>();
+
+    let mut count = 0;
+    for id in callers {
+        let func = module.funcs.get_mut(id).kind.unwrap_local_mut();
+        let mut stack = VecDeque::new();
+        stack.push_front(func.entry_block());
+
+        while let Some(seq_id) = stack.pop_back() {
+            let calls = func
+                .block(seq_id)
+                .instrs
+                .iter()
+                .enumerate()
+                .filter_map(|(idx, (instr, _))| match instr {
+                    Instr::Call(Call { func }) if wrappers.contains_key(func) => Some((idx, *func)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+
+            for (idx, callee) in calls.into_iter().rev() {
+                let wrapper = &wrappers[&callee];
+                let mut locals = HashMap::new();
+                let mut local = |old: LocalId| {
+                    *locals
+                        .entry(old)
+                        .or_insert_with(|| module.locals.add(module.locals.get(old).ty()))
+                };
+
+                let mut seqs = HashMap::new();
+                for (old, ty, _) in wrapper.seqs.iter().filter(|(old, _, _)| *old != wrapper.entry) {
+                    seqs.insert(*old, func.builder_mut().dangling_instr_seq(*ty).id());
+                }
+
+                let mut body = wrapper
+                    .args
+                    .iter()
+                    .rev()
+                    .map(|arg| Instr::LocalSet(LocalSet { local: local(*arg) }))
+                    .collect::<Vec<_>>();
+                for (old, _, instrs) in wrapper.seqs.iter() {
+                    let mut instrs = instrs.clone();
+                    for instr in instrs.iter_mut() {
+                        match instr {
+                            Instr::LocalGet(LocalGet { local: l })
+                            | Instr::LocalSet(LocalSet { local: l })
+                            | Instr::LocalTee(LocalTee { local: l }) => *l = local(*l),
+                            Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => *seq = seqs[seq],
+                            Instr::IfElse(IfElse {
+                                consequent,
+                                alternative,
+                            }) => {
+                                *consequent = seqs[consequent];
+                                *alternative = seqs[alternative];
+                            }
+                            Instr::Br(Br { block }) | Instr::BrIf(BrIf { block }) => {
+                                *block = *seqs.get(block).context("branch out of the wrapper")?;
+                            }
+                            Instr::Return(_) | Instr::BrTable(_) => bail!("can't inline {:?}", instr),
+                            _ => {}
+                        }
+                    }
+
+                    if *old == wrapper.entry {
+                        body.extend(instrs);
+                    } else {
+                        let seq = func.block_mut(seqs[old]);
+                        seq.instrs = instrs.into_iter().map(|instr| (instr, InstrLocId::default())).collect();
+                    }
+                }
+
+                let seq = func.block_mut(seq_id);
+                let loc = seq.instrs[idx].1;
+                seq.instrs.splice(idx..idx + 1, body.into_iter().map(|instr| (instr, loc)));
+                count += 1;
+            }
+
+            // nested sequences, the inlined ones included, have no wrapper calls left
+            for (instr, _) in func.block(seq_id).instrs.iter() {
+                match instr {
+                    Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => stack.push_front(*seq),
+                    Instr::IfElse(IfElse {
+                        consequent,
+                        alternative,
+                    }) => {
+                        stack.push_front(*consequent);
+                        stack.push_front(*alternative);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    Ok(count)
+}
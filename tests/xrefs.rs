@@ -0,0 +1,264 @@
+mod support;
+
+use hcaptcha_wasm_deobfuscator::analysis::addresses::{stack_pointer, AccessKind, Region, Target};
+use hcaptcha_wasm_deobfuscator::analysis::xrefs::XrefDb;
+use hcaptcha_wasm_deobfuscator::commands::deobfuscate;
+use hcaptcha_wasm_deobfuscator::commands::xrefs::{parse_query, query_json};
+use hcaptcha_wasm_deobfuscator::rng::Rng;
+use support::obfuscator::{obfuscate, plain_module};
+use walrus::ir::{BinaryOp, ExtendedLoad, LoadKind, MemArg, StoreKind, Value};
+use walrus::{
+    ConstExpr, DataKind, ElementItems, ElementKind, ExportItem, FunctionBuilder, FunctionId, GlobalKind, Module,
+    RefType, ValType,
+};
+
+fn export(module: &Module, name: &str) -> FunctionId {
+    match module.exports.iter().find(|e| e.name == name).unwrap().item {
+        ExportItem::Function(id) => id,
+        _ => unreachable!(),
+    }
+}
+
+fn arg(offset: u32) -> MemArg {
+    MemArg { align: 4, offset }
+}
+
+// One data segment at 0x1000 and a function per kind of address:
+// - frame(), stack pointer relative store
+// - read_const(), i32 load of 0x1004
+// - write_static(), store to 0x2000, outside of the data
+// - read_table(i), byte load of 0x1010 + i
+// - deref(p), load through a parameter
+// - dispatch(p, i), calls frame, then table slot 0 and slot `i` of [read_const, deref, read_table]
+fn xref_module() -> Module {
+    let mut module = Module::default();
+    let memory = module.memories.add_local(false, false, 1, None, None);
+    module.data.add(
+        DataKind::Active {
+            memory,
+            offset: ConstExpr::Value(Value::I32(0x1000)),
+        },
+        vec![0; 64],
+    );
+    let sp = module.globals.add_local(ValType::I32, true, false, ConstExpr::Value(Value::I32(0x8000)));
+
+    let fp = module.locals.add(ValType::I32);
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+    builder
+        .func_body()
+        .global_get(sp)
+        .i32_const(32)
+        .binop(BinaryOp::I32Sub)
+        .local_tee(fp)
+        .global_set(sp)
+        .local_get(fp)
+        .i32_const(7)
+        .store(memory, StoreKind::I32 { atomic: false }, arg(8))
+        .local_get(fp)
+        .i32_const(32)
+        .binop(BinaryOp::I32Add)
+        .global_set(sp);
+    let id = builder.finish(vec![], &mut module.funcs);
+    module.exports.add("frame", id);
+
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+    builder
+        .func_body()
+        .i32_const(0x1000)
+        .load(memory, LoadKind::I32 { atomic: false }, arg(4));
+    let id = builder.finish(vec![], &mut module.funcs);
+    module.exports.add("read_const", id);
+
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+    builder
+        .func_body()
+        .i32_const(0x2000)
+        .i32_const(1)
+        .store(memory, StoreKind::I32 { atomic: false }, arg(0));
+    let id = builder.finish(vec![], &mut module.funcs);
+    module.exports.add("write_static", id);
+
+    let i = module.locals.add(ValType::I32);
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+    builder.func_body().i32_const(0x1010).local_get(i).binop(BinaryOp::I32Add).load(
+        memory,
+        LoadKind::I32_8 {
+            kind: ExtendedLoad::ZeroExtend,
+        },
+        MemArg { align: 1, offset: 0 },
+    );
+    let id = builder.finish(vec![i], &mut module.funcs);
+    module.exports.add("read_table", id);
+
+    let p = module.locals.add(ValType::I32);
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+    builder
+        .func_body()
+        .local_get(p)
+        .load(memory, LoadKind::I32 { atomic: false }, arg(0));
+    let id = builder.finish(vec![p], &mut module.funcs);
+    module.exports.add("deref", id);
+
+    let table = module.tables.add_local(false, 3, None, RefType::Funcref);
+    let slots = ["read_const", "deref", "read_table"].map(|name| export(&module, name));
+    module.elements.add(
+        ElementKind::Active {
+            table,
+            offset: ConstExpr::Value(Value::I32(0)),
+        },
+        ElementItems::Functions(slots.to_vec()),
+    );
+
+    let (p, i) = (module.locals.add(ValType::I32), module.locals.add(ValType::I32));
+    let nullary = module.types.find(&[], &[ValType::I32]).unwrap();
+    let unary = module.types.find(&[ValType::I32], &[ValType::I32]).unwrap();
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32; 2], &[ValType::I32]);
+    builder
+        .func_body()
+        .call(export(&module, "frame"))
+        .i32_const(0)
+        .call_indirect(nullary, table)
+        .local_get(p)
+        .local_get(i)
+        .call_indirect(unary, table)
+        .binop(BinaryOp::I32Add);
+    let id = builder.finish(vec![p, i], &mut module.funcs);
+    module.exports.add("dispatch", id);
+
+    Module::from_buffer(&module.emit_wasm()).unwrap()
+}
+
+fn names(module: &Module, funcs: Vec<FunctionId>) -> Vec<String> {
+    let mut names = funcs
+        .into_iter()
+        .map(|id| {
+            module
+                .exports
+                .iter()
+                .find(|e| matches!(e.item, ExportItem::Function(f) if f == id))
+                .map_or(format!("{:?}", id), |e| e.name.clone())
+        })
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+#[test]
+fn accesses_are_resolved() {
+    let module = xref_module();
+    assert!(stack_pointer(&module).is_some());
+
+    let db = XrefDb::build(&module);
+    let access = |name: &str| {
+        let accesses = db.touched_by(export(&module, name)).collect::<Vec<_>>();
+        assert_eq!(accesses.len(), 1, "{}", name);
+        accesses[0].clone()
+    };
+
+    let frame = access("frame");
+    assert_eq!((frame.kind, frame.target, frame.region), (AccessKind::Write, Target::Stack(-24), Region::Stack));
+    assert!(frame.offset.is_some());
+
+    let read = access("read_const");
+    assert_eq!((read.kind, read.width), (AccessKind::Read, 4));
+    assert_eq!((read.target, read.region), (Target::Address(0x1004), Region::Data(0)));
+
+    let write = access("write_static");
+    assert_eq!((write.target, write.region), (Target::Address(0x2000), Region::Static));
+
+    let table = access("read_table");
+    assert_eq!((table.target, table.region), (Target::Indexed(0x1010), Region::Data(0)));
+
+    let deref = access("deref");
+    assert_eq!((deref.target, deref.region), (Target::Unknown, Region::Unknown));
+}
+
+#[test]
+fn address_queries() {
+    let module = xref_module();
+    let db = XrefDb::build(&module);
+    let readers = |address| names(&module, db.readers(address).map(|a| a.func).collect());
+    let writers = |address| names(&module, db.writers(address).map(|a| a.func).collect());
+
+    assert_eq!(readers(0x1007), ["read_const"]);
+    assert!(readers(0x1008).is_empty());
+    assert_eq!(readers(0x1010), ["read_table"]);
+    assert_eq!(readers(0x103f), ["read_table"]);
+    // an index is only followed to the end of its segment
+    assert!(readers(0x1040).is_empty());
+    assert_eq!(writers(0x2003), ["write_static"]);
+    assert!(writers(0x1004).is_empty());
+}
+
+#[test]
+fn code_queries() {
+    let module = xref_module();
+    let db = XrefDb::build(&module);
+    let dispatch = export(&module, "dispatch");
+
+    let calls = db.calls_from(dispatch).map(|c| (c.callee, c.indirect)).collect::<Vec<_>>();
+    let callees = names(&module, calls.iter().map(|(callee, _)| *callee).collect());
+    // slot 0 exactly, any function of the right type for slot `i`
+    assert_eq!(callees, ["deref", "frame", "read_const", "read_table"]);
+    assert_eq!(calls.iter().filter(|(_, indirect)| *indirect).count(), 3);
+    assert_eq!(names(&module, db.callers_of(export(&module, "frame")).map(|c| c.caller).collect()), ["dispatch"]);
+
+    let sp = stack_pointer(&module).unwrap();
+    let refs = db.global_refs(sp).map(|r| r.kind).collect::<Vec<_>>();
+    assert_eq!(refs, [AccessKind::Read, AccessKind::Write, AccessKind::Write]);
+    assert!(db.global_refs(sp).all(|r| r.func == export(&module, "frame")));
+
+    let constants = names(&module, db.constants_to(0x1010).map(|c| c.func).collect());
+    assert_eq!(constants, ["read_table"]);
+    assert!(db.constants_to(0x2000).next().is_none());
+}
+
+#[test]
+fn json_output() {
+    let module = xref_module();
+    let db = XrefDb::build(&module);
+
+    let query = parse_query(&module, "--addr", "0x1004").unwrap();
+    let json = query_json(&module, &db, &query);
+    assert!(json.starts_with(r#"{"address": 4100, "readers": [{"func": "#), "{}", json);
+    assert!(json.contains(r#"(read_const)", "offset": "#), "{}", json);
+    assert!(json.contains(r#""kind": "read", "width": 4, "target": {"address": 4100}, "region": {"data": 0}}"#), "{}", json);
+    assert!(json.ends_with(r#""writers": [], "constants": []}"#), "{}", json);
+
+    let index = export(&module, "frame").index().to_string();
+    let query = parse_query(&module, "--func", &index).unwrap();
+    let json = query_json(&module, &db, &query);
+    assert!(json.contains(r#""target": {"stack": -24}, "region": "stack""#), "{}", json);
+    assert!(json.contains(r#""callers": [{"func": "#), "{}", json);
+
+    assert!(parse_query(&module, "--global", "7").is_err());
+    assert!(parse_query(&module, "--func", "x").is_err());
+    assert!(parse_query(&module, "--table", "0").is_err());
+}
+
+#[test]
+fn deobfuscated_events_are_found() {
+    for seed in 0..4 {
+        let mut rng = Rng::new(seed);
+        let plain = plain_module(&mut rng);
+        // the only global of the plain module, the initial stack pointer the key is read at
+        let key = match plain.module.globals.iter().next().unwrap().kind {
+            GlobalKind::Local(ConstExpr::Value(Value::I32(key))) => key as u32,
+            _ => unreachable!(),
+        };
+        let mut module = plain.module;
+        obfuscate(&mut module, &mut rng).unwrap();
+
+        let mut module = Module::from_buffer(&module.emit_wasm()).unwrap();
+        deobfuscate(&mut module).unwrap();
+        let init_events = export(&module, "init_events");
+
+        // the events are decrypted in place, word by word
+        let db = XrefDb::build(&module);
+        let accesses = db.touched_by(init_events).collect::<Vec<_>>();
+        assert!(accesses.iter().all(|a| matches!(a.target, Target::Indexed(_))), "seed {}: {:?}", seed, accesses);
+        assert!(accesses.iter().all(|a| matches!(a.region, Region::Data(_))), "seed {}: {:?}", seed, accesses);
+        assert!(db.readers(key).any(|a| a.func == init_events), "seed {}", seed);
+        assert!(db.writers(key).any(|a| a.func == init_events), "seed {}", seed);
+    }
+}